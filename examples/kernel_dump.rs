@@ -0,0 +1,34 @@
+extern crate vmread;
+
+use vmread::KernelDumpWriter;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: {} <output.dmp>", args[0]);
+        return;
+    }
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (ctx, _) = ctx_ret.unwrap();
+
+        let now = std::time::Instant::now();
+        match KernelDumpWriter::new().comment("vmread live dump").save(&ctx, &args[1]) {
+            Ok(report) => {
+                println!("Wrote {:#x} pages in {:?}", report.pages, now.elapsed());
+                println!("KDBG at {:#x}{}", report.debugger_data, if report.encoded_debugger_data { " (decoded)" } else { "" });
+
+                for r in report.runs {
+                    println!("\t{:#x}-{:#x}", r.start(), r.end());
+                }
+            },
+            Err(e) => println!("Failed to write {}: {}", args[1], e),
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
@@ -0,0 +1,26 @@
+extern crate vmread;
+
+fn main() {
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+        println!("VMRead initialized!");
+
+        println!("{:>18} {:>8} {:>4} {:>6} {:<24} {}", "BASE ADDRESS", "SIZE", "SESS", "EXPRTS", "DRIVER OBJECT", "PATH");
+        for i in ctx.refresh_kernel_modules().kernel_module_list.iter_mut() {
+            i.refresh_exports(&c_ctx);
+
+            println!("{:#18x} {:#8x} {:>4} {:>6} {:<24} {}",
+                i.base,
+                i.size,
+                i.session_id.map(|s| s.to_string()).unwrap_or_default(),
+                i.export_list.len(),
+                i.driver_object.as_ref().map(|d| d.name.as_str()).unwrap_or(""),
+                i.path);
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
@@ -0,0 +1,44 @@
+extern crate vmread;
+
+use vmread::MinidumpWriter;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 3 {
+        println!("Usage: {} <process> <output.dmp> [--minimal]", args[0]);
+        return;
+    }
+
+    let writer = MinidumpWriter::new()
+        .full_memory(args.get(3).map(|s| s != "--minimal").unwrap_or(true));
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+
+        match ctx.refresh_processes().process_by_name_mut(&args[1]) {
+            Some(p) => {
+                p.refresh_modules(c_ctx);
+
+                let now = std::time::Instant::now();
+                match writer.save(&c_ctx, p, &args[2]) {
+                    Ok(report) => {
+                        println!("Wrote {} modules, {} threads, {} ranges ({:#x} bytes) in {:?}", report.modules,
+                            report.threads, report.memory_ranges, report.memory_bytes, now.elapsed());
+
+                        for (start, end) in report.skipped {
+                            println!("\tNot present: {:#x}-{:#x}", start, end);
+                        }
+                    },
+                    Err(e) => println!("Failed to write {}: {}", args[2], e),
+                }
+            },
+            _ => println!("Process {} not found!", args[1])
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
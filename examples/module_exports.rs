@@ -15,7 +15,7 @@ fn main() {
                     match proc_name.trim() {
                         "q" => break,
                         s => {
-                            match ctx.refresh_processes().process_list.iter_mut().find(|a| a.name == s) {
+                            match ctx.refresh_processes().process_by_name_mut(s) {
                                 Some(p) => {
                                     println!("Module list for {}", s);
                                     println!("{:#14} {:#14} {:#8} {:#6} {}", "BASE ADDRESS", "ENTRY POINT", "SIZE", "LOADC", "NAME");
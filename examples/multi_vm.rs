@@ -0,0 +1,25 @@
+extern crate vmread;
+
+use vmread::{ContextOptions, VmEvent, VmManager};
+
+fn main() {
+    let mut manager = VmManager::new(ContextOptions::default());
+
+    loop {
+        for event in manager.refresh() {
+            match event {
+                VmEvent::Attached(vm) => println!("Attached to {} ({})", vm.pid, vm.name.unwrap_or_default()),
+                VmEvent::AttachFailed { vm, error } => println!("Failed to attach to {}: {}", vm.pid, error),
+                VmEvent::Detached(vm) => println!("Detached from {}", vm.pid),
+                VmEvent::Health { vm, event } => println!("{}: {:?}", vm.pid, event),
+            }
+        }
+
+        manager.for_each(|vm| {
+            let count = vm.context.refresh_processes().process_list.len();
+            println!("{}\t{} processes", vm.info.pid, count);
+        });
+
+        std::thread::sleep(std::time::Duration::from_secs(5));
+    }
+}
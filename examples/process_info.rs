@@ -0,0 +1,28 @@
+extern crate vmread;
+
+fn main() {
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+        println!("VMRead initialized!");
+
+        println!("{:>6} {:>6} {:>4} {:>6} {:>10} {:>5} {}", "PID", "PPID", "SESS", "HNDL", "WS (KB)", "WOW64", "COMMAND LINE");
+        for i in &ctx.refresh_processes().process_list {
+            let info = i.info(&c_ctx);
+            let cmdline = i.process_parameters(&c_ctx).map(|p| p.command_line).unwrap_or_else(|| i.name.clone());
+
+            println!("{:>6} {:>6} {:>4} {:>6} {:>10} {:>5} {}",
+                info.pid,
+                info.parent_pid.map(|p| p.to_string()).unwrap_or_default(),
+                info.session_id.map(|s| s.to_string()).unwrap_or_default(),
+                info.handle_count.map(|h| h.to_string()).unwrap_or_default(),
+                info.working_set.unwrap_or(0) / 1024,
+                info.is_wow64,
+                cmdline);
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
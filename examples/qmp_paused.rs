@@ -0,0 +1,58 @@
+extern crate vmread;
+
+use vmread::QmpClient;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: {} <qmp socket>", args[0]);
+        return;
+    }
+
+    let mut qmp = match QmpClient::connect(&args[1]) {
+        Ok(q) => q,
+        Err(e) => {
+            println!("Failed to connect to {}: {}", args[1], e);
+            return;
+        }
+    };
+
+    let version = qmp.version();
+    println!("Connected to qemu {}.{}.{}", version.major, version.minor, version.micro);
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, _) = ctx_ret.unwrap();
+
+        if let Ok(map) = qmp.memory_map(ctx.c_ctx().process.mapsStart) {
+            ctx.set_memory_map(map);
+        }
+
+        for r in ctx.memory_map().map(|m| m.ranges()).unwrap_or(&[]) {
+            println!("RAM {:#x}-{:#x}", r.start, r.end);
+        }
+
+        let ret = qmp.with_paused(&mut ctx, |ctx| {
+            ctx.refresh_processes().process_list.iter()
+                .map(|p| (p.proc.pid, p.name.clone()))
+                .collect::<Vec<_>>()
+        });
+
+        let list = match ret {
+            Ok(list) => Some(list),
+            Err(e) => {
+                println!("{}", e);
+                e.into_result()
+            },
+        };
+
+        for (pid, name) in list.unwrap_or_default() {
+            println!("{:#6x}\t{}", pid, name);
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
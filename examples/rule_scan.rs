@@ -0,0 +1,64 @@
+extern crate vmread;
+
+use vmread::Rules;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 3 {
+        println!("Usage: {} <rules file> <process> [module]", args[0]);
+        return;
+    }
+
+    let rules = match std::fs::read_to_string(&args[1]).map(|s| s.parse::<Rules>()) {
+        Ok(Ok(r)) => r,
+        Ok(Err(e)) => {
+            println!("Invalid rules: {}", e);
+            return;
+        },
+        Err(e) => {
+            println!("Failed to read {}: {}", args[1], e);
+            return;
+        }
+    };
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+
+        match ctx.refresh_processes().process_by_name_mut(&args[2]) {
+            Some(p) => {
+                p.refresh_modules(c_ctx);
+
+                let now = std::time::Instant::now();
+                let matches = match args.get(3) {
+                    Some(name) => match p.module_by_name(name) {
+                        Some(m) => rules.scan_module(&p.address_space(&c_ctx), m),
+                        _ => {
+                            println!("Module {} not found!", name);
+                            return;
+                        }
+                    },
+                    None => rules.scan_process(&c_ctx, p),
+                };
+                println!("Scanned in {:?}", now.elapsed());
+
+                for m in matches {
+                    println!("{} {:?}", m.rule, m.tags);
+
+                    for s in m.strings {
+                        let symbol = p.module_by_address(s.address)
+                            .map(|d| format!(" ({}+{:#x})", d.name, s.address - d.info.baseAddress))
+                            .unwrap_or_default();
+                        println!("\t{:#x}{} {} [{}]", s.address, symbol, s.id, s.length);
+                    }
+                }
+            },
+            _ => println!("Process {} not found!", args[2])
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
@@ -0,0 +1,50 @@
+extern crate vmread;
+
+use vmread::{Pattern, Scanner};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 4 {
+        println!("Usage: {} <process> <module> <pattern>", args[0]);
+        return;
+    }
+
+    let pattern = match Pattern::parse(&args[3..].join(" ")) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Invalid pattern: {}", e);
+            return;
+        }
+    };
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+        println!("VMRead initialized!");
+
+        match ctx.refresh_processes().process_by_name_mut(&args[1]) {
+            Some(p) => {
+                p.refresh_modules(c_ctx);
+
+                match p.module_by_name(&args[2]) {
+                    Some(m) => {
+                        let now = std::time::Instant::now();
+                        let matches = Scanner::new(p.address_space(&c_ctx)).find_all_in_module(&pattern, m);
+                        println!("Scanned {} in {:?}", m.name, now.elapsed());
+
+                        for i in matches {
+                            println!("{:#x} ({}+{:#x})", i, m.name, i - m.info.baseAddress);
+                        }
+                    },
+                    _ => println!("Module {} not found!", args[2])
+                }
+            },
+            _ => println!("Process {} not found!", args[1])
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
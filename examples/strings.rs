@@ -0,0 +1,61 @@
+extern crate vmread;
+
+use vmread::{StringEncoding, StringExtractor};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: {} <process> [min length]", args[0]);
+        println!("       {} -p <start> <end> [min length]", args[0]);
+        return;
+    }
+
+    let physical = args[1] == "-p";
+
+    let parse_hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+
+    let min_len_arg = if physical { 4 } else { 2 };
+    let extractor = StringExtractor::new()
+        .min_length(args.get(min_len_arg).and_then(|s| s.parse().ok()).unwrap_or(4));
+
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (mut ctx, c_ctx) = ctx_ret.unwrap();
+
+        let mut print = |s: vmread::FoundString| {
+            let enc = match s.encoding {
+                StringEncoding::Ascii => "A",
+                StringEncoding::Utf16Le => "U",
+            };
+
+            match (s.module, s.region) {
+                (Some(m), _) => println!("{:#x} {} [{}] {}", s.address, enc, m, s.text),
+                (None, Some(r)) => println!("{:#x} {} [{:#x}{}{}] {}", s.address, enc, r.start,
+                    if r.writable { " rw" } else { " r" }, if r.executable { "x" } else { "" }, s.text),
+                _ => println!("{:#x} {} {}", s.address, enc, s.text),
+            }
+
+            true
+        };
+
+        if physical {
+            match (args.get(2).and_then(|s| parse_hex(s)), args.get(3).and_then(|s| parse_hex(s))) {
+                (Some(start), Some(end)) => extractor.extract_physical(&ctx, start, end, &mut print),
+                _ => println!("Invalid physical range!"),
+            }
+        } else {
+            match ctx.refresh_processes().process_by_name_mut(&args[1]) {
+                Some(p) => {
+                    p.refresh_modules(c_ctx);
+                    extractor.extract_process(&c_ctx, p, &mut print);
+                },
+                _ => println!("Process {} not found!", args[1])
+            }
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
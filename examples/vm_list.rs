@@ -0,0 +1,9 @@
+extern crate vmread;
+
+fn main() {
+    println!("PID\tMEMORY\t\tUUID\t\t\t\t\tNAME");
+    for vm in vmread::discover_vms() {
+        println!("{}\t{:#12x}\t{:36}\t{}", vm.pid, vm.memory_size.unwrap_or(0),
+            vm.uuid.as_deref().unwrap_or("-"), vm.name.as_deref().unwrap_or("-"));
+    }
+}
@@ -0,0 +1,30 @@
+extern crate vmread;
+
+fn main() {
+    let ctx_ret = vmread::create_context(0);
+
+    if ctx_ret.is_ok() {
+        let (ctx, _) = ctx_ret.unwrap();
+        println!("VMRead initialized!");
+
+        match ctx.windows_info() {
+            Some(info) => {
+                println!("Windows {}.{}.{}.{} ({})", info.major, info.minor, info.build, info.ubr.unwrap_or(0), info.edition());
+                println!("Kernel: {:#x} ({:#x} bytes)", info.kernel_base, info.kernel_size);
+                println!("Processors: {}", info.processor_count);
+                println!("Boot time: {:?}", info.boot_time);
+            },
+            _ => println!("Failed to read KUSER_SHARED_DATA!")
+        }
+
+        for name in &["PsLoadedModuleList", "PsInitialSystemProcess"] {
+            match ctx.kernel_export(name) {
+                Some(address) => println!("{}: {:#x}", name, address),
+                _ => println!("{}: not found", name)
+            }
+        }
+    } else {
+        let (eval, estr) = ctx_ret.err().unwrap();
+        println!("Initialization error {}: {}", eval, estr);
+    }
+}
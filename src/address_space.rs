@@ -0,0 +1,576 @@
+use crate::rwlist::*;
+use crate::memory_map::*;
+use crate::trace::*;
+use crate::tlb::*;
+use crate::backend::*;
+
+/// Kernel virtual address of `KUSER_SHARED_DATA`
+pub const KUSER_SHARED_DATA: u64 = 0xffff_f780_0000_0000;
+
+/// Mask of the physical address bits in page table entries
+const PTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Lowest address of the kernel half of the canonical address space
+pub const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+/// Highest address of the user half of the canonical address space (exclusive)
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// A virtually contiguous range of present pages sharing the same protection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+}
+
+impl MemoryRegion {
+    /// Get the address right past the end of the region
+    ///
+    /// Saturates at `u64::MAX` for a region reaching the top of the address space.
+    pub fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+
+    /// Check whether the region contains the address
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end()
+    }
+}
+
+/// A view of either physical VM memory, or a single virtual address space
+///
+/// Contrary to `WinProcess` functions, reads performed through the view report failures, which is
+/// needed when walking structures that may be paged out.
+///
+/// Reads go through the vmread C library, unless a backend or a trace recorder is attached. Virtual
+/// addresses are then translated by the view itself, with physical reads of the page tables.
+#[derive(Clone, Copy)]
+pub struct AddressSpace<'a> {
+    ctx: &'a sys::WinCtx,
+    dir_base: u64,
+    memory_map: Option<&'a MemoryMap>,
+    trace: Option<&'a TraceRecorder>,
+    backend: Option<&'a dyn PhysicalMemory>,
+}
+
+impl<'a> AddressSpace<'a> {
+    /// Create a view of the physical VM memory, without validation against the memory map
+    ///
+    /// Public code gets physical views through `WinContext::physical`, which applies the map.
+    pub(crate) fn physical(ctx: &'a sys::WinCtx) -> AddressSpace<'a> {
+        AddressSpace {
+            ctx: ctx,
+            dir_base: 0,
+            memory_map: None,
+            trace: None,
+            backend: None,
+        }
+    }
+
+    /// Create a view of the virtual address space described by `dir_base`
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `dir_base` - virtual address translation entry point. 0 for physical address mode
+    pub fn virt(ctx: &'a sys::WinCtx, dir_base: u64) -> AddressSpace<'a> {
+        AddressSpace {
+            ctx: ctx,
+            dir_base: dir_base,
+            memory_map: None,
+            trace: None,
+            backend: None,
+        }
+    }
+
+    /// Create a view of the kernel address space, as seen by the system process
+    pub fn kernel(ctx: &'a sys::WinCtx) -> AddressSpace<'a> {
+        Self::virt(ctx, ctx.initialProcess.dirBase)
+    }
+
+    /// Validate physical accesses against a guest memory map
+    ///
+    /// Physical reads outside of guest RAM fail without being attempted, and physical streaming
+    /// skips over non-RAM ranges. Page table walks are validated as well.
+    pub fn with_memory_map(mut self, memory_map: &'a MemoryMap) -> Self {
+        self.memory_map = Some(memory_map);
+        self
+    }
+
+    /// Get the memory map physical accesses are validated against
+    pub fn memory_map(&self) -> Option<&'a MemoryMap> {
+        self.memory_map
+    }
+
+    /// Record successful physical reads, including the ones made by page table walks
+    ///
+    /// Virtual addresses are translated by the view, so that the page table reads are recorded.
+    pub fn with_trace(mut self, trace: &'a TraceRecorder) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Serve physical reads from a backend instead of the vmread C library
+    ///
+    /// Virtual addresses are translated by the view. Writes through `rwlist` are not performed.
+    pub fn with_backend(mut self, backend: &'a dyn PhysicalMemory) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Get the backend physical reads are served from, `None` for the vmread C library
+    pub fn backend(&self) -> Option<&'a dyn PhysicalMemory> {
+        self.backend
+    }
+
+    /// Check whether the view translates virtual addresses by itself
+    fn walks_tables(&self) -> bool {
+        self.backend.is_some() || self.trace.is_some()
+    }
+
+    /// Get the underlying vmread C context
+    pub fn ctx(&self) -> &'a sys::WinCtx {
+        self.ctx
+    }
+
+    /// Get the translation entry point, 0 in physical mode
+    pub fn dir_base(&self) -> u64 {
+        self.dir_base
+    }
+
+    /// Check whether the view is of physical memory
+    pub fn is_physical(&self) -> bool {
+        self.dir_base == 0
+    }
+
+    /// Get a read/write list operating on this address space
+    ///
+    /// With a backend or a trace recorder attached, reads are made one by one through the view.
+    pub fn rwlist(&self) -> RWList<'a> {
+        let ret = match self.memory_map {
+            Some(map) => RWList::new(self.ctx, self.dir_base).with_memory_map(map),
+            None => RWList::new(self.ctx, self.dir_base),
+        };
+
+        if self.walks_tables() {
+            ret.with_address_space(*self)
+        } else {
+            ret
+        }
+    }
+
+    /// Translate a virtual address to a physical one
+    ///
+    /// Returns `None` if the page is not present. In physical mode the address is returned as is.
+    pub fn translate(&self, address: u64) -> Option<u64> {
+        if self.is_physical() {
+            return Some(address);
+        }
+
+        if self.walks_tables() {
+            return self.walk_translate(address);
+        }
+
+        let _time = enter_vm(&self.ctx.process);
+
+        match unsafe { sys::VTranslate(&self.ctx.process, self.dir_base, address) } {
+            0 => None,
+            a => Some(a),
+        }
+    }
+
+    /// Read a value of type `T`
+    ///
+    /// Returns `None` if the memory could not be read in full.
+    pub fn read<T>(&self, address: u64) -> Option<T> {
+        let mut ret : T = unsafe { std::mem::zeroed() };
+
+        if self.read_raw(address, &mut ret as *mut T as u64, std::mem::size_of::<T>()) {
+            Some(ret)
+        } else {
+            None
+        }
+    }
+
+    /// Read an array of values of type `T`
+    ///
+    /// Returns `false` if the memory could not be read in full.
+    pub fn read_arr<T>(&self, address: u64, out: &mut [T]) -> bool {
+        self.read_raw(address, out.as_mut_ptr() as u64, std::mem::size_of_val(out))
+    }
+
+    /// Read as many pages of the range as possible
+    ///
+    /// Unreadable pages are left untouched in `out`. Returns the number of bytes read.
+    pub fn read_sparse(&self, address: u64, out: &mut [u8]) -> usize {
+        let mut done = 0;
+        let mut off = 0;
+
+        while off < out.len() {
+            let cur = address + off as u64;
+            let len = ((0x1000 - (cur & 0xfff)) as usize).min(out.len() - off);
+
+            if self.read_arr(cur, &mut out[off..(off + len)]) {
+                done += len;
+            }
+
+            off += len;
+        }
+
+        done
+    }
+
+    /// Read a buffer with a single batch of page sized reads
+    ///
+    /// This is considerably faster than `read_sparse` for large buffers. Unreadable pages are left
+    /// untouched in `out`.
+    pub fn read_batched(&self, address: u64, out: &mut [u8]) {
+        let mut rwlist = self.rwlist();
+        let mut rest = out;
+        let mut cur = address;
+
+        while !rest.is_empty() {
+            let len = ((0x1000 - (cur & 0xfff)) as usize).min(rest.len());
+            let (page, tail) = rest.split_at_mut(len);
+            rwlist.read_arr(cur, page);
+            rest = tail;
+            cur += len as u64;
+        }
+
+        rwlist.commit_read();
+    }
+
+    /// Stream the readable memory of a range in large chunks
+    ///
+    /// In virtual address spaces only present pages are read. Consecutive chunks overlap by
+    /// `overlap` bytes, so that matches of up to `overlap + 1` bytes spanning a chunk boundary are
+    /// seen in full. The callback receives the chunk address, its data (unreadable pages zeroed),
+    /// and the number of leading bytes that are not repeated in the next chunk. Streaming stops
+    /// when the callback returns `false`.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    /// * `chunk_size` - number of new bytes per chunk
+    /// * `overlap` - number of bytes shared with the next chunk
+    /// * `f` - chunk callback
+    pub fn for_each_chunk(&self, start: u64, end: u64, chunk_size: usize, overlap: usize, f: &mut dyn FnMut(u64, &[u8], usize) -> bool) {
+        let chunk_size = chunk_size.max(0x1000);
+
+        let ranges = if self.is_physical() {
+            match self.memory_map {
+                Some(map) => map.clip(start, end),
+                None => vec![(start, end)],
+            }
+        } else {
+            // Merge adjacent regions of different protection, so that data spanning them is contiguous
+            let mut ranges: Vec<(u64, u64)> = vec![];
+            for r in self.regions(start, end) {
+                match ranges.last_mut() {
+                    Some(last) if last.1 == r.start => last.1 = r.end().min(end),
+                    _ => ranges.push((r.start.max(start), r.end().min(end))),
+                }
+            }
+            ranges
+        };
+
+        let mut buf = vec![0u8; chunk_size + overlap];
+
+        for (range_start, range_end) in ranges {
+            let mut cur = range_start;
+
+            while cur < range_end {
+                let len = ((range_end - cur) as usize).min(chunk_size + overlap);
+                let is_last = len < chunk_size + overlap || cur + len as u64 == range_end;
+                let chunk = &mut buf[..len];
+
+                chunk.iter_mut().for_each(|b| *b = 0);
+                self.read_batched(cur, chunk);
+
+                if !f(cur, chunk, if is_last { len } else { chunk_size }) {
+                    return;
+                }
+
+                if is_last {
+                    break;
+                }
+
+                cur += chunk_size as u64;
+            }
+        }
+    }
+
+    fn read_raw(&self, address: u64, local: u64, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+
+        if !self.is_physical() && self.walks_tables() {
+            return self.read_translated(address, local, size);
+        }
+
+        if let Some(map) = self.memory_map.filter(|_| self.is_physical()) {
+            if !map.contains(address, size as u64) {
+                return false;
+            }
+        }
+
+        let ok = match self.backend.filter(|_| self.is_physical()) {
+            Some(backend) => backend.read_physical(address, unsafe { std::slice::from_raw_parts_mut(local as *mut u8, size) }),
+            None => {
+                let ret = unsafe {
+                    if self.is_physical() {
+                        sys::MemRead(&self.ctx.process, local, address, size as u64)
+                    } else {
+                        let _time = enter_vm(&self.ctx.process);
+                        sys::VMemRead(&self.ctx.process, self.dir_base, local, address, size as u64)
+                    }
+                };
+
+                ret as i64 == size as i64
+            },
+        };
+
+        if let Some(trace) = self.trace.filter(|_| ok && self.is_physical()) {
+            trace.record(address, unsafe { std::slice::from_raw_parts(local as *const u8, size) });
+        }
+
+        ok
+    }
+
+    /// Read virtual memory page by page, translating each page with a page table walk
+    fn read_translated(&self, address: u64, local: u64, size: usize) -> bool {
+        let physical = AddressSpace { dir_base: 0, ..*self };
+        let mut off = 0;
+
+        while off < size {
+            let cur = address.wrapping_add(off as u64);
+            let len = ((0x1000 - (cur & 0xfff)) as usize).min(size - off);
+
+            match self.walk_translate(cur) {
+                Some(phys) if physical.read_raw(phys, local + off as u64, len) => off += len,
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Translate a virtual address by walking the 4-level x86_64 page tables
+    fn walk_translate(&self, address: u64) -> Option<u64> {
+        let physical = AddressSpace { dir_base: 0, ..*self };
+        let mut table = self.dir_base & PTE_ADDRESS_MASK;
+
+        for level in (1..=4).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let entry = physical.read::<u64>(table + ((address >> shift) & 0x1ff) * 8)?;
+
+            if entry & 1 == 0 {
+                return None;
+            }
+
+            if level == 1 || ((level == 2 || level == 3) && entry & 0x80 != 0) {
+                let offset_mask = (1u64 << shift) - 1;
+                return Some((entry & PTE_ADDRESS_MASK & !offset_mask) | (address & offset_mask));
+            }
+
+            table = entry & PTE_ADDRESS_MASK;
+        }
+
+        None
+    }
+
+    /// Read a pointer sized value
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address to read the pointer from
+    /// * `is_64bit` - whether the pointer is 8 or 4 bytes long
+    pub fn read_ptr(&self, address: u64, is_64bit: bool) -> Option<u64> {
+        if is_64bit {
+            self.read::<u64>(address)
+        } else {
+            self.read::<u32>(address).map(|v| v as u64)
+        }
+    }
+
+    /// Read a null terminated ASCII string of at most `max_len` bytes
+    pub fn read_cstr(&self, address: u64, max_len: usize) -> Option<String> {
+        let mut buf = vec![0u8; max_len];
+
+        // The string may end right before an unmapped page
+        let page_left = (0x1000 - (address & 0xfff)) as usize;
+        if !self.read_arr(address, &mut buf) && !self.read_arr(address, &mut buf[..page_left.min(max_len)]) {
+            return None;
+        }
+
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    /// Read a UTF-16 string of `len` bytes
+    pub fn read_utf16(&self, address: u64, len: usize) -> Option<String> {
+        let mut buf = vec![0u16; len / 2];
+
+        if self.read_arr(address, &mut buf) {
+            Some(String::from_utf16_lossy(&buf))
+        } else {
+            None
+        }
+    }
+
+    /// Read a `UNICODE_STRING` structure and the string it points to
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address of the `UNICODE_STRING`
+    /// * `is_64bit` - whether the structure is `UNICODE_STRING` or `UNICODE_STRING32`
+    pub fn read_unicode_string(&self, address: u64, is_64bit: bool) -> Option<String> {
+        let len = self.read::<u16>(address)? as usize;
+        let buffer = self.read_ptr(address + if is_64bit { 8 } else { 4 }, is_64bit)?;
+
+        if len == 0 || buffer == 0 {
+            return Some(String::new());
+        }
+
+        self.read_utf16(buffer, len)
+    }
+
+    /// Enumerate present pages of the address space by walking the page tables
+    ///
+    /// Only 4-level x86_64 paging is supported. Pages that are paged out, or in transition are
+    /// not reported. Returns an empty list in physical mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - lowest address of interest
+    /// * `end` - highest address of interest (exclusive)
+    pub fn regions(&self, start: u64, end: u64) -> Vec<MemoryRegion> {
+        let mut ret = vec![];
+
+        if !self.is_physical() {
+            self.walk_table(self.dir_base & PTE_ADDRESS_MASK, 4, 0, &WalkFilter { start: start, end: end, present: true }, (true, true, true), &mut ret);
+        }
+
+        ret
+    }
+
+    /// Enumerate present user-mode pages
+    pub fn user_regions(&self) -> Vec<MemoryRegion> {
+        self.regions(0, USER_SPACE_END)
+    }
+
+    /// Enumerate committed pages that are not present, i.e. paged out, or in transition
+    ///
+    /// These are found through non-zero entries without the present bit, which Windows uses as
+    /// software PTEs. Committed memory that has never been touched may have no entry at all, and is
+    /// not reported. Protection flags of the returned regions are not meaningful. Returns an empty
+    /// list in physical mode.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - lowest address of interest
+    /// * `end` - highest address of interest (exclusive)
+    pub fn non_present_regions(&self, start: u64, end: u64) -> Vec<MemoryRegion> {
+        let mut ret = vec![];
+
+        if !self.is_physical() {
+            self.walk_table(self.dir_base & PTE_ADDRESS_MASK, 4, 0, &WalkFilter { start: start, end: end, present: false }, (true, true, true), &mut ret);
+        }
+
+        ret
+    }
+
+    fn walk_table(&self, table: u64, level: u32, base: u64, filter: &WalkFilter, flags: (bool, bool, bool), out: &mut Vec<MemoryRegion>) {
+        let mut entries = [0u64; 512];
+
+        let physical = AddressSpace { dir_base: 0, ..*self };
+
+        if !physical.read_arr(table, &mut entries) {
+            return;
+        }
+
+        let shift = 12 + 9 * (level - 1);
+        let size = 1u64 << shift;
+
+        for (i, &entry) in entries.iter().enumerate() {
+            let mut address = base | ((i as u64) << shift);
+
+            // Sign extend bit 47 to get a canonical address
+            if address & USER_SPACE_END != 0 {
+                address |= KERNEL_SPACE_START;
+            }
+
+            if address >= filter.end || address.saturating_add(size) <= filter.start {
+                continue;
+            }
+
+            if entry & 1 == 0 {
+                // A paged out table covers non-present pages as a whole
+                if !filter.present && entry != 0 {
+                    push_region(out, address, size, (false, false, flags.2));
+                }
+                continue;
+            }
+
+            let entry_flags = (
+                flags.0 && entry & 0x2 != 0,
+                flags.1 && entry >> 63 == 0,
+                flags.2 && entry & 0x4 != 0,
+            );
+
+            let is_large = level == 2 || level == 3;
+
+            if level == 1 || (is_large && entry & 0x80 != 0) {
+                if filter.present {
+                    push_region(out, address, size, entry_flags);
+                }
+            } else {
+                self.walk_table(entry & PTE_ADDRESS_MASK, level - 1, address, filter, entry_flags, out);
+            }
+        }
+    }
+}
+
+/// Pages of interest to a page table walk
+struct WalkFilter {
+    start: u64,
+    end: u64,
+    /// Whether present pages are wanted, or non-present ones with a software PTE
+    present: bool,
+}
+
+/// Sort and merge overlapping, or adjacent ranges
+pub(crate) fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut ret: Vec<(u64, u64)> = vec![];
+
+    for (s, e) in ranges.into_iter().filter(|r| r.0 < r.1) {
+        match ret.last_mut() {
+            Some(last) if s <= last.1 => last.1 = last.1.max(e),
+            _ => ret.push((s, e)),
+        }
+    }
+
+    ret
+}
+
+/// Append a page range, merging it with the last region if they are contiguous and alike
+fn push_region(out: &mut Vec<MemoryRegion>, address: u64, size: u64, flags: (bool, bool, bool)) {
+    match out.last_mut() {
+        Some(last) if last.end() == address && (last.writable, last.executable, last.user) == flags => {
+            last.size += size;
+        },
+        _ => out.push(MemoryRegion {
+            start: address,
+            size: size,
+            writable: flags.0,
+            executable: flags.1,
+            user: flags.2,
+        }),
+    }
+}
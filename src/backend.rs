@@ -0,0 +1,43 @@
+//! Sources of guest physical memory other than the vmread C library
+//!
+//! An `AddressSpace` given a `PhysicalMemory` backend serves its physical reads from it, and
+//! translates virtual addresses by walking the page tables itself, instead of calling into the C
+//! library. This lets the code built on address spaces run on recorded traces (`TraceReplay`), or
+//! on plain memory images.
+
+use std::convert::TryFrom;
+
+/// Read-only guest physical memory
+pub trait PhysicalMemory {
+    /// Read physical memory into a buffer
+    ///
+    /// Returns `false` if the memory could not be read in full.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - guest physical address
+    /// * `out` - buffer to fill
+    fn read_physical(&self, address: u64, out: &mut [u8]) -> bool;
+}
+
+/// Memory image starting at guest physical address 0
+impl PhysicalMemory for [u8] {
+    fn read_physical(&self, address: u64, out: &mut [u8]) -> bool {
+        let data = usize::try_from(address).ok()
+            .and_then(|start| self.get(start..start.checked_add(out.len())?));
+
+        match data {
+            Some(data) => {
+                out.copy_from_slice(data);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl PhysicalMemory for Vec<u8> {
+    fn read_physical(&self, address: u64, out: &mut [u8]) -> bool {
+        self.as_slice().read_physical(address, out)
+    }
+}
@@ -0,0 +1,100 @@
+use crate::win_export::*;
+use crate::address_space::*;
+use crate::pe::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Identity of a module image
+///
+/// System DLLs are mapped from the same file in every process, thus images with the same name,
+/// link timestamp and size are considered to have the same exports.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ExportCacheKey {
+    /// Lowercase module name
+    pub name: String,
+    pub time_date_stamp: u32,
+    pub size_of_image: u32,
+    pub is_64bit: bool,
+}
+
+/// Cache of parsed export lists, shared across processes
+///
+/// Exports are stored relative to the image base, so they stay valid for images that got mapped
+/// at different addresses.
+#[derive(Clone, Debug, Default)]
+pub struct ExportCache {
+    entries: HashMap<ExportCacheKey, Arc<Vec<(u32, String)>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ExportCache {
+    pub fn new() -> ExportCache {
+        ExportCache::default()
+    }
+
+    /// Get the exports of an image, parsing them only if the image has not been seen before
+    ///
+    /// Returns `None` if the image headers, or its export directory could not be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space the image is mapped in
+    /// * `name` - module name
+    /// * `base` - base address of the image
+    pub fn exports(&mut self, mem: &AddressSpace, name: &str, base: u64) -> Option<Vec<WinExport>> {
+        let headers = PeHeaders::parse(mem, base)?;
+
+        let key = ExportCacheKey {
+            name: name.to_lowercase(),
+            time_date_stamp: headers.time_date_stamp,
+            size_of_image: headers.size_of_image,
+            is_64bit: headers.is_64bit,
+        };
+
+        let rvas = match self.entries.get(&key) {
+            Some(e) => {
+                self.hits += 1;
+                e.clone()
+            },
+            None => {
+                let exports = headers.exports(mem)?;
+                let rvas = Arc::new(exports.into_iter()
+                    .map(|e| ((e.address - base) as u32, e.name))
+                    .collect::<Vec<_>>());
+
+                self.misses += 1;
+                self.entries.insert(key, rvas.clone());
+                rvas
+            },
+        };
+
+        Some(rvas.iter().map(|(rva, name)| WinExport {
+            name: name.clone(),
+            address: base + *rva as u64,
+        }).collect())
+    }
+
+    /// Number of cached images
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of lookups served from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups that had to parse the image
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
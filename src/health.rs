@@ -0,0 +1,186 @@
+//! Health checks of contexts
+//!
+//! A context caches the kernel base, the system process and its page table base found at
+//! initialization. Once the guest reboots, all of them change and the cached values point to
+//! garbage, while a restarted qemu process leaves the context without a process to read from.
+//! The checks here detect both cases, `WinContext::poll_health` uses them to reinitialize the
+//! context automatically.
+
+use crate::offsets::*;
+use crate::vm_discovery::*;
+use crate::win_context::*;
+use crate::tlb::*;
+use std::time::{Duration, Instant};
+
+/// `_DISPATCHER_HEADER::Type` of process objects
+const PROCESS_OBJECT_TYPE: u8 = 3;
+
+/// PID of the system process
+const SYSTEM_PROCESS_ID: u64 = 4;
+
+/// Reason a context is no longer valid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HealthIssue {
+    /// The qemu process has exited, or its pid has been reused by another process
+    VmExited { pid: i32 },
+    /// The system process no longer looks like one, i.e. the guest has rebooted
+    SystemProcessInvalid { address: u64 },
+    /// The page table base of the system process has changed
+    DirBaseChanged { old: u64, new: u64 },
+    /// The kernel image is no longer mapped at its base address
+    KernelMoved { base: u64 },
+}
+
+impl std::fmt::Display for HealthIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HealthIssue::VmExited { pid } => write!(f, "qemu process {} has exited", pid),
+            HealthIssue::SystemProcessInvalid { address } => write!(f, "system process at {:#x} is no longer valid", address),
+            HealthIssue::DirBaseChanged { old, new } => write!(f, "system page table base changed from {:#x} to {:#x}", old, new),
+            HealthIssue::KernelMoved { base } => write!(f, "kernel is no longer mapped at {:#x}", base),
+        }
+    }
+}
+
+impl std::error::Error for HealthIssue {}
+
+/// Change in the health of a context
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HealthEvent {
+    /// The context has become invalid. Reported once until it is recovered
+    Unhealthy(HealthIssue),
+    /// The context has been reinitialized. Previously listed processes and modules are gone
+    Recovered {
+        pid: i32,
+        kernel_base: u64,
+        dir_base: u64,
+    },
+    /// Reinitialization failed. Reported again only if the error changes
+    RecoveryFailed(ContextError),
+}
+
+/// Check whether the qemu process behind a context is still the same one
+///
+/// # Arguments
+///
+/// * `pid` - pid of the qemu process
+/// * `vm` - the process as it was discovered at initialization, if it was recognized as qemu
+pub(crate) fn check_vm_process(pid: i32, vm: Option<&VmInfo>) -> Result<(), HealthIssue> {
+    let alive = match vm {
+        Some(vm) => discover_vm_in(PROC_ROOT, pid).as_ref() == Some(vm),
+        None => std::path::Path::new(PROC_ROOT).join(pid.to_string()).exists(),
+    };
+
+    if alive {
+        Ok(())
+    } else {
+        Err(HealthIssue::VmExited { pid: pid })
+    }
+}
+
+fn read_phys<T>(ctx: &sys::WinCtx, address: u64) -> Option<T> {
+    let size = std::mem::size_of::<T>() as u64;
+    let mut ret : T = unsafe { std::mem::zeroed() };
+
+    let done = unsafe {
+        sys::MemRead(&ctx.process, &mut ret as *mut T as u64, address, size)
+    };
+
+    if done as i64 == size as i64 {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+/// Validate the kernel state cached in a C context against guest memory
+///
+/// The system process has to still be a process object with PID 4 named `System`, using the
+/// same page table base, and the kernel image has to still be mapped at its base.
+///
+/// # Arguments
+///
+/// * `ctx` - vmread C context
+/// * `offsets` - structure offsets of the guest
+pub(crate) fn check_system_process(ctx: &sys::WinCtx, offsets: &Offsets) -> Result<(), HealthIssue> {
+    let system = &ctx.initialProcess;
+    let invalid = HealthIssue::SystemProcessInvalid { address: system.process };
+
+    if read_phys::<u8>(ctx, system.physProcess) != Some(PROCESS_OBJECT_TYPE) {
+        return Err(invalid);
+    }
+
+    if let Some(off) = offsets.eprocess.unique_process_id {
+        if read_phys::<u64>(ctx, system.physProcess + off as u64) != Some(SYSTEM_PROCESS_ID) {
+            return Err(invalid);
+        }
+    }
+
+    if let Some(off) = offsets.eprocess.image_file_name {
+        match read_phys::<[u8; 7]>(ctx, system.physProcess + off as u64) {
+            Some(name) if &name == b"System\0" => {},
+            _ => return Err(invalid),
+        }
+    }
+
+    if let Some(off) = offsets.kprocess.directory_table_base {
+        // The low bits may hold the PCID, and are not part of the address
+        let dir_base = read_phys::<u64>(ctx, system.physProcess + off as u64).ok_or(invalid)?;
+
+        if dir_base & !0xfff != system.dirBase & !0xfff {
+            return Err(HealthIssue::DirBaseChanged { old: system.dirBase, new: dir_base });
+        }
+    }
+
+    let mut mz = 0u16;
+    let done = unsafe {
+        let _time = enter_vm(&ctx.process);
+        sys::VMemRead(&ctx.process, system.dirBase, &mut mz as *mut u16 as u64, ctx.ntKernel, 2)
+    };
+
+    if done as i64 != 2 || mz != 0x5a4d {
+        return Err(HealthIssue::KernelMoved { base: ctx.ntKernel });
+    }
+
+    Ok(())
+}
+
+/// Rate limiter of context health checks
+///
+/// Checking is cheap, but still takes a few reads of guest memory. Long-running tools can poll the
+/// monitor in their main loop, and it only checks the context once the interval has passed.
+#[derive(Clone, Debug)]
+pub struct HealthMonitor {
+    interval: Duration,
+    last_check: Option<Instant>,
+}
+
+impl HealthMonitor {
+    /// Create a monitor, the first poll always performs a check
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - minimum time between checks
+    pub fn new(interval: Duration) -> HealthMonitor {
+        HealthMonitor {
+            interval: interval,
+            last_check: None,
+        }
+    }
+
+    /// Check the context health if the interval has passed, recovering it if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - context to check
+    pub fn poll(&mut self, ctx: &mut WinContext) -> Vec<HealthEvent> {
+        let now = Instant::now();
+
+        if self.last_check.map(|t| now.duration_since(t) < self.interval).unwrap_or(false) {
+            return vec![];
+        }
+
+        self.last_check = Some(now);
+        ctx.poll_health()
+    }
+}
@@ -0,0 +1,110 @@
+use crate::win_process::*;
+use crate::win_dll::*;
+use std::collections::HashMap;
+
+/// Lookup index over a process list
+///
+/// Stores positions into the list it was built from. Names are matched case-insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessIndex {
+    by_name: HashMap<String, Vec<usize>>,
+    by_pid: HashMap<u64, usize>,
+    len: usize,
+}
+
+impl ProcessIndex {
+    pub fn new(processes: &[WinProcess]) -> ProcessIndex {
+        let mut ret = ProcessIndex {
+            len: processes.len(),
+            ..Default::default()
+        };
+
+        for (i, p) in processes.iter().enumerate() {
+            ret.by_name.entry(p.name.to_lowercase()).or_default().push(i);
+            ret.by_pid.insert(p.proc.pid, i);
+        }
+
+        ret
+    }
+
+    /// Get the positions of all processes with the given name
+    pub fn by_name(&self, name: &str) -> &[usize] {
+        self.by_name.get(&name.to_lowercase()).map(|v| &v[..]).unwrap_or(&[])
+    }
+
+    /// Get the position of the process with the given PID
+    pub fn by_pid(&self, pid: u64) -> Option<usize> {
+        self.by_pid.get(&pid).cloned()
+    }
+
+    /// Get the length of the list the index was built from
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Lookup index over a module list
+///
+/// Stores positions into the list it was built from. Names are matched case-insensitively, and
+/// addresses are resolved with a binary search over the sorted module ranges.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleIndex {
+    by_name: HashMap<String, usize>,
+    /// `(start, end, position)`, sorted by start
+    ranges: Vec<(u64, u64, usize)>,
+}
+
+impl ModuleIndex {
+    pub fn new(modules: &[WinDll]) -> ModuleIndex {
+        let mut ret = ModuleIndex::default();
+
+        for (i, m) in modules.iter().enumerate() {
+            // Keep the first one, which is the 64-bit module in WoW64 processes
+            ret.by_name.entry(m.name.to_lowercase()).or_insert(i);
+            ret.ranges.push((m.info.baseAddress, m.info.baseAddress + m.info.sizeOfModule, i));
+        }
+
+        ret.ranges.sort();
+
+        ret
+    }
+
+    /// Get the position of the module with the given name
+    pub fn by_name(&self, name: &str) -> Option<usize> {
+        self.by_name.get(&name.to_lowercase()).cloned()
+    }
+
+    /// Get the position of the module containing the given address
+    pub fn by_address(&self, address: u64) -> Option<usize> {
+        let idx = self.ranges.partition_point(|r| r.0 <= address);
+        let (_, end, pos) = self.ranges[idx.checked_sub(1)?];
+        Some(pos).filter(|_| address < end)
+    }
+}
+
+/// Validate a position looked up in an index against the list
+///
+/// The indexed lists are public and may have been modified since the index was built, thus the
+/// position is searched for linearly whenever it is stale, or the index has no entry at all.
+pub(crate) fn checked_position<T>(list: &[T], index: Option<usize>, pred: impl Fn(&T) -> bool) -> Option<usize> {
+    match index {
+        Some(i) if list.get(i).is_some_and(&pred) => Some(i),
+        _ => list.iter().position(pred),
+    }
+}
+
+/// Validate all positions looked up in an index against the list
+///
+/// Works like `checked_position`, but for lookups matching multiple items. The list is scanned if
+/// any of the positions is stale, there are none, or the list length has changed.
+pub(crate) fn checked_positions<T>(list: &[T], indexed_len: usize, index: &[usize], pred: impl Fn(&T) -> bool) -> Vec<usize> {
+    if indexed_len == list.len() && !index.is_empty() && index.iter().all(|&i| list.get(i).is_some_and(&pred)) {
+        index.to_vec()
+    } else {
+        list.iter().enumerate().filter(|(_, v)| pred(v)).map(|(i, _)| i).collect()
+    }
+}
@@ -0,0 +1,501 @@
+//! Full kernel memory dumps of the guest
+//!
+//! `KernelDumpWriter` produces `PAGEDU64` full memory dumps, the format Windows writes to
+//! `MEMORY.DMP`, so that crash dump tooling can open live VMs. The dump header references the
+//! kernel debugger data block (`KDBG`). Since Windows 8 the block is encoded in memory unless a
+//! kernel debugger is enabled. It is located and decoded by known plaintext, and the decoded copy
+//! is substituted into the dumped physical memory.
+//!
+//! Physical memory runs are taken from the guest's own memory map (`MmPhysicalMemoryBlock`).
+
+use crate::address_space::*;
+use crate::pe::*;
+use crate::win_context::*;
+use crate::win_info::*;
+use std::io::Write;
+use std::path::Path;
+
+/// `OwnerTag` of the debugger data block, `"KDBG"`
+pub const KDBG_OWNER_TAG: u32 = 0x4742_444b;
+
+/// Offsets inside `KDDEBUGGER_DATA64`, unchanged since Windows XP
+const KDBG_HEADER: usize = 0x10;
+const KDBG_KERN_BASE: usize = 0x18;
+const KDBG_PS_LOADED_MODULE_LIST: usize = 0x48;
+const KDBG_PS_ACTIVE_PROCESS_HEAD: usize = 0x50;
+const KDBG_MM_PFN_DATABASE: usize = 0xc0;
+const KDBG_KI_PROCESSOR_BLOCK: usize = 0x218;
+const KDBG_MM_PHYSICAL_MEMORY_BLOCK: usize = 0x270;
+/// Smallest block holding all of the fields above
+const KDBG_MIN_SIZE: usize = 0x278;
+const KDBG_MAX_SIZE: usize = 0x1000;
+
+/// `_KPRCB.ProcessorState.ContextFrame`
+const KPRCB_CONTEXT_FRAME: u64 = 0x130;
+const CONTEXT_AMD64_SIZE: usize = 0x4d0;
+
+/// Upper bound of runs to accept from the guest memory map
+const MAX_MEMORY_RUNS: u32 = 0x100;
+
+const DUMP_HEADER_SIZE: usize = 0x2000;
+const DUMP_SIGNATURE: u32 = 0x4547_4150;
+const DUMP_VALID_DUMP64: u32 = 0x3436_5544;
+/// Major version of dumps written by free builds
+const DUMP_MAJOR_VERSION: u32 = 0xf;
+const DUMP_TYPE_FULL: u32 = 1;
+const IMAGE_FILE_MACHINE_AMD64: u32 = 0x8664;
+/// Bug check code of dumps taken from running systems
+const LIVE_SYSTEM_DUMP: u32 = 0x161;
+
+/// Offsets inside `DUMP_HEADER64`
+const DUMP_HEADER_DIRECTORY_TABLE_BASE: usize = 0x10;
+const DUMP_HEADER_PFN_DATABASE: usize = 0x18;
+const DUMP_HEADER_PS_LOADED_MODULE_LIST: usize = 0x20;
+const DUMP_HEADER_PS_ACTIVE_PROCESS_HEAD: usize = 0x28;
+const DUMP_HEADER_MACHINE_IMAGE_TYPE: usize = 0x30;
+const DUMP_HEADER_NUMBER_PROCESSORS: usize = 0x34;
+const DUMP_HEADER_BUG_CHECK_CODE: usize = 0x38;
+const DUMP_HEADER_BUG_CHECK_PARAMETERS: usize = 0x40;
+const DUMP_HEADER_VERSION_USER: usize = 0x60;
+const DUMP_HEADER_KD_DEBUGGER_DATA_BLOCK: usize = 0x80;
+const DUMP_HEADER_PHYSICAL_MEMORY_BLOCK: usize = 0x88;
+const DUMP_HEADER_CONTEXT_RECORD: usize = 0x348;
+const DUMP_HEADER_EXCEPTION_RECORD: usize = 0xf00;
+const DUMP_HEADER_DUMP_TYPE: usize = 0xf98;
+const DUMP_HEADER_REQUIRED_DUMP_SPACE: usize = 0xfa0;
+const DUMP_HEADER_SYSTEM_TIME: usize = 0xfa8;
+const DUMP_HEADER_COMMENT: usize = 0xfb0;
+const DUMP_HEADER_SYSTEM_UP_TIME: usize = 0x1030;
+const DUMP_HEADER_PRODUCT_TYPE: usize = 0x1040;
+const DUMP_HEADER_SUITE_MASK: usize = 0x1044;
+const DUMP_HEADER_COMMENT_LEN: usize = 0x80;
+const DUMP_HEADER_EXCEPTION_RECORD_SIZE: usize = 0x98;
+/// Number of runs fitting into `DUMP_HEADER64.PhysicalMemoryBlockBuffer`
+const DUMP_HEADER_MAX_RUNS: usize = 42;
+
+/// `KUSER_SHARED_DATA.InterruptTime` and `SystemTime`
+const KUSER_INTERRUPT_TIME: u64 = 0x8;
+const KUSER_SYSTEM_TIME: u64 = 0x14;
+
+/// Default number of pages read per batch
+pub const DEFAULT_KERNEL_DUMP_CHUNK_PAGES: usize = 0x400;
+
+/// Error produced when writing kernel dumps
+#[derive(Debug)]
+pub enum KernelDumpError {
+    Io(std::io::Error),
+    /// The kernel debugger data block could not be found, or decoded
+    DebuggerDataNotFound,
+    /// `MmPhysicalMemoryBlock` could not be read
+    MemoryMapUnavailable,
+}
+
+impl std::fmt::Display for KernelDumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KernelDumpError::Io(e) => write!(f, "kernel dump I/O error: {}", e),
+            KernelDumpError::DebuggerDataNotFound => write!(f, "kernel debugger data block not found"),
+            KernelDumpError::MemoryMapUnavailable => write!(f, "guest physical memory map unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for KernelDumpError {}
+
+impl From<std::io::Error> for KernelDumpError {
+    fn from(e: std::io::Error) -> KernelDumpError {
+        KernelDumpError::Io(e)
+    }
+}
+
+fn qword(buf: &[u8], off: usize) -> u64 {
+    let v = &buf[off..off + 8];
+    u64::from_le_bytes([v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7]])
+}
+
+/// Decoded kernel debugger data block (`KDDEBUGGER_DATA64`)
+#[derive(Clone, Debug)]
+pub struct KdDebuggerData {
+    /// Virtual address of the block inside the kernel image
+    pub address: u64,
+    /// Whether the block is encoded in guest memory
+    pub encoded: bool,
+    /// Decoded contents of the block
+    pub data: Vec<u8>,
+}
+
+impl KdDebuggerData {
+    /// Locate the debugger data block in the data sections of the kernel image
+    ///
+    /// Every quadword `x` of an encoded block decodes to the plaintext `bswap(rol(x, r) ^ c)`,
+    /// with both `r` and `c` derived from kernel secrets. As `KernBase` and `PsLoadedModuleList` are known,
+    /// both values are recovered by comparing the XOR of the two quadwords, the `"KDBG"` owner tag
+    /// confirms the match.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn find(ctx: &sys::WinCtx) -> Option<KdDebuggerData> {
+        let kernel = AddressSpace::kernel(ctx);
+        let headers = PeHeaders::parse(&kernel, ctx.ntKernel)?;
+        let loaded_modules = find_kernel_export(ctx, "PsLoadedModuleList")?;
+        let known = (ctx.ntKernel ^ loaded_modules).swap_bytes();
+
+        for section in headers.sections.iter().filter(|s| !s.is_executable()) {
+            let start = ctx.ntKernel + section.virtual_address as u64;
+            let mut buf = vec![0u8; section.virtual_size as usize];
+            kernel.read_sparse(start, &mut buf);
+
+            for off in (0..buf.len().saturating_sub(KDBG_MIN_SIZE)).step_by(8) {
+                let header = qword(&buf, off + KDBG_HEADER);
+                let max_size = (buf.len() - off).min(KDBG_MAX_SIZE);
+
+                if header as u32 == KDBG_OWNER_TAG && qword(&buf, off + KDBG_KERN_BASE) == ctx.ntKernel {
+                    let size = (header >> 32) as usize;
+
+                    if (KDBG_MIN_SIZE..=max_size).contains(&size) {
+                        return Some(KdDebuggerData {
+                            address: start + off as u64,
+                            encoded: false,
+                            data: buf[off..off + size].to_vec(),
+                        });
+                    }
+                }
+
+                let kern_base = qword(&buf, off + KDBG_KERN_BASE);
+                let diff = kern_base ^ qword(&buf, off + KDBG_PS_LOADED_MODULE_LIST);
+
+                if diff == 0 {
+                    continue;
+                }
+
+                for r in (0..64).filter(|&r| diff.rotate_left(r) == known) {
+                    let key = kern_base.rotate_left(r) ^ ctx.ntKernel.swap_bytes();
+                    let decode = |x: u64| (x.rotate_left(r) ^ key).swap_bytes();
+
+                    let header = decode(header);
+                    let size = (header >> 32) as usize;
+
+                    if header as u32 != KDBG_OWNER_TAG || size & 7 != 0 || !(KDBG_MIN_SIZE..=max_size).contains(&size) {
+                        continue;
+                    }
+
+                    let data = (0..size).step_by(8)
+                        .flat_map(|o| decode(qword(&buf, off + o)).to_le_bytes().to_vec())
+                        .take(size)
+                        .collect();
+
+                    return Some(KdDebuggerData {
+                        address: start + off as u64,
+                        encoded: true,
+                        data: data,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Read a pointer sized field of the block
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - offset of the field inside `KDDEBUGGER_DATA64`
+    pub fn field(&self, offset: usize) -> Option<u64> {
+        Some(offset).filter(|o| o + 8 <= self.data.len()).map(|o| qword(&self.data, o))
+    }
+
+    pub fn kern_base(&self) -> u64 {
+        qword(&self.data, KDBG_KERN_BASE)
+    }
+
+    /// Address of the `PsLoadedModuleList` list head
+    pub fn ps_loaded_module_list(&self) -> u64 {
+        qword(&self.data, KDBG_PS_LOADED_MODULE_LIST)
+    }
+
+    /// Address of the `PsActiveProcessHead` list head
+    pub fn ps_active_process_head(&self) -> u64 {
+        qword(&self.data, KDBG_PS_ACTIVE_PROCESS_HEAD)
+    }
+
+    /// Address of the `MmPfnDatabase` variable
+    pub fn mm_pfn_database(&self) -> u64 {
+        qword(&self.data, KDBG_MM_PFN_DATABASE)
+    }
+
+    /// Address of the `KiProcessorBlock` array of `_KPRCB` pointers
+    pub fn ki_processor_block(&self) -> u64 {
+        qword(&self.data, KDBG_KI_PROCESSOR_BLOCK)
+    }
+
+    /// Address of the `MmPhysicalMemoryBlock` variable
+    pub fn mm_physical_memory_block(&self) -> u64 {
+        qword(&self.data, KDBG_MM_PHYSICAL_MEMORY_BLOCK)
+    }
+}
+
+/// A run of physical pages in the guest memory map
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhysicalMemoryRun {
+    pub base_page: u64,
+    pub page_count: u64,
+}
+
+impl PhysicalMemoryRun {
+    /// Physical start address of the run
+    pub fn start(&self) -> u64 {
+        self.base_page << 12
+    }
+
+    /// Physical end address of the run (exclusive)
+    pub fn end(&self) -> u64 {
+        (self.base_page + self.page_count) << 12
+    }
+}
+
+/// Read the physical memory runs the guest kernel manages, from `MmPhysicalMemoryBlock`
+///
+/// Returns `None` if the `PHYSICAL_MEMORY_DESCRIPTOR` could not be read.
+///
+/// # Arguments
+///
+/// * `ctx` - vmread C context
+/// * `kdbg` - debugger data block of the guest
+pub fn physical_memory_runs(ctx: &sys::WinCtx, kdbg: &KdDebuggerData) -> Option<Vec<PhysicalMemoryRun>> {
+    let kernel = AddressSpace::kernel(ctx);
+    let descriptor = kernel.read::<u64>(kdbg.mm_physical_memory_block()).filter(|&d| d != 0)?;
+    let count = kernel.read::<u32>(descriptor)?;
+
+    if count == 0 || count > MAX_MEMORY_RUNS {
+        return None;
+    }
+
+    let mut runs = vec![[0u64; 2]; count as usize];
+    if !kernel.read_arr(descriptor + 0x10, &mut runs) {
+        return None;
+    }
+
+    Some(runs.into_iter().map(|[base_page, page_count]| PhysicalMemoryRun {
+        base_page: base_page,
+        page_count: page_count,
+    }).filter(|r| r.page_count != 0).collect())
+}
+
+/// Summary of a written kernel dump
+#[derive(Clone, Debug)]
+pub struct KernelDumpReport {
+    /// Virtual address of the debugger data block referenced by the dump
+    pub debugger_data: u64,
+    /// Whether the block had to be decoded
+    pub encoded_debugger_data: bool,
+    /// Runs of the guest memory map
+    pub runs: Vec<PhysicalMemoryRun>,
+    /// Number of pages written, including gaps between runs merged to fit the dump header
+    pub pages: u64,
+}
+
+/// Writer of `PAGEDU64` full memory dumps
+#[derive(Clone, Debug)]
+pub struct KernelDumpWriter {
+    comment: String,
+    chunk_pages: usize,
+}
+
+impl Default for KernelDumpWriter {
+    fn default() -> KernelDumpWriter {
+        KernelDumpWriter {
+            comment: String::new(),
+            chunk_pages: DEFAULT_KERNEL_DUMP_CHUNK_PAGES,
+        }
+    }
+}
+
+impl KernelDumpWriter {
+    pub fn new() -> KernelDumpWriter {
+        KernelDumpWriter::default()
+    }
+
+    /// Set the comment stored in the dump header, truncated to 127 bytes
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = comment.to_string();
+        self
+    }
+
+    /// Set the number of pages read per batch
+    pub fn chunk_pages(mut self, chunk_pages: usize) -> Self {
+        self.chunk_pages = chunk_pages.max(1);
+        self
+    }
+
+    /// Write a kernel dump to a file
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context
+    /// * `path` - output file path
+    pub fn save<P: AsRef<Path>>(&self, ctx: &WinContext, path: P) -> Result<KernelDumpReport, KernelDumpError> {
+        self.write(ctx, std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Write a kernel dump
+    ///
+    /// The guest keeps running while its memory is dumped, thus the dump is not fully consistent
+    /// unless the VM is paused.
+    ///
+    /// # Arguments
+    ///
+    /// * `win_ctx` - target context, physical reads are validated against its memory map
+    /// * `out` - output stream
+    pub fn write<W: Write>(&self, win_ctx: &WinContext, mut out: W) -> Result<KernelDumpReport, KernelDumpError> {
+        let ctx = win_ctx.c_ctx();
+        let kdbg = KdDebuggerData::find(ctx).ok_or(KernelDumpError::DebuggerDataNotFound)?;
+        let runs = physical_memory_runs(ctx, &kdbg).ok_or(KernelDumpError::MemoryMapUnavailable)?;
+        let dump_runs = merge_runs(&runs, DUMP_HEADER_MAX_RUNS);
+        let pages = dump_runs.iter().map(|r| r.page_count).sum::<u64>();
+
+        out.write_all(&self.header(ctx, &kdbg, &dump_runs, pages))?;
+
+        let kernel = AddressSpace::kernel(ctx);
+        let physical = win_ctx.physical();
+
+        // The decoded block replaces the encoded one in the dumped memory
+        let patches = if kdbg.encoded {
+            let mut ret = vec![];
+            let mut off = 0;
+
+            while off < kdbg.data.len() {
+                let va = kdbg.address + off as u64;
+                let len = ((0x1000 - (va & 0xfff)) as usize).min(kdbg.data.len() - off);
+
+                if let Some(pa) = kernel.translate(va) {
+                    ret.push((pa, &kdbg.data[off..off + len]));
+                }
+
+                off += len;
+            }
+
+            ret
+        } else {
+            vec![]
+        };
+
+        let mut buf = vec![0u8; self.chunk_pages << 12];
+
+        for run in &dump_runs {
+            let mut cur = run.start();
+
+            while cur < run.end() {
+                let chunk = &mut buf[..((run.end() - cur) as usize).min(self.chunk_pages << 12)];
+                let chunk_end = cur + chunk.len() as u64;
+                chunk.iter_mut().for_each(|b| *b = 0);
+
+                // Gaps between merged runs are not RAM, and stay zeroed
+                for r in runs.iter().filter(|r| r.start() < chunk_end && cur < r.end()) {
+                    let start = r.start().max(cur);
+                    let end = r.end().min(chunk_end);
+                    physical.read_batched(start, &mut chunk[(start - cur) as usize..(end - cur) as usize]);
+                }
+
+                for (pa, data) in patches.iter().filter(|(pa, _)| *pa >= cur && *pa < chunk_end) {
+                    let off = (pa - cur) as usize;
+                    let len = data.len().min(chunk.len() - off);
+                    chunk[off..off + len].copy_from_slice(&data[..len]);
+                }
+
+                out.write_all(chunk)?;
+                cur = chunk_end;
+            }
+        }
+
+        out.flush()?;
+
+        Ok(KernelDumpReport {
+            debugger_data: kdbg.address,
+            encoded_debugger_data: kdbg.encoded,
+            runs: runs,
+            pages: pages,
+        })
+    }
+
+    fn header(&self, ctx: &sys::WinCtx, kdbg: &KdDebuggerData, runs: &[PhysicalMemoryRun], pages: u64) -> Vec<u8> {
+        let kernel = AddressSpace::kernel(ctx);
+        let info = WindowsInfo::new(ctx);
+
+        // Unused parts of the header are filled with the signature, as Windows does
+        let mut header = DUMP_SIGNATURE.to_le_bytes().iter().cycle().take(DUMP_HEADER_SIZE).cloned().collect::<Vec<u8>>();
+        let mut put = |offset: usize, v: &[u8]| header[offset..offset + v.len()].copy_from_slice(v);
+
+        put(0x4, &DUMP_VALID_DUMP64.to_le_bytes());
+        put(0x8, &DUMP_MAJOR_VERSION.to_le_bytes());
+        put(0xc, &ctx.ntBuild.to_le_bytes());
+        put(DUMP_HEADER_DIRECTORY_TABLE_BASE, &ctx.initialProcess.dirBase.to_le_bytes());
+        put(DUMP_HEADER_PFN_DATABASE, &kernel.read::<u64>(kdbg.mm_pfn_database()).unwrap_or(0).to_le_bytes());
+        put(DUMP_HEADER_PS_LOADED_MODULE_LIST, &kdbg.ps_loaded_module_list().to_le_bytes());
+        put(DUMP_HEADER_PS_ACTIVE_PROCESS_HEAD, &kdbg.ps_active_process_head().to_le_bytes());
+        put(DUMP_HEADER_MACHINE_IMAGE_TYPE, &IMAGE_FILE_MACHINE_AMD64.to_le_bytes());
+        put(DUMP_HEADER_NUMBER_PROCESSORS, &info.as_ref().map(|i| i.processor_count).unwrap_or(1).to_le_bytes());
+        put(DUMP_HEADER_BUG_CHECK_CODE, &LIVE_SYSTEM_DUMP.to_le_bytes());
+        put(DUMP_HEADER_BUG_CHECK_PARAMETERS, &[0u8; 0x20]);
+        put(DUMP_HEADER_VERSION_USER, &[0u8; 0x20]);
+        put(DUMP_HEADER_KD_DEBUGGER_DATA_BLOCK, &kdbg.address.to_le_bytes());
+
+        // PHYSICAL_MEMORY_DESCRIPTOR64
+        put(DUMP_HEADER_PHYSICAL_MEMORY_BLOCK, &(runs.len() as u32).to_le_bytes());
+        put(DUMP_HEADER_PHYSICAL_MEMORY_BLOCK + 0x4, &[0u8; 4]);
+        put(DUMP_HEADER_PHYSICAL_MEMORY_BLOCK + 0x8, &pages.to_le_bytes());
+        for (i, r) in runs.iter().enumerate() {
+            let offset = DUMP_HEADER_PHYSICAL_MEMORY_BLOCK + 0x10 + i * 0x10;
+            put(offset, &r.base_page.to_le_bytes());
+            put(offset + 0x8, &r.page_count.to_le_bytes());
+        }
+
+        // The context last saved by the first processor, stale unless the guest has been frozen
+        let mut context = [0u8; CONTEXT_AMD64_SIZE];
+        if let Some(prcb) = kernel.read::<u64>(kdbg.ki_processor_block()).filter(|&p| p != 0) {
+            kernel.read_arr(prcb + KPRCB_CONTEXT_FRAME, &mut context);
+        }
+        put(DUMP_HEADER_CONTEXT_RECORD, &context);
+        put(DUMP_HEADER_EXCEPTION_RECORD, &[0u8; DUMP_HEADER_EXCEPTION_RECORD_SIZE]);
+
+        put(DUMP_HEADER_DUMP_TYPE, &DUMP_TYPE_FULL.to_le_bytes());
+        put(DUMP_HEADER_REQUIRED_DUMP_SPACE, &(DUMP_HEADER_SIZE as u64 + (pages << 12)).to_le_bytes());
+        put(DUMP_HEADER_SYSTEM_TIME, &kernel.read::<u64>(KUSER_SHARED_DATA + KUSER_SYSTEM_TIME).unwrap_or(0).to_le_bytes());
+        put(DUMP_HEADER_SYSTEM_UP_TIME, &kernel.read::<u64>(KUSER_SHARED_DATA + KUSER_INTERRUPT_TIME).unwrap_or(0).to_le_bytes());
+
+        let mut comment = [0u8; DUMP_HEADER_COMMENT_LEN];
+        let len = self.comment.len().min(DUMP_HEADER_COMMENT_LEN - 1);
+        comment[..len].copy_from_slice(&self.comment.as_bytes()[..len]);
+        put(DUMP_HEADER_COMMENT, &comment);
+
+        if let Some(i) = &info {
+            put(DUMP_HEADER_PRODUCT_TYPE, &match i.product_type {
+                ProductType::Workstation => 1u32,
+                ProductType::DomainController => 2,
+                ProductType::Server => 3,
+                ProductType::Unknown(v) => v,
+            }.to_le_bytes());
+            put(DUMP_HEADER_SUITE_MASK, &i.suite_mask.to_le_bytes());
+        }
+
+        header
+    }
+}
+
+/// Merge the runs separated by the smallest gaps until at most `max_runs` remain
+fn merge_runs(runs: &[PhysicalMemoryRun], max_runs: usize) -> Vec<PhysicalMemoryRun> {
+    let mut ret = runs.to_vec();
+    ret.sort_unstable_by_key(|r| r.base_page);
+
+    while ret.len() > max_runs {
+        let i = (0..ret.len() - 1)
+            .min_by_key(|&i| ret[i + 1].base_page.saturating_sub(ret[i].base_page + ret[i].page_count))
+            .unwrap_or(0);
+
+        let next = ret.remove(i + 1);
+        ret[i].page_count = (next.base_page + next.page_count).max(ret[i].base_page + ret[i].page_count) - ret[i].base_page;
+    }
+
+    ret
+}
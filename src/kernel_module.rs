@@ -0,0 +1,260 @@
+use crate::win_process::*;
+use crate::win_export::*;
+use crate::address_space::*;
+use crate::offsets::*;
+use crate::ldr::*;
+use crate::pe::*;
+
+/// Offset of the object body inside `_OBJECT_HEADER`
+const OBJECT_HEADER_BODY: u64 = 0x30;
+/// Offset of `_OBJECT_HEADER.InfoMask`
+const OBJECT_HEADER_INFO_MASK: u64 = 0x1a;
+const OBJECT_HEADER_CREATOR_INFO_SIZE: u64 = 0x20;
+const OBJECT_HEADER_NAME_INFO_SIZE: u64 = 0x20;
+/// Number of hash buckets in `_OBJECT_DIRECTORY`
+const OBJECT_DIRECTORY_BUCKETS: usize = 37;
+/// Upper bound of entries to walk in a single directory bucket
+const MAX_BUCKET_ENTRIES: usize = 0x1000;
+
+/// `IO_TYPE_DRIVER`, the value of `_DRIVER_OBJECT.Type`
+const IO_TYPE_DRIVER: u16 = 4;
+
+/// Object directories holding driver objects
+const DRIVER_DIRECTORIES: [&str; 2] = ["Driver", "FileSystem"];
+
+/// A `_DRIVER_OBJECT` found in the object manager namespace
+#[derive(Clone, Debug, Default)]
+pub struct DriverObject {
+    /// Address of the `_DRIVER_OBJECT`
+    pub address: u64,
+    /// Full driver name, i.e. `\Driver\Tcpip`
+    pub name: String,
+    pub driver_start: u64,
+    pub driver_size: u64,
+    /// Address of the `_LDR_DATA_TABLE_ENTRY` of the driver image
+    pub driver_section: u64,
+}
+
+/// A module loaded in kernel space
+#[derive(Clone, Debug, Default)]
+pub struct KernelModule {
+    /// Address of the module's entry in `PsLoadedModuleList`
+    pub entry: u64,
+    pub base: u64,
+    pub size: u64,
+    pub entry_point: u64,
+    pub name: String,
+    /// Full path, as stored by the loader, i.e. `\SystemRoot\system32\drivers\tcpip.sys`
+    pub path: String,
+    /// Session the image was found in. `None` for images mapped in global kernel space
+    pub session_id: Option<u32>,
+    /// Directory table base of the address space the image is accessible in
+    pub dir_base: u64,
+    pub driver_object: Option<DriverObject>,
+    pub export_list: Vec<WinExport>,
+}
+
+impl KernelModule {
+    /// Walk the kernel module list
+    ///
+    /// Images that are not accessible from the system process (session-space drivers like
+    /// `win32kbase.sys`) get resolved through a process that has a session.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `list_head` - address of `PsLoadedModuleList`
+    /// * `offsets` - resolved offsets of the context
+    /// * `processes` - process list used to find session processes
+    pub fn list(ctx: &sys::WinCtx, list_head: u64, offsets: &Offsets, processes: &[WinProcess]) -> Option<Vec<KernelModule>> {
+        let kernel = AddressSpace::kernel(ctx);
+        let entries = walk_ldr_list(&kernel, list_head, true, &offsets.ldr)?;
+        let drivers = driver_objects(ctx, offsets);
+
+        // Processes in a session, with the first process of every session in front
+        let mut session_procs = processes.iter()
+            .filter_map(|p| Some((p.session_id(ctx)?, p.proc.dirBase)))
+            .collect::<Vec<_>>();
+        session_procs.sort_by_key(|&(s, _)| s);
+        session_procs.dedup_by_key(|&mut (s, _)| s);
+
+        let readable = |dir_base: u64, base: u64| AddressSpace::virt(ctx, dir_base).read::<u16>(base).is_some();
+
+        let ret = entries.into_iter().map(|e| {
+            let (session_id, dir_base) = if readable(kernel.dir_base(), e.base) {
+                (None, kernel.dir_base())
+            } else {
+                session_procs.iter()
+                    .find(|&&(_, d)| readable(d, e.base))
+                    .map(|&(s, d)| (Some(s), d))
+                    .unwrap_or((None, kernel.dir_base()))
+            };
+
+            KernelModule {
+                driver_object: drivers.iter().find(|d| d.driver_section == e.entry || d.driver_start == e.base).cloned(),
+                entry: e.entry,
+                base: e.base,
+                size: e.size,
+                entry_point: e.entry_point,
+                name: e.name,
+                path: e.path,
+                session_id: session_id,
+                dir_base: dir_base,
+                export_list: vec![],
+            }
+        }).collect();
+
+        Some(ret)
+    }
+
+    /// Get a view of the address space the module is accessible in
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn address_space<'a>(&self, ctx: &'a sys::WinCtx) -> AddressSpace<'a> {
+        AddressSpace::virt(ctx, self.dir_base)
+    }
+
+    /// Check whether an address lies inside of the module image
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.size
+    }
+
+    /// Parse the PE headers of the module
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn headers(&self, ctx: &sys::WinCtx) -> Option<PeHeaders> {
+        PeHeaders::parse(&self.address_space(ctx), self.base)
+    }
+
+    /// Refresh the export list of the module
+    ///
+    /// The list is left empty if the export directory is not readable, which may happen when it
+    /// is paged out.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn refresh_exports(&mut self, ctx: &sys::WinCtx) -> &mut Self {
+        let mem = self.address_space(ctx);
+
+        self.export_list = PeHeaders::parse(&mem, self.base)
+            .and_then(|h| h.exports(&mem))
+            .unwrap_or_default();
+
+        self
+    }
+}
+
+/// Enumerate the driver objects in `\Driver` and `\FileSystem`
+///
+/// The root of the object namespace is not exported, thus it is found through the parent directory
+/// of `\GLOBAL??`, which is referenced by the device map of the system process.
+///
+/// # Arguments
+///
+/// * `ctx` - vmread C context
+/// * `offsets` - resolved offsets of the context
+pub fn driver_objects(ctx: &sys::WinCtx, offsets: &Offsets) -> Vec<DriverObject> {
+    let kernel = AddressSpace::kernel(ctx);
+
+    let root = (|| {
+        let device_map = kernel.read::<u64>(ctx.initialProcess.process + offsets.eprocess.device_map? as u64)?;
+        let global = kernel.read::<u64>(device_map + offsets.device_map.dos_devices_directory? as u64)?;
+        object_name_info(&kernel, global).map(|(dir, _)| dir).filter(|&d| d != 0)
+    })();
+
+    let root = match root {
+        Some(r) => r,
+        None => return vec![],
+    };
+
+    let root_entries = directory_objects(&kernel, root);
+    let mut ret = vec![];
+
+    for dir_name in DRIVER_DIRECTORIES.iter() {
+        let dir = match root_entries.iter().find(|(_, n)| n.eq_ignore_ascii_case(dir_name)) {
+            Some(&(d, _)) => d,
+            None => continue,
+        };
+
+        for (object, name) in directory_objects(&kernel, dir) {
+            if let Some(mut d) = read_driver_object(&kernel, object, offsets) {
+                if d.name.is_empty() {
+                    d.name = format!("\\{}\\{}", dir_name, name);
+                }
+                ret.push(d);
+            }
+        }
+    }
+
+    ret
+}
+
+/// Get the containing directory and the name of a named object
+fn object_name_info(kernel: &AddressSpace, object: u64) -> Option<(u64, String)> {
+    let header = object.wrapping_sub(OBJECT_HEADER_BODY);
+    let info_mask = kernel.read::<u8>(header + OBJECT_HEADER_INFO_MASK)?;
+
+    if info_mask & 0x2 == 0 {
+        return None;
+    }
+
+    // Optional headers are laid out in front of the object header in the order of their bits
+    let creator_size = if info_mask & 0x1 != 0 { OBJECT_HEADER_CREATOR_INFO_SIZE } else { 0 };
+    let name_info = header - creator_size - OBJECT_HEADER_NAME_INFO_SIZE;
+
+    Some((kernel.read::<u64>(name_info)?, kernel.read_unicode_string(name_info + 0x8, true)?))
+}
+
+/// List the objects of an object directory, along with their names
+fn directory_objects(kernel: &AddressSpace, directory: u64) -> Vec<(u64, String)> {
+    let mut buckets = [0u64; OBJECT_DIRECTORY_BUCKETS];
+    let mut ret = vec![];
+
+    if !kernel.read_arr(directory, &mut buckets) {
+        return ret;
+    }
+
+    for &bucket in buckets.iter() {
+        let mut entry = bucket;
+        let mut count = 0;
+
+        while entry != 0 && count < MAX_BUCKET_ENTRIES {
+            let [next, object] = match kernel.read::<[u64; 2]>(entry) {
+                Some(e) => e,
+                None => break,
+            };
+
+            if let Some((_, name)) = object_name_info(kernel, object) {
+                ret.push((object, name));
+            }
+
+            entry = next;
+            count += 1;
+        }
+    }
+
+    ret
+}
+
+fn read_driver_object(kernel: &AddressSpace, object: u64, offsets: &Offsets) -> Option<DriverObject> {
+    let offsets = &offsets.driver_object;
+
+    if kernel.read::<u16>(object)? != IO_TYPE_DRIVER {
+        return None;
+    }
+
+    Some(DriverObject {
+        address: object,
+        name: offsets.driver_name
+            .and_then(|o| kernel.read_unicode_string(object + o as u64, true))
+            .unwrap_or_default(),
+        driver_start: kernel.read::<u64>(object + offsets.driver_start? as u64)?,
+        driver_size: kernel.read::<u32>(object + offsets.driver_size? as u64)? as u64,
+        driver_section: kernel.read::<u64>(object + offsets.driver_section? as u64)?,
+    })
+}
@@ -0,0 +1,137 @@
+use crate::address_space::*;
+use crate::offsets::*;
+
+/// Upper bound of loader entries to walk, protects against corrupted or looping lists
+const MAX_LDR_ENTRIES: usize = 0x4000;
+
+/// A single module entry of the loader's `InLoadOrderModuleList`
+#[derive(Clone, Debug, Default)]
+pub struct LdrEntry {
+    /// Address of the `_LDR_DATA_TABLE_ENTRY` itself
+    pub entry: u64,
+    pub base: u64,
+    pub entry_point: u64,
+    pub size: u64,
+    pub name: String,
+    pub path: String,
+}
+
+/// Walk a loader module list
+///
+/// This works both for user-mode `PEB_LDR_DATA` lists, and kernel `PsLoadedModuleList`, since the
+/// first fields of their entries share the same layout.
+///
+/// Returns `None` if the required offsets are unknown, or the list head is unreadable.
+///
+/// # Arguments
+///
+/// * `mem` - address space the list resides in
+/// * `list_head` - address of the `LIST_ENTRY` list head
+/// * `is_64bit` - whether the list consists of 64 or 32-bit structures
+/// * `offsets` - loader structure offsets of the matching bitness
+pub fn walk_ldr_list(mem: &AddressSpace, list_head: u64, is_64bit: bool, offsets: &LdrOffsets) -> Option<Vec<LdrEntry>> {
+    let links = offsets.entry_in_load_order_links? as u64;
+    let dll_base = offsets.entry_dll_base? as u64;
+    let entry_point = offsets.entry_entry_point? as u64;
+    let size_of_image = offsets.entry_size_of_image? as u64;
+    let full_dll_name = offsets.entry_full_dll_name? as u64;
+    let base_dll_name = offsets.entry_base_dll_name? as u64;
+
+    let mut ret = vec![];
+    let mut cur = mem.read_ptr(list_head, is_64bit)?;
+
+    while cur != list_head && cur != 0 && ret.len() < MAX_LDR_ENTRIES {
+        let entry = cur.wrapping_sub(links);
+
+        let base = match mem.read_ptr(entry + dll_base, is_64bit) {
+            Some(b) => b,
+            None => break,
+        };
+
+        if base != 0 {
+            ret.push(LdrEntry {
+                entry: entry,
+                base: base,
+                entry_point: mem.read_ptr(entry + entry_point, is_64bit).unwrap_or(0),
+                size: mem.read::<u32>(entry + size_of_image).unwrap_or(0) as u64,
+                name: mem.read_unicode_string(entry + base_dll_name, is_64bit).unwrap_or_default(),
+                path: mem.read_unicode_string(entry + full_dll_name, is_64bit).unwrap_or_default(),
+            });
+        }
+
+        cur = match mem.read_ptr(cur, is_64bit) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Some(ret)
+}
+
+/// Walk a loader module list, reading only the entry addresses and image bases
+///
+/// This is a cheap way to tell whether the list has changed, without reading the module names.
+/// Returns `None` if the required offsets are unknown, or the list head is unreadable.
+///
+/// # Arguments
+///
+/// * `mem` - address space the list resides in
+/// * `list_head` - address of the `LIST_ENTRY` list head
+/// * `is_64bit` - whether the list consists of 64 or 32-bit structures
+/// * `offsets` - loader structure offsets of the matching bitness
+pub fn walk_ldr_bases(mem: &AddressSpace, list_head: u64, is_64bit: bool, offsets: &LdrOffsets) -> Option<Vec<(u64, u64)>> {
+    let links = offsets.entry_in_load_order_links? as u64;
+    let dll_base = offsets.entry_dll_base? as u64;
+
+    let mut ret = vec![];
+    let mut cur = mem.read_ptr(list_head, is_64bit)?;
+
+    while cur != list_head && cur != 0 && ret.len() < MAX_LDR_ENTRIES {
+        let entry = cur.wrapping_sub(links);
+
+        match mem.read_ptr(entry + dll_base, is_64bit) {
+            Some(base) => ret.push((entry, base)),
+            None => break,
+        }
+
+        cur = match mem.read_ptr(cur, is_64bit) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Some(ret)
+}
+
+/// Get the address of the `InLoadOrderModuleList` head of a process
+///
+/// # Arguments
+///
+/// * `mem` - address space of the process
+/// * `peb` - address of either the 64-bit `_PEB`, or the 32-bit `_PEB32`
+/// * `is_64bit` - bitness of the PEB
+/// * `offsets` - resolved offsets of the context
+pub fn peb_ldr_list_head(mem: &AddressSpace, peb: u64, is_64bit: bool, offsets: &Offsets) -> Option<u64> {
+    let peb_ldr = if is_64bit { offsets.peb.ldr } else { offsets.peb32.ldr }? as u64;
+
+    let ldr = mem.read_ptr(peb + peb_ldr, is_64bit)?;
+
+    if ldr == 0 {
+        return None;
+    }
+
+    Some(ldr + offsets.ldr_for(is_64bit).in_load_order_module_list? as u64)
+}
+
+/// Walk the module list of a process, starting from its PEB
+///
+/// # Arguments
+///
+/// * `mem` - address space of the process
+/// * `peb` - address of either the 64-bit `_PEB`, or the 32-bit `_PEB32`
+/// * `is_64bit` - bitness of the PEB
+/// * `offsets` - resolved offsets of the context
+pub fn walk_peb_modules(mem: &AddressSpace, peb: u64, is_64bit: bool, offsets: &Offsets) -> Option<Vec<LdrEntry>> {
+    let list_head = peb_ldr_list_head(mem, peb, is_64bit, offsets)?;
+    walk_ldr_list(mem, list_head, is_64bit, &offsets.ldr_for(is_64bit))
+}
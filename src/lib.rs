@@ -18,6 +18,9 @@
 //! default and internal modes, and is the best way forward if running custom kernel modules is an
 //! option.
 //!
+//! Independently of the mode, the `pdb` feature enables `PdbSymbolProvider`, which loads symbols
+//! from PDB files for the `Symbolizer`.
+//!
 //! ## Example
 //!
 //! A simple process list:
@@ -57,6 +60,36 @@ pub mod win_dll;
 pub mod win_export;
 pub mod rwlist;
 pub mod tlb;
+pub mod offsets;
+pub mod address_space;
+pub mod pe;
+pub mod win_info;
+pub mod ldr;
+pub mod process_parameters;
+pub mod process_info;
+pub mod process_tracker;
+pub mod module_watcher;
+pub mod kernel_module;
+pub mod symbolizer;
+pub mod index;
+pub mod export_cache;
+pub mod pattern;
+pub mod multi_search;
+pub mod value_scan;
+pub mod pointer_scan;
+pub mod strings;
+pub mod rules;
+pub mod snapshot;
+pub mod thread;
+pub mod minidump;
+pub mod kernel_dump;
+pub mod memory_map;
+pub mod vm_discovery;
+pub mod vm_manager;
+pub mod health;
+pub mod qmp;
+pub mod trace;
+pub mod backend;
 
 pub use self::win_context::*;
 pub use self::win_process::*;
@@ -64,6 +97,36 @@ pub use self::win_dll::*;
 pub use self::win_export::*;
 pub use self::rwlist::*;
 pub use self::tlb::*;
+pub use self::offsets::*;
+pub use self::address_space::*;
+pub use self::pe::*;
+pub use self::win_info::*;
+pub use self::ldr::*;
+pub use self::process_parameters::*;
+pub use self::process_info::*;
+pub use self::process_tracker::*;
+pub use self::module_watcher::*;
+pub use self::kernel_module::*;
+pub use self::symbolizer::*;
+pub use self::index::*;
+pub use self::export_cache::*;
+pub use self::pattern::*;
+pub use self::multi_search::*;
+pub use self::value_scan::*;
+pub use self::pointer_scan::*;
+pub use self::strings::*;
+pub use self::rules::*;
+pub use self::snapshot::*;
+pub use self::thread::*;
+pub use self::minidump::*;
+pub use self::kernel_dump::*;
+pub use self::memory_map::*;
+pub use self::vm_discovery::*;
+pub use self::vm_manager::*;
+pub use self::health::*;
+pub use self::qmp::*;
+pub use self::trace::*;
+pub use self::backend::*;
 
 #[cfg(feature="internal_rw")]
 extern crate libc;
@@ -0,0 +1,198 @@
+//! Guest physical memory map
+//!
+//! qemu backs guest RAM with a single host mapping (`sys::ProcessData::mapsStart`/`mapsSize`), but
+//! exposes it to the guest split around the PCI hole: the low part below 4 GiB, the rest remapped
+//! right above 4 GiB. Physical addresses outside of these ranges are either MMIO, or not backed at
+//! all, thus accesses to them are rejected instead of being attempted.
+
+use crate::kernel_dump::*;
+use serde::{Deserialize, Serialize};
+
+/// Start of the RAM remapped above the PCI hole
+pub const HIGH_MEMORY_START: u64 = 0x1_0000_0000;
+
+/// Legacy VGA window, MMIO even though qemu backs it with RAM
+const LEGACY_VGA_START: u64 = 0xa_0000;
+const LEGACY_VGA_END: u64 = 0xc_0000;
+
+/// Granularity the high memory size is rounded up to when inferred from the guest memory map
+const HIGH_MEMORY_ALIGNMENT: u64 = 0x20_0000;
+
+/// A range of guest physical RAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryRange {
+    /// Guest physical start address
+    pub start: u64,
+    /// Guest physical end address (exclusive)
+    pub end: u64,
+    /// Address of the range start inside of the qemu process
+    pub host_address: u64,
+}
+
+impl MemoryRange {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Check whether the range contains the physical address
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+/// Error produced by validated physical memory accesses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhysicalAccessError {
+    /// The access is not fully inside guest RAM and has not been attempted
+    OutOfRange { address: u64, size: u64 },
+    /// The access has been attempted, but could not be performed in full
+    Failed { address: u64, size: u64 },
+}
+
+impl std::fmt::Display for PhysicalAccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PhysicalAccessError::OutOfRange { address, size } => write!(f, "physical access {:#x}+{:#x} is outside of guest RAM", address, size),
+            PhysicalAccessError::Failed { address, size } => write!(f, "physical access {:#x}+{:#x} failed", address, size),
+        }
+    }
+}
+
+impl std::error::Error for PhysicalAccessError {}
+
+/// Sorted list of guest physical RAM ranges
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    ranges: Vec<MemoryRange>,
+}
+
+impl MemoryMap {
+    /// Create a memory map from a list of ranges
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - RAM ranges, in any order
+    pub fn new(mut ranges: Vec<MemoryRange>) -> MemoryMap {
+        ranges.retain(|r| r.start < r.end);
+        ranges.sort_unstable_by_key(|r| r.start);
+
+        MemoryMap {
+            ranges: ranges,
+        }
+    }
+
+    /// Create the memory map of a qemu x86 guest
+    ///
+    /// # Arguments
+    ///
+    /// * `host_start` - address of guest RAM inside of the qemu process
+    /// * `ram_size` - size of guest RAM
+    /// * `low_size` - amount of RAM mapped below 4 GiB, the rest is placed above it
+    pub fn from_layout(host_start: u64, ram_size: u64, low_size: u64) -> MemoryMap {
+        let low_size = low_size.min(ram_size).min(HIGH_MEMORY_START);
+
+        let mut ranges = vec![MemoryRange {
+            start: 0,
+            end: low_size.min(LEGACY_VGA_START),
+            host_address: host_start,
+        }];
+
+        if low_size > LEGACY_VGA_END {
+            ranges.push(MemoryRange {
+                start: LEGACY_VGA_END,
+                end: low_size,
+                host_address: host_start + LEGACY_VGA_END,
+            });
+        }
+
+        ranges.push(MemoryRange {
+            start: HIGH_MEMORY_START,
+            end: HIGH_MEMORY_START + (ram_size - low_size),
+            host_address: host_start + low_size,
+        });
+
+        MemoryMap::new(ranges)
+    }
+
+    /// Detect the memory map of the guest behind a C context
+    ///
+    /// The split between low and high memory depends on the qemu machine type. It is inferred from
+    /// the end of the highest run in the guest's own memory map, which takes a scan of kernel
+    /// memory for the debugger data block. Returns `None` if the guest memory map is unavailable,
+    /// since guessing the machine type would reject valid accesses on others.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn detect(ctx: &sys::WinCtx) -> Option<MemoryMap> {
+        let ram_size = ctx.process.mapsSize;
+
+        let kdbg = KdDebuggerData::find(ctx)?;
+        let high_end = physical_memory_runs(ctx, &kdbg)?.iter().map(|r| r.end()).max().unwrap_or(0);
+
+        let low_size = if high_end > HIGH_MEMORY_START {
+            let high_size = (high_end - HIGH_MEMORY_START + HIGH_MEMORY_ALIGNMENT - 1) & !(HIGH_MEMORY_ALIGNMENT - 1);
+            ram_size.saturating_sub(high_size)
+        } else {
+            ram_size
+        };
+
+        Some(Self::from_layout(ctx.process.mapsStart, ram_size, low_size))
+    }
+
+    /// Get the RAM ranges, sorted by address
+    pub fn ranges(&self) -> &[MemoryRange] {
+        &self.ranges
+    }
+
+    /// Get the total amount of RAM
+    pub fn size(&self) -> u64 {
+        self.ranges.iter().map(|r| r.size()).sum()
+    }
+
+    /// Get the range containing a physical address
+    pub fn range_of(&self, address: u64) -> Option<&MemoryRange> {
+        let idx = self.ranges.partition_point(|r| r.start <= address);
+        idx.checked_sub(1).map(|i| &self.ranges[i]).filter(|r| r.contains(address))
+    }
+
+    /// Check whether an access lies fully inside of a single RAM range
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - physical start address
+    /// * `size` - length of the access
+    pub fn contains(&self, address: u64, size: u64) -> bool {
+        match self.range_of(address) {
+            Some(r) => size <= r.end - address,
+            None => false,
+        }
+    }
+
+    /// Validate an access, returning the error it would produce
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - physical start address
+    /// * `size` - length of the access
+    pub fn validate(&self, address: u64, size: u64) -> Result<(), PhysicalAccessError> {
+        if self.contains(address, size) {
+            Ok(())
+        } else {
+            Err(PhysicalAccessError::OutOfRange { address: address, size: size })
+        }
+    }
+
+    /// Translate a physical address to the address inside of the qemu process
+    pub fn host_address(&self, address: u64) -> Option<u64> {
+        self.range_of(address).map(|r| r.host_address + (address - r.start))
+    }
+
+    /// Get the parts of `[start, end)` that are RAM
+    pub fn clip(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        self.ranges.iter()
+            .filter(|r| r.start < end && start < r.end)
+            .map(|r| (r.start.max(start), r.end.min(end)))
+            .collect()
+    }
+}
@@ -0,0 +1,568 @@
+//! Windows minidump writer for guest processes
+//!
+//! Dumps are built entirely from the outside of the guest, and contain the `SystemInfo`,
+//! `MiscInfo`, `ModuleList`, `ThreadList`, `UnloadedModuleList` and `Memory64List` streams, so that
+//! they open in WinDbg and other minidump readers.
+//!
+//! Thread contexts come from the last user-mode trap frame of every thread, thus only `rip`,
+//! `rsp`, `rbp`, the flags and volatile registers are reliable. Memory that is paged out can not
+//! be dumped. Such ranges inside of module images and thread stacks are listed in a comment
+//! stream and in the returned `MinidumpReport`.
+
+use crate::address_space::*;
+use crate::ldr::*;
+use crate::pattern::*;
+use crate::pe::*;
+use crate::thread::*;
+use crate::win_info::*;
+use crate::win_process::*;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINIDUMP_SIGNATURE: u32 = 0x504d_444d;
+const MINIDUMP_VERSION: u32 = 0xa793;
+/// `MiniDumpWithFullMemory`
+const MINIDUMP_WITH_FULL_MEMORY: u64 = 0x2;
+/// `MiniDumpWithUnloadedModules`
+const MINIDUMP_WITH_UNLOADED_MODULES: u64 = 0x20;
+
+const THREAD_LIST_STREAM: u32 = 3;
+const MODULE_LIST_STREAM: u32 = 4;
+const SYSTEM_INFO_STREAM: u32 = 7;
+const MEMORY64_LIST_STREAM: u32 = 9;
+const COMMENT_STREAM_A: u32 = 10;
+const UNLOADED_MODULE_LIST_STREAM: u32 = 14;
+const MISC_INFO_STREAM: u32 = 15;
+
+const PROCESSOR_ARCHITECTURE_AMD64: u16 = 9;
+const VER_PLATFORM_WIN32_NT: u32 = 2;
+const MINIDUMP_MISC1_PROCESS_ID: u32 = 0x1;
+const MINIDUMP_MISC1_PROCESS_TIMES: u32 = 0x2;
+
+/// Size of the AMD64 `CONTEXT` structure
+const CONTEXT_AMD64_SIZE: usize = 0x4d0;
+/// `CONTEXT_AMD64 | CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS`
+const CONTEXT_AMD64_FULL: u32 = 0x10_0007;
+
+const MINIDUMP_UNLOADED_MODULE_SIZE: usize = 24;
+
+/// Maximum number of entries in the unload event trace of `ntdll.dll`
+const MAX_UNLOAD_EVENTS: u32 = 64;
+/// Offset of the image name inside of `RTL_UNLOAD_EVENT_TRACE`
+const UNLOAD_EVENT_IMAGE_NAME: u64 = 0x1c;
+const UNLOAD_EVENT_IMAGE_NAME_LEN: usize = 32;
+
+/// Size of a single batched read when writing memory
+const DUMP_CHUNK_SIZE: usize = 0x10_0000;
+
+/// Summary of a written minidump
+#[derive(Clone, Debug, Default)]
+pub struct MinidumpReport {
+    pub modules: usize,
+    pub unloaded_modules: usize,
+    pub threads: usize,
+    /// Number of memory ranges in the `Memory64List` stream
+    pub memory_ranges: usize,
+    pub memory_bytes: u64,
+    /// `(start, end)` ranges of module images, thread stacks and other committed memory that were
+    /// not present in memory
+    pub skipped: Vec<(u64, u64)>,
+}
+
+/// Minidump writer
+#[derive(Clone, Copy, Debug)]
+pub struct MinidumpWriter {
+    full_memory: bool,
+    unloaded_modules: bool,
+}
+
+impl Default for MinidumpWriter {
+    fn default() -> MinidumpWriter {
+        MinidumpWriter {
+            full_memory: true,
+            unloaded_modules: true,
+        }
+    }
+}
+
+struct DumpModule {
+    base: u64,
+    size: u32,
+    checksum: u32,
+    timestamp: u32,
+    path: String,
+    codeview: Option<CodeView>,
+}
+
+/// Little endian serialization helpers, the buffer offset of data is its RVA
+#[derive(Default)]
+struct DumpBuffer(Vec<u8>);
+
+impl DumpBuffer {
+    fn rva(&self) -> u32 {
+        self.0.len() as u32
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    fn align(&mut self, alignment: usize) -> &mut Self {
+        let padding = (alignment - self.0.len() % alignment) % alignment;
+        self.0.resize(self.0.len() + padding, 0);
+        self
+    }
+
+    fn patch_u32(&mut self, offset: usize, v: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    /// Append a `MINIDUMP_STRING`, returning its RVA
+    fn string(&mut self, s: &str) -> u32 {
+        self.align(4);
+        let rva = self.rva();
+        let utf16 = s.encode_utf16().collect::<Vec<_>>();
+
+        self.u32(utf16.len() as u32 * 2);
+        for c in utf16 {
+            self.u16(c);
+        }
+        self.u16(0);
+
+        rva
+    }
+}
+
+impl MinidumpWriter {
+    /// Create a writer producing full memory dumps with unloaded modules
+    pub fn new() -> MinidumpWriter {
+        MinidumpWriter::default()
+    }
+
+    /// Dump all present user-mode memory, or only module images, thread stacks and TEBs
+    pub fn full_memory(mut self, full_memory: bool) -> Self {
+        self.full_memory = full_memory;
+        self
+    }
+
+    /// Include the unload event trace of `ntdll.dll`
+    pub fn unloaded_modules(mut self, unloaded_modules: bool) -> Self {
+        self.unloaded_modules = unloaded_modules;
+        self
+    }
+
+    /// Write a minidump of a process to a file
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process, with a refreshed module list
+    /// * `path` - output file path
+    pub fn save<P: AsRef<Path>>(&self, ctx: &sys::WinCtx, process: &WinProcess, path: P) -> std::io::Result<MinidumpReport> {
+        self.write(ctx, process, std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Write a minidump of a process
+    ///
+    /// The process should be suspended, or otherwise idle, for the dump to be consistent.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process, with a refreshed module list
+    /// * `out` - output stream
+    pub fn write<W: Write>(&self, ctx: &sys::WinCtx, process: &WinProcess, mut out: W) -> std::io::Result<MinidumpReport> {
+        let mem = process.address_space(ctx);
+        let modules = self.modules(ctx, process);
+        let threads = process.threads(ctx);
+        let unloaded = if self.unloaded_modules { self.unloaded(&mem, process) } else { vec![] };
+
+        let present = merge_ranges(mem.user_regions().into_iter().map(|r| (r.start, r.end())).collect());
+
+        // Module images and stacks are wanted in any case, missing parts of them get reported
+        let mut wanted = modules.iter().map(|m| (m.base, m.base + m.size as u64)).collect::<Vec<_>>();
+        wanted.extend(threads.iter().filter(|t| t.stack_base > t.stack_limit).map(|t| (t.stack_limit, t.stack_base)));
+        let wanted = merge_ranges(wanted);
+
+        let ranges = if self.full_memory {
+            present.clone()
+        } else {
+            let mut selected = wanted.clone();
+            selected.extend(threads.iter().filter(|t| t.teb != 0).map(|t| (t.teb & !0xfff, (t.teb & !0xfff) + 0x2000)));
+            selected.extend(process.peb_address(ctx).map(|p| (p & !0xfff, (p & !0xfff) + 0x1000)));
+            intersect_ranges(&merge_ranges(selected), &present)
+        };
+
+        // Besides missing parts of the wanted ranges, committed memory that is paged out is missing
+        let mut skipped = subtract_ranges(&wanted, &present);
+        skipped.extend(mem.non_present_regions(0, USER_SPACE_END).into_iter().map(|r| (r.start, r.end())));
+        let skipped = merge_ranges(skipped);
+
+        let report = MinidumpReport {
+            modules: modules.len(),
+            unloaded_modules: unloaded.len(),
+            threads: threads.len(),
+            memory_ranges: ranges.len(),
+            memory_bytes: ranges.iter().map(|r| r.1 - r.0).sum(),
+            skipped: skipped,
+        };
+
+        let mut streams = vec![SYSTEM_INFO_STREAM, MISC_INFO_STREAM, MODULE_LIST_STREAM, THREAD_LIST_STREAM];
+        if self.unloaded_modules {
+            streams.push(UNLOADED_MODULE_LIST_STREAM);
+        }
+        if !report.skipped.is_empty() {
+            streams.push(COMMENT_STREAM_A);
+        }
+        streams.push(MEMORY64_LIST_STREAM);
+
+        let mut buf = DumpBuffer::default();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+        let flags = if self.full_memory { MINIDUMP_WITH_FULL_MEMORY } else { 0 }
+            | if self.unloaded_modules { MINIDUMP_WITH_UNLOADED_MODULES } else { 0 };
+
+        buf.u32(MINIDUMP_SIGNATURE).u32(MINIDUMP_VERSION).u32(streams.len() as u32).u32(32).u32(0).u32(timestamp).u64(flags);
+
+        let directory = buf.0.len();
+        buf.bytes(&vec![0u8; streams.len() * 12]);
+
+        let mut stack_patches = vec![];
+
+        // Variable sized data is written ahead of the fixed size part of every stream, as some
+        // readers require stream sizes to match exactly
+        for (i, &stream) in streams.iter().enumerate() {
+            let start = match stream {
+                SYSTEM_INFO_STREAM => Self::write_system_info(ctx, &mut buf),
+                MISC_INFO_STREAM => Self::write_misc_info(ctx, process, &mut buf),
+                MODULE_LIST_STREAM => Self::write_modules(&modules, &mut buf),
+                THREAD_LIST_STREAM => {
+                    let (start, patches) = Self::write_threads(&threads, &ranges, &mut buf);
+                    stack_patches = patches;
+                    start
+                },
+                UNLOADED_MODULE_LIST_STREAM => Self::write_unloaded(&unloaded, &mut buf),
+                COMMENT_STREAM_A => {
+                    let mut comment = String::from("Memory not present in the guest:\n");
+                    for (s, e) in &report.skipped {
+                        comment.push_str(&format!("{:#x}-{:#x}\n", s, e));
+                    }
+
+                    let start = buf.rva();
+                    buf.bytes(comment.as_bytes()).bytes(&[0]);
+                    start
+                },
+                _ => {
+                    let start = buf.align(8).rva();
+                    let base_rva = start as u64 + 16 + ranges.len() as u64 * 16;
+
+                    buf.u64(ranges.len() as u64).u64(base_rva);
+                    for (s, e) in &ranges {
+                        buf.u64(*s).u64(e - s);
+                    }
+
+                    // Stack descriptors point into the memory data, as long as it is within 32-bit RVAs,
+                    // others are emptied, the data size preceding the RVA
+                    for (offset, range, rel) in stack_patches.drain(..) {
+                        let rva = base_rva + ranges[..range].iter().map(|r| r.1 - r.0).sum::<u64>() + rel;
+                        if rva <= u32::MAX as u64 {
+                            buf.patch_u32(offset, rva as u32);
+                        } else {
+                            buf.patch_u32(offset - 4, 0);
+                        }
+                    }
+
+                    start
+                },
+            };
+
+            // The stream directory entry of the stream: type, data size, RVA
+            let entry = directory + i * 12;
+            buf.patch_u32(entry, stream);
+            buf.patch_u32(entry + 4, buf.rva() - start);
+            buf.patch_u32(entry + 8, start);
+        }
+
+        out.write_all(&buf.0)?;
+
+        let mut data = vec![0u8; DUMP_CHUNK_SIZE];
+
+        for &(start, end) in &ranges {
+            let mut cur = start;
+
+            while cur < end {
+                let chunk = &mut data[..((end - cur) as usize).min(DUMP_CHUNK_SIZE)];
+                chunk.iter_mut().for_each(|b| *b = 0);
+                mem.read_batched(cur, chunk);
+                out.write_all(chunk)?;
+                cur += chunk.len() as u64;
+            }
+        }
+
+        out.flush()?;
+        Ok(report)
+    }
+
+    fn modules(&self, ctx: &sys::WinCtx, process: &WinProcess) -> Vec<DumpModule> {
+        let mem = process.address_space(ctx);
+
+        // Full paths are only kept in the loader entries
+        let ldr = process.peb_address(ctx)
+            .and_then(|peb| walk_peb_modules(&mem, peb, true, &process.offsets))
+            .unwrap_or_default();
+
+        process.module_list.iter().map(|m| {
+            let base = m.info.baseAddress;
+            let headers = PeHeaders::parse(&mem, base);
+
+            DumpModule {
+                base: base,
+                size: m.info.sizeOfModule as u32,
+                checksum: headers.as_ref().map(|h| h.checksum).unwrap_or(0),
+                timestamp: headers.as_ref().map(|h| h.time_date_stamp).unwrap_or(0),
+                path: ldr.iter().find(|e| e.base == base).map(|e| e.path.clone()).unwrap_or_else(|| m.name.clone()),
+                codeview: headers.and_then(|h| h.codeview(&mem)),
+            }
+        }).collect()
+    }
+
+    /// Read the unload event trace through `RtlGetUnloadEventTraceEx` of `ntdll.dll`
+    ///
+    /// The export only stores the addresses of the trace variables, which are recovered from its
+    /// `lea rax, [rip + x]; mov [reg], rax` sequences.
+    fn unloaded(&self, mem: &AddressSpace, process: &WinProcess) -> Vec<DumpModule> {
+        (|| {
+            let ntdll = process.module_list.iter().find(|m| m.name.eq_ignore_ascii_case("ntdll.dll"))?;
+            let headers = PeHeaders::parse(mem, ntdll.info.baseAddress)?;
+            let func = headers.exports(mem)?.into_iter().find(|e| e.name == "RtlGetUnloadEventTraceEx")?.address;
+
+            let code = mem.read::<[u8; 0x40]>(func)?;
+            let (mut size, mut count, mut trace) = (None, None, None);
+
+            for i in 0..code.len() - 10 {
+                if code[i..i + 3] != [0x48, 0x8d, 0x05] {
+                    continue;
+                }
+
+                let target = resolve_rip_relative(mem, func + i as u64, 3, 7);
+
+                match code[i + 7..i + 10] {
+                    [0x48, 0x89, 0x01] => size = target,
+                    [0x48, 0x89, 0x02] => count = target,
+                    [0x49, 0x89, 0x00] => trace = target,
+                    _ => {},
+                }
+            }
+
+            let element_size = mem.read::<u32>(size?)? as u64;
+            let count = mem.read::<u32>(count?)?.min(MAX_UNLOAD_EVENTS);
+            let trace = mem.read::<u64>(trace?)?;
+
+            if trace == 0 || element_size < UNLOAD_EVENT_IMAGE_NAME + UNLOAD_EVENT_IMAGE_NAME_LEN as u64 * 2 {
+                return None;
+            }
+
+            let ret = (0..count as u64).filter_map(|i| {
+                let entry = trace + i * element_size;
+                let [base, size] = mem.read::<[u64; 2]>(entry)?;
+                let [_, timestamp, checksum] = mem.read::<[u32; 3]>(entry + 0x10)?;
+                let name = mem.read::<[u16; UNLOAD_EVENT_IMAGE_NAME_LEN]>(entry + UNLOAD_EVENT_IMAGE_NAME)?;
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+                Some(DumpModule {
+                    base: base,
+                    size: size as u32,
+                    checksum: checksum,
+                    timestamp: timestamp,
+                    path: String::from_utf16_lossy(&name[..len]),
+                    codeview: None,
+                }).filter(|m| m.base != 0)
+            }).collect();
+
+            Some(ret)
+        })().unwrap_or_default()
+    }
+
+    fn write_system_info(ctx: &sys::WinCtx, buf: &mut DumpBuffer) -> u32 {
+        let info = WindowsInfo::new(ctx);
+        let (major, minor) = match &info {
+            Some(i) => (i.major, i.minor),
+            None => (ctx.ntVersion as u32 / 100, ctx.ntVersion as u32 % 100 / 10),
+        };
+
+        // CSDVersionRva has to point to a string, even an empty one
+        let csd = buf.string("");
+        let start = buf.align(8).rva();
+
+        buf.u16(PROCESSOR_ARCHITECTURE_AMD64).u16(0).u16(0)
+            .bytes(&[info.as_ref().map(|i| i.processor_count.min(255) as u8).unwrap_or(1)])
+            .bytes(&[info.as_ref().map(|i| match i.product_type {
+                ProductType::Workstation => 1,
+                ProductType::DomainController => 2,
+                ProductType::Server => 3,
+                ProductType::Unknown(v) => v as u8,
+            }).unwrap_or(1)])
+            .u32(major).u32(minor).u32(ctx.ntBuild).u32(VER_PLATFORM_WIN32_NT)
+            .u32(csd)
+            .u16(info.as_ref().map(|i| i.suite_mask as u16).unwrap_or(0)).u16(0)
+            .bytes(&[0u8; 24]);
+
+        start
+    }
+
+    fn write_misc_info(ctx: &sys::WinCtx, process: &WinProcess, buf: &mut DumpBuffer) -> u32 {
+        let create_time = process.info(ctx).create_time
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as u32);
+
+        let flags = MINIDUMP_MISC1_PROCESS_ID | if create_time.is_some() { MINIDUMP_MISC1_PROCESS_TIMES } else { 0 };
+
+        let start = buf.align(8).rva();
+        buf.u32(24).u32(flags).u32(process.proc.pid as u32).u32(create_time.unwrap_or(0)).u32(0).u32(0);
+        start
+    }
+
+    fn write_modules(modules: &[DumpModule], buf: &mut DumpBuffer) -> u32 {
+        let mut entries = DumpBuffer::default();
+        entries.u32(modules.len() as u32);
+
+        for m in modules {
+            let name_rva = buf.string(&m.path);
+
+            let cv = m.codeview.as_ref().map(|cv| {
+                let rva = buf.align(4).rva();
+                buf.u32(CV_SIGNATURE_RSDS).bytes(&cv.guid).u32(cv.age).bytes(cv.pdb_path.as_bytes()).bytes(&[0]);
+                (buf.rva() - rva, rva)
+            }).unwrap_or((0, 0));
+
+            entries.u64(m.base).u32(m.size).u32(m.checksum).u32(m.timestamp).u32(name_rva)
+                .bytes(&[0u8; 52])
+                .u32(cv.0).u32(cv.1)
+                .u64(0).u64(0).u64(0);
+        }
+
+        let start = buf.align(8).rva();
+        buf.bytes(&entries.0);
+        start
+    }
+
+    /// Returns the stream RVA and `(buffer offset, memory range index, offset in range)` of stack RVAs to patch
+    fn write_threads(threads: &[WinThread], ranges: &[(u64, u64)], buf: &mut DumpBuffer) -> (u32, Vec<(usize, usize, u64)>) {
+        let mut patches = vec![];
+        let mut entries = DumpBuffer::default();
+        entries.u32(threads.len() as u32);
+
+        for t in threads {
+            let context_rva = buf.align(16).rva();
+            let mut context = [0u8; CONTEXT_AMD64_SIZE];
+
+            if let Some(f) = &t.trap_frame {
+                let mut put = |offset: usize, v: &[u8]| context[offset..offset + v.len()].copy_from_slice(v);
+
+                put(0x30, &CONTEXT_AMD64_FULL.to_le_bytes());
+                put(0x38, &f.cs.to_le_bytes());
+                put(0x42, &f.ss.to_le_bytes());
+                put(0x44, &f.eflags.to_le_bytes());
+
+                let regs = [f.rax, f.rcx, f.rdx, f.rbx, f.rsp, f.rbp, f.rsi, f.rdi, f.r8, f.r9, f.r10, f.r11];
+                for (j, r) in regs.iter().enumerate() {
+                    put(0x78 + j * 8, &r.to_le_bytes());
+                }
+
+                put(0xf8, &f.rip.to_le_bytes());
+            }
+
+            buf.bytes(&context);
+
+            // The captured stack is everything from the stack pointer up to the stack base
+            let stack_start = t.trap_frame.map(|f| f.rsp).filter(|&sp| sp >= t.stack_limit && sp < t.stack_base).unwrap_or(t.stack_limit);
+            let stack_range = ranges.iter().position(|r| stack_start >= r.0 && t.stack_base <= r.1 && stack_start < t.stack_base);
+
+            entries.u32(t.thread_id as u32).u32(0).u32(0).u32(0).u64(t.teb);
+
+            match stack_range {
+                Some(r) => {
+                    entries.u64(stack_start).u32((t.stack_base - stack_start) as u32);
+                    patches.push((entries.0.len(), r, stack_start - ranges[r].0));
+                    entries.u32(0);
+                },
+                None => {
+                    entries.u64(0).u32(0).u32(0);
+                },
+            }
+
+            entries.u32(CONTEXT_AMD64_SIZE as u32).u32(context_rva);
+        }
+
+        let start = buf.align(8).rva();
+        buf.bytes(&entries.0);
+
+        let patches = patches.into_iter().map(|(offset, r, rel)| (start as usize + offset, r, rel)).collect();
+        (start, patches)
+    }
+
+    fn write_unloaded(modules: &[DumpModule], buf: &mut DumpBuffer) -> u32 {
+        let mut entries = DumpBuffer::default();
+        entries.u32(12).u32(MINIDUMP_UNLOADED_MODULE_SIZE as u32).u32(modules.len() as u32);
+
+        for m in modules {
+            let name_rva = buf.string(&m.path);
+            entries.u64(m.base).u32(m.size).u32(m.checksum).u32(m.timestamp).u32(name_rva);
+        }
+
+        let start = buf.align(8).rva();
+        buf.bytes(&entries.0);
+        start
+    }
+}
+
+/// Intersect two sorted lists of disjoint ranges
+fn intersect_ranges(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut ret = vec![];
+
+    for &(s, e) in a {
+        for &(bs, be) in b.iter().filter(|r| r.0 < e && s < r.1) {
+            ret.push((s.max(bs), e.min(be)));
+        }
+    }
+
+    ret
+}
+
+/// Get the parts of sorted, disjoint `a` ranges not covered by sorted, disjoint `b` ranges
+fn subtract_ranges(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut ret = vec![];
+
+    for &(s, e) in a {
+        let mut cur = s;
+
+        for &(bs, be) in b.iter().filter(|r| r.0 < e && s < r.1) {
+            if bs > cur {
+                ret.push((cur, bs));
+            }
+            cur = cur.max(be);
+        }
+
+        if cur < e {
+            ret.push((cur, e));
+        }
+    }
+
+    ret
+}
@@ -0,0 +1,185 @@
+use crate::win_process::*;
+use crate::process_tracker::*;
+use crate::ldr::*;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Kind of a module list change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleEventKind {
+    Loaded,
+    Unloaded,
+}
+
+/// A module that appeared in, or disappeared from a process' loader list
+#[derive(Clone, Debug)]
+pub struct ModuleEvent {
+    pub kind: ModuleEventKind,
+    pub process: ProcessKey,
+    pub base: u64,
+    pub size: u64,
+    pub name: String,
+    pub path: String,
+    /// Whether the module comes from the 64-bit, or the WoW64 loader list
+    pub is_64bit: bool,
+    /// Time the change was observed
+    pub timestamp: SystemTime,
+}
+
+/// Snapshot of the links of a single loader list
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct ListChain {
+    head: u64,
+    /// Entry addresses and image bases, in list order
+    entries: Vec<(u64, u64)>,
+}
+
+#[derive(Clone, Default)]
+struct ModuleState {
+    chains: Vec<(bool, ListChain)>,
+    modules: BTreeMap<(u64, bool), LdrEntry>,
+}
+
+/// Watches the loader module lists of processes for loads and unloads
+///
+/// The lists are walked natively, so both the 64-bit and the WoW64 module lists are covered. Every
+/// refresh walks the entry links and image bases only, which catches modules unlinked from the
+/// middle of a list too. Names and paths are read again only if the chain has changed.
+#[derive(Clone, Default)]
+pub struct ModuleWatcher {
+    states: BTreeMap<ProcessKey, ModuleState>,
+}
+
+impl ModuleWatcher {
+    pub fn new() -> ModuleWatcher {
+        ModuleWatcher::default()
+    }
+
+    /// Check a process for module changes
+    ///
+    /// The first refresh of a process reports all of its modules as loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn refresh(&mut self, ctx: &sys::WinCtx, process: &WinProcess) -> Vec<ModuleEvent> {
+        let key = ProcessTracker::key_of(ctx, process);
+        let mem = process.address_space(ctx);
+
+        let mut pebs = vec![];
+        if let Some(peb) = process.peb_address(ctx) {
+            pebs.push((true, peb));
+        }
+        if let Some(peb32) = process.peb32(ctx) {
+            pebs.push((false, peb32));
+        }
+
+        let offsets = &process.offsets;
+
+        let chains = pebs.iter()
+            .filter_map(|&(is_64bit, peb)| {
+                let head = peb_ldr_list_head(&mem, peb, is_64bit, offsets)?;
+                Some((is_64bit, ListChain {
+                    head: head,
+                    entries: walk_ldr_bases(&mem, head, is_64bit, &offsets.ldr_for(is_64bit))?,
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        let state = self.states.entry(key).or_default();
+
+        let first = state.modules.is_empty() && state.chains.is_empty();
+
+        if !first && state.chains == chains {
+            return vec![];
+        }
+
+        let mut current = BTreeMap::new();
+
+        for (is_64bit, head) in &chains {
+            let is_64bit = *is_64bit;
+
+            // Keep the old entries of a list that failed to be walked, rather than reporting unloads
+            let entries = match walk_ldr_list(&mem, head.head, is_64bit, &offsets.ldr_for(is_64bit)) {
+                Some(e) => e,
+                None => {
+                    current.extend(state.modules.iter()
+                        .filter(|(k, _)| k.1 == is_64bit)
+                        .map(|(k, v)| (*k, v.clone())));
+                    continue;
+                },
+            };
+
+            current.extend(entries.into_iter().map(|e| ((e.base, is_64bit), e)));
+        }
+
+        state.chains = chains;
+
+        let old = std::mem::replace(&mut state.modules, current);
+        let timestamp = SystemTime::now();
+
+        let event = |kind: ModuleEventKind, is_64bit: bool, entry: &LdrEntry| ModuleEvent {
+            kind: kind,
+            process: key,
+            base: entry.base,
+            size: entry.size,
+            name: entry.name.clone(),
+            path: entry.path.clone(),
+            is_64bit: is_64bit,
+            timestamp: timestamp,
+        };
+
+        let mut events = vec![];
+
+        for (k, entry) in &state.modules {
+            match old.get(k) {
+                // A different image mapped at the same base counts as an unload and a load
+                Some(o) if o.size == entry.size && o.path == entry.path => {},
+                Some(o) => {
+                    events.push(event(ModuleEventKind::Unloaded, k.1, o));
+                    events.push(event(ModuleEventKind::Loaded, k.1, entry));
+                },
+                None => events.push(event(ModuleEventKind::Loaded, k.1, entry)),
+            }
+        }
+
+        for (k, entry) in &old {
+            if !state.modules.contains_key(k) {
+                events.push(event(ModuleEventKind::Unloaded, k.1, entry));
+            }
+        }
+
+        events
+    }
+
+    /// Check all processes of a tracker for module changes
+    ///
+    /// State of processes that are no longer tracked is dropped, without emitting unload events.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `tracker` - process tracker, refreshed by the caller
+    pub fn refresh_tracked(&mut self, ctx: &sys::WinCtx, tracker: &ProcessTracker) -> Vec<ModuleEvent> {
+        self.states.retain(|k, _| tracker.get(k).is_some());
+
+        let mut events = vec![];
+
+        for (_, process) in tracker.processes() {
+            events.extend(self.refresh(ctx, process));
+        }
+
+        events
+    }
+
+    /// Get the last known modules of a process
+    pub fn modules(&self, key: &ProcessKey) -> impl Iterator<Item = &LdrEntry> {
+        self.states.get(key).into_iter().flat_map(|s| s.modules.values())
+    }
+
+    /// Drop the state of a process
+    pub fn forget(&mut self, key: &ProcessKey) {
+        self.states.remove(key);
+    }
+}
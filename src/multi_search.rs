@@ -0,0 +1,171 @@
+//! Search for many byte strings at once
+//!
+//! `MultiPattern` compiles a set of byte strings into an Aho-Corasick automaton, and streams
+//! memory through it in page aligned chunks, so that the cost of a search barely depends on the
+//! number of patterns.
+
+use crate::address_space::*;
+use crate::win_context::*;
+use crate::win_process::*;
+use aho_corasick::{AhoCorasick, MatchKind};
+
+pub use aho_corasick::BuildError as MultiPatternError;
+
+/// Default size of a single batched read
+pub const DEFAULT_SEARCH_CHUNK_SIZE: usize = 0x40_0000;
+
+/// A single match of a multi-pattern search
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchMatch {
+    /// Process the match was found in, `None` when searching physical memory
+    pub pid: Option<u64>,
+    /// Virtual address of the match, `None` when searching physical memory
+    pub virtual_address: Option<u64>,
+    /// Physical address of the first byte of the match, `None` if it could not be translated
+    pub physical_address: Option<u64>,
+    /// Index of the pattern in the list the search was built from
+    pub pattern: usize,
+}
+
+/// A compiled set of byte strings
+#[derive(Clone, Debug)]
+pub struct MultiPattern {
+    automaton: AhoCorasick,
+    max_len: usize,
+    chunk_size: usize,
+}
+
+impl MultiPattern {
+    /// Compile a set of patterns
+    ///
+    /// Overlapping matches of different patterns are all reported.
+    ///
+    /// # Arguments
+    ///
+    /// * `patterns` - byte strings to search for, identified by their index
+    pub fn new<I, P>(patterns: I) -> Result<MultiPattern, MultiPatternError>
+        where I: IntoIterator<Item = P>,
+              P: AsRef<[u8]> {
+        Self::build(patterns, false)
+    }
+
+    /// Compile a set of patterns matching ASCII letters case-insensitively
+    ///
+    /// Used as a prefilter, whose candidates are verified by the caller.
+    pub(crate) fn ascii_case_insensitive<I, P>(patterns: I) -> Result<MultiPattern, MultiPatternError>
+        where I: IntoIterator<Item = P>,
+              P: AsRef<[u8]> {
+        Self::build(patterns, true)
+    }
+
+    fn build<I, P>(patterns: I, nocase: bool) -> Result<MultiPattern, MultiPatternError>
+        where I: IntoIterator<Item = P>,
+              P: AsRef<[u8]> {
+        let patterns = patterns.into_iter().collect::<Vec<P>>();
+
+        Ok(MultiPattern {
+            automaton: AhoCorasick::builder()
+                .match_kind(MatchKind::Standard)
+                .ascii_case_insensitive(nocase)
+                .build(&patterns)?,
+            max_len: patterns.iter().map(|p| p.as_ref().len()).max().unwrap_or(0),
+            chunk_size: DEFAULT_SEARCH_CHUNK_SIZE,
+        })
+    }
+
+    /// Set the size of a single batched read
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Number of patterns in the set
+    pub fn len(&self) -> usize {
+        self.automaton.patterns_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find all, possibly overlapping, matches in a buffer, as `(offset, pattern index)` pairs
+    pub(crate) fn find_overlapping<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.automaton.find_overlapping_iter(data).map(|m| (m.start(), m.pattern().as_usize()))
+    }
+
+    /// Search a range of an address space, calling `on_match` until it returns `false`
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space to search
+    /// * `pid` - process the address space belongs to, reported in the matches
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    /// * `on_match` - match callback, returning whether to continue searching
+    pub fn search(&self, mem: &AddressSpace, pid: Option<u64>, start: u64, end: u64, on_match: &mut dyn FnMut(SearchMatch) -> bool) {
+        if self.is_empty() {
+            return;
+        }
+
+        let is_physical = mem.is_physical();
+
+        mem.for_each_chunk(start, end, self.chunk_size, self.max_len.saturating_sub(1), &mut |address, chunk, report_len| {
+            for m in self.automaton.find_overlapping_iter(chunk) {
+                // Matches starting in the overlap are reported by the next chunk
+                if m.start() >= report_len {
+                    continue;
+                }
+
+                let match_address = address + m.start() as u64;
+
+                let keep_going = on_match(SearchMatch {
+                    pid: pid,
+                    virtual_address: if is_physical { None } else { Some(match_address) },
+                    physical_address: mem.translate(match_address),
+                    pattern: m.pattern().as_usize(),
+                });
+
+                if !keep_going {
+                    return false;
+                }
+            }
+
+            true
+        });
+    }
+
+    /// Search all present user-mode memory of a process
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn search_process(&self, ctx: &sys::WinCtx, process: &WinProcess) -> Vec<SearchMatch> {
+        let mut ret = vec![];
+
+        self.search(&process.address_space(ctx), Some(process.proc.pid), 0, USER_SPACE_END, &mut |m| {
+            ret.push(m);
+            true
+        });
+
+        ret
+    }
+
+    /// Search a range of guest physical memory
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context, accesses are validated against its memory map
+    /// * `start` - start physical address
+    /// * `end` - end physical address (exclusive)
+    pub fn search_physical(&self, ctx: &WinContext, start: u64, end: u64) -> Vec<SearchMatch> {
+        let mut ret = vec![];
+
+        self.search(&ctx.physical(), None, start, end, &mut |m| {
+            ret.push(m);
+            true
+        });
+
+        ret
+    }
+}
@@ -0,0 +1,922 @@
+//! Data-driven kernel structure offsets
+//!
+//! The C library ships with hardcoded offset tables that get selected in `SetupOffsets` based on
+//! the NT version and build of the guest. Whenever a new Windows build changes the layout of
+//! `_EPROCESS` and friends, context creation fails (or silently produces garbage) until those
+//! tables get updated.
+//!
+//! This module allows supplying offset profiles from TOML or JSON files. The profiles are matched
+//! against `ntVersion`/`ntBuild` of the guest at context creation and overlaid on top of the
+//! offsets chosen by the C library. Every resolved field is recorded in an `OffsetReport`,
+//! describing where its value came from.
+//!
+//! ## Profile format
+//!
+//! ```toml
+//! [[profile]]
+//! name = "Windows 11 Insider"
+//! nt_version = 1000
+//! min_build = 26100
+//!
+//! [profile.eprocess]
+//! active_process_links = 0x1d8
+//! image_file_name = 0x338
+//! peb = 0x2e0
+//! thread_list_head = 0x370
+//!
+//! [profile.ethread]
+//! thread_list_entry = 0x578
+//! ```
+//!
+//! The equivalent JSON document has a top level `profile` array. Since JSON has no hexadecimal
+//! literals, offsets may also be given as strings, like `"0x1d8"`.
+
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::Path;
+
+/// Where the value of a particular offset has come from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OffsetSource {
+    /// Selected by the C library in `SetupOffsets`
+    Library,
+    /// Built into this crate. Used for structures that are stable across supported builds
+    Builtin,
+    /// Computed from other resolved offsets
+    Derived,
+    /// Taken from a user supplied profile with the given name
+    Profile(String),
+}
+
+/// A single resolved offset
+#[derive(Clone, Debug)]
+pub struct OffsetEntry {
+    pub field: &'static str,
+    pub value: i64,
+    pub source: OffsetSource,
+}
+
+/// Report of how each offset got resolved during context creation
+#[derive(Clone, Debug, Default)]
+pub struct OffsetReport {
+    pub entries: Vec<OffsetEntry>,
+    /// Fields that no table or profile provided
+    pub missing: Vec<&'static str>,
+}
+
+impl OffsetReport {
+    fn set(&mut self, field: &'static str, value: i64, source: &OffsetSource) {
+        match self.entries.iter_mut().find(|e| e.field == field) {
+            Some(e) => {
+                e.value = value;
+                e.source = source.clone();
+            },
+            None => self.entries.push(OffsetEntry {
+                field: field,
+                value: value,
+                source: source.clone(),
+            }),
+        }
+    }
+
+    /// Get the source of a particular field, i.e. `"eprocess.peb"`
+    pub fn source_of(&self, field: &str) -> Option<&OffsetSource> {
+        self.entries.iter().find(|e| e.field == field).map(|e| &e.source)
+    }
+}
+
+fn de_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Int(i64),
+        Str(String),
+    }
+
+    match Raw::deserialize(deserializer)? {
+        Raw::Int(v) => Ok(Some(v)),
+        Raw::Str(s) => {
+            let s = s.trim();
+            let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => s.parse(),
+            };
+            parsed.map(Some).map_err(|_| serde::de::Error::custom(format!("invalid offset \"{}\"", s)))
+        },
+    }
+}
+
+macro_rules! offset_group {
+    ($(#[$meta:meta])* $name:ident, $prefix:literal { $($(#[$fmeta:meta])* $field:ident,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct $name {
+            $(
+                $(#[$fmeta])*
+                #[serde(deserialize_with = "de_offset", skip_serializing_if = "Option::is_none")]
+                pub $field: Option<i64>,
+            )*
+        }
+
+        impl $name {
+            fn overlay(&mut self, other: &Self, source: &OffsetSource, report: &mut OffsetReport) {
+                $(
+                    if let Some(v) = other.$field {
+                        self.$field = Some(v);
+                        report.set(concat!($prefix, ".", stringify!($field)), v, source);
+                    }
+                )*
+            }
+
+            fn fill(&mut self, other: &Self, source: &OffsetSource, report: &mut OffsetReport) {
+                $(
+                    if let (None, Some(v)) = (self.$field, other.$field) {
+                        self.$field = Some(v);
+                        report.set(concat!($prefix, ".", stringify!($field)), v, source);
+                    }
+                )*
+            }
+
+            fn collect_missing(&self, missing: &mut Vec<&'static str>) {
+                $(
+                    if self.$field.is_none() {
+                        missing.push(concat!($prefix, ".", stringify!($field)));
+                    }
+                )*
+            }
+        }
+    };
+}
+
+offset_group!(
+    /// Offsets inside `_EPROCESS`
+    EprocessOffsets, "eprocess" {
+        unique_process_id,
+        active_process_links,
+        session,
+        image_file_name,
+        peb,
+        thread_list_head,
+        wow64_process,
+        create_time,
+        exit_time,
+        inherited_from_unique_process_id,
+        exit_status,
+        protection,
+        object_table,
+        commit_charge,
+        mitigation_flags,
+        mitigation_flags2,
+        device_map,
+        vm,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_KPROCESS`
+    KprocessOffsets, "kprocess" {
+        directory_table_base,
+        stack_count,
+        user_directory_table_base,
+        execute_options,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_MMSUPPORT`, or `_MMSUPPORT_INSTANCE` on builds splitting it
+    MmSupportOffsets, "mm_support" {
+        working_set_size,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_MM_SESSION_SPACE`
+    MmSessionSpaceOffsets, "mm_session_space" {
+        session_id,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_HANDLE_TABLE`
+    HandleTableOffsets, "handle_table" {
+        table_code,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_DEVICE_MAP`
+    DeviceMapOffsets, "device_map" {
+        dos_devices_directory,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_DRIVER_OBJECT`
+    DriverObjectOffsets, "driver_object" {
+        driver_start,
+        driver_size,
+        driver_section,
+        driver_name,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_ETHREAD`
+    EthreadOffsets, "ethread" {
+        thread_list_entry,
+        cid,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_KTHREAD`
+    KthreadOffsets, "kthread" {
+        teb,
+        trap_frame,
+    }
+);
+
+offset_group!(
+    /// Offsets inside the 32-bit `_TEB32` of WoW64 threads
+    Teb32Offsets, "teb32" {
+        process_environment_block,
+    }
+);
+
+offset_group!(
+    /// Offsets inside the 64-bit `_PEB`
+    PebOffsets, "peb" {
+        image_base_address,
+        ldr,
+        process_parameters,
+    }
+);
+
+offset_group!(
+    /// Offsets inside the 32-bit `_PEB32` of WoW64 processes
+    Peb32Offsets, "peb32" {
+        image_base_address,
+        ldr,
+        process_parameters,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_RTL_USER_PROCESS_PARAMETERS`
+    ProcessParametersOffsets, "process_parameters" {
+        current_directory,
+        image_path_name,
+        command_line,
+        environment,
+        window_title,
+        environment_size,
+    }
+);
+
+offset_group!(
+    /// Offsets inside the 32-bit `_RTL_USER_PROCESS_PARAMETERS32`
+    ProcessParameters32Offsets, "process_parameters32" {
+        current_directory,
+        image_path_name,
+        command_line,
+        environment,
+        window_title,
+        environment_size,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_PEB_LDR_DATA` and `_LDR_DATA_TABLE_ENTRY`
+    LdrOffsets, "ldr" {
+        in_load_order_module_list,
+        entry_in_load_order_links,
+        entry_dll_base,
+        entry_entry_point,
+        entry_size_of_image,
+        entry_full_dll_name,
+        entry_base_dll_name,
+    }
+);
+
+offset_group!(
+    /// Offsets inside `_PEB_LDR_DATA32` and `_LDR_DATA_TABLE_ENTRY32`
+    Ldr32Offsets, "ldr32" {
+        in_load_order_module_list,
+        entry_in_load_order_links,
+        entry_dll_base,
+        entry_entry_point,
+        entry_size_of_image,
+        entry_full_dll_name,
+        entry_base_dll_name,
+    }
+);
+
+/// Full set of structure offsets used by the library
+///
+/// Fields are `None` when no source provided them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Offsets {
+    pub eprocess: EprocessOffsets,
+    pub kprocess: KprocessOffsets,
+    pub mm_session_space: MmSessionSpaceOffsets,
+    pub mm_support: MmSupportOffsets,
+    pub handle_table: HandleTableOffsets,
+    pub device_map: DeviceMapOffsets,
+    pub driver_object: DriverObjectOffsets,
+    pub ethread: EthreadOffsets,
+    pub kthread: KthreadOffsets,
+    pub teb32: Teb32Offsets,
+    pub peb: PebOffsets,
+    pub peb32: Peb32Offsets,
+    pub process_parameters: ProcessParametersOffsets,
+    pub process_parameters32: ProcessParameters32Offsets,
+    pub ldr: LdrOffsets,
+    pub ldr32: Ldr32Offsets,
+}
+
+impl Offsets {
+    fn overlay(&mut self, other: &Offsets, source: &OffsetSource, report: &mut OffsetReport) {
+        self.eprocess.overlay(&other.eprocess, source, report);
+        self.kprocess.overlay(&other.kprocess, source, report);
+        self.mm_session_space.overlay(&other.mm_session_space, source, report);
+        self.mm_support.overlay(&other.mm_support, source, report);
+        self.handle_table.overlay(&other.handle_table, source, report);
+        self.device_map.overlay(&other.device_map, source, report);
+        self.driver_object.overlay(&other.driver_object, source, report);
+        self.ethread.overlay(&other.ethread, source, report);
+        self.kthread.overlay(&other.kthread, source, report);
+        self.teb32.overlay(&other.teb32, source, report);
+        self.peb.overlay(&other.peb, source, report);
+        self.peb32.overlay(&other.peb32, source, report);
+        self.process_parameters.overlay(&other.process_parameters, source, report);
+        self.process_parameters32.overlay(&other.process_parameters32, source, report);
+        self.ldr.overlay(&other.ldr, source, report);
+        self.ldr32.overlay(&other.ldr32, source, report);
+    }
+
+    fn fill(&mut self, other: &Offsets, source: &OffsetSource, report: &mut OffsetReport) {
+        self.eprocess.fill(&other.eprocess, source, report);
+        self.kprocess.fill(&other.kprocess, source, report);
+        self.mm_session_space.fill(&other.mm_session_space, source, report);
+        self.mm_support.fill(&other.mm_support, source, report);
+        self.handle_table.fill(&other.handle_table, source, report);
+        self.device_map.fill(&other.device_map, source, report);
+        self.driver_object.fill(&other.driver_object, source, report);
+        self.ethread.fill(&other.ethread, source, report);
+        self.kthread.fill(&other.kthread, source, report);
+        self.teb32.fill(&other.teb32, source, report);
+        self.peb.fill(&other.peb, source, report);
+        self.peb32.fill(&other.peb32, source, report);
+        self.process_parameters.fill(&other.process_parameters, source, report);
+        self.process_parameters32.fill(&other.process_parameters32, source, report);
+        self.ldr.fill(&other.ldr, source, report);
+        self.ldr32.fill(&other.ldr32, source, report);
+    }
+
+    fn collect_missing(&self) -> Vec<&'static str> {
+        let mut missing = vec![];
+        self.eprocess.collect_missing(&mut missing);
+        self.kprocess.collect_missing(&mut missing);
+        self.mm_session_space.collect_missing(&mut missing);
+        self.mm_support.collect_missing(&mut missing);
+        self.handle_table.collect_missing(&mut missing);
+        self.device_map.collect_missing(&mut missing);
+        self.driver_object.collect_missing(&mut missing);
+        self.ethread.collect_missing(&mut missing);
+        self.kthread.collect_missing(&mut missing);
+        self.teb32.collect_missing(&mut missing);
+        self.peb.collect_missing(&mut missing);
+        self.peb32.collect_missing(&mut missing);
+        self.process_parameters.collect_missing(&mut missing);
+        self.process_parameters32.collect_missing(&mut missing);
+        self.ldr.collect_missing(&mut missing);
+        self.ldr32.collect_missing(&mut missing);
+        missing
+    }
+
+    /// Get the loader structure offsets for the given bitness
+    pub fn ldr_for(&self, is_64bit: bool) -> LdrOffsets {
+        if is_64bit {
+            self.ldr
+        } else {
+            LdrOffsets {
+                in_load_order_module_list: self.ldr32.in_load_order_module_list,
+                entry_in_load_order_links: self.ldr32.entry_in_load_order_links,
+                entry_dll_base: self.ldr32.entry_dll_base,
+                entry_entry_point: self.ldr32.entry_entry_point,
+                entry_size_of_image: self.ldr32.entry_size_of_image,
+                entry_full_dll_name: self.ldr32.entry_full_dll_name,
+                entry_base_dll_name: self.ldr32.entry_base_dll_name,
+            }
+        }
+    }
+
+    /// Get the process parameter offsets for the given bitness
+    pub fn process_parameters_for(&self, is_64bit: bool) -> ProcessParametersOffsets {
+        if is_64bit {
+            self.process_parameters
+        } else {
+            ProcessParametersOffsets {
+                current_directory: self.process_parameters32.current_directory,
+                image_path_name: self.process_parameters32.image_path_name,
+                command_line: self.process_parameters32.command_line,
+                environment: self.process_parameters32.environment,
+                window_title: self.process_parameters32.window_title,
+                environment_size: self.process_parameters32.environment_size,
+            }
+        }
+    }
+
+    /// Convert offsets chosen by the C library
+    pub fn from_library(c_offsets: &sys::WinOffsets) -> Offsets {
+        let mut ret = Offsets::default();
+        ret.eprocess.active_process_links = Some(c_offsets.apl);
+        ret.eprocess.session = Some(c_offsets.session);
+        ret.eprocess.image_file_name = Some(c_offsets.imageFileName);
+        ret.eprocess.peb = Some(c_offsets.peb);
+        ret.eprocess.thread_list_head = Some(c_offsets.threadListHead);
+        ret.kprocess.directory_table_base = Some(c_offsets.dirBase);
+        ret.kprocess.stack_count = Some(c_offsets.stackCount);
+        ret.ethread.thread_list_entry = Some(c_offsets.threadListEntry);
+        ret.kthread.teb = Some(c_offsets.teb);
+        ret.teb32.process_environment_block = Some(c_offsets.peb32);
+        ret
+    }
+
+    /// Write the offsets back into the C library structure
+    ///
+    /// Fails with the name of the first field that is needed by the C library, but is unresolved.
+    pub fn to_library(&self, c_offsets: &mut sys::WinOffsets) -> Result<(), &'static str> {
+        c_offsets.apl = self.eprocess.active_process_links.ok_or("eprocess.active_process_links")?;
+        c_offsets.session = self.eprocess.session.ok_or("eprocess.session")?;
+        c_offsets.imageFileName = self.eprocess.image_file_name.ok_or("eprocess.image_file_name")?;
+        c_offsets.peb = self.eprocess.peb.ok_or("eprocess.peb")?;
+        c_offsets.threadListHead = self.eprocess.thread_list_head.ok_or("eprocess.thread_list_head")?;
+        c_offsets.dirBase = self.kprocess.directory_table_base.ok_or("kprocess.directory_table_base")?;
+        c_offsets.stackCount = self.kprocess.stack_count.ok_or("kprocess.stack_count")?;
+        c_offsets.threadListEntry = self.ethread.thread_list_entry.ok_or("ethread.thread_list_entry")?;
+        c_offsets.teb = self.kthread.teb.ok_or("kthread.teb")?;
+        c_offsets.peb32 = self.teb32.process_environment_block.ok_or("teb32.process_environment_block")?;
+        Ok(())
+    }
+
+    /// Offsets built into the crate for the given Windows version
+    ///
+    /// These cover structures that did not change layout on 64-bit Windows since Vista, and a
+    /// table of `_EPROCESS` fields for common builds. Build specific fields are known for:
+    ///
+    /// * 7600-7601 - creation and exit times, commit charge, handle table, parent, WoW64 and exit
+    ///   status
+    /// * 10240-18363 - WoW64 only
+    /// * 19041-22631 - parent, handle table, WoW64, device map and user page table base
+    /// * 19041-19045 - additionally exit status, protection, mitigations and working set
+    ///
+    /// Other builds and fields, such as `kprocess.execute_options`, need an `OffsetProfile`.
+    pub fn builtin(_nt_version: u16, nt_build: u32) -> Offsets {
+        let mut ret = Offsets::default();
+
+        match nt_build {
+            7600..=7601 => {
+                ret.kthread.trap_frame = Some(0x1d8);
+                ret.eprocess.create_time = Some(0x168);
+                ret.eprocess.exit_time = Some(0x170);
+                ret.eprocess.commit_charge = Some(0x1b8);
+                ret.eprocess.object_table = Some(0x200);
+                ret.eprocess.inherited_from_unique_process_id = Some(0x290);
+                ret.eprocess.wow64_process = Some(0x320);
+                ret.eprocess.exit_status = Some(0x444);
+            },
+            10240..=18363 => {
+                ret.eprocess.wow64_process = Some(0x428);
+                ret.kthread.trap_frame = Some(0x90);
+            },
+            19041..=22631 => {
+                ret.eprocess.inherited_from_unique_process_id = Some(0x540);
+                ret.eprocess.object_table = Some(0x570);
+                ret.eprocess.wow64_process = Some(0x580);
+                ret.eprocess.device_map = Some(0x588);
+                ret.kprocess.user_directory_table_base = Some(0x388);
+                ret.kthread.trap_frame = Some(0x90);
+            },
+            _ => {},
+        }
+
+        if let 19041..=19045 = nt_build {
+            ret.eprocess.exit_status = Some(0x7d4);
+            ret.eprocess.protection = Some(0x87a);
+            ret.eprocess.mitigation_flags = Some(0x9d0);
+            ret.eprocess.mitigation_flags2 = Some(0x9d4);
+            ret.eprocess.vm = Some(0x680);
+            ret.mm_support.working_set_size = Some(0x88);
+            ret.ethread.cid = Some(0x478);
+        }
+
+        // TableCode moved behind NextHandleNeedingPool in Windows 8
+        ret.handle_table.table_code = Some(if nt_build >= 9200 { 0x8 } else { 0x0 });
+        ret.mm_session_space.session_id = Some(0x8);
+        ret.device_map.dos_devices_directory = Some(0x0);
+        ret.driver_object.driver_start = Some(0x18);
+        ret.driver_object.driver_size = Some(0x20);
+        ret.driver_object.driver_section = Some(0x28);
+        ret.driver_object.driver_name = Some(0x38);
+        ret.kprocess.directory_table_base = Some(0x28);
+        ret.teb32.process_environment_block = Some(0x30);
+        ret.peb.image_base_address = Some(0x10);
+        ret.peb.ldr = Some(0x18);
+        ret.peb.process_parameters = Some(0x20);
+        ret.ldr.in_load_order_module_list = Some(0x10);
+        ret.ldr.entry_in_load_order_links = Some(0x0);
+        ret.ldr.entry_dll_base = Some(0x30);
+        ret.ldr.entry_entry_point = Some(0x38);
+        ret.ldr.entry_size_of_image = Some(0x40);
+        ret.ldr.entry_full_dll_name = Some(0x48);
+        ret.ldr.entry_base_dll_name = Some(0x58);
+        ret.peb32.image_base_address = Some(0x8);
+        ret.peb32.ldr = Some(0xc);
+        ret.peb32.process_parameters = Some(0x10);
+        ret.process_parameters.current_directory = Some(0x38);
+        ret.process_parameters.image_path_name = Some(0x60);
+        ret.process_parameters.command_line = Some(0x70);
+        ret.process_parameters.environment = Some(0x80);
+        ret.process_parameters.window_title = Some(0xb0);
+        ret.process_parameters.environment_size = Some(0x3f0);
+        ret.process_parameters32.current_directory = Some(0x24);
+        ret.process_parameters32.image_path_name = Some(0x38);
+        ret.process_parameters32.command_line = Some(0x40);
+        ret.process_parameters32.environment = Some(0x48);
+        ret.process_parameters32.window_title = Some(0x70);
+        ret.process_parameters32.environment_size = Some(0x290);
+        ret.ldr32.in_load_order_module_list = Some(0xc);
+        ret.ldr32.entry_in_load_order_links = Some(0x0);
+        ret.ldr32.entry_dll_base = Some(0x18);
+        ret.ldr32.entry_entry_point = Some(0x1c);
+        ret.ldr32.entry_size_of_image = Some(0x20);
+        ret.ldr32.entry_full_dll_name = Some(0x24);
+        ret.ldr32.entry_base_dll_name = Some(0x2c);
+        ret
+    }
+
+    fn derive(&mut self, nt_version: u16, report: &mut OffsetReport) {
+        let mut derived = Offsets::default();
+        // UniqueProcessId directly precedes ActiveProcessLinks on every 64-bit build
+        derived.eprocess.unique_process_id = self.eprocess.active_process_links.map(|apl| apl - 8);
+        // On Windows 10 and newer RundownProtect, Flags2 and Flags separate it from CreateTime
+        if nt_version >= 1000 {
+            derived.eprocess.create_time = self.eprocess.active_process_links.map(|apl| apl + 0x20);
+        }
+        self.fill(&derived, &OffsetSource::Derived, report);
+    }
+
+    /// Resolve the final set of offsets for a given Windows version
+    ///
+    /// # Arguments
+    ///
+    /// * `library` - offsets selected by the C library, `None` if it does not support the build
+    /// * `profiles` - user supplied profiles, matching ones are applied on top
+    /// * `nt_version` - NT version of the guest, as reported by vmread
+    /// * `nt_build` - NT build number of the guest
+    pub fn resolve(library: Option<&sys::WinOffsets>, profiles: &OffsetProfiles, nt_version: u16, nt_build: u32) -> (Offsets, OffsetReport) {
+        let mut report = OffsetReport::default();
+        let mut ret = Offsets::default();
+
+        if let Some(c_offsets) = library {
+            ret.overlay(&Offsets::from_library(c_offsets), &OffsetSource::Library, &mut report);
+        }
+
+        ret.fill(&Offsets::builtin(nt_version, nt_build), &OffsetSource::Builtin, &mut report);
+
+        for profile in profiles.matching(nt_version, nt_build) {
+            ret.overlay(&profile.offsets, &OffsetSource::Profile(profile.display_name()), &mut report);
+        }
+
+        ret.derive(nt_version, &mut report);
+        report.missing = ret.collect_missing();
+
+        (ret, report)
+    }
+}
+
+/// Offsets for a particular set of Windows builds
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "RawProfile")]
+pub struct OffsetProfile {
+    /// Human readable name, used in the `OffsetReport`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// NT version, in vmread's format (major * 100 + minor, i.e. 1000 for Windows 10)
+    pub nt_version: u16,
+    /// Exact build number this profile applies to
+    #[serde(default)]
+    pub build: Option<u32>,
+    /// Lowest build number (inclusive) this profile applies to
+    #[serde(default)]
+    pub min_build: Option<u32>,
+    /// Highest build number (inclusive) this profile applies to
+    #[serde(default)]
+    pub max_build: Option<u32>,
+    #[serde(flatten)]
+    pub offsets: Offsets,
+}
+
+/// Profile as written in a file
+///
+/// Serde ignores `deny_unknown_fields` on structures with flattened fields, thus the offset groups
+/// are listed here explicitly, so that misspelled keys are rejected instead of silently dropped.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    #[serde(default)]
+    name: Option<String>,
+    nt_version: u16,
+    #[serde(default)]
+    build: Option<u32>,
+    #[serde(default)]
+    min_build: Option<u32>,
+    #[serde(default)]
+    max_build: Option<u32>,
+    #[serde(default)]
+    eprocess: EprocessOffsets,
+    #[serde(default)]
+    kprocess: KprocessOffsets,
+    #[serde(default)]
+    mm_session_space: MmSessionSpaceOffsets,
+    #[serde(default)]
+    mm_support: MmSupportOffsets,
+    #[serde(default)]
+    handle_table: HandleTableOffsets,
+    #[serde(default)]
+    device_map: DeviceMapOffsets,
+    #[serde(default)]
+    driver_object: DriverObjectOffsets,
+    #[serde(default)]
+    ethread: EthreadOffsets,
+    #[serde(default)]
+    kthread: KthreadOffsets,
+    #[serde(default)]
+    teb32: Teb32Offsets,
+    #[serde(default)]
+    peb: PebOffsets,
+    #[serde(default)]
+    peb32: Peb32Offsets,
+    #[serde(default)]
+    process_parameters: ProcessParametersOffsets,
+    #[serde(default)]
+    process_parameters32: ProcessParameters32Offsets,
+    #[serde(default)]
+    ldr: LdrOffsets,
+    #[serde(default)]
+    ldr32: Ldr32Offsets,
+}
+
+impl From<RawProfile> for OffsetProfile {
+    fn from(raw: RawProfile) -> OffsetProfile {
+        OffsetProfile {
+            name: raw.name,
+            nt_version: raw.nt_version,
+            build: raw.build,
+            min_build: raw.min_build,
+            max_build: raw.max_build,
+            offsets: Offsets {
+                eprocess: raw.eprocess,
+                kprocess: raw.kprocess,
+                mm_session_space: raw.mm_session_space,
+                mm_support: raw.mm_support,
+                handle_table: raw.handle_table,
+                device_map: raw.device_map,
+                driver_object: raw.driver_object,
+                ethread: raw.ethread,
+                kthread: raw.kthread,
+                teb32: raw.teb32,
+                peb: raw.peb,
+                peb32: raw.peb32,
+                process_parameters: raw.process_parameters,
+                process_parameters32: raw.process_parameters32,
+                ldr: raw.ldr,
+                ldr32: raw.ldr32,
+            },
+        }
+    }
+}
+
+impl OffsetProfile {
+    /// Check whether the profile applies to the given Windows version
+    pub fn matches(&self, nt_version: u16, nt_build: u32) -> bool {
+        self.nt_version == nt_version
+            && self.build.is_none_or(|b| b == nt_build)
+            && self.min_build.is_none_or(|b| b <= nt_build)
+            && self.max_build.is_none_or(|b| b >= nt_build)
+    }
+
+    /// Higher values mean narrower build constraints
+    fn specificity(&self) -> u32 {
+        if self.build.is_some() {
+            3
+        } else if self.min_build.is_some() && self.max_build.is_some() {
+            2
+        } else if self.min_build.is_some() || self.max_build.is_some() {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}:{}", self.nt_version, match self.build {
+                Some(b) => b.to_string(),
+                None => format!("{}-{}",
+                    self.min_build.map(|b| b.to_string()).unwrap_or_default(),
+                    self.max_build.map(|b| b.to_string()).unwrap_or_default()),
+            }),
+        }
+    }
+}
+
+/// Error produced when loading offset profiles
+#[derive(Debug)]
+pub enum ProfileError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    /// File extension is neither `.toml`, nor `.json`
+    UnknownFormat,
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProfileError::Io(e) => write!(f, "failed to read profile: {}", e),
+            ProfileError::Toml(e) => write!(f, "failed to parse TOML profile: {}", e),
+            ProfileError::Json(e) => write!(f, "failed to parse JSON profile: {}", e),
+            ProfileError::UnknownFormat => write!(f, "unknown profile format"),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// A collection of offset profiles
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OffsetProfiles {
+    #[serde(default, rename = "profile")]
+    pub profiles: Vec<OffsetProfile>,
+}
+
+impl OffsetProfiles {
+    /// Parse profiles from a TOML document
+    pub fn from_toml_str(s: &str) -> Result<OffsetProfiles, ProfileError> {
+        toml::from_str(s).map_err(ProfileError::Toml)
+    }
+
+    /// Parse profiles from a JSON document
+    pub fn from_json_str(s: &str) -> Result<OffsetProfiles, ProfileError> {
+        serde_json::from_str(s).map_err(ProfileError::Json)
+    }
+
+    /// Load profiles from a file
+    ///
+    /// The format is picked based on the file extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<OffsetProfiles, ProfileError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(ProfileError::Io)?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml_str(&contents),
+            Some("json") => Self::from_json_str(&contents),
+            _ => Err(ProfileError::UnknownFormat),
+        }
+    }
+
+    /// Append profiles from another collection
+    ///
+    /// Among equally specific profiles, the later ones take priority.
+    pub fn extend(&mut self, other: OffsetProfiles) -> &mut Self {
+        self.profiles.extend(other.profiles);
+        self
+    }
+
+    /// Get the profiles applicable to a given version, sorted from the least to the most specific
+    pub fn matching(&self, nt_version: u16, nt_build: u32) -> Vec<&OffsetProfile> {
+        let mut ret = self.profiles.iter().filter(|p| p.matches(nt_version, nt_build)).collect::<Vec<_>>();
+        ret.sort_by_key(|p| p.specificity());
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> sys::WinOffsets {
+        sys::WinOffsets {
+            apl: 0x448,
+            session: 0x558,
+            stackCount: 0x23c,
+            imageFileName: 0x5a8,
+            dirBase: 0x28,
+            peb: 0x550,
+            peb32: 0x30,
+            threadListHead: 0x5e0,
+            threadListEntry: 0x4e8,
+            teb: 0xf0,
+        }
+    }
+
+    #[test]
+    fn overlays_library_builtin_and_profiles() {
+        let profiles = OffsetProfiles::from_toml_str(r#"
+            [[profile]]
+            name = "exact"
+            nt_version = 1000
+            build = 19045
+
+            [profile.eprocess]
+            peb = 0x777
+
+            [[profile]]
+            name = "range"
+            nt_version = 1000
+            min_build = 19041
+            max_build = 19045
+
+            [profile.eprocess]
+            peb = 0x999
+            wow64_process = 0x111
+
+            [[profile]]
+            nt_version = 1000
+            build = 22000
+
+            [profile.eprocess]
+            peb = 0x123
+        "#).unwrap();
+
+        let (offsets, report) = Offsets::resolve(Some(&library()), &profiles, 1000, 19045);
+
+        // The most specific profile is applied last, whatever the order in the file
+        assert_eq!(offsets.eprocess.peb, Some(0x777));
+        assert_eq!(report.source_of("eprocess.peb"), Some(&OffsetSource::Profile("exact".to_string())));
+        assert_eq!(offsets.eprocess.wow64_process, Some(0x111));
+        assert_eq!(report.source_of("eprocess.wow64_process"), Some(&OffsetSource::Profile("range".to_string())));
+
+        // Built-in offsets only fill in what the library has not provided
+        assert_eq!(report.source_of("kprocess.directory_table_base"), Some(&OffsetSource::Library));
+        assert_eq!(offsets.eprocess.object_table, Some(0x570));
+        assert_eq!(report.source_of("eprocess.object_table"), Some(&OffsetSource::Builtin));
+
+        assert_eq!(offsets.eprocess.unique_process_id, Some(0x440));
+        assert_eq!(report.source_of("eprocess.unique_process_id"), Some(&OffsetSource::Derived));
+
+        assert!(report.missing.contains(&"kprocess.execute_options"));
+        assert!(!report.missing.contains(&"eprocess.peb"));
+        assert_eq!(report.entries.iter().filter(|e| e.field == "eprocess.peb").count(), 1);
+    }
+
+    #[test]
+    fn names_unnamed_profiles_by_builds() {
+        let profiles = OffsetProfiles::from_json_str(r#"{
+            "profile": [{ "nt_version": 1000, "min_build": 26100, "eprocess": { "peb": "0x2e0" } }]
+        }"#).unwrap();
+
+        let (offsets, report) = Offsets::resolve(None, &profiles, 1000, 26200);
+
+        assert_eq!(offsets.eprocess.peb, Some(0x2e0));
+        assert_eq!(report.source_of("eprocess.peb"), Some(&OffsetSource::Profile("1000:26100-".to_string())));
+        assert!(Offsets::resolve(None, &profiles, 1000, 26000).1.source_of("eprocess.peb").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let misspelled_offset = "[[profile]]\nnt_version = 1000\n\n[profile.eprocess]\npebb = 0x550\n";
+        let misspelled_group = "[[profile]]\nnt_version = 1000\n\n[profile.eproces]\npeb = 0x550\n";
+        let misspelled_field = "[[profile]]\nnt_versoin = 1000\n";
+
+        for s in &[misspelled_offset, misspelled_group, misspelled_field] {
+            assert!(matches!(OffsetProfiles::from_toml_str(s), Err(ProfileError::Toml(_))), "{}", s);
+        }
+
+        let json = r#"{ "profile": [{ "nt_version": 1000, "kprocess": { "dir_base": 40 } }] }"#;
+        assert!(matches!(OffsetProfiles::from_json_str(json), Err(ProfileError::Json(_))));
+
+        let json = r#"{ "profile": [{ "nt_version": 1000, "kprocess": { "directory_table_base": "0xzz" } }] }"#;
+        assert!(matches!(OffsetProfiles::from_json_str(json), Err(ProfileError::Json(_))));
+    }
+
+    #[test]
+    fn names_the_missing_library_field() {
+        let mut c_offsets = sys::WinOffsets::default();
+
+        let (offsets, _) = Offsets::resolve(None, &OffsetProfiles::default(), 1000, 19045);
+        assert_eq!(offsets.to_library(&mut c_offsets), Err("eprocess.active_process_links"));
+
+        let mut offsets = Offsets::from_library(&library());
+        offsets.kthread.teb = None;
+        assert_eq!(offsets.to_library(&mut c_offsets), Err("kthread.teb"));
+
+        offsets.kthread.teb = Some(0xf0);
+        assert_eq!(offsets.to_library(&mut c_offsets), Ok(()));
+        assert_eq!(c_offsets.threadListEntry, 0x4e8);
+    }
+}
@@ -0,0 +1,347 @@
+//! IDA-style byte pattern scanning
+//!
+//! Patterns are written as space separated hexadecimal bytes, where `??` (or `?`) matches any
+//! byte, and a `?` in place of a single digit matches any nibble:
+//!
+//! ```text
+//! 48 8B 05 ?? ?? ?? ?? 48 85 C0
+//! 4? 8B ?5
+//! ```
+//!
+//! `Scanner` reads memory in large batched chunks and only scans present pages, so that scanning
+//! big modules does not flood the backend with tiny reads.
+
+use crate::address_space::*;
+use crate::win_dll::*;
+use crate::pe::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// Default size of a single batched read
+pub const DEFAULT_SCAN_CHUNK_SIZE: usize = 0x40_0000;
+
+/// Error produced when parsing a pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatternError {
+    Empty,
+    /// A token is not a valid byte, or wildcard
+    InvalidToken(String),
+    /// Byte and mask lengths do not match
+    LengthMismatch,
+    /// The pattern consists only of wildcards
+    OnlyWildcards,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternError::Empty => write!(f, "empty pattern"),
+            PatternError::InvalidToken(t) => write!(f, "invalid pattern token \"{}\"", t),
+            PatternError::LengthMismatch => write!(f, "pattern bytes and mask differ in length"),
+            PatternError::OnlyWildcards => write!(f, "pattern consists only of wildcards"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// A byte pattern with per-bit masks
+///
+/// A memory byte `b` matches position `i` if `b & masks[i] == bytes[i]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<u8>,
+    masks: Vec<u8>,
+    /// Position of a fully specified byte, used to quickly find match candidates
+    anchor: usize,
+}
+
+impl Pattern {
+    /// Parse an IDA-style pattern, i.e. `"48 8B 05 ?? ?? ?? ?? 48 85 C0"`
+    pub fn parse(pattern: &str) -> Result<Pattern, PatternError> {
+        let mut bytes = vec![];
+        let mut masks = vec![];
+
+        for token in pattern.split_whitespace() {
+            let (byte, mask) = match token {
+                "?" | "??" => (0, 0),
+                t if t.len() == 2 => {
+                    let mut byte = 0;
+                    let mut mask = 0;
+
+                    for c in t.chars() {
+                        byte <<= 4;
+                        mask <<= 4;
+
+                        if c != '?' {
+                            byte |= c.to_digit(16).ok_or_else(|| PatternError::InvalidToken(t.to_string()))? as u8;
+                            mask |= 0xf;
+                        }
+                    }
+
+                    (byte, mask)
+                },
+                t => return Err(PatternError::InvalidToken(t.to_string())),
+            };
+
+            bytes.push(byte);
+            masks.push(mask);
+        }
+
+        Self::from_masks(&bytes, &masks)
+    }
+
+    /// Create a pattern from bytes and a code style mask, i.e. `"xxx????xx"`
+    ///
+    /// `x` marks a byte that has to match, `?` (or any other character) marks a wildcard.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - pattern bytes, wildcard values are ignored
+    /// * `mask` - mask string with a character per byte
+    pub fn from_code_mask(bytes: &[u8], mask: &str) -> Result<Pattern, PatternError> {
+        let masks = mask.chars().map(|c| if c == 'x' { 0xff } else { 0 }).collect::<Vec<u8>>();
+        Self::from_masks(bytes, &masks)
+    }
+
+    /// Create a pattern from bytes and per-byte bit masks
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - pattern bytes
+    /// * `masks` - bits of each byte that have to match
+    pub fn from_masks(bytes: &[u8], masks: &[u8]) -> Result<Pattern, PatternError> {
+        if bytes.is_empty() {
+            return Err(PatternError::Empty);
+        }
+
+        if bytes.len() != masks.len() {
+            return Err(PatternError::LengthMismatch);
+        }
+
+        let anchor = masks.iter().position(|&m| m == 0xff)
+            .or_else(|| masks.iter().position(|&m| m != 0))
+            .ok_or(PatternError::OnlyWildcards)?;
+
+        Ok(Pattern {
+            bytes: bytes.iter().zip(masks.iter()).map(|(b, m)| b & m).collect(),
+            masks: masks.to_vec(),
+            anchor: anchor,
+        })
+    }
+
+    /// Create a pattern matching the bytes exactly
+    pub fn exact(bytes: &[u8]) -> Result<Pattern, PatternError> {
+        Self::from_masks(bytes, &vec![0xff; bytes.len()])
+    }
+
+    /// Length of the pattern in bytes
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Check whether the data starts with the pattern
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.len()
+            && data.iter().zip(self.bytes.iter().zip(self.masks.iter())).all(|(d, (b, m))| d & m == *b)
+    }
+
+    /// Find the longest run of bytes that are matched exactly, or ASCII case-insensitively
+    ///
+    /// Returns the offset of the run in the pattern and its bytes, used as literals for
+    /// multi-pattern prefilters.
+    pub(crate) fn literal_run(&self) -> Option<(usize, Vec<u8>)> {
+        let is_literal = |i: usize| self.masks[i] == 0xff || (self.masks[i] == 0xdf && self.bytes[i].is_ascii_alphabetic());
+        let mut best = (0, 0);
+        let mut start = 0;
+
+        for i in 0..=self.len() {
+            if i < self.len() && is_literal(i) {
+                continue;
+            }
+
+            if i - start > best.1 - best.0 {
+                best = (start, i);
+            }
+
+            start = i + 1;
+        }
+
+        if best.0 == best.1 {
+            None
+        } else {
+            Some((best.0, self.bytes[best.0..best.1].to_vec()))
+        }
+    }
+
+    /// Find all offsets of the pattern inside of a buffer
+    pub fn find_iter<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let end = if data.len() >= self.len() { data.len() - self.len() + 1 } else { 0 };
+        let anchor_byte = self.bytes[self.anchor];
+        let exact_anchor = self.masks[self.anchor] == 0xff;
+        let anchor_data = data.get(self.anchor..(self.anchor + end)).unwrap_or(&[]);
+
+        let candidates: Box<dyn Iterator<Item = usize> + 'a> = if exact_anchor {
+            Box::new(memchr::memchr_iter(anchor_byte, anchor_data))
+        } else {
+            Box::new(0..end)
+        };
+
+        candidates.filter(move |&i| self.matches(&data[i..]))
+    }
+
+    /// Find the first offset of the pattern inside of a buffer
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        self.find_iter(data).next()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Pattern, PatternError> {
+        Pattern::parse(s)
+    }
+}
+
+/// Pattern scanner over an address space
+#[derive(Clone, Copy)]
+pub struct Scanner<'a> {
+    mem: AddressSpace<'a>,
+    chunk_size: usize,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(mem: AddressSpace<'a>) -> Scanner<'a> {
+        Scanner {
+            mem: mem,
+            chunk_size: DEFAULT_SCAN_CHUNK_SIZE,
+        }
+    }
+
+    /// Set the size of a single batched read
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(0x1000);
+        self
+    }
+
+    /// Get the address space being scanned
+    pub fn address_space(&self) -> &AddressSpace<'a> {
+        &self.mem
+    }
+
+    /// Find all matches of a pattern inside of an address range
+    ///
+    /// In virtual address spaces only present pages are scanned.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - pattern to search for
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    pub fn find_all(&self, pattern: &Pattern, start: u64, end: u64) -> Vec<u64> {
+        let mut ret = vec![];
+        self.scan(pattern, start, end, &mut |a| {
+            ret.push(a);
+            true
+        });
+        ret
+    }
+
+    /// Find the first match of a pattern inside of an address range
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - pattern to search for
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    pub fn find_first(&self, pattern: &Pattern, start: u64, end: u64) -> Option<u64> {
+        let mut ret = None;
+        self.scan(pattern, start, end, &mut |a| {
+            ret = Some(a);
+            false
+        });
+        ret
+    }
+
+    /// Find all matches of a pattern in the executable sections of a module
+    ///
+    /// The whole image is scanned if its section headers are not readable.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - pattern to search for
+    /// * `module` - module to scan, mapped in the scanned address space
+    pub fn find_all_in_module(&self, pattern: &Pattern, module: &WinDll) -> Vec<u64> {
+        self.module_ranges(module).into_iter()
+            .flat_map(|(start, end)| self.find_all(pattern, start, end))
+            .collect()
+    }
+
+    /// Find the first match of a pattern in the executable sections of a module
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - pattern to search for
+    /// * `module` - module to scan, mapped in the scanned address space
+    pub fn find_first_in_module(&self, pattern: &Pattern, module: &WinDll) -> Option<u64> {
+        self.module_ranges(module).into_iter()
+            .find_map(|(start, end)| self.find_first(pattern, start, end))
+    }
+
+    fn module_ranges(&self, module: &WinDll) -> Vec<(u64, u64)> {
+        let base = module.info.baseAddress;
+
+        match PeHeaders::parse(&self.mem, base).map(|h| h.executable_ranges()) {
+            Some(ranges) if !ranges.is_empty() => ranges,
+            _ => vec![(base, base + module.info.sizeOfModule)],
+        }
+    }
+
+    /// Scan a range, calling `on_match` with every match until it returns `false`
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - pattern to search for
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    /// * `on_match` - match callback, returning whether to continue scanning
+    pub fn scan(&self, pattern: &Pattern, start: u64, end: u64, on_match: &mut dyn FnMut(u64) -> bool) {
+        self.mem.for_each_chunk(start, end, self.chunk_size, pattern.len() - 1, &mut |address, chunk, report_len| {
+            // Matches starting past report_len get reported by the next chunk
+            pattern.find_iter(chunk)
+                .take_while(|&off| off < report_len)
+                .all(|off| on_match(address + off as u64))
+        });
+    }
+}
+
+/// Resolve the target of a RIP-relative instruction operand
+///
+/// For `48 8B 05 xx xx xx xx` (`mov rax, [rip + disp32]`) at `address`, the displacement is at
+/// offset 3, and the instruction is 7 bytes long.
+///
+/// # Arguments
+///
+/// * `mem` - address space the instruction is in
+/// * `address` - address of the instruction
+/// * `disp_offset` - offset of the 32-bit displacement inside of the instruction
+/// * `instruction_len` - length of the whole instruction
+pub fn resolve_rip_relative(mem: &AddressSpace, address: u64, disp_offset: u64, instruction_len: u64) -> Option<u64> {
+    let disp = mem.read::<i32>(address + disp_offset)?;
+    Some((address + instruction_len).wrapping_add(disp as i64 as u64))
+}
+
+/// Resolve the target of a relative `call` or `jmp` (`E8`/`E9 rel32`)
+///
+/// # Arguments
+///
+/// * `mem` - address space the instruction is in
+/// * `address` - address of the instruction
+pub fn resolve_relative_branch(mem: &AddressSpace, address: u64) -> Option<u64> {
+    resolve_rip_relative(mem, address, 1, 5)
+}
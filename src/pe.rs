@@ -0,0 +1,303 @@
+use crate::address_space::*;
+use crate::win_export::*;
+
+pub const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+pub const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+
+const IMAGE_DOS_SIGNATURE: u16 = 0x5a4d;
+const IMAGE_NT_SIGNATURE: u32 = 0x4550;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+pub(crate) const CV_SIGNATURE_RSDS: u32 = 0x5344_5352;
+
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x20;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+/// Maximum length of an export name
+const MAX_EXPORT_NAME: usize = 0x100;
+
+/// Maximum length of a PDB path in the CodeView record
+const MAX_PDB_PATH: usize = 0x104;
+
+/// Upper bound of the section count, the loader itself allows no more than 96
+const MAX_SECTIONS: usize = 96;
+
+/// Upper bound of the export directory size read at once
+const MAX_EXPORT_DIRECTORY: u64 = 0x100_0000;
+
+/// Upper bound of the function and name counts, ordinals are 16-bit
+const MAX_EXPORTS: u64 = 0x10000;
+
+/// Single entry of the optional header's data directory
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+/// `IMAGE_SECTION_HEADER`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct RawSectionHeader {
+    name: [u8; 8],
+    virtual_size: u32,
+    virtual_address: u32,
+    size_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+    pointer_to_relocations: u32,
+    pointer_to_linenumbers: u32,
+    number_of_relocations: u16,
+    number_of_linenumbers: u16,
+    characteristics: u32,
+}
+
+/// A section of a PE image
+#[derive(Clone, Debug, Default)]
+pub struct Section {
+    pub name: String,
+    /// RVA of the section
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub characteristics: u32,
+}
+
+impl Section {
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & (IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_CNT_CODE) != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE != 0
+    }
+}
+
+/// `IMAGE_DEBUG_DIRECTORY` entry
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DebugDirectory {
+    characteristics: u32,
+    time_date_stamp: u32,
+    major_version: u16,
+    minor_version: u16,
+    kind: u32,
+    size_of_data: u32,
+    address_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+}
+
+/// PDB identity from an RSDS CodeView debug record
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CodeView {
+    pub guid: [u8; 16],
+    pub age: u32,
+    /// Path of the PDB, as embedded by the linker
+    pub pdb_path: String,
+}
+
+impl CodeView {
+    /// File name of the PDB, without the directory
+    pub fn pdb_name(&self) -> &str {
+        self.pdb_path.rsplit(['\\', '/']).next().unwrap_or("")
+    }
+
+    /// GUID in the big-endian byte order, as used by UUID types
+    pub fn guid_be(&self) -> [u8; 16] {
+        let g = &self.guid;
+        [g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15]]
+    }
+
+    /// Identifier used by symbol servers, the GUID followed by the age, in hexadecimal
+    pub fn symbol_id(&self) -> String {
+        let mut ret = self.guid_be().iter().map(|b| format!("{:02X}", b)).collect::<String>();
+        ret.push_str(&format!("{:X}", self.age));
+        ret
+    }
+}
+
+/// Parsed headers of a PE image mapped in memory
+#[derive(Clone, Debug, Default)]
+pub struct PeHeaders {
+    pub base: u64,
+    pub machine: u16,
+    pub time_date_stamp: u32,
+    pub checksum: u32,
+    pub is_64bit: bool,
+    pub size_of_image: u32,
+    pub data_directories: Vec<DataDirectory>,
+    pub sections: Vec<Section>,
+}
+
+impl PeHeaders {
+    /// Parse the headers of an image at `base`
+    ///
+    /// Returns `None` if the headers are not readable or invalid.
+    pub fn parse(mem: &AddressSpace, base: u64) -> Option<PeHeaders> {
+        if mem.read::<u16>(base)? != IMAGE_DOS_SIGNATURE {
+            return None;
+        }
+
+        let nt = base + mem.read::<u32>(base + 0x3c)? as u64;
+
+        if mem.read::<u32>(nt)? != IMAGE_NT_SIGNATURE {
+            return None;
+        }
+
+        let opt = nt + 0x18;
+        let is_64bit = mem.read::<u16>(opt)? == IMAGE_NT_OPTIONAL_HDR64_MAGIC;
+        let (num_dirs_off, dirs_off) = if is_64bit { (0x6c, 0x70) } else { (0x5c, 0x60) };
+
+        let num_dirs = mem.read::<u32>(opt + num_dirs_off)?.min(16) as usize;
+        let mut data_directories = vec![DataDirectory::default(); num_dirs];
+
+        if !mem.read_arr(opt + dirs_off, &mut data_directories) {
+            return None;
+        }
+
+        let num_sections = (mem.read::<u16>(nt + 0x6)? as usize).min(MAX_SECTIONS);
+        let size_of_optional_header = mem.read::<u16>(nt + 0x14)? as u64;
+        let mut raw_sections = vec![RawSectionHeader::default(); num_sections];
+
+        // Headers may be partially paged out, sections are not essential
+        if !mem.read_arr(opt + size_of_optional_header, &mut raw_sections) {
+            raw_sections.clear();
+        }
+
+        let sections = raw_sections.iter().map(|s| Section {
+            name: String::from_utf8_lossy(&s.name).trim_end_matches('\0').to_string(),
+            virtual_address: s.virtual_address,
+            virtual_size: s.virtual_size.max(s.size_of_raw_data),
+            characteristics: s.characteristics,
+        }).collect();
+
+        Some(PeHeaders {
+            base: base,
+            machine: mem.read::<u16>(nt + 0x4)?,
+            time_date_stamp: mem.read::<u32>(nt + 0x8)?,
+            checksum: mem.read::<u32>(opt + 0x40)?,
+            is_64bit: is_64bit,
+            size_of_image: mem.read::<u32>(opt + 0x38)?,
+            data_directories: data_directories,
+            sections: sections,
+        })
+    }
+
+    /// Get the absolute address ranges of executable sections
+    pub fn executable_ranges(&self) -> Vec<(u64, u64)> {
+        self.sections.iter()
+            .filter(|s| s.is_executable())
+            .map(|s| (self.base + s.virtual_address as u64, self.base + s.virtual_address as u64 + s.virtual_size as u64))
+            .collect()
+    }
+
+    /// Get a data directory entry, if it is present
+    pub fn data_directory(&self, index: usize) -> Option<DataDirectory> {
+        self.data_directories.get(index).cloned().filter(|d| d.virtual_address != 0 && d.size != 0)
+    }
+
+    /// Parse the export directory of the image
+    ///
+    /// Export names are resolved from a single read of the export directory whenever they are
+    /// located inside of it, which is the case for most images. Sizes and counts found in guest
+    /// memory are bounded by the image size before anything is allocated, `None` is returned if
+    /// they do not fit.
+    pub fn exports(&self, mem: &AddressSpace) -> Option<Vec<WinExport>> {
+        let dir = self.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT)?;
+        let dir_start = self.base + dir.virtual_address as u64;
+        let image_size = self.size_of_image as u64;
+
+        let dir_size = (dir.size as u64)
+            .min(image_size.checked_sub(dir.virtual_address as u64)?)
+            .min(MAX_EXPORT_DIRECTORY);
+
+        let mut buf = vec![0u8; dir_size as usize];
+        mem.read_sparse(dir_start, &mut buf);
+
+        let read_u32 = |address: u64| -> Option<u32> {
+            match address.checked_sub(dir_start).map(|o| o as usize) {
+                Some(o) if o + 4 <= buf.len() => Some(u32::from_le_bytes([buf[o], buf[o + 1], buf[o + 2], buf[o + 3]])),
+                _ => mem.read::<u32>(address),
+            }
+        };
+
+        let number_of_functions = read_u32(dir_start + 0x14)? as u64;
+        let number_of_names = read_u32(dir_start + 0x18)? as u64;
+
+        let max_entries = (image_size / 4).min(MAX_EXPORTS);
+
+        if number_of_functions > max_entries || number_of_names > max_entries {
+            return None;
+        }
+
+        let number_of_names = number_of_names as usize;
+        let address_of_functions = self.base + read_u32(dir_start + 0x1c)? as u64;
+        let address_of_names = self.base + read_u32(dir_start + 0x20)? as u64;
+        let address_of_ordinals = self.base + read_u32(dir_start + 0x24)? as u64;
+
+        let mut functions = vec![0u32; number_of_functions as usize];
+        let mut names = vec![0u32; number_of_names];
+        let mut ordinals = vec![0u16; number_of_names];
+
+        if !mem.read_arr(address_of_functions, &mut functions)
+            || !mem.read_arr(address_of_names, &mut names)
+            || !mem.read_arr(address_of_ordinals, &mut ordinals) {
+            return None;
+        }
+
+        let mut ret = Vec::with_capacity(number_of_names);
+
+        for (name_rva, ordinal) in names.iter().zip(ordinals.iter()) {
+            let function = match functions.get(*ordinal as usize) {
+                Some(&f) if f != 0 => f,
+                _ => continue,
+            };
+
+            let name_addr = self.base + *name_rva as u64;
+            let name = match name_addr.checked_sub(dir_start).map(|o| o as usize) {
+                Some(o) if o < buf.len() => {
+                    let len = buf[o..].iter().position(|&c| c == 0).unwrap_or(buf.len() - o);
+                    String::from_utf8_lossy(&buf[o..(o + len)]).into_owned()
+                },
+                _ => match mem.read_cstr(name_addr, MAX_EXPORT_NAME) {
+                    Some(n) => n,
+                    None => continue,
+                },
+            };
+
+            ret.push(WinExport {
+                name: name,
+                address: self.base + function as u64,
+            });
+        }
+
+        Some(ret)
+    }
+
+    /// Find the RSDS CodeView record in the debug directory
+    pub fn codeview(&self, mem: &AddressSpace) -> Option<CodeView> {
+        let dir = self.data_directory(IMAGE_DIRECTORY_ENTRY_DEBUG)?;
+        let count = dir.size as usize / std::mem::size_of::<DebugDirectory>();
+
+        let mut entries = vec![DebugDirectory::default(); count.min(16)];
+
+        if !mem.read_arr(self.base + dir.virtual_address as u64, &mut entries) {
+            return None;
+        }
+
+        let entry = entries.iter().find(|e| e.kind == IMAGE_DEBUG_TYPE_CODEVIEW && e.address_of_raw_data != 0)?;
+        let record = self.base + entry.address_of_raw_data as u64;
+
+        if mem.read::<u32>(record)? != CV_SIGNATURE_RSDS {
+            return None;
+        }
+
+        Some(CodeView {
+            guid: mem.read::<[u8; 16]>(record + 0x4)?,
+            age: mem.read::<u32>(record + 0x14)?,
+            pdb_path: mem.read_cstr(record + 0x18, MAX_PDB_PATH)?,
+        })
+    }
+}
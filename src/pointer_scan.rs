@@ -0,0 +1,298 @@
+//! Pointer scanning for stable pointer paths
+//!
+//! A `PointerSnapshot` records every value in the readable memory of a process that points into
+//! readable memory, and indexes them by the value. Searching the snapshot backwards from a target
+//! address yields `PointerPath`s, which start at a static address inside of a module image, like
+//! `game.exe+0x1A2B30 -> +0x18 -> +0x40`. Since paths reference modules by name, they can be
+//! re-validated against a later snapshot, or a restarted process.
+
+use crate::address_space::*;
+use crate::win_dll::*;
+use crate::win_process::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Settings of a pointer path search
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PointerScanOptions {
+    /// Maximum number of dereferences in a path
+    pub max_depth: usize,
+    /// Maximum offset added to a dereferenced pointer
+    pub max_offset: u64,
+    /// Stop searching after this many paths are found
+    pub max_results: usize,
+}
+
+impl Default for PointerScanOptions {
+    fn default() -> PointerScanOptions {
+        PointerScanOptions {
+            max_depth: 4,
+            max_offset: 0x1000,
+            max_results: 100_000,
+        }
+    }
+}
+
+/// A chain of pointers leading from a module to an address
+///
+/// The path is resolved by reading a pointer at `module + module_offset`, then repeatedly adding
+/// the next offset and reading a pointer again, except for the last offset, which is only added.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PointerPath {
+    pub module: String,
+    pub module_offset: u64,
+    pub offsets: Vec<u64>,
+}
+
+impl fmt::Display for PointerPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.module, self.module_offset)?;
+        for o in &self.offsets {
+            write!(f, " -> +{:#x}", o)?;
+        }
+        Ok(())
+    }
+}
+
+impl PointerPath {
+    fn resolve_with(&self, base: u64, read: impl Fn(u64) -> Option<u64>) -> Option<u64> {
+        let mut address = base + self.module_offset;
+
+        for o in &self.offsets {
+            address = read(address)?.checked_add(*o)?;
+        }
+
+        Some(address)
+    }
+
+    /// Resolve the path in live memory
+    ///
+    /// Returns `None` if the module is not loaded, or a pointer on the way is unreadable.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space of the process
+    /// * `modules` - module list of the process
+    /// * `is_64bit` - whether pointers are 8 or 4 bytes long
+    pub fn resolve(&self, mem: &AddressSpace, modules: &[WinDll], is_64bit: bool) -> Option<u64> {
+        let base = modules.iter().find(|m| m.name.eq_ignore_ascii_case(&self.module))?.info.baseAddress;
+        self.resolve_with(base, |a| mem.read_ptr(a, is_64bit))
+    }
+
+    /// Resolve the path in a snapshot
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - pointer snapshot of the process
+    pub fn resolve_in(&self, snapshot: &PointerSnapshot) -> Option<u64> {
+        let base = snapshot.modules.iter().find(|m| m.name.eq_ignore_ascii_case(&self.module))?.base;
+        self.resolve_with(base, |a| snapshot.pointer_at(a))
+    }
+}
+
+/// Serializable result of a pointer scan
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PointerScanResult {
+    /// Address the paths lead to at the time of the scan
+    pub target: u64,
+    pub options: Option<PointerScanOptions>,
+    pub paths: Vec<PointerPath>,
+}
+
+impl PointerScanResult {
+    /// Keep only the paths that lead to `target` in live memory
+    ///
+    /// Used after the process got restarted, or the target moved, with the new target address
+    /// found by other means. Returns the number of paths left.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space of the process
+    /// * `modules` - module list of the process
+    /// * `is_64bit` - whether pointers are 8 or 4 bytes long
+    /// * `target` - address the paths should lead to
+    pub fn revalidate(&mut self, mem: &AddressSpace, modules: &[WinDll], is_64bit: bool, target: u64) -> usize {
+        self.paths.retain(|p| p.resolve(mem, modules, is_64bit) == Some(target));
+        self.target = target;
+        self.paths.len()
+    }
+
+    /// Keep only the paths that lead to `target` in a snapshot
+    ///
+    /// Returns the number of paths left.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - pointer snapshot of the process
+    /// * `target` - address the paths should lead to
+    pub fn revalidate_in(&mut self, snapshot: &PointerSnapshot, target: u64) -> usize {
+        self.paths.retain(|p| p.resolve_in(snapshot) == Some(target));
+        self.target = target;
+        self.paths.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SnapshotModule {
+    name: String,
+    base: u64,
+    size: u64,
+}
+
+/// Every pointer into readable memory of a process at a point in time
+#[derive(Clone, Debug)]
+pub struct PointerSnapshot {
+    /// `(address, value)`, sorted by address
+    pointers: Vec<(u64, u64)>,
+    /// Indices into `pointers`, sorted by value
+    by_value: Vec<u32>,
+    /// Sorted by base
+    modules: Vec<SnapshotModule>,
+    is_64bit: bool,
+}
+
+impl PointerSnapshot {
+    /// Take a snapshot of an address space
+    ///
+    /// Only pointer aligned values are considered.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space of the process
+    /// * `modules` - module list of the process, providing the static bases
+    /// * `is_64bit` - whether pointers are 8 or 4 bytes long
+    pub fn new(mem: &AddressSpace, modules: &[WinDll], is_64bit: bool) -> PointerSnapshot {
+        let regions = mem.user_regions();
+        let ptr_size = if is_64bit { 8 } else { 4 };
+        let mut pointers = vec![];
+
+        let is_valid = |v: u64| {
+            let idx = regions.partition_point(|r| r.start <= v);
+            idx > 0 && regions[idx - 1].contains(v)
+        };
+
+        mem.for_each_chunk(0, USER_SPACE_END, 0x40_0000, 0, &mut |address, chunk, _| {
+            for (i, v) in chunk.chunks_exact(ptr_size).enumerate() {
+                let value = if is_64bit {
+                    u64::from_le_bytes([v[0], v[1], v[2], v[3], v[4], v[5], v[6], v[7]])
+                } else {
+                    u32::from_le_bytes([v[0], v[1], v[2], v[3]]) as u64
+                };
+
+                if value != 0 && is_valid(value) {
+                    pointers.push((address + (i * ptr_size) as u64, value));
+                }
+            }
+            true
+        });
+
+        pointers.sort_unstable();
+        pointers.dedup_by_key(|p| p.0);
+
+        let mut by_value = (0..pointers.len() as u32).collect::<Vec<_>>();
+        by_value.sort_unstable_by_key(|&i| pointers[i as usize].1);
+
+        let mut modules = modules.iter().map(|m| SnapshotModule {
+            name: m.name.clone(),
+            base: m.info.baseAddress,
+            size: m.info.sizeOfModule,
+        }).collect::<Vec<_>>();
+        modules.sort_by_key(|m| m.base);
+
+        PointerSnapshot {
+            pointers: pointers,
+            by_value: by_value,
+            modules: modules,
+            is_64bit: is_64bit,
+        }
+    }
+
+    /// Take a snapshot of a process
+    ///
+    /// The module list of the process needs to be refreshed beforehand.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn from_process(ctx: &sys::WinCtx, process: &WinProcess) -> PointerSnapshot {
+        Self::new(&process.address_space(ctx), &process.module_list, !process.is_wow64(ctx))
+    }
+
+    /// Number of pointers in the snapshot
+    pub fn len(&self) -> usize {
+        self.pointers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pointers.is_empty()
+    }
+
+    /// Whether the snapshot was taken with 8 byte pointers
+    pub fn is_64bit(&self) -> bool {
+        self.is_64bit
+    }
+
+    /// Get the pointer stored at an address, if it points into readable memory
+    pub fn pointer_at(&self, address: u64) -> Option<u64> {
+        self.pointers.binary_search_by_key(&address, |p| p.0).ok().map(|i| self.pointers[i].1)
+    }
+
+    /// Get the addresses holding pointers with values in the inclusive range
+    pub fn pointers_to(&self, min: u64, max: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let start = self.by_value.partition_point(|&i| self.pointers[i as usize].1 < min);
+
+        self.by_value[start..].iter()
+            .map(move |&i| self.pointers[i as usize])
+            .take_while(move |p| p.1 <= max)
+    }
+
+    fn module_at(&self, address: u64) -> Option<&SnapshotModule> {
+        let idx = self.modules.partition_point(|m| m.base <= address);
+        self.modules[..idx].last().filter(|m| address - m.base < m.size)
+    }
+
+    /// Search for pointer paths leading to an address
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - address the paths should lead to
+    /// * `options` - search limits
+    pub fn find_paths(&self, target: u64, options: &PointerScanOptions) -> PointerScanResult {
+        let mut ret = PointerScanResult {
+            target: target,
+            options: Some(*options),
+            paths: vec![],
+        };
+
+        let mut offsets = vec![];
+        self.search(target, options, &mut offsets, &mut ret.paths);
+
+        ret
+    }
+
+    fn search(&self, target: u64, options: &PointerScanOptions, offsets: &mut Vec<u64>, out: &mut Vec<PointerPath>) {
+        if offsets.len() >= options.max_depth {
+            return;
+        }
+
+        for (address, value) in self.pointers_to(target.saturating_sub(options.max_offset), target) {
+            if out.len() >= options.max_results {
+                return;
+            }
+
+            offsets.push(target - value);
+
+            match self.module_at(address) {
+                Some(m) => out.push(PointerPath {
+                    module: m.name.clone(),
+                    module_offset: address - m.base,
+                    offsets: offsets.iter().rev().cloned().collect(),
+                }),
+                None => self.search(address, options, offsets, out),
+            }
+
+            offsets.pop();
+        }
+    }
+}
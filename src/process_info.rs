@@ -0,0 +1,243 @@
+use crate::address_space::*;
+use crate::offsets::*;
+use crate::win_info::*;
+use std::time::SystemTime;
+
+/// `STATUS_PENDING`, the exit status of a process that is still running
+pub const STATUS_PENDING: i32 = 0x103;
+
+/// Number of handle table entries in a single lowest level table page
+const HANDLE_ENTRIES_PER_PAGE: usize = 0x1000 / 16;
+
+/// Upper bound of handle table pages to walk
+const MAX_HANDLE_PAGES: usize = 0x4000;
+
+/// `KEXECUTE_OPTIONS.ExecuteDisable`, DEP has been enabled for a 32-bit process
+const EXECUTE_DISABLE: u8 = 0x1;
+
+/// `KEXECUTE_OPTIONS.ExecuteEnable`, DEP has been disabled for the process
+const EXECUTE_ENABLE: u8 = 0x2;
+
+/// Type of process protection, `PS_PROTECTION.Type`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionType {
+    None,
+    ProtectedLight,
+    Protected,
+    Unknown(u8),
+}
+
+/// Signer of a protected process, `PS_PROTECTION.Signer`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionSigner {
+    None,
+    Authenticode,
+    CodeGen,
+    Antimalware,
+    Lsa,
+    Windows,
+    WinTcb,
+    WinSystem,
+    App,
+    Unknown(u8),
+}
+
+/// Process protection level, as stored in `_EPROCESS.Protection`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protection(pub u8);
+
+impl Protection {
+    pub fn kind(&self) -> ProtectionType {
+        match self.0 & 0x7 {
+            0 => ProtectionType::None,
+            1 => ProtectionType::ProtectedLight,
+            2 => ProtectionType::Protected,
+            v => ProtectionType::Unknown(v),
+        }
+    }
+
+    pub fn audit(&self) -> bool {
+        self.0 & 0x8 != 0
+    }
+
+    pub fn signer(&self) -> ProtectionSigner {
+        match self.0 >> 4 {
+            0 => ProtectionSigner::None,
+            1 => ProtectionSigner::Authenticode,
+            2 => ProtectionSigner::CodeGen,
+            3 => ProtectionSigner::Antimalware,
+            4 => ProtectionSigner::Lsa,
+            5 => ProtectionSigner::Windows,
+            6 => ProtectionSigner::WinTcb,
+            7 => ProtectionSigner::WinSystem,
+            8 => ProtectionSigner::App,
+            v => ProtectionSigner::Unknown(v),
+        }
+    }
+}
+
+/// Exploit mitigation state of a process
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mitigations {
+    /// Raw `_EPROCESS.MitigationFlags`
+    pub flags: u32,
+    /// Raw `_EPROCESS.MitigationFlags2`
+    pub flags2: u32,
+    /// Data Execution Prevention, from `_KPROCESS.Flags`. `None` if its offset is unknown
+    pub dep: Option<bool>,
+}
+
+impl Mitigations {
+    /// Control Flow Guard
+    pub fn cfg(&self) -> bool {
+        self.flags & (1 << 0) != 0
+    }
+
+    /// Strict Control Flow Guard
+    pub fn cfg_strict(&self) -> bool {
+        self.flags & (1 << 2) != 0
+    }
+
+    /// Arbitrary Code Guard (dynamic code is prohibited)
+    pub fn acg(&self) -> bool {
+        self.flags & (1 << 8) != 0
+    }
+
+    /// Win32k system calls are disallowed
+    pub fn win32k_disabled(&self) -> bool {
+        self.flags & (1 << 12) != 0
+    }
+}
+
+/// Extended process metadata parsed from `_EPROCESS`
+///
+/// Fields are `None` if the respective offsets are unknown for the running build, or the memory
+/// could not be read.
+#[derive(Clone, Debug)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    pub parent_pid: Option<u64>,
+    pub create_time: Option<SystemTime>,
+    /// `None` if the process has not exited yet
+    pub exit_time: Option<SystemTime>,
+    pub session_id: Option<u32>,
+    /// `STATUS_PENDING` while the process is running
+    pub exit_status: Option<i32>,
+    pub protection: Option<Protection>,
+    /// Directory table base used in user mode when KPTI is enabled
+    pub user_directory_table_base: Option<u64>,
+    pub handle_count: Option<u32>,
+    /// Working set size in bytes, from `_EPROCESS.Vm`
+    pub working_set: Option<u64>,
+    /// Committed private memory in bytes
+    pub private_bytes: Option<u64>,
+    pub mitigations: Option<Mitigations>,
+    pub is_wow64: bool,
+}
+
+impl ProcessInfo {
+    /// Read the metadata of a process
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `proc` - target process
+    /// * `offsets` - resolved offsets of the context
+    /// * `is_wow64` - whether the process runs under WoW64
+    pub fn read(ctx: &sys::WinCtx, proc: &sys::WinProc, offsets: &Offsets, is_wow64: bool) -> ProcessInfo {
+        let kernel = AddressSpace::kernel(ctx);
+        let eprocess = &offsets.eprocess;
+
+        let field = |off: Option<i64>| off.map(|o| proc.process + o as u64);
+
+        let read_time = |off: Option<i64>| -> Option<u64> { kernel.read::<u64>(field(off)?) };
+
+        // Native 64-bit processes have DEP unless it was explicitly disabled, 32-bit ones need it enabled
+        let dep = field(offsets.kprocess.execute_options)
+            .and_then(|a| kernel.read::<u8>(a))
+            .map(|o| o & EXECUTE_ENABLE == 0 && (!is_wow64 || o & EXECUTE_DISABLE != 0));
+
+        let mitigations = match (field(eprocess.mitigation_flags), field(eprocess.mitigation_flags2)) {
+            (Some(f1), Some(f2)) => Some(Mitigations {
+                flags: kernel.read::<u32>(f1).unwrap_or(0),
+                flags2: kernel.read::<u32>(f2).unwrap_or(0),
+                dep: dep,
+            }),
+            _ => None,
+        };
+
+        ProcessInfo {
+            pid: proc.pid,
+            parent_pid: field(eprocess.inherited_from_unique_process_id).and_then(|a| kernel.read::<u64>(a)),
+            create_time: read_time(eprocess.create_time).map(filetime_to_system_time),
+            exit_time: read_time(eprocess.exit_time).filter(|&t| t != 0).map(filetime_to_system_time),
+            session_id: Self::read_session_id(&kernel, proc, offsets),
+            exit_status: field(eprocess.exit_status).and_then(|a| kernel.read::<i32>(a)),
+            protection: field(eprocess.protection).and_then(|a| kernel.read::<u8>(a)).map(Protection),
+            user_directory_table_base: field(offsets.kprocess.user_directory_table_base).and_then(|a| kernel.read::<u64>(a)),
+            handle_count: Self::read_handle_count(&kernel, proc, offsets),
+            working_set: Self::read_working_set(&kernel, proc, offsets),
+            private_bytes: field(eprocess.commit_charge).and_then(|a| kernel.read::<u64>(a)).map(|pages| pages * 0x1000),
+            mitigations: mitigations,
+            is_wow64: is_wow64,
+        }
+    }
+
+    /// Read `_EPROCESS.Vm.WorkingSetSize`, which is counted in pages
+    fn read_working_set(kernel: &AddressSpace, proc: &sys::WinProc, offsets: &Offsets) -> Option<u64> {
+        let vm = proc.process + offsets.eprocess.vm? as u64;
+        kernel.read::<u64>(vm + offsets.mm_support.working_set_size? as u64).map(|pages| pages * 0x1000)
+    }
+
+    pub(crate) fn read_session_id(kernel: &AddressSpace, proc: &sys::WinProc, offsets: &Offsets) -> Option<u32> {
+        let session = kernel.read::<u64>(proc.process + offsets.eprocess.session? as u64)?;
+
+        // The system process and early boot processes do not belong to a session
+        if session == 0 {
+            return None;
+        }
+
+        kernel.read::<u32>(session + offsets.mm_session_space.session_id? as u64)
+    }
+
+    /// Count the used entries of the process handle table
+    ///
+    /// Newer builds do not keep a handle count in `_HANDLE_TABLE`, so the table is walked instead.
+    fn read_handle_count(kernel: &AddressSpace, proc: &sys::WinProc, offsets: &Offsets) -> Option<u32> {
+        let table = kernel.read::<u64>(proc.process + offsets.eprocess.object_table? as u64)?;
+
+        if table == 0 {
+            return None;
+        }
+
+        let table_code = kernel.read::<u64>(table + offsets.handle_table.table_code? as u64)?;
+        let mut pages = vec![table_code & !0x7];
+
+        // Resolve the upper levels down to pages of handle table entries
+        for _ in 0..(table_code & 0x3) {
+            let mut next = vec![];
+
+            for page in pages {
+                let mut ptrs = [0u64; 0x1000 / 8];
+                if kernel.read_arr(page, &mut ptrs) {
+                    next.extend(ptrs.iter().cloned().filter(|&p| p != 0));
+                }
+            }
+
+            next.truncate(MAX_HANDLE_PAGES);
+            pages = next;
+        }
+
+        let mut count = 0;
+
+        for page in pages {
+            let mut entries = [[0u64; 2]; HANDLE_ENTRIES_PER_PAGE];
+            if kernel.read_arr(page, &mut entries) {
+                // The first entry of every page is reserved
+                count += entries.iter().skip(1).filter(|e| e[0] != 0).count() as u32;
+            }
+        }
+
+        Some(count)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::address_space::*;
+use crate::offsets::*;
+use std::collections::BTreeMap;
+
+/// Environment blocks larger than this are truncated
+const MAX_ENVIRONMENT_SIZE: u64 = 0x10_0000;
+
+/// Parsed `_RTL_USER_PROCESS_PARAMETERS` of a process
+#[derive(Clone, Debug, Default)]
+pub struct ProcessParameters {
+    pub image_path: String,
+    pub command_line: String,
+    pub current_directory: String,
+    pub window_title: String,
+    /// Environment variables. Names starting with `=` (per-drive current directories) are kept
+    pub environment: BTreeMap<String, String>,
+}
+
+impl ProcessParameters {
+    /// Parse the process parameters
+    ///
+    /// Strings that could not be read are left empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space of the process
+    /// * `address` - address of the `_RTL_USER_PROCESS_PARAMETERS` structure
+    /// * `is_64bit` - whether the structure is the 64-bit, or the WoW64 variant
+    /// * `offsets` - resolved offsets of the context
+    pub fn read(mem: &AddressSpace, address: u64, is_64bit: bool, offsets: &Offsets) -> Option<ProcessParameters> {
+        let offsets = offsets.process_parameters_for(is_64bit);
+
+        let read_string = |off: Option<i64>| -> String {
+            off.and_then(|o| mem.read_unicode_string(address + o as u64, is_64bit)).unwrap_or_default()
+        };
+
+        let environment = mem.read_ptr(address + offsets.environment? as u64, is_64bit)?;
+        let environment_size = offsets.environment_size
+            .and_then(|o| mem.read_ptr(address + o as u64, is_64bit))
+            .unwrap_or(MAX_ENVIRONMENT_SIZE);
+
+        Some(ProcessParameters {
+            image_path: read_string(offsets.image_path_name),
+            command_line: read_string(offsets.command_line),
+            current_directory: read_string(offsets.current_directory),
+            window_title: read_string(offsets.window_title),
+            environment: Self::read_environment(mem, environment, environment_size.min(MAX_ENVIRONMENT_SIZE)),
+        })
+    }
+
+    fn read_environment(mem: &AddressSpace, address: u64, size: u64) -> BTreeMap<String, String> {
+        let mut ret = BTreeMap::new();
+
+        if address == 0 {
+            return ret;
+        }
+
+        let mut buf = vec![0u8; size as usize & !1];
+        mem.read_sparse(address, &mut buf);
+
+        let wide = buf.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect::<Vec<u16>>();
+
+        for entry in wide.split(|&c| c == 0).take_while(|e| !e.is_empty()) {
+            let entry = String::from_utf16_lossy(entry);
+
+            // Skip the first character so that "=C:=C:\" splits after the drive letter
+            match entry.char_indices().skip(1).find(|&(_, c)| c == '=') {
+                Some((i, _)) => ret.insert(entry[..i].to_string(), entry[(i + 1)..].to_string()),
+                None => ret.insert(entry, String::new()),
+            };
+        }
+
+        ret
+    }
+}
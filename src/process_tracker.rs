@@ -0,0 +1,258 @@
+use crate::win_context::*;
+use crate::win_process::*;
+use crate::address_space::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Unique identity of a process instance
+///
+/// Process IDs get reused, thus the `_EPROCESS` address and the creation time are included to
+/// tell apart different processes that had the same PID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProcessKey {
+    pub pid: u64,
+    pub eprocess: u64,
+    /// Raw `FILETIME` of the process creation, 0 if unknown
+    pub create_time: u64,
+}
+
+/// A change in the process list
+#[derive(Clone)]
+pub enum ProcessEvent {
+    Started(WinProcess),
+    Exited(WinProcess),
+    /// A process exited and a new one got the same PID between two refreshes
+    Reused {
+        old: WinProcess,
+        new: WinProcess,
+    },
+}
+
+#[derive(Clone)]
+struct TrackedProcess {
+    process: WinProcess,
+    parent_pid: Option<u64>,
+}
+
+/// Incrementally tracks the process list of a context
+///
+/// Each `refresh` diffs the current process list against the previous one and reports the
+/// differences as `ProcessEvent`s. The very first refresh reports every running process as
+/// started.
+#[derive(Clone, Default)]
+pub struct ProcessTracker {
+    processes: BTreeMap<ProcessKey, TrackedProcess>,
+}
+
+impl ProcessTracker {
+    pub fn new() -> ProcessTracker {
+        ProcessTracker::default()
+    }
+
+    /// Compute the identity of a process
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn key_of(ctx: &sys::WinCtx, process: &WinProcess) -> ProcessKey {
+        let create_time = process.offsets.eprocess.create_time
+            .and_then(|o| AddressSpace::kernel(ctx).read::<u64>(process.proc.process + o as u64))
+            .unwrap_or(0);
+
+        ProcessKey {
+            pid: process.proc.pid,
+            eprocess: process.proc.process,
+            create_time: create_time,
+        }
+    }
+
+    /// Walk the process list of the context and compute the changes
+    ///
+    /// The context's own `process_list` is left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context
+    pub fn refresh(&mut self, ctx: &WinContext) -> Vec<ProcessEvent> {
+        let c_ctx = ctx.c_ctx();
+        let mut current = BTreeMap::new();
+        let mut started = vec![];
+
+        for p in ctx.list_processes() {
+            let key = Self::key_of(c_ctx, &p);
+
+            let tracked = match self.processes.remove(&key) {
+                Some(mut t) => {
+                    // Keep the state gathered by the user, like module lists
+                    t.process.proc = p.proc;
+                    t
+                },
+                None => {
+                    started.push(key);
+                    TrackedProcess {
+                        parent_pid: p.offsets.eprocess.inherited_from_unique_process_id
+                            .and_then(|o| AddressSpace::kernel(c_ctx).read::<u64>(p.proc.process + o as u64)),
+                        process: p,
+                    }
+                },
+            };
+
+            current.insert(key, tracked);
+        }
+
+        // Whatever is left in the old map has exited
+        let mut exited = std::mem::take(&mut self.processes)
+            .into_iter()
+            .map(|(k, v)| (k.pid, v.process))
+            .collect::<BTreeMap<u64, WinProcess>>();
+
+        let mut events = vec![];
+
+        for key in started {
+            let new = current[&key].process.clone();
+
+            events.push(match exited.remove(&key.pid) {
+                Some(old) => ProcessEvent::Reused {
+                    old: old,
+                    new: new,
+                },
+                None => ProcessEvent::Started(new),
+            });
+        }
+
+        events.extend(exited.into_values().map(ProcessEvent::Exited));
+
+        self.processes = current;
+
+        events
+    }
+
+    /// Get the tracked processes
+    pub fn processes(&self) -> impl Iterator<Item = (&ProcessKey, &WinProcess)> {
+        self.processes.iter().map(|(k, v)| (k, &v.process))
+    }
+
+    /// Get a tracked process by its identity
+    pub fn get(&self, key: &ProcessKey) -> Option<&WinProcess> {
+        self.processes.get(key).map(|t| &t.process)
+    }
+
+    /// Build the parent/child tree of tracked processes
+    ///
+    /// A process is considered the parent only if it was created before the child, so that
+    /// reused PIDs do not produce bogus links. Processes whose parent is gone become roots.
+    pub fn tree(&self) -> ProcessTree {
+        let mut tree = ProcessTree::default();
+
+        for (key, tracked) in &self.processes {
+            let parent = tracked.parent_pid.and_then(|ppid| {
+                self.processes.keys()
+                    .filter(|k| k.pid == ppid && k != &key && k.create_time <= key.create_time)
+                    .max_by_key(|k| k.create_time)
+                    .cloned()
+            });
+
+            tree.parents.insert(*key, parent);
+
+            match parent {
+                Some(p) => tree.children.entry(p).or_default().push(*key),
+                None => tree.roots.push(*key),
+            }
+        }
+
+        tree
+    }
+
+    /// Get a non-blocking poller of process events
+    ///
+    /// The process list is walked at most once every `interval`, whenever there are no pending
+    /// events. The first poll walks it right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context
+    /// * `interval` - minimum time between walks of the process list
+    pub fn events<'a>(&'a mut self, ctx: &'a WinContext, interval: Duration) -> ProcessEvents<'a> {
+        ProcessEvents {
+            tracker: self,
+            ctx: ctx,
+            interval: interval,
+            pending: VecDeque::new(),
+            last_poll: None,
+        }
+    }
+}
+
+/// Parent/child relationships of processes
+#[derive(Clone, Debug, Default)]
+pub struct ProcessTree {
+    pub roots: Vec<ProcessKey>,
+    parents: BTreeMap<ProcessKey, Option<ProcessKey>>,
+    children: BTreeMap<ProcessKey, Vec<ProcessKey>>,
+}
+
+impl ProcessTree {
+    /// Get the parent of a process, if it is still running
+    pub fn parent(&self, key: &ProcessKey) -> Option<&ProcessKey> {
+        self.parents.get(key).and_then(|p| p.as_ref())
+    }
+
+    /// Get the direct children of a process
+    pub fn children(&self, key: &ProcessKey) -> &[ProcessKey] {
+        self.children.get(key).map(|c| &c[..]).unwrap_or(&[])
+    }
+
+    /// Get the chain of parents of a process, starting from the direct parent
+    pub fn ancestors(&self, key: &ProcessKey) -> Vec<ProcessKey> {
+        let mut ret = vec![];
+        let mut cur = key;
+
+        while let Some(p) = self.parent(cur) {
+            if ret.contains(p) {
+                break;
+            }
+            ret.push(*p);
+            cur = p;
+        }
+
+        ret
+    }
+}
+
+/// Poller returned by `ProcessTracker::events`
+///
+/// Iterating never sleeps. `None` means that no event is available right now, the poller can be
+/// iterated again later, i.e. from the main loop of the tool, once `time_until_poll` has passed.
+pub struct ProcessEvents<'a> {
+    tracker: &'a mut ProcessTracker,
+    ctx: &'a WinContext,
+    interval: Duration,
+    pending: VecDeque<ProcessEvent>,
+    last_poll: Option<Instant>,
+}
+
+impl ProcessEvents<'_> {
+    /// Get the time left until the process list is walked again
+    ///
+    /// Zero if events are pending, or the list is due to be walked.
+    pub fn time_until_poll(&self) -> Duration {
+        match self.last_poll {
+            Some(t) if self.pending.is_empty() => self.interval.saturating_sub(t.elapsed()),
+            _ => Duration::default(),
+        }
+    }
+}
+
+impl Iterator for ProcessEvents<'_> {
+    type Item = ProcessEvent;
+
+    fn next(&mut self) -> Option<ProcessEvent> {
+        if self.pending.is_empty() && self.time_until_poll() == Duration::default() {
+            self.last_poll = Some(Instant::now());
+            self.pending.extend(self.tracker.refresh(self.ctx));
+        }
+
+        self.pending.pop_front()
+    }
+}
@@ -0,0 +1,786 @@
+//! Client of the QEMU Machine Protocol
+//!
+//! QMP is the JSON protocol qemu exposes on a monitor socket, i.e. one created with
+//! `-qmp unix:/tmp/qmp.sock,server=on,wait=off`. It is used here to pause the VM while its memory
+//! is being walked, so that process lists and page tables can not change midway, to query the
+//! guest memory layout, and to let qemu dump guest memory by itself.
+//!
+//! Every message is a JSON object. Commands are answered either with a `return` or an `error`
+//! object, while asynchronous events may arrive in between, and are queued.
+
+use crate::memory_map::*;
+use crate::tlb::*;
+use crate::vm_discovery::*;
+use crate::win_context::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Error produced by QMP operations
+#[derive(Debug)]
+pub enum QmpError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// qemu has refused to execute a command
+    Command { class: String, desc: String },
+    /// The peer does not speak QMP, or has closed the connection
+    Protocol(&'static str),
+}
+
+impl std::fmt::Display for QmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QmpError::Io(e) => write!(f, "QMP I/O error: {}", e),
+            QmpError::Json(e) => write!(f, "invalid QMP message: {}", e),
+            QmpError::Command { class, desc } => write!(f, "QMP command failed ({}): {}", class, desc),
+            QmpError::Protocol(e) => write!(f, "QMP protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QmpError {}
+
+impl From<std::io::Error> for QmpError {
+    fn from(e: std::io::Error) -> QmpError {
+        QmpError::Io(e)
+    }
+}
+
+/// Error produced by `QmpClient::with_paused`
+#[derive(Debug)]
+pub enum PausedError<R> {
+    /// The VM could not be paused, the operation has not been run
+    Pause(QmpError),
+    /// The operation has run, but the VM could not be resumed
+    Resume { result: R, error: QmpError },
+}
+
+impl<R> PausedError<R> {
+    /// Get the QMP error, whether pausing or resuming failed
+    pub fn qmp_error(&self) -> &QmpError {
+        match self {
+            PausedError::Pause(e) => e,
+            PausedError::Resume { error, .. } => error,
+        }
+    }
+
+    /// Get the result of the operation, if it has run
+    pub fn into_result(self) -> Option<R> {
+        match self {
+            PausedError::Pause(_) => None,
+            PausedError::Resume { result, .. } => Some(result),
+        }
+    }
+}
+
+impl<R> std::fmt::Display for PausedError<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PausedError::Pause(e) => write!(f, "failed to pause the VM: {}", e),
+            PausedError::Resume { error, .. } => write!(f, "failed to resume the VM: {}", error),
+        }
+    }
+}
+
+impl<R: std::fmt::Debug> std::error::Error for PausedError<R> {}
+
+/// Version of qemu announced in the QMP greeting
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QmpVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub micro: u64,
+    /// Distribution specific package version
+    pub package: String,
+}
+
+/// Asynchronous event sent by qemu, i.e. `STOP` or `RESUME`
+#[derive(Clone, Debug, PartialEq)]
+pub struct QmpEvent {
+    pub event: String,
+    /// Event specific data, `Null` if there is none
+    pub data: Value,
+    /// Host time of the event since the Unix epoch
+    pub timestamp: Duration,
+}
+
+impl QmpEvent {
+    fn from_value(msg: &Value) -> QmpEvent {
+        let timestamp = &msg["timestamp"];
+
+        QmpEvent {
+            event: msg["event"].as_str().unwrap_or_default().to_string(),
+            data: msg.get("data").cloned().unwrap_or(Value::Null),
+            timestamp: Duration::new(timestamp["seconds"].as_u64().unwrap_or(0), timestamp["microseconds"].as_u64().unwrap_or(0) as u32 * 1000),
+        }
+    }
+}
+
+/// Run state of the VM, as returned by `query-status`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct VmStatus {
+    pub running: bool,
+    /// Detailed state, i.e. `"running"`, `"paused"` or `"shutdown"`
+    pub status: String,
+}
+
+/// Amount of guest RAM, as returned by `query-memory-size-summary`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MemorySizeSummary {
+    /// RAM the VM has been started with
+    pub base_memory: u64,
+    /// RAM added through memory devices
+    pub plugged_memory: Option<u64>,
+}
+
+/// Properties of a memory device. Which ones are set depends on the device type
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct MemoryDeviceData {
+    pub id: Option<String>,
+    /// Guest physical base address
+    pub addr: Option<u64>,
+    pub size: Option<u64>,
+    pub slot: Option<u64>,
+    pub node: Option<u64>,
+    /// Memory backend providing the RAM
+    pub memdev: Option<String>,
+    pub hotplugged: Option<bool>,
+}
+
+/// Memory device plugged into the VM, as returned by `query-memory-devices`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct MemoryDevice {
+    /// Device type, i.e. `"dimm"` or `"virtio-mem"`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub data: MemoryDeviceData,
+}
+
+/// A range of the flattened guest physical address space, as printed by `info mtree -f`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryTreeRange {
+    /// Guest physical start address
+    pub start: u64,
+    /// Guest physical end address (exclusive)
+    pub end: u64,
+    /// Region type, i.e. `"ram"`, `"rom"` or `"i/o"`
+    pub kind: String,
+    /// Name of the memory region
+    pub name: String,
+    /// Offset of the range start inside of the memory region
+    pub offset: u64,
+}
+
+/// Parse the flat view of the system address space from `info mtree -f` output
+///
+/// # Arguments
+///
+/// * `text` - monitor output
+pub fn parse_memory_tree(text: &str) -> Vec<MemoryTreeRange> {
+    let mut ret = vec![];
+    let mut in_system = false;
+
+    for line in text.lines().map(|l| l.trim()) {
+        if line.starts_with("FlatView") {
+            // Views are printed once with every address space sharing them
+            if in_system {
+                break;
+            }
+            continue;
+        }
+
+        if line.starts_with("AS \"memory\"") {
+            in_system = true;
+            continue;
+        }
+
+        if !in_system {
+            continue;
+        }
+
+        // 0000000100000000-000000017fffffff (prio 0, ram): pc.ram @00000000c0000000 KVM
+        let parsed = (|| {
+            let (range, rest) = line.split_once(' ')?;
+            let (start, end) = range.split_once('-')?;
+            let (attrs, rest) = rest.strip_prefix('(')?.split_once("):")?;
+            let kind = attrs.rsplit(", ").next()?;
+            let mut words = rest.split_whitespace();
+            let name = words.next()?;
+            let offset = match words.next().and_then(|w| w.strip_prefix('@')) {
+                Some(o) => u64::from_str_radix(o, 16).ok()?,
+                None => 0,
+            };
+
+            Some(MemoryTreeRange {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?.checked_add(1)?,
+                kind: kind.to_string(),
+                name: name.to_string(),
+                offset: offset,
+            })
+        })();
+
+        ret.extend(parsed);
+    }
+
+    ret
+}
+
+/// Format of a memory dump written by qemu
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Elf,
+    KdumpZlib,
+    KdumpLzo,
+    KdumpSnappy,
+    /// Windows crash dump, requires the guest to have a vmcoreinfo device
+    WinDmp,
+}
+
+impl DumpFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            DumpFormat::Elf => "elf",
+            DumpFormat::KdumpZlib => "kdump-zlib",
+            DumpFormat::KdumpLzo => "kdump-lzo",
+            DumpFormat::KdumpSnappy => "kdump-snappy",
+            DumpFormat::WinDmp => "win-dmp",
+        }
+    }
+}
+
+/// Connection to a QMP socket
+pub struct QmpClient {
+    stream: BufReader<UnixStream>,
+    version: QmpVersion,
+    next_id: u64,
+    events: Vec<QmpEvent>,
+}
+
+/// Resumes the VM when dropped, even if the paused operation panics
+struct PauseGuard<'a> {
+    client: &'a mut QmpClient,
+    resume: bool,
+}
+
+impl Drop for PauseGuard<'_> {
+    fn drop(&mut self) {
+        if self.resume {
+            let _ = self.client.cont();
+        }
+    }
+}
+
+impl QmpClient {
+    /// Connect to a QMP socket and negotiate capabilities
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the unix socket
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<QmpClient, QmpError> {
+        let mut client = QmpClient {
+            stream: BufReader::new(UnixStream::connect(path)?),
+            version: QmpVersion::default(),
+            next_id: 0,
+            events: vec![],
+        };
+
+        let greeting = client.receive()?;
+        let version = greeting.get("QMP").map(|q| &q["version"]).ok_or(QmpError::Protocol("missing greeting"))?;
+
+        client.version = QmpVersion {
+            major: version["qemu"]["major"].as_u64().unwrap_or(0),
+            minor: version["qemu"]["minor"].as_u64().unwrap_or(0),
+            micro: version["qemu"]["micro"].as_u64().unwrap_or(0),
+            package: version["package"].as_str().unwrap_or_default().trim().to_string(),
+        };
+
+        client.execute("qmp_capabilities", None)?;
+
+        Ok(client)
+    }
+
+    /// Connect to the QMP socket given on the command line of a VM
+    ///
+    /// Fails with `NotFound` if the VM has no QMP socket.
+    pub fn connect_vm(vm: &VmInfo) -> Result<QmpClient, QmpError> {
+        let path = vm.qmp_socket().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "VM has no QMP socket"))?;
+        Self::connect(path)
+    }
+
+    /// Set the time to wait for a reply, `None` to wait indefinitely
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<&mut Self, QmpError> {
+        self.stream.get_ref().set_read_timeout(timeout)?;
+        Ok(self)
+    }
+
+    /// Get the qemu version announced by the server
+    pub fn version(&self) -> &QmpVersion {
+        &self.version
+    }
+
+    /// Take the events received so far
+    pub fn events(&mut self) -> Vec<QmpEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn receive(&mut self) -> Result<Value, QmpError> {
+        match serde_json::Deserializer::from_reader(&mut self.stream).into_iter::<Value>().next() {
+            Some(Ok(v)) => Ok(v),
+            Some(Err(e)) if e.is_io() => Err(QmpError::Io(e.into())),
+            Some(Err(e)) => Err(QmpError::Json(e)),
+            None => Err(QmpError::Protocol("connection closed")),
+        }
+    }
+
+    /// Execute a command, returning the contents of its reply
+    ///
+    /// Events received while waiting are queued, see `events`.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - command name, i.e. `"query-status"`
+    /// * `arguments` - arguments object, if the command takes any
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, QmpError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request = json!({ "execute": command, "id": id });
+
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+
+        let mut line = serde_json::to_vec(&request).map_err(QmpError::Json)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line)?;
+
+        loop {
+            let mut msg = self.receive()?;
+
+            if msg.get("event").is_some() {
+                self.events.push(QmpEvent::from_value(&msg));
+                continue;
+            }
+
+            // Replies to commands that have timed out earlier
+            if msg.get("id").map(|i| *i != json!(id)).unwrap_or(false) {
+                continue;
+            }
+
+            if let Some(ret) = msg.get_mut("return") {
+                return Ok(ret.take());
+            }
+
+            return match msg.get("error") {
+                Some(e) => Err(QmpError::Command {
+                    class: e["class"].as_str().unwrap_or_default().to_string(),
+                    desc: e["desc"].as_str().unwrap_or_default().to_string(),
+                }),
+                None => Err(QmpError::Protocol("unexpected message")),
+            };
+        }
+    }
+
+    fn execute_as<T: serde::de::DeserializeOwned>(&mut self, command: &str, arguments: Option<Value>) -> Result<T, QmpError> {
+        let ret = self.execute(command, arguments)?;
+        serde_json::from_value(ret).map_err(QmpError::Json)
+    }
+
+    /// Pause the VM
+    pub fn stop(&mut self) -> Result<&mut Self, QmpError> {
+        self.execute("stop", None)?;
+        Ok(self)
+    }
+
+    /// Resume the VM
+    pub fn cont(&mut self) -> Result<&mut Self, QmpError> {
+        self.execute("cont", None)?;
+        Ok(self)
+    }
+
+    /// Get the run state of the VM
+    pub fn status(&mut self) -> Result<VmStatus, QmpError> {
+        self.execute_as("query-status", None)
+    }
+
+    /// Run an operation on a context while the VM is paused
+    ///
+    /// The VM is only paused and resumed if it is running beforehand. Translation caches of
+    /// the VM are dropped on all threads once it is paused, as they may hold translations from
+    /// before. The VM is resumed
+    /// even if the operation panics. If resuming fails, the error carries the operation's result.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - context of the VM behind this socket
+    /// * `f` - operation to run
+    pub fn with_paused<R, F: FnOnce(&mut WinContext) -> R>(&mut self, ctx: &mut WinContext, f: F) -> Result<R, PausedError<R>> {
+        let resume = self.status().map_err(PausedError::Pause)?.running;
+
+        if resume {
+            self.stop().map_err(PausedError::Pause)?;
+        }
+
+        let mut guard = PauseGuard {
+            client: self,
+            resume: resume,
+        };
+
+        invalidate_vm(&ctx.c_ctx().process);
+
+        let ret = f(ctx);
+
+        if guard.resume {
+            guard.resume = false;
+
+            if let Err(e) = guard.client.cont() {
+                return Err(PausedError::Resume {
+                    result: ret,
+                    error: e,
+                });
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Get the amount of guest RAM
+    pub fn memory_size(&mut self) -> Result<MemorySizeSummary, QmpError> {
+        self.execute_as("query-memory-size-summary", None)
+    }
+
+    /// List the memory devices plugged into the VM
+    ///
+    /// RAM the VM has been started with is not a memory device, see `memory_size`.
+    pub fn memory_devices(&mut self) -> Result<Vec<MemoryDevice>, QmpError> {
+        self.execute_as("query-memory-devices", None)
+    }
+
+    /// Run a human monitor command, returning its output
+    ///
+    /// # Arguments
+    ///
+    /// * `command_line` - command, i.e. `"info mtree -f"`
+    pub fn human_monitor_command(&mut self, command_line: &str) -> Result<String, QmpError> {
+        self.execute_as("human-monitor-command", Some(json!({ "command-line": command_line })))
+    }
+
+    /// Get the flattened guest physical address space
+    ///
+    /// QMP has no equivalent of `info mtree`, thus the human monitor output gets parsed.
+    pub fn memory_tree(&mut self) -> Result<Vec<MemoryTreeRange>, QmpError> {
+        let text = self.human_monitor_command("info mtree -f")?;
+        Ok(parse_memory_tree(&text))
+    }
+
+    /// Build the guest memory map from the address space layout known to qemu
+    ///
+    /// Unlike `MemoryMap::detect`, this does not depend on the guest kernel. The largest RAM
+    /// region is taken to be the one mapped at `host_start`.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_start` - address of guest RAM inside of the qemu process, `mapsStart` of the context
+    pub fn memory_map(&mut self, host_start: u64) -> Result<MemoryMap, QmpError> {
+        let ram = self.memory_tree()?.into_iter()
+            .filter(|r| r.kind == "ram")
+            .collect::<Vec<_>>();
+
+        let main = ram.iter()
+            .map(|r| &r.name)
+            .max_by_key(|&n| ram.iter().filter(|r| &r.name == n).map(|r| r.end - r.start).sum::<u64>());
+
+        Ok(MemoryMap::new(ram.iter()
+            .filter(|r| Some(&r.name) == main)
+            .map(|r| MemoryRange {
+                start: r.start,
+                end: r.end,
+                host_address: host_start + r.offset,
+            })
+            .collect()))
+    }
+
+    /// Let qemu dump guest memory into a file
+    ///
+    /// Returns once the dump is complete, a long timeout may be needed for large guests. The path
+    /// is opened by the qemu process, thus it has to be absolute and writable by qemu.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - output file path
+    /// * `format` - dump format
+    pub fn dump_guest_memory<P: AsRef<Path>>(&mut self, path: P, format: DumpFormat) -> Result<&mut Self, QmpError> {
+        let arguments = json!({
+            "paging": false,
+            "protocol": format!("file:{}", path.as_ref().display()),
+            "format": format.as_str(),
+        });
+
+        self.execute("dump-guest-memory", Some(arguments))?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::JoinHandle;
+
+    static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+    const GREETING: &[u8] = b"{\"QMP\": {\"version\": {\"qemu\": {\"micro\": 1, \"minor\": 2, \"major\": 8}, \"package\": \" Debian 1:8.2.1 \"}, \"capabilities\": [\"oob\"]}}\r\n";
+
+    // Output of a q35 guest with 4G of RAM, trimmed to the interesting views
+    const MTREE: &str = "FlatView #0
+ AS \"I/O\", root: io
+ Root memory region: io
+  0000000000000000-0000000000000007 (prio 0, i/o): dma-chan
+  0000000000000008-000000000000000f (prio 0, i/o): dma-cont
+
+FlatView #1
+ AS \"memory\", root: system
+ AS \"cpu-memory-0\", root: system
+ Root memory region: system
+  0000000000000000-000000000009ffff (prio 0, ram): pc.ram KVM
+  00000000000a0000-00000000000bffff (prio 1, i/o): vga-lowmem
+  00000000000c0000-00000000000dffff (prio 1, rom): pc.ram @00000000000c0000 KVM
+  00000000000e0000-000000007fffffff (prio 0, ram): pc.ram @00000000000e0000 KVM
+  00000000b0000000-00000000bfffffff (prio 0, i/o): pcie-mmcfg-mmio
+  00000000fffc0000-00000000ffffffff (prio 0, rom): pc.bios KVM
+  0000000100000000-000000017fffffff (prio 0, ram): pc.ram @0000000080000000 KVM
+
+FlatView #2
+ AS \"e1000e\", root: bus master container
+ Root memory region: (none)
+  No rendered FlatView
+";
+
+    /// Serve a single QMP connection, passing every command to `handler` to reply to it
+    ///
+    /// Returns the socket path, and a thread returning the names of the commands received.
+    fn fake_server<F: FnMut(&Value, &mut UnixStream) + Send + 'static>(mut handler: F) -> (PathBuf, JoinHandle<Vec<String>>) {
+        let path = std::env::temp_dir().join(format!("vmread-qmp-{}-{}.sock", std::process::id(), NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let thread = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut commands = vec![];
+
+            writer.write_all(GREETING).unwrap();
+
+            for line in std::io::BufReader::new(stream).lines() {
+                let request = serde_json::from_str::<Value>(&line.unwrap()).unwrap();
+                commands.push(request["execute"].as_str().unwrap().to_string());
+                handler(&request, &mut writer);
+            }
+
+            commands
+        });
+
+        (path, thread)
+    }
+
+    fn reply(stream: &mut UnixStream, request: &Value, ret: Value) {
+        let msg = json!({ "return": ret, "id": request["id"] });
+        stream.write_all(format!("{}\r\n", msg).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let (path, server) = fake_server(|request, stream| reply(stream, request, json!({})));
+        let client = QmpClient::connect(&path).unwrap();
+
+        assert_eq!(client.version(), &QmpVersion {
+            major: 8,
+            minor: 2,
+            micro: 1,
+            package: "Debian 1:8.2.1".to_string(),
+        });
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), vec!["qmp_capabilities"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn pauses_and_resumes() {
+        let mut running = true;
+
+        let (path, server) = fake_server(move |request, stream| {
+            let ret = match request["execute"].as_str().unwrap() {
+                "query-status" => json!({ "running": running, "singlestep": false, "status": if running { "running" } else { "paused" } }),
+                "stop" => {
+                    running = false;
+                    // Events may arrive before the reply to the command causing them
+                    stream.write_all(b"{\"timestamp\": {\"seconds\": 1700000000, \"microseconds\": 250}, \"event\": \"STOP\"}\r\n").unwrap();
+                    json!({})
+                },
+                "cont" => {
+                    running = true;
+                    json!({})
+                },
+                _ => json!({}),
+            };
+
+            // Pretty printed replies span several lines
+            let msg = json!({ "return": ret, "id": request["id"] });
+            stream.write_all(serde_json::to_string_pretty(&msg).unwrap().as_bytes()).unwrap();
+            stream.write_all(b"\r\n").unwrap();
+        });
+
+        let mut client = QmpClient::connect(&path).unwrap();
+
+        assert!(client.status().unwrap().running);
+        client.stop().unwrap();
+
+        assert_eq!(client.status().unwrap(), VmStatus {
+            running: false,
+            status: "paused".to_string(),
+        });
+
+        assert_eq!(client.events(), vec![QmpEvent {
+            event: "STOP".to_string(),
+            data: Value::Null,
+            timestamp: Duration::new(1700000000, 250000),
+        }]);
+
+        assert!(client.events().is_empty());
+        client.cont().unwrap();
+        assert!(client.status().unwrap().running);
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), vec!["qmp_capabilities", "query-status", "stop", "query-status", "cont", "query-status"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn skips_stale_replies() {
+        let (path, server) = fake_server(|request, stream| {
+            if request["execute"] == "query-memory-size-summary" {
+                // Reply to an earlier command that has timed out on the client side
+                let stale = json!({ "return": { "running": true, "status": "running" }, "id": request["id"].as_u64().unwrap() - 1 });
+                stream.write_all(format!("{}\r\n", stale).as_bytes()).unwrap();
+                reply(stream, request, json!({ "base-memory": 4u64 << 30 }));
+            } else {
+                reply(stream, request, json!({}));
+            }
+        });
+
+        let mut client = QmpClient::connect(&path).unwrap();
+
+        assert_eq!(client.memory_size().unwrap(), MemorySizeSummary {
+            base_memory: 4 << 30,
+            plugged_memory: None,
+        });
+
+        drop(client);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reports_command_errors() {
+        let (path, server) = fake_server(|request, stream| {
+            if request["execute"] == "qmp_capabilities" {
+                reply(stream, request, json!({}));
+            } else {
+                let msg = json!({ "error": { "class": "CommandNotFound", "desc": "The command bogus has not been found" }, "id": request["id"] });
+                stream.write_all(format!("{}\r\n", msg).as_bytes()).unwrap();
+            }
+        });
+
+        let mut client = QmpClient::connect(&path).unwrap();
+
+        match client.execute("bogus", None) {
+            Err(QmpError::Command { class, desc }) => {
+                assert_eq!(class, "CommandNotFound");
+                assert_eq!(desc, "The command bogus has not been found");
+            },
+            r => panic!("unexpected {:?}", r),
+        }
+
+        drop(client);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn detects_closed_connections() {
+        let path = std::env::temp_dir().join(format!("vmread-qmp-{}-{}.sock", std::process::id(), NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"{\"hello\": 1}\r\n").unwrap();
+        });
+
+        match QmpClient::connect(&path) {
+            Err(QmpError::Protocol(_)) => {},
+            r => panic!("unexpected {:?}", r.map(|c| c.version().clone())),
+        }
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn parses_memory_tree() {
+        let ranges = parse_memory_tree(MTREE);
+
+        assert_eq!(ranges.len(), 7);
+
+        assert_eq!(ranges[0], MemoryTreeRange {
+            start: 0,
+            end: 0xa0000,
+            kind: "ram".to_string(),
+            name: "pc.ram".to_string(),
+            offset: 0,
+        });
+
+        assert_eq!(ranges[1].kind, "i/o");
+        assert_eq!(ranges[1].name, "vga-lowmem");
+
+        assert_eq!(ranges[6], MemoryTreeRange {
+            start: 0x1_0000_0000,
+            end: 0x1_8000_0000,
+            kind: "ram".to_string(),
+            name: "pc.ram".to_string(),
+            offset: 0x8000_0000,
+        });
+
+        assert!(parse_memory_tree("FlatView #0\n AS \"I/O\", root: io\n  0000-0007 (prio 0, i/o): dma\n").is_empty());
+    }
+
+    #[test]
+    fn builds_memory_map() {
+        let (path, server) = fake_server(|request, stream| match request["execute"].as_str().unwrap() {
+            "human-monitor-command" => reply(stream, request, json!(MTREE)),
+            _ => reply(stream, request, json!({})),
+        });
+
+        let mut client = QmpClient::connect(&path).unwrap();
+        let map = client.memory_map(0x7f00_0000_0000).unwrap();
+
+        assert_eq!(map.ranges().len(), 3);
+        assert_eq!(map.host_address(0x1000), Some(0x7f00_0000_1000));
+        assert_eq!(map.host_address(0xa0000), None);
+        assert_eq!(map.host_address(0x1_0000_0000), Some(0x7f00_8000_0000));
+
+        drop(client);
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,1612 @@
+//! YARA-like rules evaluated over guest memory
+//!
+//! The common subset of the YARA rule syntax is supported, so that existing detection rules can
+//! often be used unchanged:
+//!
+//! ```text
+//! rule Example : tag {
+//!     meta:
+//!         author = "someone"
+//!     strings:
+//!         $text = "connect" ascii wide nocase fullword
+//!         $hex = { 48 8B ?? ?5 [2-4] ( C3 | CC ) }
+//!     condition:
+//!         uint16(0) == 0x5A4D and ($text or #hex > 2) and @hex[1] < 0x1000
+//! }
+//! ```
+//!
+//! Conditions support `and`, `or`, `not`, comparisons, integer arithmetic, `filesize`, string
+//! counts (`#a`), offsets (`@a[i]`), lengths (`!a[i]`), `$a at x`, `$a in (x..y)`,
+//! `any/all/none/N of (...)` with `them` and `$a*` sets, `intN/uintN(x)` reads with `be` variants,
+//! and references to previous rules. Regular expressions, modules (`import`), `for` loops, and
+//! the `xor`/`base64` string modifiers are rejected with an error.
+//!
+//! Offsets in conditions are relative to the start of the scanned object: the image base for
+//! modules, the start of the range for physical memory, the region start for regions, and 0 for
+//! whole processes, where offsets are virtual addresses.
+
+use crate::address_space::*;
+use crate::multi_search::*;
+use crate::pattern::*;
+use crate::win_context::*;
+use crate::win_dll::*;
+use crate::win_process::*;
+use std::fmt;
+use std::str::FromStr;
+
+/// Size of a single batched read
+const RULES_CHUNK_SIZE: usize = 0x40_0000;
+/// Upper bound of unbounded hex string jumps, like `[4-]`
+const MAX_UNBOUNDED_JUMP: usize = 0x1000;
+/// Maximum number of alternatives a hex string may expand to
+const MAX_HEX_ALTERNATIVES: usize = 256;
+/// Matches of a single string past this count are dropped
+const MAX_STRING_MATCHES: usize = 1_000_000;
+
+/// Error produced when parsing rules
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleError {
+    /// Line of the rule source the error occurred on
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+/// A single match of a rule string
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StringMatch {
+    /// String identifier, i.e. `$a`
+    pub id: String,
+    /// Address of the match in the scanned address space
+    pub address: u64,
+    pub length: usize,
+}
+
+/// A rule whose condition held on a scanned object
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub tags: Vec<String>,
+    pub meta: Vec<(String, String)>,
+    /// Matches of the non-private strings of the rule
+    pub strings: Vec<StringMatch>,
+}
+
+/// A parsed rule
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub meta: Vec<(String, String)>,
+    /// Private rules are only used by other rules, and never reported
+    pub private: bool,
+    /// When a global rule does not match, no rule matches
+    pub global: bool,
+    /// Indices into `Rules::strings`
+    strings: Vec<usize>,
+    condition: Expr,
+}
+
+/// A compiled set of rules
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    strings: Vec<RuleString>,
+    max_len: usize,
+    /// Literals of the first fragments of all string alternatives, searched for in a single pass
+    prefilter: Option<MultiPattern>,
+    /// `(string, alternative, offset of the literal in the fragment)` of every prefilter pattern
+    prefilter_owners: Vec<(usize, usize, usize)>,
+    /// `(string, alternative)` of alternatives without a literal, searched for one by one
+    unanchored: Vec<(usize, usize)>,
+}
+
+impl FromStr for Rules {
+    type Err = RuleError;
+
+    fn from_str(s: &str) -> Result<Rules, RuleError> {
+        Rules::parse(s)
+    }
+}
+
+impl Rules {
+    /// Parse rules from source
+    pub fn parse(source: &str) -> Result<Rules, RuleError> {
+        let tokens = lex(source)?;
+        let mut parser = Parser {
+            tokens: tokens,
+            pos: 0,
+            rules: Rules::default(),
+        };
+
+        while !parser.at_end() {
+            parser.parse_rule()?;
+        }
+
+        let mut ret = parser.rules;
+        ret.max_len = ret.strings.iter().map(|s| s.max_len).max().unwrap_or(0);
+        ret.build_prefilter();
+        Ok(ret)
+    }
+
+    fn build_prefilter(&mut self) {
+        let mut literals = vec![];
+
+        for (s, string) in self.strings.iter().enumerate() {
+            for (a, alt) in string.alternatives.iter().enumerate() {
+                match alt[0].pattern.literal_run() {
+                    Some((offset, literal)) => {
+                        literals.push(literal);
+                        self.prefilter_owners.push((s, a, offset));
+                    },
+                    None => self.unanchored.push((s, a)),
+                }
+            }
+        }
+
+        if literals.is_empty() {
+            return;
+        }
+
+        match MultiPattern::ascii_case_insensitive(&literals) {
+            Ok(p) => self.prefilter = Some(p),
+            Err(_) => {
+                self.unanchored.extend(self.prefilter_owners.drain(..).map(|(s, a, _)| (s, a)));
+            },
+        }
+    }
+
+    /// Get the parsed rules
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Number of rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Evaluate the rules over a range of an address space
+    ///
+    /// Offsets in conditions are relative to `start`, and `filesize` is the size of the range.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space to scan
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    pub fn scan(&self, mem: &AddressSpace, start: u64, end: u64) -> Vec<RuleMatch> {
+        let (matches, _) = self.collect(mem, start, end);
+        self.evaluate(mem, start, end.saturating_sub(start), &matches)
+    }
+
+    /// Evaluate the rules over a module image
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space the module is mapped in
+    /// * `module` - module to scan
+    pub fn scan_module(&self, mem: &AddressSpace, module: &WinDll) -> Vec<RuleMatch> {
+        self.scan(mem, module.info.baseAddress, module.info.baseAddress + module.info.sizeOfModule)
+    }
+
+    /// Evaluate the rules over all present user-mode memory of a process as a whole
+    ///
+    /// Offsets in conditions are virtual addresses, and `filesize` is the amount of present memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn scan_process(&self, ctx: &sys::WinCtx, process: &WinProcess) -> Vec<RuleMatch> {
+        let mem = process.address_space(ctx);
+        let (matches, scanned) = self.collect(&mem, 0, USER_SPACE_END);
+        self.evaluate(&mem, 0, scanned, &matches)
+    }
+
+    /// Evaluate the rules over every present user-mode region of a process separately
+    ///
+    /// Memory is streamed only once. Returns the regions at least one rule matched in.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    pub fn scan_regions(&self, ctx: &sys::WinCtx, process: &WinProcess) -> Vec<(MemoryRegion, Vec<RuleMatch>)> {
+        let mem = process.address_space(ctx);
+        let (matches, _) = self.collect(&mem, 0, USER_SPACE_END);
+
+        mem.user_regions().into_iter().filter_map(|r| {
+            let region_matches = matches.iter().map(|m| {
+                let lo = m.partition_point(|&(a, _)| a < r.start);
+                let hi = m.partition_point(|&(a, _)| a < r.end());
+                m[lo..hi].to_vec()
+            }).collect::<Vec<_>>();
+
+            let ret = self.evaluate(&mem, r.start, r.size, &region_matches);
+            if ret.is_empty() { None } else { Some((r, ret)) }
+        }).collect()
+    }
+
+    /// Evaluate the rules over a range of guest physical memory
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context, accesses are validated against its memory map
+    /// * `start` - start physical address
+    /// * `end` - end physical address (exclusive)
+    pub fn scan_physical(&self, ctx: &WinContext, start: u64, end: u64) -> Vec<RuleMatch> {
+        self.scan(&ctx.physical(), start, end)
+    }
+
+    /// Find the matches of all strings, sorted by address, along with the number of bytes scanned
+    fn collect(&self, mem: &AddressSpace, start: u64, end: u64) -> (Vec<Vec<(u64, usize)>>, u64) {
+        let mut matches = vec![vec![]; self.strings.len()];
+        let mut scanned = 0;
+        let mut prev: Option<(u64, u8)> = None;
+
+        if self.strings.is_empty() {
+            return (matches, end.saturating_sub(start));
+        }
+
+        mem.for_each_chunk(start, end, RULES_CHUNK_SIZE, self.max_len.saturating_sub(1), &mut |address, chunk, report_len| {
+            // Byte in front of the chunk, for fullword checks
+            let before = prev.filter(|&(a, _)| a + 1 == address).map(|(_, b)| b);
+
+            let mut try_match = |s: usize, a: usize, off: usize| {
+                let string = &self.strings[s];
+                let alt = &string.alternatives[a];
+
+                if matches[s].len() >= MAX_STRING_MATCHES || !alt[0].pattern.matches(&chunk[off..]) {
+                    return;
+                }
+
+                let end = match match_fragments(&alt[1..], chunk, off + alt[0].pattern.len()) {
+                    Some(e) => e,
+                    None => return,
+                };
+
+                if string.fullword {
+                    let prev_byte = if off > 0 { Some(chunk[off - 1]) } else { before };
+                    if prev_byte.is_some_and(is_word_byte) || chunk.get(end).is_some_and(|&b| is_word_byte(b)) {
+                        return;
+                    }
+                }
+
+                matches[s].push((address + off as u64, end - off));
+            };
+
+            // Candidates starting before the chunk have been reported by the previous one
+            for (pos, p) in self.prefilter.iter().flat_map(|f| f.find_overlapping(chunk)) {
+                let (s, a, literal_offset) = self.prefilter_owners[p];
+
+                match pos.checked_sub(literal_offset) {
+                    Some(off) if off < report_len => try_match(s, a, off),
+                    _ => {},
+                }
+            }
+
+            for &(s, a) in &self.unanchored {
+                let first = &self.strings[s].alternatives[a][0].pattern;
+
+                for off in first.find_iter(chunk).take_while(|&off| off < report_len) {
+                    try_match(s, a, off);
+                }
+            }
+
+            scanned += report_len as u64;
+            prev = Some((address + report_len as u64 - 1, chunk[report_len - 1]));
+            true
+        });
+
+        for m in matches.iter_mut() {
+            m.sort_unstable();
+            m.dedup_by_key(|m| m.0);
+        }
+
+        (matches, scanned)
+    }
+
+    fn evaluate(&self, mem: &AddressSpace, base: u64, size: u64, matches: &[Vec<(u64, usize)>]) -> Vec<RuleMatch> {
+        let mut results = Vec::with_capacity(self.rules.len());
+
+        for rule in &self.rules {
+            let eval = Eval {
+                mem: mem,
+                base: base,
+                size: size,
+                matches: matches,
+                results: &results,
+            };
+
+            let matched = eval.eval_bool(&rule.condition);
+            results.push(matched);
+        }
+
+        if self.rules.iter().zip(results.iter()).any(|(r, &m)| r.global && !m) {
+            return vec![];
+        }
+
+        self.rules.iter().zip(results.iter())
+            .filter(|&(r, &m)| m && !r.private)
+            .map(|(r, _)| RuleMatch {
+                rule: r.name.clone(),
+                tags: r.tags.clone(),
+                meta: r.meta.clone(),
+                strings: r.strings.iter()
+                    .filter(|&&s| !self.strings[s].private)
+                    .flat_map(|&s| matches[s].iter().map(move |&(a, l)| StringMatch {
+                        id: self.strings[s].id.clone(),
+                        address: a,
+                        length: l,
+                    }))
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// A fixed part of a string, preceded by a variable number of arbitrary bytes
+#[derive(Clone, Debug)]
+struct Fragment {
+    min_gap: usize,
+    max_gap: usize,
+    pattern: Pattern,
+}
+
+#[derive(Clone, Debug)]
+struct RuleString {
+    id: String,
+    /// Fragment sequences, any of which matching is a match of the string
+    alternatives: Vec<Vec<Fragment>>,
+    fullword: bool,
+    private: bool,
+    /// Upper bound of the match length
+    max_len: usize,
+}
+
+/// Match the fragments following the first one, returning the end offset of the match
+fn match_fragments(fragments: &[Fragment], data: &[u8], pos: usize) -> Option<usize> {
+    let (f, rest) = match fragments.split_first() {
+        Some(f) => f,
+        None => return Some(pos),
+    };
+
+    for gap in f.min_gap..=f.max_gap {
+        let start = pos + gap;
+
+        if start + f.pattern.len() > data.len() {
+            break;
+        }
+
+        if f.pattern.matches(&data[start..]) {
+            if let Some(end) = match_fragments(rest, data, start + f.pattern.len()) {
+                return Some(end);
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Clone, Debug)]
+enum Quantifier {
+    All,
+    Any,
+    None,
+    Count(Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Bool(bool),
+    Int(i64),
+    FileSize,
+    /// Index of a previous rule
+    Rule(usize),
+    /// The following variants hold indices into `Rules::strings`
+    Matched(usize),
+    At(usize, Box<Expr>),
+    In(usize, Box<Expr>, Box<Expr>),
+    Count(usize),
+    Offset(usize, Box<Expr>),
+    Length(usize, Box<Expr>),
+    Of(Quantifier, Vec<usize>),
+    /// `(size, signed, big endian)` integer read at an offset
+    Read(usize, bool, bool, Box<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(CmpOp, Box<Expr>, Box<Expr>),
+    Arith(ArithOp, Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+}
+
+struct Eval<'a> {
+    mem: &'a AddressSpace<'a>,
+    base: u64,
+    size: u64,
+    matches: &'a [Vec<(u64, usize)>],
+    results: &'a [bool],
+}
+
+impl Eval<'_> {
+    fn eval_bool(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Bool(b) => *b,
+            Expr::Rule(r) => self.results[*r],
+            Expr::Matched(s) => !self.matches[*s].is_empty(),
+            Expr::At(s, e) => match self.eval_int(e) {
+                Some(o) => self.matches[*s].iter().any(|&(a, _)| self.offset(a) == o),
+                None => false,
+            },
+            Expr::In(s, lo, hi) => match (self.eval_int(lo), self.eval_int(hi)) {
+                (Some(lo), Some(hi)) => self.matches[*s].iter().any(|&(a, _)| (lo..=hi).contains(&self.offset(a))),
+                _ => false,
+            },
+            Expr::Of(q, set) => {
+                let count = set.iter().filter(|&&s| !self.matches[s].is_empty()).count() as i64;
+
+                match q {
+                    Quantifier::All => count == set.len() as i64,
+                    Quantifier::Any => count > 0,
+                    Quantifier::None => count == 0,
+                    Quantifier::Count(n) => self.eval_int(n).is_some_and(|n| count >= n),
+                }
+            },
+            Expr::Not(e) => !self.eval_bool(e),
+            Expr::And(a, b) => self.eval_bool(a) && self.eval_bool(b),
+            Expr::Or(a, b) => self.eval_bool(a) || self.eval_bool(b),
+            Expr::Cmp(op, a, b) => match (self.eval_int(a), self.eval_int(b)) {
+                (Some(a), Some(b)) => match op {
+                    CmpOp::Eq => a == b,
+                    CmpOp::Ne => a != b,
+                    CmpOp::Lt => a < b,
+                    CmpOp::Le => a <= b,
+                    CmpOp::Gt => a > b,
+                    CmpOp::Ge => a >= b,
+                },
+                _ => false,
+            },
+            e => self.eval_int(e).is_some_and(|v| v != 0),
+        }
+    }
+
+    fn eval_int(&self, expr: &Expr) -> Option<i64> {
+        match expr {
+            Expr::Int(v) => Some(*v),
+            Expr::FileSize => Some(self.size as i64),
+            Expr::Count(s) => Some(self.matches[*s].len() as i64),
+            Expr::Offset(s, i) => self.nth_match(*s, i).map(|(a, _)| self.offset(a)),
+            Expr::Length(s, i) => self.nth_match(*s, i).map(|(_, l)| l as i64),
+            Expr::Read(size, signed, be, offset) => {
+                let address = self.base.wrapping_add(self.eval_int(offset)? as u64);
+                let mut buf = [0u8; 8];
+
+                if !self.mem.read_arr(address, &mut buf[..*size]) {
+                    return None;
+                }
+
+                let bytes = &mut buf[..*size];
+                if *be {
+                    bytes.reverse();
+                }
+
+                let raw = u64::from_le_bytes(buf);
+                let shift = 64 - 8 * *size as u32;
+
+                Some(if *signed { ((raw << shift) as i64) >> shift } else { raw as i64 })
+            },
+            Expr::Arith(op, a, b) => {
+                let (a, b) = (self.eval_int(a)?, self.eval_int(b)?);
+
+                match op {
+                    ArithOp::Add => a.checked_add(b),
+                    ArithOp::Sub => a.checked_sub(b),
+                    ArithOp::Mul => a.checked_mul(b),
+                    ArithOp::Div => a.checked_div(b),
+                    ArithOp::Mod => a.checked_rem(b),
+                }
+            },
+            Expr::Neg(e) => self.eval_int(e)?.checked_neg(),
+            e => Some(self.eval_bool(e) as i64),
+        }
+    }
+
+    fn offset(&self, address: u64) -> i64 {
+        address.wrapping_sub(self.base) as i64
+    }
+
+    /// Get the match at a 1-based index
+    fn nth_match(&self, s: usize, index: &Expr) -> Option<(u64, usize)> {
+        let i = self.eval_int(index)?;
+        if i < 1 {
+            return None;
+        }
+
+        self.matches[s].get((i - 1) as usize).copied()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    /// `$name`, possibly with a trailing `*`
+    StringId(String),
+    /// `#name`
+    Count(String),
+    /// `@name`
+    Offset(String),
+    /// `!name`
+    Length(String),
+    Int(i64),
+    Text(Vec<u8>),
+    /// Raw contents of a hex string
+    Hex(String),
+    Punct(&'static str),
+}
+
+const PUNCTUATION: [&str; 21] = [
+    "..", "==", "!=", "<=", ">=", "<", ">", "{", "}", "(", ")", "[", "]", ":", "=", ",", "+", "-", "*", "\\", "%",
+];
+
+fn lex(source: &str) -> Result<Vec<(Token, usize)>, RuleError> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut ret: Vec<(Token, usize)> = vec![];
+    let mut line = 1;
+    let mut i = 0;
+
+    let err = |line: usize, message: &str| RuleError {
+        line: line,
+        message: message.to_string(),
+    };
+
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let after_assign = matches!(ret.last(), Some((Token::Punct("="), _)));
+
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c == '/' && after_assign {
+            return Err(err(line, "regular expressions are not supported"));
+        } else if c == '{' && after_assign {
+            let start = i + 1;
+            let start_line = line;
+
+            while i < chars.len() && chars[i] != '}' {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+
+            if i >= chars.len() {
+                return Err(err(start_line, "unterminated hex string"));
+            }
+
+            ret.push((Token::Hex(chars[start..i].iter().collect()), start_line));
+            i += 1;
+        } else if c == '"' {
+            let mut text = vec![];
+            i += 1;
+
+            loop {
+                match chars.get(i) {
+                    None | Some('\n') => return Err(err(line, "unterminated text string")),
+                    Some('"') => break,
+                    Some('\\') => {
+                        let escaped = match chars.get(i + 1) {
+                            Some('n') => '\n' as u32,
+                            Some('t') => '\t' as u32,
+                            Some('r') => '\r' as u32,
+                            Some('\\') => '\\' as u32,
+                            Some('"') => '"' as u32,
+                            Some('x') => {
+                                let hex = chars.get(i + 2..i + 4).map(|h| h.iter().collect::<String>());
+                                i += 2;
+                                hex.and_then(|h| u32::from_str_radix(&h, 16).ok())
+                                    .ok_or_else(|| err(line, "invalid \\x escape"))?
+                            },
+                            _ => return Err(err(line, "invalid escape sequence")),
+                        };
+                        text.push(escaped as u8);
+                        i += 2;
+                    },
+                    Some(&c) => {
+                        let mut buf = [0u8; 4];
+                        text.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        i += 1;
+                    },
+                }
+            }
+
+            ret.push((Token::Text(text), line));
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && is_ident(chars[i]) {
+                i += 1;
+            }
+
+            let s = chars[start..i].iter().collect::<String>();
+
+            let value = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                i64::from_str_radix(hex, 16).ok()
+            } else if let Some(kb) = s.strip_suffix("KB") {
+                kb.parse::<i64>().ok().and_then(|v| v.checked_mul(1024))
+            } else if let Some(mb) = s.strip_suffix("MB") {
+                mb.parse::<i64>().ok().and_then(|v| v.checked_mul(1024 * 1024))
+            } else {
+                s.parse().ok()
+            };
+
+            ret.push((Token::Int(value.ok_or_else(|| err(line, &format!("invalid number \"{}\"", s)))?), line));
+        } else if "$#@!".contains(c) && !(c == '!' && next == Some('=')) {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && is_ident(chars[i]) {
+                i += 1;
+            }
+            if c == '$' && chars.get(i) == Some(&'*') {
+                i += 1;
+            }
+
+            let name = chars[start..i].iter().collect::<String>();
+            ret.push((match c {
+                '$' => Token::StringId(name),
+                '#' => Token::Count(name),
+                '@' => Token::Offset(name),
+                _ => Token::Length(name),
+            }, line));
+        } else if is_ident(c) {
+            let start = i;
+            while i < chars.len() && is_ident(chars[i]) {
+                i += 1;
+            }
+            ret.push((Token::Ident(chars[start..i].iter().collect()), line));
+        } else {
+            let rest = &chars[i..];
+            let punct = PUNCTUATION.iter()
+                .find(|p| rest.len() >= p.len() && p.chars().zip(rest.iter()).all(|(a, &b)| a == b))
+                .ok_or_else(|| err(line, &format!("unexpected character '{}'", c)))?;
+
+            ret.push((Token::Punct(punct), line));
+            i += punct.len();
+        }
+    }
+
+    Ok(ret)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum HexItem {
+    Byte(u8, u8),
+    Jump(usize, usize),
+}
+
+/// Parse the contents of a hex string into its alternative item sequences
+fn parse_hex(hex: &str) -> Result<Vec<Vec<HexItem>>, String> {
+    let chars = hex.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    let mut pos = 0;
+    let ret = parse_hex_seq(&chars, &mut pos, false)?;
+
+    if pos != chars.len() {
+        return Err(format!("unexpected '{}' in hex string", chars[pos]));
+    }
+
+    Ok(ret)
+}
+
+fn parse_hex_seq(chars: &[char], pos: &mut usize, nested: bool) -> Result<Vec<Vec<HexItem>>, String> {
+    let mut seqs: Vec<Vec<HexItem>> = vec![vec![]];
+
+    while let Some(&c) = chars.get(*pos) {
+        let alts = match c {
+            '|' | ')' if nested => break,
+            '[' => {
+                let end = chars[*pos..].iter().position(|&c| c == ']').ok_or("unterminated jump")? + *pos;
+                let jump = chars[*pos + 1..end].iter().collect::<String>();
+                *pos = end + 1;
+
+                let parse = |s: &str| s.parse::<usize>().map_err(|_| format!("invalid jump [{}]", jump));
+
+                let (min, max) = match jump.split_once('-') {
+                    None => (parse(&jump)?, parse(&jump)?),
+                    Some((lo, "")) => (if lo.is_empty() { 0 } else { parse(lo)? }, MAX_UNBOUNDED_JUMP),
+                    Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+                };
+
+                if min > max {
+                    return Err(format!("invalid jump [{}]", jump));
+                }
+
+                vec![vec![HexItem::Jump(min, max)]]
+            },
+            '(' => {
+                *pos += 1;
+                let mut alts = vec![];
+
+                loop {
+                    alts.extend(parse_hex_seq(chars, pos, true)?);
+
+                    match chars.get(*pos) {
+                        Some('|') => *pos += 1,
+                        Some(')') => {
+                            *pos += 1;
+                            break;
+                        },
+                        _ => return Err("unterminated alternative".to_string()),
+                    }
+                }
+
+                alts
+            },
+            '~' => return Err("negated hex bytes are not supported".to_string()),
+            _ => {
+                let token = chars.get(*pos..*pos + 2).ok_or("incomplete hex byte")?;
+                let mut byte = 0;
+                let mut mask = 0;
+
+                for &c in token {
+                    byte <<= 4;
+                    mask <<= 4;
+
+                    if c != '?' {
+                        byte |= c.to_digit(16).ok_or_else(|| format!("invalid hex byte \"{}{}\"", token[0], token[1]))? as u8;
+                        mask |= 0xf;
+                    }
+                }
+
+                *pos += 2;
+                vec![vec![HexItem::Byte(byte, mask)]]
+            },
+        };
+
+        seqs = seqs.iter()
+            .flat_map(|s| alts.iter().map(move |a| s.iter().chain(a.iter()).copied().collect()))
+            .collect();
+
+        if seqs.len() > MAX_HEX_ALTERNATIVES {
+            return Err("hex string has too many alternatives".to_string());
+        }
+    }
+
+    Ok(seqs)
+}
+
+/// Split a hex item sequence into fragments, returning them along with the maximum match length
+fn hex_fragments(items: &[HexItem]) -> Result<(Vec<Fragment>, usize), String> {
+    let mut ret: Vec<Fragment> = vec![];
+    let mut bytes = vec![];
+    let mut masks = vec![];
+    let mut gap = (0, 0);
+    let mut max_len = 0;
+
+    let mut flush = |bytes: &mut Vec<u8>, masks: &mut Vec<u8>, gap: &mut (usize, usize), ret: &mut Vec<Fragment>| -> Result<(), String> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        max_len += gap.1 + bytes.len();
+
+        if masks.iter().all(|&m| m == 0) {
+            // Wildcards only, thus a fixed jump
+            gap.0 += bytes.len();
+            gap.1 += bytes.len();
+        } else {
+            if ret.is_empty() && gap.1 > 0 {
+                return Err("hex string can not start with a jump".to_string());
+            }
+
+            ret.push(Fragment {
+                min_gap: gap.0,
+                max_gap: gap.1,
+                pattern: Pattern::from_masks(bytes, masks).map_err(|e| e.to_string())?,
+            });
+            *gap = (0, 0);
+        }
+
+        bytes.clear();
+        masks.clear();
+        Ok(())
+    };
+
+    for item in items {
+        match *item {
+            HexItem::Byte(b, m) => {
+                bytes.push(b);
+                masks.push(m);
+            },
+            HexItem::Jump(min, max) => {
+                flush(&mut bytes, &mut masks, &mut gap, &mut ret)?;
+                gap.0 += min;
+                gap.1 += max;
+            },
+        }
+    }
+
+    flush(&mut bytes, &mut masks, &mut gap, &mut ret)?;
+
+    if ret.is_empty() {
+        return Err("hex string consists only of wildcards".to_string());
+    }
+
+    if gap.1 > 0 {
+        return Err("hex string can not end with a jump".to_string());
+    }
+
+    Ok((ret, max_len))
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    rules: Rules,
+}
+
+/// Strings of the rule being parsed, as `(id, index into Rules::strings)`
+type LocalStrings = Vec<(String, usize)>;
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.0)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, RuleError> {
+        let line = self.tokens.get(self.pos).or_else(|| self.tokens.last()).map(|t| t.1).unwrap_or(1);
+
+        Err(RuleError {
+            line: line,
+            message: message.to_string(),
+        })
+    }
+
+    fn next(&mut self) -> Result<Token, RuleError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.0.clone())
+            },
+            None => self.error("unexpected end of rules"),
+        }
+    }
+
+    fn is_punct(&self, p: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(t)) if *t == p)
+    }
+
+    fn is_keyword(&self, k: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(t)) if t == k)
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<(), RuleError> {
+        if self.is_punct(p) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", p))
+        }
+    }
+
+    fn expect_keyword(&mut self, k: &str) -> Result<(), RuleError> {
+        if self.is_keyword(k) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected \"{}\"", k))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, RuleError> {
+        match self.next()? {
+            Token::Ident(s) => Ok(s),
+            _ => {
+                self.pos -= 1;
+                self.error("expected an identifier")
+            },
+        }
+    }
+
+    fn parse_rule(&mut self) -> Result<(), RuleError> {
+        let mut private = false;
+        let mut global = false;
+
+        loop {
+            if self.is_keyword("private") {
+                private = true;
+            } else if self.is_keyword("global") {
+                global = true;
+            } else if self.is_keyword("import") || self.is_keyword("include") {
+                return self.error("modules and includes are not supported");
+            } else {
+                break;
+            }
+            self.pos += 1;
+        }
+
+        self.expect_keyword("rule")?;
+        let name = self.ident()?;
+
+        if self.rules.rules.iter().any(|r| r.name == name) {
+            return self.error(&format!("duplicate rule \"{}\"", name));
+        }
+
+        let mut tags = vec![];
+        if self.is_punct(":") {
+            self.pos += 1;
+            while let Some(Token::Ident(_)) = self.peek() {
+                tags.push(self.ident()?);
+            }
+        }
+
+        self.expect_punct("{")?;
+
+        let mut meta = vec![];
+        if self.is_keyword("meta") {
+            self.pos += 1;
+            self.expect_punct(":")?;
+
+            while !self.is_keyword("strings") && !self.is_keyword("condition") {
+                let key = self.ident()?;
+                self.expect_punct("=")?;
+
+                let negative = self.is_punct("-");
+                if negative {
+                    self.pos += 1;
+                }
+
+                let value = match self.next()? {
+                    Token::Text(t) => String::from_utf8_lossy(&t).into_owned(),
+                    Token::Int(v) => (if negative { -v } else { v }).to_string(),
+                    Token::Ident(b) if b == "true" || b == "false" => b,
+                    _ => {
+                        self.pos -= 1;
+                        return self.error("invalid meta value");
+                    },
+                };
+
+                meta.push((key, value));
+            }
+        }
+
+        let mut strings: LocalStrings = vec![];
+        if self.is_keyword("strings") {
+            self.pos += 1;
+            self.expect_punct(":")?;
+
+            while let Some(Token::StringId(_)) = self.peek() {
+                self.parse_string(&mut strings)?;
+            }
+        }
+
+        self.expect_keyword("condition")?;
+        self.expect_punct(":")?;
+        let condition = self.parse_or(&strings)?;
+        self.expect_punct("}")?;
+
+        self.rules.rules.push(Rule {
+            name: name,
+            tags: tags,
+            meta: meta,
+            private: private,
+            global: global,
+            strings: strings.iter().map(|s| s.1).collect(),
+            condition: condition,
+        });
+
+        Ok(())
+    }
+
+    fn parse_string(&mut self, strings: &mut LocalStrings) -> Result<(), RuleError> {
+        let id = match self.next()? {
+            Token::StringId(id) if !id.ends_with('*') => format!("${}", id),
+            _ => {
+                self.pos -= 1;
+                return self.error("invalid string identifier");
+            },
+        };
+
+        if id != "$" && strings.iter().any(|s| s.0 == id) {
+            return self.error(&format!("duplicate string \"{}\"", id));
+        }
+
+        self.expect_punct("=")?;
+        let value = self.next()?;
+
+        let mut ascii = false;
+        let mut wide = false;
+        let mut nocase = false;
+        let mut fullword = false;
+        let mut private = false;
+
+        // Modifiers end at the next section, like `condition:`
+        while let Some(Token::Ident(m)) = self.peek() {
+            if matches!(self.tokens.get(self.pos + 1), Some((Token::Punct(":"), _))) {
+                break;
+            }
+
+            match m.as_str() {
+                "ascii" => ascii = true,
+                "wide" => wide = true,
+                "nocase" => nocase = true,
+                "fullword" => fullword = true,
+                "private" => private = true,
+                m => return self.error(&format!("unsupported string modifier \"{}\"", m)),
+            }
+            self.pos += 1;
+        }
+
+        let (alternatives, max_len) = match value {
+            Token::Text(text) => {
+                if text.is_empty() {
+                    return self.error("empty text string");
+                }
+
+                let mut alternatives = vec![];
+                let mut push = |bytes: Vec<u8>, masks: Vec<u8>| {
+                    if let Ok(p) = Pattern::from_masks(&bytes, &masks) {
+                        alternatives.push(vec![Fragment {
+                            min_gap: 0,
+                            max_gap: 0,
+                            pattern: p,
+                        }]);
+                    }
+                };
+
+                let mask = |c: u8| if nocase && c.is_ascii_alphabetic() { 0xdf } else { 0xff };
+
+                if ascii || !wide {
+                    push(text.clone(), text.iter().map(|&c| mask(c)).collect());
+                }
+
+                if wide {
+                    push(text.iter().flat_map(|&c| [c, 0]).collect(), text.iter().flat_map(|&c| [mask(c), 0xff]).collect());
+                }
+
+                let max_len = text.len() * if wide { 2 } else { 1 };
+                (alternatives, max_len)
+            },
+            Token::Hex(hex) => {
+                if ascii || wide || nocase || fullword {
+                    return self.error("hex strings only support the private modifier");
+                }
+
+                let mut alternatives = vec![];
+                let mut max_len = 0;
+
+                for seq in parse_hex(&hex).or_else(|e| self.error(&e))? {
+                    let (fragments, len) = hex_fragments(&seq).or_else(|e| self.error(&e))?;
+                    alternatives.push(fragments);
+                    max_len = max_len.max(len);
+                }
+
+                (alternatives, max_len)
+            },
+            _ => return self.error("expected a text or hex string"),
+        };
+
+        strings.push((id.clone(), self.rules.strings.len()));
+        self.rules.strings.push(RuleString {
+            id: id,
+            alternatives: alternatives,
+            fullword: fullword,
+            private: private,
+            max_len: max_len,
+        });
+
+        Ok(())
+    }
+
+    fn string_index(&self, strings: &LocalStrings, name: &str) -> Result<usize, RuleError> {
+        let id = format!("${}", name);
+
+        match strings.iter().find(|s| s.0 == id) {
+            Some(s) => Ok(s.1),
+            None => self.error(&format!("undefined string \"{}\"", id)),
+        }
+    }
+
+    fn parse_or(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        let mut ret = self.parse_and(strings)?;
+
+        while self.is_keyword("or") {
+            self.pos += 1;
+            ret = Expr::Or(Box::new(ret), Box::new(self.parse_and(strings)?));
+        }
+
+        Ok(ret)
+    }
+
+    fn parse_and(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        let mut ret = self.parse_not(strings)?;
+
+        while self.is_keyword("and") {
+            self.pos += 1;
+            ret = Expr::And(Box::new(ret), Box::new(self.parse_not(strings)?));
+        }
+
+        Ok(ret)
+    }
+
+    fn parse_not(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        if self.is_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not(strings)?)));
+        }
+
+        self.parse_cmp(strings)
+    }
+
+    fn parse_cmp(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        let lhs = self.parse_add(strings)?;
+
+        let op = match self.peek() {
+            Some(Token::Punct("==")) => CmpOp::Eq,
+            Some(Token::Punct("!=")) => CmpOp::Ne,
+            Some(Token::Punct("<")) => CmpOp::Lt,
+            Some(Token::Punct("<=")) => CmpOp::Le,
+            Some(Token::Punct(">")) => CmpOp::Gt,
+            Some(Token::Punct(">=")) => CmpOp::Ge,
+            _ => return Ok(lhs),
+        };
+
+        self.pos += 1;
+        Ok(Expr::Cmp(op, Box::new(lhs), Box::new(self.parse_add(strings)?)))
+    }
+
+    fn parse_add(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        let mut ret = self.parse_mul(strings)?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Punct("+")) => ArithOp::Add,
+                Some(Token::Punct("-")) => ArithOp::Sub,
+                _ => return Ok(ret),
+            };
+
+            self.pos += 1;
+            ret = Expr::Arith(op, Box::new(ret), Box::new(self.parse_mul(strings)?));
+        }
+    }
+
+    fn parse_mul(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        let mut ret = self.parse_unary(strings)?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Punct("*")) => ArithOp::Mul,
+                Some(Token::Punct("\\")) => ArithOp::Div,
+                Some(Token::Punct("%")) => ArithOp::Mod,
+                _ => return Ok(ret),
+            };
+
+            self.pos += 1;
+            ret = Expr::Arith(op, Box::new(ret), Box::new(self.parse_unary(strings)?));
+        }
+    }
+
+    fn parse_unary(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        if self.is_punct("-") {
+            self.pos += 1;
+            return Ok(Expr::Neg(Box::new(self.parse_unary(strings)?)));
+        }
+
+        self.parse_primary(strings)
+    }
+
+    /// Parse an optional `[index]`, defaulting to the first match
+    fn parse_index(&mut self, strings: &LocalStrings) -> Result<Box<Expr>, RuleError> {
+        if !self.is_punct("[") {
+            return Ok(Box::new(Expr::Int(1)));
+        }
+
+        self.pos += 1;
+        let ret = self.parse_add(strings)?;
+        self.expect_punct("]")?;
+        Ok(Box::new(ret))
+    }
+
+    fn parse_set(&mut self, strings: &LocalStrings) -> Result<Vec<usize>, RuleError> {
+        if self.is_keyword("them") {
+            self.pos += 1;
+            return Ok(strings.iter().map(|s| s.1).collect());
+        }
+
+        self.expect_punct("(")?;
+        let mut ret = vec![];
+
+        loop {
+            match self.next()? {
+                Token::StringId(id) => match id.strip_suffix('*') {
+                    Some(prefix) => {
+                        let prefix = format!("${}", prefix);
+                        let matching = strings.iter().filter(|s| s.0.starts_with(&prefix)).map(|s| s.1).collect::<Vec<_>>();
+
+                        if matching.is_empty() {
+                            return self.error(&format!("no strings match \"{}*\"", prefix));
+                        }
+
+                        ret.extend(matching);
+                    },
+                    None => ret.push(self.string_index(strings, &id)?),
+                },
+                _ => {
+                    self.pos -= 1;
+                    return self.error("expected a string identifier");
+                },
+            }
+
+            if self.is_punct(",") {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        self.expect_punct(")")?;
+        Ok(ret)
+    }
+
+    fn parse_of(&mut self, quantifier: Quantifier, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        self.expect_keyword("of")?;
+        Ok(Expr::Of(quantifier, self.parse_set(strings)?))
+    }
+
+    fn parse_primary(&mut self, strings: &LocalStrings) -> Result<Expr, RuleError> {
+        match self.next()? {
+            Token::Punct("(") => {
+                let ret = self.parse_or(strings)?;
+                self.expect_punct(")")?;
+                Ok(ret)
+            },
+            Token::Int(v) => {
+                if self.is_keyword("of") {
+                    self.parse_of(Quantifier::Count(Box::new(Expr::Int(v))), strings)
+                } else {
+                    Ok(Expr::Int(v))
+                }
+            },
+            Token::StringId(id) => {
+                if id.is_empty() || id.ends_with('*') {
+                    self.pos -= 1;
+                    return self.error("expected a string identifier");
+                }
+
+                let s = self.string_index(strings, &id)?;
+
+                if self.is_keyword("at") {
+                    self.pos += 1;
+                    Ok(Expr::At(s, Box::new(self.parse_add(strings)?)))
+                } else if self.is_keyword("in") {
+                    self.pos += 1;
+                    self.expect_punct("(")?;
+                    let lo = self.parse_add(strings)?;
+                    self.expect_punct("..")?;
+                    let hi = self.parse_add(strings)?;
+                    self.expect_punct(")")?;
+                    Ok(Expr::In(s, Box::new(lo), Box::new(hi)))
+                } else {
+                    Ok(Expr::Matched(s))
+                }
+            },
+            Token::Count(id) => Ok(Expr::Count(self.string_index(strings, &id)?)),
+            Token::Offset(id) => {
+                let s = self.string_index(strings, &id)?;
+                Ok(Expr::Offset(s, self.parse_index(strings)?))
+            },
+            Token::Length(id) => {
+                let s = self.string_index(strings, &id)?;
+                Ok(Expr::Length(s, self.parse_index(strings)?))
+            },
+            Token::Ident(ident) => match ident.as_str() {
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                "filesize" => Ok(Expr::FileSize),
+                "all" => self.parse_of(Quantifier::All, strings),
+                "any" => self.parse_of(Quantifier::Any, strings),
+                "none" => self.parse_of(Quantifier::None, strings),
+                "for" => self.error("for loops are not supported"),
+                _ => {
+                    if let Some(read) = Self::read_function(&ident) {
+                        self.expect_punct("(")?;
+                        let offset = self.parse_add(strings)?;
+                        self.expect_punct(")")?;
+                        return Ok(Expr::Read(read.0, read.1, read.2, Box::new(offset)));
+                    }
+
+                    match self.rules.rules.iter().position(|r| r.name == ident) {
+                        Some(r) => Ok(Expr::Rule(r)),
+                        None => {
+                            self.pos -= 1;
+                            self.error(&format!("undefined identifier \"{}\"", ident))
+                        },
+                    }
+                },
+            },
+            _ => {
+                self.pos -= 1;
+                self.error("expected an expression")
+            },
+        }
+    }
+
+    /// Parse an integer read function name, like `uint16be`, into `(size, signed, big endian)`
+    fn read_function(name: &str) -> Option<(usize, bool, bool)> {
+        let (name, be) = match name.strip_suffix("be") {
+            Some(n) => (n, true),
+            None => (name, false),
+        };
+
+        let (bits, signed) = match name.strip_prefix("uint") {
+            Some(b) => (b, false),
+            None => (name.strip_prefix("int")?, true),
+        };
+
+        match bits {
+            "8" => Some((1, signed, be)),
+            "16" => Some((2, signed, be)),
+            "32" => Some((4, signed, be)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a buffer with data placed at the given offsets
+    fn buffer(size: usize, parts: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut ret = vec![0u8; size];
+
+        for (off, data) in parts {
+            ret[*off..*off + data.len()].copy_from_slice(data);
+        }
+
+        ret
+    }
+
+    fn wide(text: &str) -> Vec<u8> {
+        text.bytes().flat_map(|c| [c, 0]).collect()
+    }
+
+    /// Scan a buffer as if it was guest physical memory
+    fn scan(rules: &Rules, data: &[u8]) -> Vec<RuleMatch> {
+        let ctx = sys::WinCtx::default();
+        let backend = data.to_vec();
+        let mem = AddressSpace::physical(&ctx).with_backend(&backend);
+        rules.scan(&mem, 0, data.len() as u64)
+    }
+
+    fn matching(source: &str, data: &[u8]) -> Vec<String> {
+        scan(&Rules::parse(source).unwrap(), data).into_iter().map(|m| m.rule).collect()
+    }
+
+    fn parse_error(source: &str) -> RuleError {
+        Rules::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rules = Rules::parse(r#"
+            // comment
+            global private rule First : tag1 tag2 {
+                meta:
+                    author = "someone"
+                    score = -5
+                    enabled = true
+                strings:
+                    $a = "text" ascii wide
+                    $b = { 4D 5A }
+                condition:
+                    any of them
+            }
+
+            rule Second { condition: First and filesize > 0x10 }
+        "#).unwrap();
+
+        assert_eq!(rules.len(), 2);
+
+        let first = &rules.rules()[0];
+        assert_eq!(first.name, "First");
+        assert!(first.global && first.private);
+        assert_eq!(first.tags, vec!["tag1", "tag2"]);
+        assert_eq!(first.meta, vec![
+            ("author".to_string(), "someone".to_string()),
+            ("score".to_string(), "-5".to_string()),
+            ("enabled".to_string(), "true".to_string()),
+        ]);
+
+        let second = &rules.rules()[1];
+        assert!(!second.global && !second.private);
+        assert!(second.tags.is_empty());
+    }
+
+    #[test]
+    fn rejects_unsupported_rules() {
+        assert_eq!(parse_error("import \"pe\"\nrule a { condition: true }").line, 1);
+        assert_eq!(parse_error("rule a {\n strings:\n  $a = \"x\" xor\n condition: $a }").line, 3);
+        assert_eq!(parse_error("rule a {\n condition:\n  $b }").line, 3);
+
+        parse_error("rule a { condition: true } rule a { condition: true }");
+        parse_error("rule a { strings: $a = \"x\" $a = \"y\" condition: $a }");
+        parse_error("rule a { strings: $a = { 4D 5A } nocase condition: $a }");
+        parse_error("rule a { strings: $a = { 4D [2-1] 5A } condition: $a }");
+        parse_error("rule a { strings: $a = \"\" condition: $a }");
+        parse_error("rule a { condition: b }");
+        parse_error("rule a { condition: true");
+    }
+
+    #[test]
+    fn matches_hex_strings() {
+        let data = buffer(0x1000, &[
+            (0, b"MZ"),
+            (0x100, &[0x48, 0x8b, 0x11, 0x25, 0x00, 0xc3]),
+            (0x200, &[0x48, 0x8b, 0x22, 0x35, 0x00, 0x00, 0x00, 0xcc]),
+            (0x300, &[0xe8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x90]),
+            (0x400, &[0x11, 0x90, 0xcc]),
+        ]);
+
+        let rules = Rules::parse(r#"
+            rule Jump { strings: $h = { 48 8B ?? ?5 [1-2] ( C3 | CC ) } condition: #h == 1 and @h[1] == 0x100 }
+            rule LongJump { strings: $h = { 48 8B ?? ?5 [1-3] ( C3 | CC ) } condition: #h == 2 and !h[2] == 8 }
+            rule Unbounded { strings: $h = { E8 [4-] 90 } condition: $h at 0x300 }
+            rule Alternation { strings: $h = { ( 11 | 22 ) 90 ( CC | C3 ) } condition: $h in (0x3f0..0x410) }
+            rule Header { condition: uint16(0) == 0x5A4D and uint16be(0) == 0x4D5A }
+            rule Missing { strings: $h = { 48 8B ?? ?6 } condition: $h }
+        "#).unwrap();
+
+        let found = scan(&rules, &data);
+
+        assert_eq!(found.iter().map(|m| m.rule.as_str()).collect::<Vec<_>>(), vec!["Jump", "LongJump", "Unbounded", "Alternation", "Header"]);
+
+        assert_eq!(found[0].strings, vec![StringMatch {
+            id: "$h".to_string(),
+            address: 0x100,
+            length: 6,
+        }]);
+    }
+
+    #[test]
+    fn matches_text_modifiers() {
+        let data = buffer(0x1000, &[
+            (0x100, b"CoNNect"),
+            (0x200, &wide("connect")),
+            (0x300, b"xconnect"),
+            (0x400, b"connect"),
+        ]);
+
+        let rules = Rules::parse(r#"
+            rule Ascii { strings: $t = "connect" condition: #t == 2 }
+            rule Nocase { strings: $t = "connect" nocase condition: #t == 3 and @t[1] == 0x100 }
+            rule Wide { strings: $t = "connect" wide condition: #t == 1 and @t[1] == 0x200 and !t[1] == 14 }
+            rule Both { strings: $t = "connect" ascii wide condition: #t == 3 }
+            rule Fullword { strings: $t = "connect" ascii wide nocase fullword condition: #t == 3 and @t[3] == 0x400 }
+            rule Case { strings: $t = "CONNECT" condition: $t }
+        "#).unwrap();
+
+        assert_eq!(scan(&rules, &data).into_iter().map(|m| m.rule).collect::<Vec<_>>(), vec!["Ascii", "Nocase", "Wide", "Both", "Fullword"]);
+    }
+
+    #[test]
+    fn evaluates_counts_offsets_and_lengths() {
+        let data = buffer(0x3000, &[(0x10, b"abc"), (0x20, b"abc"), (0x2ffd, b"abc")]);
+
+        let rules = "
+            rule Count { strings: $a = \"abc\" condition: #a == 3 and #a * 2 - 1 == 5 }
+            rule Offsets { strings: $a = \"abc\" condition: @a[1] == 0x10 and @a[2] == 0x20 and @a[3] == 0x2ffd }
+            rule Length { strings: $a = \"abc\" condition: !a[1] == 3 and !a[3] == 3 }
+            rule OutOfRange { strings: $a = \"abc\" condition: @a[4] == 0 or !a[0] == 0 }
+            rule At { strings: $a = \"abc\" condition: $a at 0x20 and not $a at 0x21 }
+            rule In { strings: $a = \"abc\" condition: $a in (0x11..0x20) and not $a in (0x21..0x2ffc) }
+            rule Size { condition: filesize == 0x3000 and uint8(0x2fff) == 0x63 }
+        ";
+
+        assert_eq!(matching(rules, &data), vec!["Count", "Offsets", "Length", "At", "In", "Size"]);
+    }
+
+    #[test]
+    fn evaluates_string_sets() {
+        let data = buffer(0x1000, &[(0, b"foo1"), (0x10, b"foo2"), (0x20, b"bar")]);
+
+        let rules = r#"
+            rule AnyOf { strings: $foo1 = "foo1" $foo3 = "foo3" condition: any of them }
+            rule AllOf { strings: $foo1 = "foo1" $foo3 = "foo3" condition: all of them }
+            rule NoneOf { strings: $a = "foo3" $b = "foo4" condition: none of ($a, $b) }
+            rule Wildcard { strings: $foo1 = "foo1" $foo2 = "foo2" $foo3 = "foo3" $bar = "bar" condition: 2 of ($foo*) and not all of ($foo*) }
+            rule Count { strings: $a = "foo1" $b = "foo2" $c = "bar" condition: 3 of them }
+            rule TooMany { strings: $a = "foo1" $b = "foo3" condition: 2 of them }
+        "#;
+
+        assert_eq!(matching(rules, &data), vec!["AnyOf", "NoneOf", "Wildcard", "Count"]);
+    }
+
+    #[test]
+    fn applies_global_and_private_rules() {
+        let data = buffer(0x100, &[(0, b"MZ"), (0x10, b"secret")]);
+
+        let rules = r#"
+            private rule IsPe { condition: uint16(0) == 0x5A4D }
+            rule Secret { strings: $s = "secret" private $t = "secret" condition: IsPe and $s and $t }
+        "#;
+
+        let found = scan(&Rules::parse(rules).unwrap(), &data);
+
+        // Private rules and strings are used, but not reported
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].rule, "Secret");
+        assert_eq!(found[0].strings.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), vec!["$t"]);
+
+        let gated = format!("{} global rule Large {{ condition: filesize > 0x1000 }}", rules);
+        assert!(matching(&gated, &data).is_empty());
+
+        let passing = format!("{} global rule Small {{ condition: filesize <= 0x1000 }}", rules);
+        assert_eq!(matching(&passing, &data), vec!["Secret", "Small"]);
+    }
+
+    #[test]
+    fn matches_across_chunks() {
+        let mut data = vec![0u8; RULES_CHUNK_SIZE + 0x1000];
+        data[RULES_CHUNK_SIZE - 3..RULES_CHUNK_SIZE + 3].copy_from_slice(b"border");
+
+        let rules = "rule Border { strings: $a = \"border\" condition: #a == 1 and @a[1] == 0x3ffffd }";
+        assert_eq!(matching(rules, &data), vec!["Border"]);
+    }
+}
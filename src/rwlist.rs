@@ -1,3 +1,6 @@
+use crate::memory_map::*;
+use crate::tlb::*;
+use crate::address_space::*;
 use std::marker::PhantomData;
 use smallvec::{SmallVec, smallvec};
 
@@ -10,6 +13,8 @@ pub struct RWList<'a> {
     dir_base: u64,
     read_list: SmallVec<[sys::RWInfo; 8]>,
     write_list: SmallVec<[sys::RWInfo; 8]>,
+    memory_map: Option<&'a MemoryMap>,
+    space: Option<AddressSpace<'a>>,
     phantom: PhantomData<&'a u8>,
 }
 
@@ -26,10 +31,30 @@ impl<'a> RWList<'a> {
             dir_base: dir_base,
             read_list: smallvec![],
             write_list: smallvec![],
+            memory_map: None,
+            space: None,
             phantom: PhantomData,
         }
     }
 
+    /// Validate physical operations against a guest memory map
+    ///
+    /// Operations not fully inside of guest RAM are dropped on commit without being attempted, and
+    /// do not count towards the number of bytes done.
+    pub fn with_memory_map(mut self, memory_map: &'a MemoryMap) -> Self {
+        self.memory_map = Some(memory_map);
+        self
+    }
+
+    /// Make reads one by one through an address space, instead of batching them in the C library
+    ///
+    /// Used for address spaces with a backend or a trace recorder. Writes are not performed if the
+    /// address space has a backend.
+    pub(crate) fn with_address_space(mut self, space: AddressSpace<'a>) -> Self {
+        self.space = Some(space);
+        self
+    }
+
     /// Queue a write operation
     ///
     /// # Arguments
@@ -103,19 +128,29 @@ impl<'a> RWList<'a> {
     pub fn commit(&mut self, read_start: usize, write_start: usize) -> (&mut Self, usize, usize) {
         let mut done_rwlen : usize = 0;
         let mut queued_rwlen : usize = 0;
+        let memory_map = self.memory_map.filter(|_| self.dir_base == 0);
 
         if read_start < self.read_list.len() {
             {
                 let read_list = &mut self.read_list[read_start..];
-                read_list.sort_unstable_by(|a, b| (a.remote & !0xfff).partial_cmp(&(b.remote & !0xfff)).unwrap());
                 queued_rwlen += read_list.iter().fold(0, |acc, a| acc + a.size) as usize;
+
+                let valid = retain_in_ram(read_list, memory_map);
+                let read_list = &mut read_list[..valid];
+                read_list.sort_unstable_by(|a, b| (a.remote & !0xfff).partial_cmp(&(b.remote & !0xfff)).unwrap());
                
-                done_rwlen += unsafe {
-                    (if self.dir_base != 0 {
-                        sys::VMemReadMul(self.process, self.dir_base, read_list.as_mut_ptr(), read_list.len() as u64)
-                    } else {
-                        sys::MemReadMul(self.process, read_list.as_mut_ptr(), read_list.len() as u64)
-                    }) as usize
+                done_rwlen += match self.space {
+                    Some(space) => read_list.iter()
+                        .filter(|r| space.read_arr(r.remote, unsafe { std::slice::from_raw_parts_mut(r.local as *mut u8, r.size as usize) }))
+                        .fold(0, |acc, r| acc + r.size as usize),
+                    None => unsafe {
+                        (if self.dir_base != 0 {
+                            let _time = enter_vm(&*self.process);
+                            sys::VMemReadMul(self.process, self.dir_base, read_list.as_mut_ptr(), read_list.len() as u64)
+                        } else {
+                            sys::MemReadMul(self.process, read_list.as_mut_ptr(), read_list.len() as u64)
+                        }) as usize
+                    },
                 };
             }
 
@@ -125,16 +160,24 @@ impl<'a> RWList<'a> {
         if write_start < self.write_list.len() {
             {
                 let write_list = &mut self.write_list[write_start..];
-                write_list.sort_unstable_by(|a, b| (a.remote & !0xfff).partial_cmp(&(b.remote & !0xfff)).unwrap());
                 queued_rwlen += write_list.iter().fold(0, |acc, a| acc + a.size) as usize;
 
-                done_rwlen += unsafe {
-                    (if self.dir_base != 0 {
-                        sys::VMemWriteMul(self.process, self.dir_base, write_list.as_mut_ptr(), write_list.len() as u64)
-                    } else {
-                        sys::MemWriteMul(self.process, write_list.as_mut_ptr(), write_list.len() as u64)
-                    }) as usize
-                } 
+                let valid = retain_in_ram(write_list, memory_map);
+                let write_list = &mut write_list[..valid];
+                write_list.sort_unstable_by(|a, b| (a.remote & !0xfff).partial_cmp(&(b.remote & !0xfff)).unwrap());
+
+                // Backends are read-only
+                done_rwlen += match self.space.and_then(|s| s.backend()) {
+                    Some(_) => 0,
+                    None => unsafe {
+                        (if self.dir_base != 0 {
+                            let _time = enter_vm(&*self.process);
+                            sys::VMemWriteMul(self.process, self.dir_base, write_list.as_mut_ptr(), write_list.len() as u64)
+                        } else {
+                            sys::MemWriteMul(self.process, write_list.as_mut_ptr(), write_list.len() as u64)
+                        }) as usize
+                    },
+                };
             }
             
             self.write_list.truncate(write_start);
@@ -166,3 +209,22 @@ impl Drop for RWList<'_> {
         self.commit_rw();
     }
 }
+
+/// Move the operations inside of guest RAM to the front of the list, returning their count
+fn retain_in_ram(list: &mut [sys::RWInfo], memory_map: Option<&MemoryMap>) -> usize {
+    let map = match memory_map {
+        Some(map) => map,
+        None => return list.len(),
+    };
+
+    let mut valid = 0;
+
+    for i in 0..list.len() {
+        if map.contains(list[i].remote, list[i].size) {
+            list.swap(valid, i);
+            valid += 1;
+        }
+    }
+
+    valid
+}
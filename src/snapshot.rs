@@ -0,0 +1,626 @@
+//! Process memory snapshots and their differences
+//!
+//! A `MemorySnapshot` holds copies of selected present regions of a process, along with their
+//! protection and the module they belong to. Snapshots are saved in a compact format:
+//!
+//! ```text
+//! magic "VMRSNAP\0" | u32 version | u64 header length | JSON header | page data
+//! ```
+//!
+//! The header describes every region, and lists its zero-filled pages, which are not stored.
+//! All integers are little endian.
+
+use crate::address_space::*;
+use crate::win_process::*;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"VMRSNAP\0";
+const SNAPSHOT_VERSION: u32 = 1;
+const PAGE_SIZE: usize = 0x1000;
+
+/// Changes closer together than this many bytes get merged into a single `ByteChange`
+pub const DIFF_MERGE_DISTANCE: usize = 8;
+
+/// Error produced when saving or loading snapshots
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The data is not a snapshot, or is truncated
+    InvalidFormat(&'static str),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot I/O error: {}", e),
+            SnapshotError::Json(e) => write!(f, "invalid snapshot header: {}", e),
+            SnapshotError::InvalidFormat(e) => write!(f, "invalid snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+/// Metadata of a snapshot region
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionInfo {
+    pub start: u64,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+    /// Module image the region is part of
+    pub module: Option<String>,
+}
+
+impl RegionInfo {
+    /// Get the address right past the end of the region
+    ///
+    /// Saturates at `u64::MAX`, as regions of loaded snapshots come from untrusted headers.
+    pub fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+
+    /// Check whether the region contains the address
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end()
+    }
+}
+
+/// A copy of a region of memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotRegion {
+    pub info: RegionInfo,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegionHeader {
+    #[serde(flatten)]
+    info: RegionInfo,
+    /// Indices of pages that are all zeroes, and thus not stored
+    zero_pages: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    pid: u64,
+    name: String,
+    is_64bit: bool,
+    /// Seconds since the UNIX epoch
+    timestamp: u64,
+    regions: Vec<RegionHeader>,
+}
+
+/// Copies of selected regions of a process at a point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemorySnapshot {
+    pub pid: u64,
+    pub name: String,
+    pub is_64bit: bool,
+    pub timestamp: SystemTime,
+    /// Sorted by address, not overlapping
+    pub regions: Vec<SnapshotRegion>,
+}
+
+impl MemorySnapshot {
+    /// Capture the present parts of address ranges of a process
+    ///
+    /// The module list of the process needs to be refreshed beforehand for module annotations.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    /// * `ranges` - `(start, end)` address ranges to capture, which may overlap
+    pub fn capture(ctx: &sys::WinCtx, process: &WinProcess, ranges: &[(u64, u64)]) -> MemorySnapshot {
+        let mem = process.address_space(ctx);
+        let mut regions = vec![];
+
+        for (start, end) in merge_ranges(ranges.to_vec()) {
+            for r in mem.regions(start, end) {
+                let start = r.start.max(start);
+                let end = r.end().min(end);
+
+                if start >= end {
+                    continue;
+                }
+
+                let mut data = vec![0u8; (end - start) as usize];
+                mem.read_batched(start, &mut data);
+
+                regions.push(SnapshotRegion {
+                    info: RegionInfo {
+                        start: start,
+                        size: end - start,
+                        writable: r.writable,
+                        executable: r.executable,
+                        module: process.module_by_address(start).map(|m| m.name.clone()),
+                    },
+                    data: data,
+                });
+            }
+        }
+
+        regions.sort_by_key(|r| r.info.start);
+
+        MemorySnapshot {
+            pid: process.proc.pid,
+            name: process.name.clone(),
+            is_64bit: !process.is_wow64(ctx),
+            timestamp: SystemTime::now(),
+            regions: regions,
+        }
+    }
+
+    /// Capture whole module images of a process
+    ///
+    /// Modules that are not loaded are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process, with a refreshed module list
+    /// * `modules` - names of the modules to capture
+    pub fn capture_modules(ctx: &sys::WinCtx, process: &WinProcess, modules: &[&str]) -> MemorySnapshot {
+        let ranges = modules.iter()
+            .filter_map(|n| process.module_by_name(n))
+            .map(|m| (m.info.baseAddress, m.info.baseAddress + m.info.sizeOfModule))
+            .collect::<Vec<_>>();
+
+        Self::capture(ctx, process, &ranges)
+    }
+
+    /// Capture all present user-mode memory of a process
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    /// * `writable_only` - only capture writable regions, which hold the process data
+    pub fn capture_process(ctx: &sys::WinCtx, process: &WinProcess, writable_only: bool) -> MemorySnapshot {
+        let ranges = process.address_space(ctx).user_regions().into_iter()
+            .filter(|r| r.writable || !writable_only)
+            .map(|r| (r.start, r.end()))
+            .collect::<Vec<_>>();
+
+        Self::capture(ctx, process, &ranges)
+    }
+
+    /// Get the region containing an address
+    pub fn region_at(&self, address: u64) -> Option<&SnapshotRegion> {
+        let idx = self.regions.partition_point(|r| r.info.start <= address);
+        self.regions[..idx].last().filter(|r| r.info.contains(address))
+    }
+
+    /// Get captured bytes, if the whole range lies inside of a single region
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - start address
+    /// * `len` - number of bytes
+    pub fn bytes(&self, address: u64, len: usize) -> Option<&[u8]> {
+        let r = self.region_at(address)?;
+        let off = (address - r.info.start) as usize;
+        r.data.get(off..off.checked_add(len)?)
+    }
+
+    /// Read a pointer sized value from the snapshot
+    pub fn read_ptr(&self, address: u64) -> Option<u64> {
+        if self.is_64bit {
+            self.bytes(address, 8).map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        } else {
+            self.bytes(address, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64)
+        }
+    }
+
+    /// Write the snapshot in the on-disk format
+    pub fn write_to<W: Write>(&self, mut out: W) -> Result<(), SnapshotError> {
+        let is_zero = |p: &[u8]| p.iter().all(|&b| b == 0);
+
+        let header = SnapshotHeader {
+            pid: self.pid,
+            name: self.name.clone(),
+            is_64bit: self.is_64bit,
+            timestamp: self.timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            regions: self.regions.iter().map(|r| RegionHeader {
+                info: r.info.clone(),
+                zero_pages: r.data.chunks(PAGE_SIZE).enumerate()
+                    .filter(|(_, p)| is_zero(p))
+                    .map(|(i, _)| i as u32)
+                    .collect(),
+            }).collect(),
+        };
+
+        let header = serde_json::to_vec(&header).map_err(SnapshotError::Json)?;
+
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(&header)?;
+
+        for r in &self.regions {
+            for page in r.data.chunks(PAGE_SIZE).filter(|p| !is_zero(p)) {
+                out.write_all(page)?;
+            }
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Read a snapshot in the on-disk format
+    pub fn read_from<R: Read>(mut input: R) -> Result<MemorySnapshot, SnapshotError> {
+        let mut magic = [0u8; 8];
+        let mut version = [0u8; 4];
+        let mut header_len = [0u8; 8];
+
+        input.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidFormat("bad magic"));
+        }
+
+        input.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+            return Err(SnapshotError::InvalidFormat("unsupported version"));
+        }
+
+        input.read_exact(&mut header_len)?;
+        let mut header = vec![];
+        input.by_ref().take(u64::from_le_bytes(header_len)).read_to_end(&mut header)?;
+        let header: SnapshotHeader = serde_json::from_slice(&header).map_err(SnapshotError::Json)?;
+
+        let mut regions = vec![];
+
+        for r in header.regions {
+            let size = r.info.size;
+            let pages = size.div_ceil(PAGE_SIZE as u64);
+            let page_len = |i: u64| (size - i * PAGE_SIZE as u64).min(PAGE_SIZE as u64) as usize;
+
+            if !r.zero_pages.windows(2).all(|w| w[0] < w[1]) || r.zero_pages.last().is_some_and(|&z| z as u64 >= pages) {
+                return Err(SnapshotError::InvalidFormat("invalid zero page list"));
+            }
+
+            // The stored data is read before allocating the region, so that a corrupted size
+            // can not make us allocate more than the input and the header provide
+            let stored_len = size - r.zero_pages.iter().map(|&z| page_len(z as u64) as u64).sum::<u64>();
+            let mut stored = vec![];
+            input.by_ref().take(stored_len).read_to_end(&mut stored)?;
+
+            if stored.len() as u64 != stored_len {
+                return Err(SnapshotError::InvalidFormat("truncated page data"));
+            }
+
+            let mut data = Vec::with_capacity(size as usize);
+            let mut stored_pages = stored.chunks(PAGE_SIZE);
+            let mut zero_pages = r.zero_pages.iter().peekable();
+
+            for i in 0..pages {
+                match zero_pages.next_if(|&&z| z as u64 == i) {
+                    Some(_) => data.resize(data.len() + page_len(i), 0),
+                    None => data.extend_from_slice(stored_pages.next().unwrap_or_default()),
+                }
+            }
+
+            regions.push(SnapshotRegion {
+                info: r.info,
+                data: data,
+            });
+        }
+
+        regions.sort_by_key(|r| r.info.start);
+
+        Ok(MemorySnapshot {
+            pid: header.pid,
+            name: header.name,
+            is_64bit: header.is_64bit,
+            timestamp: UNIX_EPOCH + Duration::from_secs(header.timestamp),
+            regions: regions,
+        })
+    }
+
+    /// Save the snapshot to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        self.write_to(std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Load a snapshot from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MemorySnapshot, SnapshotError> {
+        Self::read_from(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Compare the snapshot with a later one
+    ///
+    /// # Arguments
+    ///
+    /// * `newer` - snapshot taken after this one
+    pub fn diff(&self, newer: &MemorySnapshot) -> SnapshotDiff {
+        let overlaps = |a: &RegionInfo, b: &RegionInfo| a.start < b.end() && b.start < a.end();
+        let mut ret = SnapshotDiff::default();
+
+        for old in &self.regions {
+            let matching = newer.regions.iter().filter(|n| overlaps(&old.info, &n.info)).collect::<Vec<_>>();
+
+            if matching.is_empty() {
+                ret.removed.push(old.info.clone());
+                continue;
+            }
+
+            for new in matching {
+                if new.info.start != old.info.start || new.info.size != old.info.size {
+                    ret.resized.push((old.info.clone(), new.info.clone()));
+                }
+
+                let start = old.info.start.max(new.info.start);
+                let end = old.info.end().min(new.info.end());
+                let old_data = &old.data[(start - old.info.start) as usize..(end - old.info.start) as usize];
+                let new_data = &new.data[(start - new.info.start) as usize..(end - new.info.start) as usize];
+
+                self.diff_data(newer, start, old_data, new_data, &mut ret);
+            }
+        }
+
+        ret.added = newer.regions.iter()
+            .filter(|n| !self.regions.iter().any(|o| overlaps(&o.info, &n.info)))
+            .map(|n| n.info.clone())
+            .collect();
+
+        ret
+    }
+
+    fn diff_data(&self, newer: &MemorySnapshot, start: u64, old: &[u8], new: &[u8], out: &mut SnapshotDiff) {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+
+        // Compare whole pages first, which skips unchanged memory quickly
+        for (i, (o, n)) in old.chunks(PAGE_SIZE).zip(new.chunks(PAGE_SIZE)).enumerate() {
+            if o == n {
+                continue;
+            }
+
+            for (j, _) in o.iter().zip(n.iter()).enumerate().filter(|(_, (a, b))| a != b) {
+                let off = i * PAGE_SIZE + j;
+
+                match ranges.last_mut() {
+                    Some(last) if off - last.1 < DIFF_MERGE_DISTANCE => last.1 = off + 1,
+                    _ => ranges.push((off, off + 1)),
+                }
+            }
+        }
+
+        let ptr_size = if self.is_64bit { 8 } else { 4 };
+        let points_into = |s: &MemorySnapshot, v: u64| v != 0 && s.region_at(v).is_some();
+
+        for (lo, hi) in ranges {
+            out.changed.push(ByteChange {
+                address: start + lo as u64,
+                old: old[lo..hi].to_vec(),
+                new: new[lo..hi].to_vec(),
+            });
+
+            // Pointer aligned slots overlapping the change
+            let first = (start + lo as u64) & !(ptr_size as u64 - 1);
+
+            for address in (first..start + hi as u64).step_by(ptr_size) {
+                if let (Some(o), Some(n)) = (self.read_ptr(address), newer.read_ptr(address)) {
+                    if o != n && (points_into(self, o) || points_into(newer, n)) {
+                        out.pointers.push(PointerChange {
+                            address: address,
+                            old: o,
+                            new: n,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A range of bytes that differs between two snapshots
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteChange {
+    pub address: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+/// A pointer aligned value that changed, and points into captured memory in either snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PointerChange {
+    pub address: u64,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Differences between two snapshots
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Regions only present in the newer snapshot
+    pub added: Vec<RegionInfo>,
+    /// Regions only present in the older snapshot
+    pub removed: Vec<RegionInfo>,
+    /// Overlapping regions with different bounds, as `(old, new)`
+    pub resized: Vec<(RegionInfo, RegionInfo)>,
+    /// Changed bytes in memory present in both snapshots, sorted by address
+    pub changed: Vec<ByteChange>,
+    pub pointers: Vec<PointerChange>,
+}
+
+impl SnapshotDiff {
+    /// Check whether the snapshots are identical
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.resized.is_empty() && self.changed.is_empty()
+    }
+
+    /// Total number of changed bytes
+    pub fn changed_bytes(&self) -> usize {
+        self.changed.iter().map(|c| c.old.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: u64, data: Vec<u8>) -> SnapshotRegion {
+        SnapshotRegion {
+            info: RegionInfo {
+                start: start,
+                size: data.len() as u64,
+                writable: true,
+                executable: false,
+                module: None,
+            },
+            data: data,
+        }
+    }
+
+    fn snapshot(regions: Vec<SnapshotRegion>) -> MemorySnapshot {
+        MemorySnapshot {
+            pid: 4,
+            name: "test.exe".to_string(),
+            is_64bit: true,
+            timestamp: UNIX_EPOCH + Duration::from_secs(1000),
+            regions: regions,
+        }
+    }
+
+    /// Set bytes of a region at an address
+    fn poke(snapshot: &mut MemorySnapshot, address: u64, bytes: &[u8]) {
+        let r = snapshot.regions.iter_mut().find(|r| r.info.contains(address)).unwrap();
+        let off = (address - r.info.start) as usize;
+        r.data[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn serialize(snapshot: &MemorySnapshot) -> Vec<u8> {
+        let mut out = vec![];
+        snapshot.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn format_error(data: &[u8]) -> &'static str {
+        match MemorySnapshot::read_from(data) {
+            Err(SnapshotError::InvalidFormat(e)) => e,
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    fn header_len(data: &[u8]) -> usize {
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&data[12..20]);
+        u64::from_le_bytes(len) as usize
+    }
+
+    /// Rewrite the JSON header of a serialized snapshot
+    fn with_header(data: &[u8], f: impl FnOnce(&mut serde_json::Value)) -> Vec<u8> {
+        let header_len = header_len(data);
+        let mut header = serde_json::from_slice(&data[20..20 + header_len]).unwrap();
+        f(&mut header);
+        let header = serde_json::to_vec(&header).unwrap();
+
+        let mut ret = data[..12].to_vec();
+        ret.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        ret.extend_from_slice(&header);
+        ret.extend_from_slice(&data[20 + header_len..]);
+        ret
+    }
+
+    #[test]
+    fn round_trips_zero_and_partial_pages() {
+        // A zero page in the middle, and a partial last page
+        let mut data = vec![0u8; 0x2800];
+        data[..0x1000].fill(0xaa);
+        data[0x2000..].fill(0xbb);
+
+        let snap = snapshot(vec![region(0x10000, data), region(0x20000, vec![0u8; 0x1800])]);
+        let out = serialize(&snap);
+        let header_len = header_len(&out);
+
+        // Zero pages are not stored, only the first page and the partial one are
+        assert_eq!(out.len(), 20 + header_len + 0x1000 + 0x800);
+        assert_eq!(MemorySnapshot::read_from(&out[..]).unwrap(), snap);
+    }
+
+    #[test]
+    fn rejects_truncated_snapshots() {
+        let mut data = vec![0u8; 0x1800];
+        data[0x1000..].fill(0xcc);
+        let out = serialize(&snapshot(vec![region(0x10000, data)]));
+        let header_len = header_len(&out);
+
+        assert_eq!(format_error(&out[..out.len() - 1]), "truncated page data");
+        assert!(matches!(MemorySnapshot::read_from(&out[..20 + header_len - 1]), Err(SnapshotError::Json(_))));
+        assert!(matches!(MemorySnapshot::read_from(&out[..6]), Err(SnapshotError::Io(_))));
+    }
+
+    #[test]
+    fn rejects_corrupt_headers() {
+        let out = serialize(&snapshot(vec![region(0x10000, vec![0u8; 0x3000])]));
+
+        let mut bad_magic = out.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(format_error(&bad_magic), "bad magic");
+
+        let mut bad_version = out.clone();
+        bad_version[8] = 2;
+        assert_eq!(format_error(&bad_version), "unsupported version");
+
+        let unsorted = with_header(&out, |h| h["regions"][0]["zero_pages"] = serde_json::json!([1, 0, 2]));
+        assert_eq!(format_error(&unsorted), "invalid zero page list");
+
+        let past_end = with_header(&out, |h| h["regions"][0]["zero_pages"] = serde_json::json!([0, 1, 3]));
+        assert_eq!(format_error(&past_end), "invalid zero page list");
+
+        // A size far beyond the stored data must not be trusted
+        let huge = with_header(&out, |h| h["regions"][0]["size"] = serde_json::json!(u64::MAX));
+        assert_eq!(format_error(&huge), "truncated page data");
+
+        let missing = with_header(&out, |h| h.as_object_mut().unwrap().remove("pid").map(|_| ()).unwrap());
+        assert!(matches!(MemorySnapshot::read_from(&missing[..]), Err(SnapshotError::Json(_))));
+    }
+
+    #[test]
+    fn diffs_resized_regions_and_changes() {
+        let old = snapshot(vec![region(0x10000, vec![0u8; 0x1000]), region(0x30000, vec![0u8; 0x1000])]);
+        let mut new = snapshot(vec![region(0x10000, vec![0u8; 0x2000]), region(0x30000, vec![0u8; 0x1000])]);
+
+        // A pointer into the second region, changes close enough to merge, and a lone one
+        poke(&mut new, 0x10008, &0x30010u64.to_le_bytes());
+        poke(&mut new, 0x10020, &[1]);
+        poke(&mut new, 0x10024, &[2]);
+        poke(&mut new, 0x10040, &[3]);
+        // Outside of the old region, thus not compared
+        poke(&mut new, 0x11000, &[4]);
+
+        let diff = old.diff(&new);
+
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.resized, vec![(old.regions[0].info.clone(), new.regions[0].info.clone())]);
+        assert_eq!(diff.changed, vec![
+            ByteChange { address: 0x10008, old: vec![0; 3], new: vec![0x10, 0, 3] },
+            ByteChange { address: 0x10020, old: vec![0; 5], new: vec![1, 0, 0, 0, 2] },
+            ByteChange { address: 0x10040, old: vec![0], new: vec![3] },
+        ]);
+        assert_eq!(diff.pointers, vec![PointerChange { address: 0x10008, old: 0, new: 0x30010 }]);
+        assert_eq!(diff.changed_bytes(), 9);
+    }
+
+    #[test]
+    fn diffs_added_and_removed_regions() {
+        let old = snapshot(vec![region(0x10000, vec![0u8; 0x1000])]);
+        let new = snapshot(vec![region(0x20000, vec![0u8; 0x1000])]);
+        let diff = old.diff(&new);
+
+        assert_eq!(diff.removed, vec![old.regions[0].info.clone()]);
+        assert_eq!(diff.added, vec![new.regions[0].info.clone()]);
+        assert!(old.diff(&old).is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+//! Extraction of ASCII and UTF-16LE strings from memory
+//!
+//! `StringExtractor` streams memory in large chunks and runs a small state machine over every
+//! byte, so strings spanning chunk boundaries are found without buffering whole address spaces.
+//! UTF-16 strings are found at both even and odd addresses.
+
+use crate::address_space::*;
+use crate::win_context::*;
+use crate::win_process::*;
+
+/// Default size of a single batched read
+pub const DEFAULT_STRINGS_CHUNK_SIZE: usize = 0x40_0000;
+
+/// Encoding a string was found in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StringEncoding {
+    Ascii,
+    Utf16Le,
+}
+
+/// Set of characters strings may consist of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Charset {
+    /// Printable ASCII characters and tabs
+    Printable,
+    /// ASCII letters and digits
+    Alphanumeric,
+    /// Explicitly listed characters. Bytes above 0x7f are interpreted as Latin-1
+    Custom(Vec<u8>),
+}
+
+impl Charset {
+    fn table(&self) -> [bool; 256] {
+        let mut ret = [false; 256];
+
+        for (c, allowed) in ret.iter_mut().enumerate() {
+            let c = c as u8;
+            *allowed = match self {
+                Charset::Printable => c == b'\t' || (0x20..0x7f).contains(&c),
+                Charset::Alphanumeric => c.is_ascii_alphanumeric(),
+                Charset::Custom(_) => false,
+            };
+        }
+
+        if let Charset::Custom(chars) = self {
+            for &c in chars {
+                ret[c as usize] = true;
+            }
+        }
+
+        ret
+    }
+}
+
+/// A string found in memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FoundString {
+    /// Address of the first byte, virtual or physical depending on the searched address space
+    pub address: u64,
+    /// Physical address of the first byte, `None` if it could not be translated
+    pub physical_address: Option<u64>,
+    pub encoding: StringEncoding,
+    pub text: String,
+    /// Region the string starts in, only set for process memory
+    pub region: Option<MemoryRegion>,
+    /// Module image the string starts in, only set for process memory
+    pub module: Option<String>,
+}
+
+#[derive(Clone, Default)]
+struct Run {
+    start: u64,
+    text: Vec<u8>,
+    /// Low byte of an UTF-16 code unit waiting for its high byte
+    pending: Option<u8>,
+}
+
+/// Streaming string extractor
+#[derive(Clone, Debug)]
+pub struct StringExtractor {
+    min_length: usize,
+    max_length: usize,
+    ascii: bool,
+    utf16: bool,
+    charset: [bool; 256],
+    chunk_size: usize,
+}
+
+impl Default for StringExtractor {
+    fn default() -> StringExtractor {
+        StringExtractor {
+            min_length: 4,
+            max_length: 0x1000,
+            ascii: true,
+            utf16: true,
+            charset: Charset::Printable.table(),
+            chunk_size: DEFAULT_STRINGS_CHUNK_SIZE,
+        }
+    }
+}
+
+impl StringExtractor {
+    /// Create an extractor of printable ASCII and UTF-16LE strings of at least 4 characters
+    pub fn new() -> StringExtractor {
+        StringExtractor::default()
+    }
+
+    /// Set the minimum number of characters of a string
+    pub fn min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length.max(1);
+        self.max_length = self.max_length.max(self.min_length);
+        self
+    }
+
+    /// Set the maximum number of characters of a string
+    ///
+    /// Longer strings are split into multiple ones.
+    pub fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length.max(self.min_length);
+        self
+    }
+
+    /// Select the encodings to search for
+    pub fn encodings(mut self, ascii: bool, utf16: bool) -> Self {
+        self.ascii = ascii;
+        self.utf16 = utf16;
+        self
+    }
+
+    /// Set the characters strings may consist of
+    pub fn charset(mut self, charset: &Charset) -> Self {
+        self.charset = charset.table();
+        self.charset[0] = false;
+        self
+    }
+
+    /// Set the size of a single batched read
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Extract strings from a range of an address space, calling `on_string` until it returns `false`
+    ///
+    /// In virtual address spaces only present pages are read. Strings are reported in the order
+    /// they end in, thus not strictly sorted by address.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space to search
+    /// * `start` - start address of the range
+    /// * `end` - end address of the range (exclusive)
+    /// * `on_string` - string callback, returning whether to continue
+    pub fn extract(&self, mem: &AddressSpace, start: u64, end: u64, on_string: &mut dyn FnMut(FoundString) -> bool) {
+        // ASCII, and UTF-16 at even and odd addresses
+        let mut runs: [Run; 3] = Default::default();
+        let mut next_address = start;
+        let mut stopped = false;
+
+        let mut emit = |run: &mut Run, encoding: StringEncoding| -> bool {
+            let keep_going = run.text.len() < self.min_length || on_string(FoundString {
+                address: run.start,
+                physical_address: mem.translate(run.start),
+                encoding: encoding,
+                text: run.text.iter().map(|&c| c as char).collect(),
+                region: None,
+                module: None,
+            });
+
+            run.text.clear();
+            run.pending = None;
+            keep_going
+        };
+
+        mem.for_each_chunk(start, end, self.chunk_size, 0, &mut |address, chunk, _| {
+            // Strings do not continue over unmapped gaps
+            if address != next_address {
+                for (i, run) in runs.iter_mut().enumerate() {
+                    if !emit(run, Self::encoding_of(i)) {
+                        stopped = true;
+                        return false;
+                    }
+                }
+            }
+
+            next_address = address + chunk.len() as u64;
+
+            for (off, &b) in chunk.iter().enumerate() {
+                let cur = address + off as u64;
+
+                if self.ascii {
+                    let run = &mut runs[0];
+
+                    if self.charset[b as usize] {
+                        if run.text.is_empty() {
+                            run.start = cur;
+                        }
+                        run.text.push(b);
+
+                        if run.text.len() >= self.max_length && !emit(run, StringEncoding::Ascii) {
+                            stopped = true;
+                            return false;
+                        }
+                    } else if !run.text.is_empty() && !emit(run, StringEncoding::Ascii) {
+                        stopped = true;
+                        return false;
+                    }
+                }
+
+                if self.utf16 {
+                    let parity = (cur & 1) as usize;
+
+                    // The byte is the low half of a code unit in one run, and the high half in the other
+                    let run = &mut runs[1 + parity];
+                    run.pending = Some(b);
+
+                    let run = &mut runs[2 - parity];
+                    match run.pending.take() {
+                        Some(lo) if b == 0 && self.charset[lo as usize] => {
+                            if run.text.is_empty() {
+                                run.start = cur - 1;
+                            }
+                            run.text.push(lo);
+
+                            if run.text.len() >= self.max_length && !emit(run, StringEncoding::Utf16Le) {
+                                stopped = true;
+                                return false;
+                            }
+                        },
+                        _ => if !run.text.is_empty() && !emit(run, StringEncoding::Utf16Le) {
+                            stopped = true;
+                            return false;
+                        },
+                    }
+                }
+            }
+
+            true
+        });
+
+        if !stopped {
+            for (i, run) in runs.iter_mut().enumerate() {
+                if !emit(run, Self::encoding_of(i)) {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn encoding_of(run: usize) -> StringEncoding {
+        if run == 0 { StringEncoding::Ascii } else { StringEncoding::Utf16Le }
+    }
+
+    /// Extract strings from all present user-mode memory of a process
+    ///
+    /// Strings are annotated with the region and module they start in. The module list of the
+    /// process needs to be refreshed beforehand for module annotations.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `process` - target process
+    /// * `on_string` - string callback, returning whether to continue
+    pub fn extract_process(&self, ctx: &sys::WinCtx, process: &WinProcess, on_string: &mut dyn FnMut(FoundString) -> bool) {
+        let mem = process.address_space(ctx);
+        let regions = mem.user_regions();
+
+        self.extract(&mem, 0, USER_SPACE_END, &mut |mut s| {
+            let idx = regions.partition_point(|r| r.start <= s.address);
+            s.region = idx.checked_sub(1).map(|i| regions[i]).filter(|r| r.contains(s.address));
+            s.module = process.module_by_address(s.address).map(|m| m.name.clone());
+            on_string(s)
+        });
+    }
+
+    /// Extract strings from a range of guest physical memory
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - target context, accesses are validated against its memory map
+    /// * `start` - start physical address
+    /// * `end` - end physical address (exclusive)
+    /// * `on_string` - string callback, returning whether to continue
+    pub fn extract_physical(&self, ctx: &WinContext, start: u64, end: u64, on_string: &mut dyn FnMut(FoundString) -> bool) {
+        self.extract(&ctx.physical(), start, end, on_string);
+    }
+}
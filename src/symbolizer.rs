@@ -0,0 +1,304 @@
+//! Resolution of virtual addresses to `module!symbol+offset` strings
+//!
+//! The `Symbolizer` keeps modules sorted by their base address, and symbols of every module sorted
+//! by their address, so that each lookup is a pair of binary searches. Symbols come from module
+//! export lists, and optionally from PDB files through a `SymbolProvider`.
+
+use crate::win_dll::*;
+use crate::kernel_module::*;
+use crate::address_space::*;
+use crate::pe::*;
+use std::fmt;
+
+/// Default value of `Symbolizer::max_symbol_distance`
+pub const DEFAULT_MAX_SYMBOL_DISTANCE: u64 = 0x10000;
+
+/// Source of symbols for modules, typically backed by debug information files
+pub trait SymbolProvider {
+    /// Get the symbols of a module as `(rva, name)` pairs
+    ///
+    /// Returns `None` if no symbols are available for the module.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - module name
+    /// * `codeview` - PDB identity of the module image
+    fn symbols(&self, module: &str, codeview: &CodeView) -> Option<Vec<(u32, String)>>;
+}
+
+/// Result of a symbol lookup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub module: &'a str,
+    /// Nearest preceding symbol, `None` if there is no symbol close enough
+    pub name: Option<&'a str>,
+    /// Offset from the symbol, or from the module base if there is no symbol
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.name, self.offset) {
+            (Some(name), 0) => write!(f, "{}!{}", self.module, name),
+            (Some(name), offset) => write!(f, "{}!{}+{:#x}", self.module, name, offset),
+            (None, offset) => write!(f, "{}+{:#x}", self.module, offset),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SymbolModule {
+    base: u64,
+    size: u64,
+    name: String,
+    /// Absolute addresses, sorted
+    symbols: Vec<(u64, String)>,
+    has_debug_symbols: bool,
+}
+
+/// Sorted index of modules and their symbols
+#[derive(Clone, Debug)]
+pub struct Symbolizer {
+    modules: Vec<SymbolModule>,
+    /// Symbols further away from the address than this are not used
+    pub max_symbol_distance: u64,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Symbolizer {
+        Symbolizer {
+            modules: vec![],
+            max_symbol_distance: DEFAULT_MAX_SYMBOL_DISTANCE,
+        }
+    }
+}
+
+impl Symbolizer {
+    pub fn new() -> Symbolizer {
+        Symbolizer::default()
+    }
+
+    /// Build an index over process or kernel modules
+    ///
+    /// Export lists are used as they are, thus `WinDll::refresh_exports` needs to be called
+    /// beforehand for modules whose exports are wanted.
+    ///
+    /// # Arguments
+    ///
+    /// * `modules` - i.e. `WinProcess::module_list` or `WinContext::kmod_list`
+    pub fn from_modules(modules: &[WinDll]) -> Symbolizer {
+        let mut ret = Symbolizer::new();
+
+        for m in modules {
+            ret.add_module(m.info.baseAddress, m.info.sizeOfModule, &m.name,
+                m.export_list.iter().map(|e| (e.address, e.name.clone())));
+        }
+
+        ret
+    }
+
+    /// Build an index over kernel modules
+    ///
+    /// # Arguments
+    ///
+    /// * `modules` - i.e. `WinContext::kernel_module_list`
+    pub fn from_kernel_modules(modules: &[KernelModule]) -> Symbolizer {
+        let mut ret = Symbolizer::new();
+
+        for m in modules {
+            ret.add_module(m.base, m.size, &m.name, m.export_list.iter().map(|e| (e.address, e.name.clone())));
+        }
+
+        ret
+    }
+
+    /// Add a module to the index
+    ///
+    /// A module already present at the same base address gets replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - base address of the module
+    /// * `size` - size of the module image
+    /// * `name` - module name, used as the prefix of symbolized addresses
+    /// * `symbols` - absolute addresses and names of the module symbols
+    pub fn add_module<I: IntoIterator<Item = (u64, String)>>(&mut self, base: u64, size: u64, name: &str, symbols: I) -> &mut Self {
+        let mut symbols = symbols.into_iter().collect::<Vec<_>>();
+        symbols.sort();
+        symbols.dedup_by_key(|s| s.0);
+
+        let module = SymbolModule {
+            base: base,
+            size: size,
+            name: name.to_string(),
+            symbols: symbols,
+            has_debug_symbols: false,
+        };
+
+        match self.modules.binary_search_by_key(&base, |m| m.base) {
+            Ok(i) => self.modules[i] = module,
+            Err(i) => self.modules.insert(i, module),
+        }
+
+        self
+    }
+
+    /// Load additional symbols for the indexed modules from a provider
+    ///
+    /// Symbols of the provider take precedence over exports at the same address. Modules that
+    /// already have provider symbols, or whose headers are not readable in `mem`, are skipped.
+    /// For kernel modules, this can be called once per `KernelModule::address_space` to cover
+    /// session-space drivers.
+    ///
+    /// Returns the number of modules symbols were loaded for.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space the modules are mapped in
+    /// * `provider` - symbol provider
+    pub fn load_symbols(&mut self, mem: &AddressSpace, provider: &dyn SymbolProvider) -> usize {
+        let mut ret = 0;
+
+        for m in self.modules.iter_mut().filter(|m| !m.has_debug_symbols) {
+            let codeview = match PeHeaders::parse(mem, m.base).and_then(|h| h.codeview(mem)) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let symbols = match provider.symbols(&m.name, &codeview) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let mut merged = symbols.into_iter().map(|(rva, name)| (m.base + rva as u64, name)).collect::<Vec<_>>();
+            merged.sort();
+            merged.dedup_by_key(|s| s.0);
+
+            for export in m.symbols.drain(..) {
+                if let Err(i) = merged.binary_search_by_key(&export.0, |s| s.0) {
+                    merged.insert(i, export);
+                }
+            }
+
+            m.symbols = merged;
+            m.has_debug_symbols = true;
+            ret += 1;
+        }
+
+        ret
+    }
+
+    /// Look up the module and the nearest preceding symbol of an address
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - virtual address to look up
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'_>> {
+        let idx = self.modules.partition_point(|m| m.base <= address);
+        let module = &self.modules[idx.checked_sub(1)?];
+
+        if address - module.base >= module.size {
+            return None;
+        }
+
+        let sym_idx = module.symbols.partition_point(|s| s.0 <= address);
+
+        let symbol = sym_idx.checked_sub(1)
+            .map(|i| &module.symbols[i])
+            .filter(|s| address - s.0 <= self.max_symbol_distance);
+
+        Some(match symbol {
+            Some((sym_address, name)) => Symbol {
+                module: &module.name,
+                name: Some(name),
+                offset: address - sym_address,
+            },
+            None => Symbol {
+                module: &module.name,
+                name: None,
+                offset: address - module.base,
+            },
+        })
+    }
+
+    /// Symbolize an address
+    ///
+    /// Returns a string like `ntdll.dll!RtlUserThreadStart+0x21`, `module.dll+0x1234` when there is
+    /// no symbol nearby, or just the hexadecimal address when it is outside of any module.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - virtual address to symbolize
+    pub fn symbolize(&self, address: u64) -> String {
+        match self.lookup(address) {
+            Some(s) => s.to_string(),
+            None => format!("{:#x}", address),
+        }
+    }
+}
+
+#[cfg(feature="pdb")]
+pub use self::pdb_provider::*;
+
+#[cfg(feature="pdb")]
+mod pdb_provider {
+    use super::*;
+    use pdb::FallibleIterator;
+    use std::path::PathBuf;
+
+    /// Symbol provider reading public symbols from local PDB files
+    ///
+    /// Each directory is searched both in the symbol store layout
+    /// (`<dir>/<pdb name>/<symbol id>/<pdb name>`), and for a plain `<dir>/<pdb name>` file. Files
+    /// whose GUID does not match the image are ignored.
+    #[derive(Clone, Debug, Default)]
+    pub struct PdbSymbolProvider {
+        pub search_paths: Vec<PathBuf>,
+    }
+
+    impl PdbSymbolProvider {
+        pub fn new<P: Into<PathBuf>>(search_paths: Vec<P>) -> PdbSymbolProvider {
+            PdbSymbolProvider {
+                search_paths: search_paths.into_iter().map(Into::into).collect(),
+            }
+        }
+
+        fn read_pdb(path: &PathBuf, codeview: &CodeView) -> Option<Vec<(u32, String)>> {
+            let mut pdb = pdb::PDB::open(std::fs::File::open(path).ok()?).ok()?;
+
+            if pdb.pdb_information().ok()?.guid.as_bytes() != &codeview.guid_be() {
+                return None;
+            }
+
+            let address_map = pdb.address_map().ok()?;
+            let symbol_table = pdb.global_symbols().ok()?;
+            let mut symbols = symbol_table.iter();
+            let mut ret = vec![];
+
+            while let Ok(Some(symbol)) = symbols.next() {
+                if let Ok(pdb::SymbolData::Public(data)) = symbol.parse() {
+                    if let Some(rva) = data.offset.to_rva(&address_map) {
+                        ret.push((rva.0, data.name.to_string().into_owned()));
+                    }
+                }
+            }
+
+            Some(ret)
+        }
+    }
+
+    impl SymbolProvider for PdbSymbolProvider {
+        fn symbols(&self, _module: &str, codeview: &CodeView) -> Option<Vec<(u32, String)>> {
+            let name = codeview.pdb_name();
+
+            if name.is_empty() {
+                return None;
+            }
+
+            self.search_paths.iter()
+                .flat_map(|dir| vec![dir.join(name).join(codeview.symbol_id()).join(name), dir.join(name)])
+                .filter(|p| p.is_file())
+                .find_map(|p| Self::read_pdb(&p, codeview))
+        }
+    }
+}
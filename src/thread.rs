@@ -0,0 +1,166 @@
+//! Threads of guest processes
+//!
+//! Threads are found by walking `_EPROCESS.ThreadListHead`. Every thread's TEB provides its user
+//! stack bounds, and its `_KTRAP_FRAME` the user-mode registers saved on the last kernel entry,
+//! which is what minidumps use as the thread context.
+
+use crate::address_space::*;
+use crate::offsets::*;
+
+/// Upper bound of threads to walk, protects against corrupted or looping lists
+const MAX_THREADS: usize = 0x10000;
+
+/// Offsets inside the 64-bit `_TEB`, unchanged since XP x64
+const TEB_STACK_BASE: u64 = 0x8;
+const TEB_STACK_LIMIT: u64 = 0x10;
+const TEB_CLIENT_ID_THREAD: u64 = 0x48;
+
+/// Offsets inside the x64 `_KTRAP_FRAME`, unchanged since Vista
+const TRAP_FRAME_RAX: u64 = 0x30;
+const TRAP_FRAME_RCX: u64 = 0x38;
+const TRAP_FRAME_RDX: u64 = 0x40;
+const TRAP_FRAME_R8: u64 = 0x48;
+const TRAP_FRAME_R9: u64 = 0x50;
+const TRAP_FRAME_R10: u64 = 0x58;
+const TRAP_FRAME_R11: u64 = 0x60;
+const TRAP_FRAME_RBX: u64 = 0x140;
+const TRAP_FRAME_RDI: u64 = 0x148;
+const TRAP_FRAME_RSI: u64 = 0x150;
+const TRAP_FRAME_RBP: u64 = 0x158;
+const TRAP_FRAME_RIP: u64 = 0x168;
+const TRAP_FRAME_SEG_CS: u64 = 0x170;
+const TRAP_FRAME_EFLAGS: u64 = 0x178;
+const TRAP_FRAME_RSP: u64 = 0x180;
+const TRAP_FRAME_SEG_SS: u64 = 0x188;
+
+/// User-mode register state saved on the last transition into the kernel
+///
+/// Only the volatile registers, `rbp` and the control registers are saved on system calls, thus
+/// `rbx`, `rdi` and `rsi` may be stale.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub rbx: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub eflags: u32,
+    pub cs: u16,
+    pub ss: u16,
+}
+
+impl TrapFrame {
+    fn read(kernel: &AddressSpace, address: u64) -> Option<TrapFrame> {
+        let cs = kernel.read::<u16>(address + TRAP_FRAME_SEG_CS)?;
+
+        // Frames of kernel-mode code, or not yet initialized ones, have no ring 3 selector
+        if cs & 3 != 3 {
+            return None;
+        }
+
+        let reg = |offset: u64| kernel.read::<u64>(address + offset);
+
+        Some(TrapFrame {
+            rax: reg(TRAP_FRAME_RAX)?,
+            rcx: reg(TRAP_FRAME_RCX)?,
+            rdx: reg(TRAP_FRAME_RDX)?,
+            r8: reg(TRAP_FRAME_R8)?,
+            r9: reg(TRAP_FRAME_R9)?,
+            r10: reg(TRAP_FRAME_R10)?,
+            r11: reg(TRAP_FRAME_R11)?,
+            rbx: reg(TRAP_FRAME_RBX)?,
+            rdi: reg(TRAP_FRAME_RDI)?,
+            rsi: reg(TRAP_FRAME_RSI)?,
+            rbp: reg(TRAP_FRAME_RBP)?,
+            rip: reg(TRAP_FRAME_RIP)?,
+            rsp: reg(TRAP_FRAME_RSP)?,
+            eflags: kernel.read::<u32>(address + TRAP_FRAME_EFLAGS)?,
+            cs: cs,
+            ss: kernel.read::<u16>(address + TRAP_FRAME_SEG_SS)?,
+        })
+    }
+}
+
+/// A thread of a process
+#[derive(Clone, Debug, Default)]
+pub struct WinThread {
+    /// Address of the `_ETHREAD`
+    pub ethread: u64,
+    /// Thread ID, 0 if neither `_ETHREAD.Cid`, nor the TEB is readable
+    pub thread_id: u64,
+    /// Address of the 64-bit TEB, 0 for kernel threads
+    pub teb: u64,
+    /// Highest address of the user-mode stack (exclusive)
+    pub stack_base: u64,
+    /// Lowest committed address of the user-mode stack
+    pub stack_limit: u64,
+    pub trap_frame: Option<TrapFrame>,
+}
+
+impl WinThread {
+    /// Walk `_EPROCESS.ThreadListHead` of a process
+    ///
+    /// Returns `None` if the required offsets are unknown, or the list head is unreadable.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `proc` - process to list the threads of
+    /// * `offsets` - resolved offsets of the context
+    pub fn list(ctx: &sys::WinCtx, proc: &sys::WinProc, offsets: &Offsets) -> Option<Vec<WinThread>> {
+        let kernel = AddressSpace::kernel(ctx);
+        let user = AddressSpace::virt(ctx, proc.dirBase);
+        let list_head = proc.process + offsets.eprocess.thread_list_head? as u64;
+        let list_entry = offsets.ethread.thread_list_entry? as u64;
+
+        let mut ret = vec![];
+        let mut cur = kernel.read::<u64>(list_head)?;
+
+        while cur != list_head && cur != 0 && ret.len() < MAX_THREADS {
+            let ethread = cur.wrapping_sub(list_entry);
+
+            let teb = offsets.kthread.teb
+                .and_then(|o| kernel.read::<u64>(ethread + o as u64))
+                .unwrap_or(0);
+
+            let tid = offsets.ethread.cid
+                .and_then(|o| kernel.read::<u64>(ethread + o as u64 + 8))
+                .or_else(|| Some(teb).filter(|&t| t != 0).and_then(|t| user.read::<u64>(t + TEB_CLIENT_ID_THREAD)))
+                .unwrap_or(0);
+
+            let (stack_base, stack_limit) = match teb {
+                0 => (0, 0),
+                t => (user.read::<u64>(t + TEB_STACK_BASE).unwrap_or(0), user.read::<u64>(t + TEB_STACK_LIMIT).unwrap_or(0)),
+            };
+
+            let trap_frame = offsets.kthread.trap_frame
+                .and_then(|o| kernel.read::<u64>(ethread + o as u64))
+                .filter(|&t| t != 0)
+                .and_then(|t| TrapFrame::read(&kernel, t));
+
+            ret.push(WinThread {
+                ethread: ethread,
+                thread_id: tid,
+                teb: teb,
+                stack_base: stack_base,
+                stack_limit: stack_limit,
+                trap_frame: trap_frame,
+            });
+
+            cur = match kernel.read::<u64>(cur) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        Some(ret)
+    }
+}
@@ -1,4 +1,208 @@
+use std::cell::Cell;
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
+/// Identity of a VM as seen by the C library, the qemu pid and the base of its guest RAM mapping
+type VmKey = (i32, u64);
+
+/// Translation state of the current thread
+#[derive(Clone, Copy)]
+struct ThreadVm {
+    vm: VmKey,
+    epoch: u64,
+    invalidation: u64,
+    generation: u64,
+    time: usize,
+}
+
+/// Cache validity time requested by a context
+struct CacheTime {
+    owner: u64,
+    vm: VmKey,
+    time: usize,
+}
+
+/// Marks an unset time, `GLOBAL_TIME` then stands for the library default
+const UNSET_TIME: usize = usize::MAX;
+
+/// Bumped whenever translations of every VM have to be dropped, i.e. when a kernel is searched for
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+/// Bumped whenever translations of a single VM have to be dropped
+static INVALIDATION: AtomicU64 = AtomicU64::new(0);
+/// Value of `INVALIDATION` each VM has last been invalidated at
+static VM_INVALIDATIONS: Mutex<Vec<(VmKey, u64)>> = Mutex::new(Vec::new());
+/// Bumped whenever a cache time changes
+static TIMES_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Cache time last passed to the C library, which keeps it globally
+static APPLIED_TIME: AtomicUsize = AtomicUsize::new(UNSET_TIME);
+/// Cache time of VMs no context has set one for
+static GLOBAL_TIME: AtomicUsize = AtomicUsize::new(UNSET_TIME);
+static CACHE_TIMES: Mutex<Vec<CacheTime>> = Mutex::new(Vec::new());
+/// Read-held across translating C calls, write-held to change `APPLIED_TIME`
+static TIME_LOCK: RwLock<()> = RwLock::new(());
+
+thread_local! {
+    /// VM the translation caches of the current thread were last used with
+    static CURRENT_VM: Cell<Option<ThreadVm>> = const { Cell::new(None) };
+}
+
+/// Keeps the cache time applied for a translating C call from changing until it returns
+///
+/// Calls with the same time run concurrently, calls needing another time wait for them.
+pub(crate) struct TimeGuard {
+    _lock: RwLockReadGuard<'static, ()>,
+}
+
+/// Apply a cache time, and hold it until the returned guard is dropped
+///
+/// The guard must not be held while calling this again on the same thread.
+fn hold_mem_cache_time(time: usize) -> TimeGuard {
+    loop {
+        let lock = TIME_LOCK.read().unwrap_or_else(|e| e.into_inner());
+
+        if APPLIED_TIME.load(Ordering::Relaxed) == time {
+            return TimeGuard { _lock: lock };
+        }
+
+        drop(lock);
+
+        let _lock = TIME_LOCK.write().unwrap_or_else(|e| e.into_inner());
+
+        if APPLIED_TIME.swap(time, Ordering::Relaxed) != time {
+            unsafe {
+                sys::SetMemCacheTime(time as u64);
+            }
+        }
+    }
+}
+
+fn global_mem_cache_time() -> usize {
+    match GLOBAL_TIME.load(Ordering::Relaxed) {
+        UNSET_TIME => Tlb::get_default_mem_cache_time(),
+        time => time,
+    }
+}
+
+/// Cache time of a VM, the shortest one set by the contexts attached to it
+fn vm_mem_cache_time(vm: VmKey) -> usize {
+    CACHE_TIMES.lock().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|t| t.vm == vm)
+        .map(|t| t.time)
+        .min()
+        .unwrap_or_else(global_mem_cache_time)
+}
+
+/// Prepare the translation state of the calling thread for accessing a VM
+///
+/// Has to be called before every C call that translates virtual addresses. The C library keeps
+/// translation caches per thread, not per VM, so they are flushed when the thread moves on to
+/// another VM, since page table addresses of different VMs may collide.
+///
+/// The C library keeps a single cache time for the whole process, thus the time of the VM is
+/// applied, and the returned guard keeps other threads from changing it. The guard has to be
+/// held until the C call returns, and dropped before the next `enter_vm` on the thread.
+///
+/// # Arguments
+///
+/// * `process` - qemu process of the VM
+pub(crate) fn enter_vm(process: &sys::ProcessData) -> TimeGuard {
+    let vm = (process.pid, process.mapsStart);
+    let epoch = EPOCH.load(Ordering::Acquire);
+    let invalidation = INVALIDATION.load(Ordering::Acquire);
+    let generation = TIMES_GENERATION.load(Ordering::Acquire);
+
+    let time = CURRENT_VM.with(|current| {
+        let prev = current.get().filter(|p| p.vm == vm);
+
+        let valid = match prev {
+            Some(p) if p.epoch == epoch => p.invalidation == invalidation || !invalidated_since(vm, p.invalidation),
+            _ => false,
+        };
+
+        if !valid {
+            Tlb::from_current_thread().flush_tlb();
+        }
+
+        let time = match prev {
+            Some(p) if p.generation == generation => p.time,
+            _ => vm_mem_cache_time(vm),
+        };
+
+        current.set(Some(ThreadVm {
+            vm: vm,
+            epoch: epoch,
+            invalidation: invalidation,
+            generation: generation,
+            time: time,
+        }));
+
+        time
+    });
+
+    hold_mem_cache_time(time)
+}
+
+/// Check whether a VM has been invalidated after a given `INVALIDATION` value
+fn invalidated_since(vm: VmKey, invalidation: u64) -> bool {
+    VM_INVALIDATIONS.lock().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|&(v, i)| v == vm && i > invalidation)
+}
+
+/// Drop the translations of a VM, on all threads
+///
+/// Each thread flushes its caches on its next `enter_vm` for the VM, e.g. once the VM has been
+/// paused, as the caches may hold translations from while it was running.
+///
+/// # Arguments
+///
+/// * `process` - qemu process of the VM
+pub(crate) fn invalidate_vm(process: &sys::ProcessData) {
+    let vm = (process.pid, process.mapsStart);
+    let mut invalidations = VM_INVALIDATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let invalidation = INVALIDATION.fetch_add(1, Ordering::AcqRel) + 1;
+
+    invalidations.retain(|&(v, _)| v != vm);
+    invalidations.push((vm, invalidation));
+}
+
+/// Drop the translations of every VM, on all threads
+///
+/// Used before the C library searches for a kernel, which must not be found through translations
+/// of a previous one. The returned guard holds the cache time, as with `enter_vm`.
+///
+/// # Arguments
+///
+/// * `mem_cache_time` - cache time to search with, `None` for the global one
+pub(crate) fn reset_translations(mem_cache_time: Option<usize>) -> TimeGuard {
+    EPOCH.fetch_add(1, Ordering::AcqRel);
+    Tlb::from_current_thread().flush_tlb();
+    CURRENT_VM.with(|current| current.set(None));
+    hold_mem_cache_time(mem_cache_time.unwrap_or_else(global_mem_cache_time))
+}
+
+/// Set the cache time a context requests for its VM
+///
+/// # Arguments
+///
+/// * `owner` - unique ID of the context
+/// * `process` - qemu process of the VM, `None` to only remove the request of the context
+/// * `mem_cache_time` - validity time in milliseconds, `None` to remove the request
+pub(crate) fn set_vm_mem_cache_time(owner: u64, process: Option<&sys::ProcessData>, mem_cache_time: Option<usize>) {
+    let mut times = CACHE_TIMES.lock().unwrap_or_else(|e| e.into_inner());
+    times.retain(|t| t.owner != owner);
+
+    if let (Some(process), Some(time)) = (process, mem_cache_time) {
+        times.push(CacheTime {
+            owner: owner,
+            vm: (process.pid, process.mapsStart),
+            time: time,
+        });
+    }
+
+    TIMES_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
 
 pub struct Tlb {
     tlbp: *mut sys::tlb_t
@@ -40,13 +244,15 @@ impl Tlb {
     /// the page tables update in that period. Especially dangerous if write operations are to be
     /// performed.
     ///
+    /// The time applies to VMs that no context has set its own time for, see
+    /// `WinContext::set_mem_cache_time`. It takes effect with the next read or write.
+    ///
     /// # Arguments
     ///
     /// * `new_time` - new validity time
     pub fn set_mem_cache_time(new_time: usize) {
-        unsafe {
-            sys::SetMemCacheTime(new_time as u64);
-        }
+        GLOBAL_TIME.store(new_time, Ordering::Relaxed);
+        TIMES_GENERATION.fetch_add(1, Ordering::AcqRel);
     }
 
     /// Get the default TLB validity in milliseconds
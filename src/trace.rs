@@ -0,0 +1,574 @@
+//! Recording and replaying of physical memory reads
+//!
+//! A `TraceRecorder` attached to a context captures the physical reads made through it, along with
+//! the data read and the time of the read. The resulting `MemoryTrace` is saved in a compact format:
+//!
+//! ```text
+//! magic "VMRTRACE" | u32 version | varint context length | context JSON | records
+//! record: varint time delta (us) | varint address | varint length | data
+//! ```
+//!
+//! The context state, i.e. the kernel location and the offsets, is stored along, so that
+//! `WinContext::replay` can recreate the context without a VM. Its `TraceReplay` backend serves
+//! the recorded reads back, so that code built on the context and its address spaces can be rerun
+//! deterministically, i.e. as a regression test.
+//!
+//! # Limitations
+//!
+//! Only reads performed by this crate are seen. While recording, address spaces of the context
+//! translate virtual addresses themselves and the process list is walked in Rust, so that their
+//! reads are recorded too. The vmread C library still reads guest memory by itself during context
+//! creation, and when listing modules, and it has no pluggable memory backend to hook. Those reads,
+//! as well as the ones of code taking a C context, such as `WinProcess` functions, are neither
+//! recorded, nor can they be replayed. Writes are not recorded either.
+
+use crate::backend::*;
+use crate::win_context::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const TRACE_MAGIC: &[u8; 8] = b"VMRTRACE";
+const TRACE_VERSION: u32 = 2;
+/// Last version without the context state
+const TRACE_VERSION_NO_CONTEXT: u32 = 1;
+
+/// Error produced by trace operations
+#[derive(Debug)]
+pub enum TraceError {
+    Io(std::io::Error),
+    /// The data is not a trace, or is truncated
+    InvalidFormat(&'static str),
+    /// The read has not been recorded, i.e. the replayed code has diverged from the recording
+    NotRecorded { address: u64, size: u64 },
+}
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TraceError::Io(e) => write!(f, "trace I/O error: {}", e),
+            TraceError::InvalidFormat(e) => write!(f, "invalid trace: {}", e),
+            TraceError::NotRecorded { address, size } => write!(f, "read {:#x}+{:#x} is not in the trace", address, size),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<std::io::Error> for TraceError {
+    fn from(e: std::io::Error) -> TraceError {
+        TraceError::Io(e)
+    }
+}
+
+/// A single recorded read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Time since the start of the recording
+    pub timestamp: Duration,
+    /// Guest physical address
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Recorded physical reads, in the order they have been made
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryTrace {
+    /// State of the context the reads have been made through, needed for replaying
+    pub context: Option<ContextState>,
+    pub records: Vec<TraceRecord>,
+}
+
+fn write_varint<W: Write>(out: &mut W, mut v: u64) -> std::io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        buf[len] = (v & 0x7f) as u8;
+        v >>= 7;
+
+        if v == 0 {
+            len += 1;
+            break;
+        }
+
+        buf[len] |= 0x80;
+        len += 1;
+    }
+
+    out.write_all(&buf[..len])
+}
+
+/// Read a varint, returning `None` on a clean end of input
+fn read_varint<R: Read>(input: &mut R) -> Result<Option<u64>, TraceError> {
+    let mut ret = 0u64;
+    let mut byte = [0u8];
+
+    for i in 0..10 {
+        if input.read(&mut byte)? == 0 {
+            return match i {
+                0 => Ok(None),
+                _ => Err(TraceError::InvalidFormat("truncated record")),
+            };
+        }
+
+        ret |= ((byte[0] & 0x7f) as u64) << (7 * i);
+
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(ret));
+        }
+    }
+
+    Err(TraceError::InvalidFormat("varint too long"))
+}
+
+impl MemoryTrace {
+    /// Get the total amount of data read
+    pub fn size(&self) -> u64 {
+        self.records.iter().map(|r| r.data.len() as u64).sum()
+    }
+
+    /// Write the trace in the trace format
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), TraceError> {
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+
+        let context = match &self.context {
+            Some(c) => serde_json::to_vec(c).map_err(|_| TraceError::InvalidFormat("unserializable context"))?,
+            None => vec![],
+        };
+
+        write_varint(out, context.len() as u64)?;
+        out.write_all(&context)?;
+
+        let mut last = 0;
+
+        for r in &self.records {
+            let timestamp = r.timestamp.as_micros() as u64;
+
+            write_varint(out, timestamp.saturating_sub(last))?;
+            write_varint(out, r.address)?;
+            write_varint(out, r.data.len() as u64)?;
+            out.write_all(&r.data)?;
+            last = last.max(timestamp);
+        }
+
+        Ok(())
+    }
+
+    /// Read a trace written by `write`
+    pub fn read<R: Read>(input: &mut R) -> Result<MemoryTrace, TraceError> {
+        let mut header = [0u8; 12];
+        input.read_exact(&mut header).map_err(|_| TraceError::InvalidFormat("truncated header"))?;
+
+        if &header[..8] != TRACE_MAGIC {
+            return Err(TraceError::InvalidFormat("bad magic"));
+        }
+
+        let mut ret = MemoryTrace::default();
+
+        match u32::from_le_bytes([header[8], header[9], header[10], header[11]]) {
+            TRACE_VERSION => {
+                let len = read_varint(input)?.ok_or(TraceError::InvalidFormat("truncated header"))?;
+
+                if len != 0 {
+                    let mut context = vec![];
+                    input.take(len).read_to_end(&mut context)?;
+
+                    ret.context = Some(serde_json::from_slice(&context).map_err(|_| TraceError::InvalidFormat("invalid context"))?);
+                }
+            },
+            TRACE_VERSION_NO_CONTEXT => {},
+            _ => return Err(TraceError::InvalidFormat("unsupported version")),
+        }
+
+        let mut timestamp = Duration::default();
+
+        while let Some(delta) = read_varint(input)? {
+            let address = read_varint(input)?.ok_or(TraceError::InvalidFormat("truncated record"))?;
+            let len = read_varint(input)?.ok_or(TraceError::InvalidFormat("truncated record"))?;
+
+            let mut data = vec![];
+            input.take(len).read_to_end(&mut data)?;
+
+            if data.len() as u64 != len {
+                return Err(TraceError::InvalidFormat("truncated record"));
+            }
+
+            timestamp += Duration::from_micros(delta);
+
+            ret.records.push(TraceRecord {
+                timestamp: timestamp,
+                address: address,
+                data: data,
+            });
+        }
+
+        Ok(ret)
+    }
+
+    /// Save the trace to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), TraceError> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Load a trace from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MemoryTrace, TraceError> {
+        Self::read(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+}
+
+/// Collector of physical reads
+///
+/// Reads are recorded through a shared reference, so that views borrowing the context can record.
+#[derive(Debug)]
+pub struct TraceRecorder {
+    start: Instant,
+    trace: RefCell<MemoryTrace>,
+}
+
+impl Default for TraceRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceRecorder {
+    /// Start a recording, timestamps are relative to this moment
+    pub fn new() -> TraceRecorder {
+        TraceRecorder {
+            start: Instant::now(),
+            trace: RefCell::new(MemoryTrace::default()),
+        }
+    }
+
+    /// Record a successful read
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - guest physical address
+    /// * `data` - data that has been read
+    pub fn record(&self, address: u64, data: &[u8]) {
+        self.trace.borrow_mut().records.push(TraceRecord {
+            timestamp: self.start.elapsed(),
+            address: address,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Get the number of reads recorded so far
+    pub fn len(&self) -> usize {
+        self.trace.borrow().records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finish the recording
+    pub fn finish(self) -> MemoryTrace {
+        self.trace.into_inner()
+    }
+}
+
+/// Server of reads from a recorded trace
+///
+/// Reads are matched by their address and size. Repeated reads of the same memory are served the
+/// recorded data in order, and the last recorded data once the recording runs out, as the memory
+/// is assumed not to have changed since. Reads that have not been recorded fail.
+pub struct TraceReplay {
+    trace: MemoryTrace,
+    reads: HashMap<(u64, usize), (Vec<usize>, Cell<usize>)>,
+}
+
+impl TraceReplay {
+    /// Prepare a trace for replaying
+    pub fn new(trace: MemoryTrace) -> TraceReplay {
+        let mut reads: HashMap<(u64, usize), (Vec<usize>, Cell<usize>)> = HashMap::new();
+
+        for (i, r) in trace.records.iter().enumerate() {
+            reads.entry((r.address, r.data.len())).or_default().0.push(i);
+        }
+
+        TraceReplay {
+            trace: trace,
+            reads: reads,
+        }
+    }
+
+    /// Load a trace file for replaying
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TraceReplay, TraceError> {
+        Ok(Self::new(MemoryTrace::load(path)?))
+    }
+
+    /// Get the trace being replayed
+    pub fn trace(&self) -> &MemoryTrace {
+        &self.trace
+    }
+
+    /// Serve a read into a buffer
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - guest physical address
+    /// * `out` - buffer to fill, its length has to match the recorded read
+    pub fn read_raw(&self, address: u64, out: &mut [u8]) -> Result<(), TraceError> {
+        let (indices, next) = self.reads.get(&(address, out.len()))
+            .ok_or(TraceError::NotRecorded { address: address, size: out.len() as u64 })?;
+
+        let idx = indices[next.get().min(indices.len() - 1)];
+        next.set(next.get() + 1);
+
+        out.copy_from_slice(&self.trace.records[idx].data);
+        Ok(())
+    }
+
+    /// Serve a read of a value of type `T`
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - guest physical address
+    pub fn read_physical<T>(&self, address: u64) -> Result<T, TraceError> {
+        let mut ret : T = unsafe { std::mem::zeroed() };
+        let buf = unsafe { std::slice::from_raw_parts_mut(&mut ret as *mut T as *mut u8, std::mem::size_of::<T>()) };
+
+        self.read_raw(address, buf)?;
+        Ok(ret)
+    }
+
+    /// Start serving every read from the beginning of the recording again
+    pub fn rewind(&mut self) -> &mut Self {
+        self.reads.values_mut().for_each(|(_, next)| next.set(0));
+        self
+    }
+}
+
+impl PhysicalMemory for TraceReplay {
+    fn read_physical(&self, address: u64, out: &mut [u8]) -> bool {
+        self.read_raw(address, out).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::*;
+    use crate::offsets::*;
+
+    const PML4: u64 = 0x1000;
+    const KERNEL_BASE: u64 = 0xffff_f800_0010_0000;
+
+    /// Guest RAM holding 4-level page tables, starting at `PML4`
+    struct FakeGuest {
+        mem: Vec<u8>,
+        next_table: u64,
+    }
+
+    impl FakeGuest {
+        fn new(size: usize) -> FakeGuest {
+            FakeGuest {
+                mem: vec![0; size],
+                next_table: PML4 + 0x1000,
+            }
+        }
+
+        fn write(&mut self, address: u64, data: &[u8]) {
+            self.mem[address as usize..address as usize + data.len()].copy_from_slice(data);
+        }
+
+        fn read_u64(&self, address: u64) -> u64 {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&self.mem[address as usize..address as usize + 8]);
+            u64::from_le_bytes(buf)
+        }
+
+        /// Map a 4 KiB page, allocating page tables on the way
+        fn map(&mut self, va: u64, pa: u64) {
+            let mut table = PML4;
+
+            for level in (2..=4).rev() {
+                let entry = table + ((va >> (12 + 9 * (level - 1))) & 0x1ff) * 8;
+
+                table = match self.read_u64(entry) {
+                    0 => {
+                        let new = self.next_table;
+                        self.next_table += 0x1000;
+                        self.write(entry, &(new | 0x3).to_le_bytes());
+                        new
+                    },
+                    e => e & 0x000f_ffff_ffff_f000,
+                };
+            }
+
+            self.write(table + ((va >> 12) & 0x1ff) * 8, &(pa | 0x3).to_le_bytes());
+        }
+    }
+
+    fn offsets() -> Offsets {
+        Offsets {
+            eprocess: EprocessOffsets {
+                unique_process_id: Some(0x440),
+                active_process_links: Some(0x448),
+                image_file_name: Some(0x5a8),
+                ..Default::default()
+            },
+            kprocess: KprocessOffsets {
+                directory_table_base: Some(0x28),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Guest with three processes, their `EPROCESS` structures on consecutive kernel pages
+    fn fake_guest() -> (FakeGuest, ContextState) {
+        let mut guest = FakeGuest::new(0x40000);
+        let processes: [(u64, &str, u64); 3] = [(4, "System", PML4), (0x1a0, "smss.exe", 0x9000), (0x1234, "explorer.exe", 0xa000)];
+
+        for (i, (pid, name, dir_base)) in processes.iter().enumerate() {
+            let va = KERNEL_BASE + i as u64 * 0x1000;
+            let pa = 0x20000 + i as u64 * 0x1000;
+            let next = KERNEL_BASE + ((i as u64 + 1) % 3) * 0x1000;
+
+            guest.map(va, pa);
+            guest.write(pa + 0x28, &dir_base.to_le_bytes());
+            guest.write(pa + 0x440, &pid.to_le_bytes());
+            guest.write(pa + 0x448, &(next + 0x448).to_le_bytes());
+            guest.write(pa + 0x5a8, name.as_bytes());
+        }
+
+        let state = ContextState {
+            nt_kernel: KERNEL_BASE,
+            nt_version: 1000,
+            nt_build: 19045,
+            system_process: KERNEL_BASE,
+            system_dir_base: PML4,
+            offsets: offsets(),
+            memory_map: Some(vec![MemoryRange {
+                start: 0,
+                end: guest.mem.len() as u64,
+                host_address: 0,
+            }]),
+        };
+
+        (guest, state)
+    }
+
+    fn processes(ctx: &WinContext) -> Vec<(u64, String, u64, u64)> {
+        ctx.process_list.iter().map(|p| (p.proc.pid, p.name.clone(), p.proc.dirBase, p.proc.physProcess)).collect()
+    }
+
+    #[test]
+    fn round_trips_traces() {
+        let trace = MemoryTrace {
+            context: Some(fake_guest().1),
+            records: vec![
+                TraceRecord { timestamp: Duration::from_micros(5), address: 0x1000, data: vec![1, 2, 3] },
+                TraceRecord { timestamp: Duration::from_micros(300), address: 0xffff_ffff, data: vec![] },
+            ],
+        };
+
+        let mut buf = vec![];
+        trace.write(&mut buf).unwrap();
+        assert_eq!(MemoryTrace::read(&mut &buf[..]).unwrap(), trace);
+
+        // Version 1 traces have no context state
+        let mut v1 = b"VMRTRACE\x01\x00\x00\x00".to_vec();
+        v1.extend_from_slice(&[5, 0x80, 0x20, 3, 1, 2, 3]);
+
+        assert_eq!(MemoryTrace::read(&mut &v1[..]).unwrap(), MemoryTrace {
+            context: None,
+            records: vec![TraceRecord { timestamp: Duration::from_micros(5), address: 0x1000, data: vec![1, 2, 3] }],
+        });
+
+        assert!(MemoryTrace::read(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(MemoryTrace::read(&mut &b"VMRTRACE\x07\x00\x00\x00"[..]).is_err());
+    }
+
+    #[test]
+    fn replays_recorded_session() {
+        let (guest, state) = fake_guest();
+        let mut live = WinContext::from_backend(Box::new(guest.mem), &state);
+
+        live.start_recording();
+        live.refresh_processes();
+
+        let listed = processes(&live);
+        let regions = live.kernel().regions(KERNEL_BASE, KERNEL_BASE + 0x10000);
+        let pid = live.kernel().read::<u64>(KERNEL_BASE + 0x1000 + 0x440);
+        let dir_base = live.read_physical::<u64>(0x22028).ok();
+
+        let trace = live.stop_recording().unwrap();
+        assert!(!live.is_recording());
+
+        assert_eq!(listed, vec![
+            (4, "System".to_string(), PML4, 0x20000),
+            (0x1a0, "smss.exe".to_string(), 0x9000, 0x21000),
+            (0x1234, "explorer.exe".to_string(), 0xa000, 0x22000),
+        ]);
+
+        assert_eq!(regions.len(), 1);
+        assert_eq!((regions[0].start, regions[0].size), (KERNEL_BASE, 0x3000));
+        assert_eq!(pid, Some(0x1a0));
+        assert_eq!(dir_base, Some(0xa000));
+
+        // Page table walks are recorded along with the data they lead to
+        assert!(trace.records.iter().any(|r| r.address == PML4 + 0x1f0 * 8 && r.data.len() == 8));
+        assert!(trace.records.iter().any(|r| r.address == PML4 && r.data.len() == 0x1000));
+
+        let mut buf = vec![];
+        trace.write(&mut buf).unwrap();
+        let trace = MemoryTrace::read(&mut &buf[..]).unwrap();
+        assert_eq!(trace.context.as_ref(), Some(&state));
+
+        let mut replay = WinContext::replay(trace).unwrap();
+        replay.refresh_processes();
+
+        assert!(replay.has_backend());
+        assert_eq!(processes(&replay), listed);
+        assert_eq!(replay.kernel().regions(KERNEL_BASE, KERNEL_BASE + 0x10000), regions);
+        assert_eq!(replay.kernel().read::<u64>(KERNEL_BASE + 0x1000 + 0x440), pid);
+        assert_eq!(replay.read_physical::<u64>(0x22028).ok(), dir_base);
+        assert_eq!(replay.memory_map().map(|m| m.size()), Some(0x40000));
+
+        // Memory the session has not read is not in the trace
+        assert!(replay.read_physical::<u64>(0x22030).is_err());
+        assert_eq!(replay.kernel().read::<u64>(KERNEL_BASE + 0x3000), None);
+        assert!(replay.write_physical(0x22028, &0u64).is_err());
+        assert!(replay.check_health().is_ok());
+    }
+
+    #[test]
+    fn requires_context_state() {
+        match WinContext::replay(MemoryTrace::default()) {
+            Err(TraceError::InvalidFormat(_)) => {},
+            _ => panic!("replayed a trace without context state"),
+        }
+    }
+
+    #[test]
+    fn serves_repeated_reads_in_order() {
+        let replay = TraceReplay::new(MemoryTrace {
+            context: None,
+            records: vec![
+                TraceRecord { timestamp: Duration::default(), address: 0x10, data: vec![1, 0] },
+                TraceRecord { timestamp: Duration::default(), address: 0x10, data: vec![2, 0] },
+            ],
+        });
+
+        assert_eq!(replay.read_physical::<u16>(0x10).unwrap(), 1);
+        assert_eq!(replay.read_physical::<u16>(0x10).unwrap(), 2);
+        assert_eq!(replay.read_physical::<u16>(0x10).unwrap(), 2);
+        assert!(replay.read_physical::<u8>(0x10).is_err());
+
+        let mut buf = [0u8; 2];
+        let memory: &dyn PhysicalMemory = &replay;
+        assert!(memory.read_physical(0x10, &mut buf));
+        assert!(!memory.read_physical(0x12, &mut buf));
+    }
+}
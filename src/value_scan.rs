@@ -0,0 +1,604 @@
+//! Iterative value scanning
+//!
+//! The first scan finds all addresses holding a value matching a predicate, and every next scan
+//! narrows the candidates down by comparing their current values against the previous ones.
+//!
+//! Candidates are stored per batch of pages of a memory region as a bitmap of aligned slots, along
+//! with their last seen values. Batches with many candidates keep a raw copy of their memory
+//! instead, which is smaller than the packed values when most slots are candidates, like after an
+//! unknown initial value scan.
+
+use crate::address_space::*;
+use std::marker::PhantomData;
+
+/// Number of pages read in a single batch
+const PAGES_PER_BATCH: usize = 0x400;
+
+/// A primitive type that can be scanned for
+pub trait ScanValue: Copy + PartialOrd {
+    const SIZE: usize;
+
+    /// Decode the value from little-endian bytes, `bytes` is exactly `SIZE` long
+    fn from_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! scan_value {
+    ($($t:ty),*) => {
+        $(
+            impl ScanValue for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_bytes(bytes: &[u8]) -> Self {
+                    let mut buf = [0u8; std::mem::size_of::<$t>()];
+                    buf.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+scan_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// Predicate of the first scan
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FirstScan<T> {
+    /// Value is equal to the given one. Floats are compared exactly, prefer `Range` for them
+    Exact(T),
+    /// Value is within the inclusive range
+    Range(T, T),
+    /// Every slot is a candidate
+    Unknown,
+}
+
+/// Predicate of a next scan
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NextScan<T> {
+    /// Value is equal to the given one
+    Exact(T),
+    /// Value is within the inclusive range
+    Range(T, T),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+/// Predicate of a next string scan
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NextStringScan {
+    /// String is equal to the given one, which has to have the same length as the original
+    Exact(Vec<u8>),
+    Changed,
+    Unchanged,
+}
+
+/// Options of the first scan
+#[derive(Clone, Copy, Debug)]
+pub struct ScanOptions {
+    pub start: u64,
+    pub end: u64,
+    /// Alignment of candidate addresses. Defaults to the value size for typed scans
+    pub alignment: Option<usize>,
+    /// Skip read-only memory
+    pub writable_only: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            start: 0,
+            end: USER_SPACE_END,
+            alignment: None,
+            writable_only: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum RegionValues {
+    /// Raw copy of the whole candidate region
+    Dense(Vec<u8>),
+    /// Values of the candidates, in address order
+    Packed(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+struct CandidateRegion {
+    start: u64,
+    size: usize,
+    bitmap: Vec<u64>,
+    count: usize,
+    values: RegionValues,
+}
+
+impl CandidateRegion {
+    fn slots(&self, width: usize, alignment: usize) -> usize {
+        if self.size < width { 0 } else { (self.size - width) / alignment + 1 }
+    }
+
+    fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bitmap.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64).filter(move |b| word & (1 << b) != 0).map(move |b| i * 64 + b)
+        })
+    }
+
+    fn value(&self, slot: usize, index: usize, width: usize, alignment: usize) -> &[u8] {
+        match &self.values {
+            RegionValues::Dense(d) => &d[(slot * alignment)..(slot * alignment + width)],
+            RegionValues::Packed(p) => &p[(index * width)..((index + 1) * width)],
+        }
+    }
+}
+
+/// Untyped candidate set shared by the typed scanners
+#[derive(Clone, Debug)]
+struct ScanCore {
+    width: usize,
+    alignment: usize,
+    regions: Vec<CandidateRegion>,
+}
+
+/// Pages read in a batch, with the information which of them could be read
+struct PageBuffer {
+    pages: Vec<u64>,
+    ok: Vec<bool>,
+    data: Vec<u8>,
+}
+
+impl PageBuffer {
+    fn read(mem: &AddressSpace, pages: Vec<u64>) -> PageBuffer {
+        let mut data = vec![0u8; pages.len() * 0x1000];
+
+        let (queued, done) = {
+            let mut rwlist = mem.rwlist();
+            for (page, buf) in pages.iter().zip(data.chunks_mut(0x1000)) {
+                rwlist.read_arr(*page, buf);
+            }
+            let (_, queued, done) = rwlist.commit_read();
+            (queued, done)
+        };
+
+        // Find out which pages failed only if the batch has not been read in full
+        let ok = if done >= queued {
+            vec![true; pages.len()]
+        } else {
+            pages.iter().zip(data.chunks_mut(0x1000)).map(|(page, buf)| mem.read_arr(*page, buf)).collect()
+        };
+
+        PageBuffer {
+            pages: pages,
+            ok: ok,
+            data: data,
+        }
+    }
+
+    /// Get `len` bytes at `address`, if all of the pages are present in the buffer and readable
+    fn get(&self, address: u64, len: usize, hint: &mut usize) -> Option<&[u8]> {
+        let page = address & !0xfff;
+        let last_page = (address + len as u64 - 1) & !0xfff;
+
+        while *hint < self.pages.len() && self.pages[*hint] < page {
+            *hint += 1;
+        }
+
+        let first = *hint;
+        let last = first + ((last_page - page) >> 12) as usize;
+
+        if last >= self.pages.len() || self.pages[first] != page || self.pages[last] != last_page || !self.ok[first..=last].iter().all(|&o| o) {
+            return None;
+        }
+
+        let off = first * 0x1000 + (address & 0xfff) as usize;
+        Some(&self.data[off..(off + len)])
+    }
+}
+
+impl ScanCore {
+    fn first(mem: &AddressSpace, width: usize, options: &ScanOptions, pred: &dyn Fn(&[u8]) -> bool) -> ScanCore {
+        let alignment = options.alignment.unwrap_or(width).max(1);
+        let mut ret = ScanCore {
+            width: width,
+            alignment: alignment,
+            regions: vec![],
+        };
+
+        for region in mem.regions(options.start, options.end) {
+            if options.writable_only && !region.writable {
+                continue;
+            }
+
+            let end = region.end().min(options.end);
+            let mut slot_start = region.start.max(options.start);
+
+            // Regions are scanned one batch of pages at a time, each batch with candidates becomes
+            // a candidate region of its own. Values starting in a batch may extend into the next.
+            while end.saturating_sub(slot_start) >= width as u64 {
+                let batch_end = ((slot_start & !0xfff) + (PAGES_PER_BATCH * 0x1000) as u64).min(end);
+                let values_end = batch_end.saturating_add(width as u64 - 1).min(end);
+                let mut candidate = CandidateRegion {
+                    start: slot_start,
+                    size: (values_end - slot_start) as usize,
+                    bitmap: vec![],
+                    count: 0,
+                    values: RegionValues::Dense(vec![]),
+                };
+
+                let slots = candidate.slots(width, alignment);
+                candidate.bitmap = vec![0u64; slots.div_ceil(64)];
+
+                let pages = ((slot_start & !0xfff)..values_end).step_by(0x1000).collect::<Vec<_>>();
+                let buffer = PageBuffer::read(mem, pages);
+                let mut hint = 0;
+
+                for slot in 0..slots {
+                    let address = slot_start + (slot * alignment) as u64;
+
+                    if buffer.get(address, width, &mut hint).is_some_and(pred) {
+                        candidate.bitmap[slot / 64] |= 1 << (slot % 64);
+                        candidate.count += 1;
+                    }
+                }
+
+                slot_start += (slots * alignment) as u64;
+
+                if candidate.count == 0 {
+                    continue;
+                }
+
+                let head = (candidate.start & 0xfff) as usize;
+                let mut raw = buffer.data;
+                raw.truncate(head + candidate.size);
+                raw.drain(..head);
+
+                candidate.values = RegionValues::Dense(raw);
+                ret.compact(&mut candidate);
+                ret.regions.push(candidate);
+            }
+        }
+
+        ret
+    }
+
+    fn next(&mut self, mem: &AddressSpace, pred: &dyn Fn(&[u8], &[u8]) -> bool) {
+        let (width, alignment) = (self.width, self.alignment);
+        let mut regions = std::mem::take(&mut self.regions);
+
+        for region in regions.iter_mut() {
+            let candidates = region.candidates().collect::<Vec<_>>();
+            let mut new_bitmap = vec![0u64; region.bitmap.len()];
+            let mut new_values = vec![];
+            let mut count = 0;
+            let mut i = 0;
+
+            // Read the pages of candidates in groups, so that no value spans two batches
+            while i < candidates.len() {
+                let group_start = i;
+                let mut pages = vec![];
+
+                while i < candidates.len() && pages.len() < PAGES_PER_BATCH {
+                    let address = region.start + (candidates[i] * alignment) as u64;
+                    let mut page = address & !0xfff;
+
+                    while page < address + width as u64 {
+                        if pages.last() != Some(&page) {
+                            pages.push(page);
+                        }
+                        page += 0x1000;
+                    }
+
+                    i += 1;
+                }
+
+                let buffer = PageBuffer::read(mem, pages);
+                let mut hint = 0;
+
+                for (index, &slot) in candidates.iter().enumerate().take(i).skip(group_start) {
+                    let address = region.start + (slot * alignment) as u64;
+
+                    let new = match buffer.get(address, width, &mut hint) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+
+                    if pred(new, region.value(slot, index, width, alignment)) {
+                        new_bitmap[slot / 64] |= 1 << (slot % 64);
+                        new_values.extend_from_slice(new);
+                        count += 1;
+                    }
+                }
+            }
+
+            region.bitmap = new_bitmap;
+            region.count = count;
+            region.values = RegionValues::Packed(new_values);
+        }
+
+        regions.retain(|r| r.count > 0);
+        self.regions = regions;
+    }
+
+    /// Switch a dense region to packed values once that takes less memory
+    fn compact(&self, region: &mut CandidateRegion) {
+        let packed_size = region.count * self.width;
+
+        if let RegionValues::Dense(dense) = &region.values {
+            if packed_size < dense.len() {
+                let mut packed = Vec::with_capacity(packed_size);
+                for slot in region.candidates() {
+                    let off = slot * self.alignment;
+                    packed.extend_from_slice(&dense[off..(off + self.width)]);
+                }
+                region.values = RegionValues::Packed(packed);
+            }
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.regions.iter().map(|r| r.count).sum()
+    }
+
+    fn results(&self) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        let (width, alignment) = (self.width, self.alignment);
+
+        self.regions.iter().flat_map(move |r| {
+            r.candidates().enumerate().map(move |(index, slot)| {
+                (r.start + (slot * alignment) as u64, r.value(slot, index, width, alignment))
+            })
+        })
+    }
+}
+
+/// Value scan over a primitive type
+#[derive(Clone, Debug)]
+pub struct ValueScan<T: ScanValue> {
+    core: ScanCore,
+    phantom: PhantomData<T>,
+}
+
+impl<T: ScanValue> ValueScan<T> {
+    /// Perform the first scan
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space to scan, usually `WinProcess::address_space`
+    /// * `scan` - value predicate
+    /// * `options` - scanned range and alignment
+    pub fn first_scan(mem: &AddressSpace, scan: FirstScan<T>, options: &ScanOptions) -> ValueScan<T> {
+        let pred = |b: &[u8]| {
+            let v = T::from_bytes(b);
+            match scan {
+                FirstScan::Exact(e) => v == e,
+                FirstScan::Range(min, max) => v >= min && v <= max,
+                FirstScan::Unknown => true,
+            }
+        };
+
+        ValueScan {
+            core: ScanCore::first(mem, T::SIZE, options, &pred),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Narrow the candidates down by their current values
+    ///
+    /// Candidates that are no longer readable are dropped. Returns the number of candidates left.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space the first scan was performed on
+    /// * `scan` - value predicate
+    pub fn next_scan(&mut self, mem: &AddressSpace, scan: NextScan<T>) -> usize {
+        let pred = |new: &[u8], old: &[u8]| {
+            let (new, old) = (T::from_bytes(new), T::from_bytes(old));
+            match scan {
+                NextScan::Exact(e) => new == e,
+                NextScan::Range(min, max) => new >= min && new <= max,
+                NextScan::Changed => new != old,
+                NextScan::Unchanged => new == old,
+                NextScan::Increased => new > old,
+                NextScan::Decreased => new < old,
+            }
+        };
+
+        self.core.next(mem, &pred);
+        self.count()
+    }
+
+    /// Number of candidates
+    pub fn count(&self) -> usize {
+        self.core.count()
+    }
+
+    /// Get the candidate addresses, with their values as of the last scan
+    pub fn results(&self) -> impl Iterator<Item = (u64, T)> + '_ {
+        self.core.results().map(|(a, v)| (a, T::from_bytes(v)))
+    }
+}
+
+/// Scan for byte strings, like ASCII or UTF-16 text
+#[derive(Clone, Debug)]
+pub struct StringScan {
+    core: ScanCore,
+}
+
+impl StringScan {
+    /// Find all occurrences of a byte string
+    ///
+    /// Use `utf16_bytes` to search for wide strings.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space to scan
+    /// * `needle` - bytes to search for
+    /// * `options` - scanned range and alignment, which defaults to 1
+    pub fn first_scan(mem: &AddressSpace, needle: &[u8], options: &ScanOptions) -> StringScan {
+        let options = ScanOptions {
+            alignment: Some(options.alignment.unwrap_or(1)),
+            ..*options
+        };
+
+        StringScan {
+            core: ScanCore::first(mem, needle.len().max(1), &options, &|b| b == needle),
+        }
+    }
+
+    /// Narrow the candidates down by their current contents
+    ///
+    /// Returns the number of candidates left.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - address space the first scan was performed on
+    /// * `scan` - string predicate
+    pub fn next_scan(&mut self, mem: &AddressSpace, scan: &NextStringScan) -> usize {
+        let pred = |new: &[u8], old: &[u8]| match scan {
+            NextStringScan::Exact(e) => new == &e[..],
+            NextStringScan::Changed => new != old,
+            NextStringScan::Unchanged => new == old,
+        };
+
+        self.core.next(mem, &pred);
+        self.count()
+    }
+
+    /// Number of candidates
+    pub fn count(&self) -> usize {
+        self.core.count()
+    }
+
+    /// Get the candidate addresses, with their contents as of the last scan
+    pub fn results(&self) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        self.core.results()
+    }
+}
+
+/// Encode a string as UTF-16LE bytes, for scanning wide strings
+pub fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PML4: u64 = 0x1000;
+    const DATA: u64 = 0x100000;
+    const BASE: u64 = 0x7ff0_0000_0000;
+
+    /// Guest with `pages` user pages mapped from `BASE` on, the ones in `holes` left unmapped
+    struct FakeGuest {
+        mem: Vec<u8>,
+        next_table: u64,
+    }
+
+    impl FakeGuest {
+        fn new(pages: u64, holes: &[u64]) -> FakeGuest {
+            let mut guest = FakeGuest {
+                mem: vec![0; (DATA + pages * 0x1000) as usize],
+                next_table: PML4 + 0x1000,
+            };
+
+            for i in (0..pages).filter(|i| !holes.contains(i)) {
+                guest.map(BASE + i * 0x1000, DATA + i * 0x1000);
+            }
+
+            guest
+        }
+
+        fn entry(&self, address: u64) -> u64 {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&self.mem[address as usize..address as usize + 8]);
+            u64::from_le_bytes(buf)
+        }
+
+        fn map(&mut self, va: u64, pa: u64) {
+            let mut table = PML4;
+
+            for level in (2..=4).rev() {
+                let entry = table + ((va >> (12 + 9 * (level - 1))) & 0x1ff) * 8;
+
+                table = match self.entry(entry) {
+                    0 => {
+                        let new = self.next_table;
+                        self.next_table += 0x1000;
+                        self.mem[entry as usize..entry as usize + 8].copy_from_slice(&(new | 0x7).to_le_bytes());
+                        new
+                    },
+                    e => e & 0x000f_ffff_ffff_f000,
+                };
+            }
+
+            let entry = (table + ((va >> 12) & 0x1ff) * 8) as usize;
+            self.mem[entry..entry + 8].copy_from_slice(&(pa | 0x7).to_le_bytes());
+        }
+
+        /// Write to a mapped virtual address
+        fn write(&mut self, va: u64, data: &[u8]) {
+            let pa = (DATA + va - BASE) as usize;
+            self.mem[pa..pa + data.len()].copy_from_slice(data);
+        }
+    }
+
+    fn scan<T: ScanValue>(guest: &FakeGuest, scan: FirstScan<T>, options: &ScanOptions) -> Vec<(u64, T)> {
+        let mut ctx = sys::WinCtx::default();
+        ctx.initialProcess.dirBase = PML4;
+        let mem = AddressSpace::kernel(&ctx).with_backend(&guest.mem);
+        ValueScan::first_scan(&mem, scan, options).results().collect()
+    }
+
+    #[test]
+    fn finds_values_across_batches() {
+        let boundary = BASE + (PAGES_PER_BATCH * 0x1000) as u64;
+        let mut guest = FakeGuest::new(PAGES_PER_BATCH as u64 + 4, &[PAGES_PER_BATCH as u64 + 2]);
+
+        guest.write(BASE + 0x10, &0x1234_5678u32.to_le_bytes());
+        guest.write(boundary - 2, &0x1234_5678u32.to_le_bytes());
+        guest.write(boundary + 0x100, &0x1234_5678u32.to_le_bytes());
+        // Runs into the unmapped page, thus is not readable
+        guest.write(boundary + 0x1ffe, &0x5678u16.to_le_bytes());
+
+        let options = ScanOptions {
+            alignment: Some(1),
+            ..Default::default()
+        };
+
+        assert_eq!(scan(&guest, FirstScan::Exact(0x1234_5678u32), &options), vec![
+            (BASE + 0x10, 0x1234_5678),
+            (boundary - 2, 0x1234_5678),
+            (boundary + 0x100, 0x1234_5678),
+        ]);
+
+        // Aligned scans skip the value spanning the batches
+        assert_eq!(scan(&guest, FirstScan::Exact(0x1234_5678u32), &ScanOptions::default()).len(), 2);
+    }
+
+    #[test]
+    fn narrows_down_unknown_values() {
+        let mut guest = FakeGuest::new(PAGES_PER_BATCH as u64 + 1, &[]);
+        let options = ScanOptions {
+            start: BASE + 0x800,
+            ..Default::default()
+        };
+
+        let mut ctx = sys::WinCtx::default();
+        ctx.initialProcess.dirBase = PML4;
+
+        let mut value_scan = {
+            let mem = AddressSpace::kernel(&ctx).with_backend(&guest.mem);
+            ValueScan::first_scan(&mem, FirstScan::<u64>::Unknown, &options)
+        };
+
+        assert_eq!(value_scan.count(), ((PAGES_PER_BATCH + 1) * 0x1000 - 0x800) / 8);
+
+        guest.write(BASE + 0x1000, &7u64.to_le_bytes());
+        guest.write(BASE + (PAGES_PER_BATCH * 0x1000) as u64, &9u64.to_le_bytes());
+
+        let mem = AddressSpace::kernel(&ctx).with_backend(&guest.mem);
+        assert_eq!(value_scan.next_scan(&mem, NextScan::Increased), 2);
+        assert_eq!(value_scan.results().collect::<Vec<_>>(), vec![
+            (BASE + 0x1000, 7),
+            (BASE + (PAGES_PER_BATCH * 0x1000) as u64, 9),
+        ]);
+    }
+}
@@ -0,0 +1,503 @@
+//! Discovery of qemu/KVM processes on the host
+//!
+//! Candidates are found by their executable name in `/proc/<pid>/cmdline`, and described by the
+//! `-name`, `-uuid` and `-m` arguments they were started with. The `/proc` root is a parameter, so
+//! that discovery can be run against a prepared directory tree.
+
+use std::path::{Path, PathBuf};
+
+/// Default location of the proc filesystem
+pub const PROC_ROOT: &str = "/proc";
+
+/// A qemu process running a VM
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmInfo {
+    pub pid: i32,
+    /// Guest name given by `-name`
+    pub name: Option<String>,
+    /// Guest UUID given by `-uuid`, lowercase
+    pub uuid: Option<String>,
+    /// Guest RAM size in bytes, from `-m` or the RAM memory backend
+    pub memory_size: Option<u64>,
+    /// Full command line of the process
+    pub cmdline: Vec<String>,
+    /// Start time of the process in clock ticks since host boot, telling apart reused pids
+    pub start_time: Option<u64>,
+}
+
+impl VmInfo {
+    /// Parse the command line of a process
+    ///
+    /// Returns `None` if the process is not qemu.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - process ID
+    /// * `cmdline` - NUL separated contents of `/proc/<pid>/cmdline`
+    pub fn from_cmdline(pid: i32, cmdline: &[u8]) -> Option<VmInfo> {
+        let cmdline = cmdline.strip_suffix(&[0]).unwrap_or(cmdline);
+        let args = cmdline.split(|&b| b == 0)
+            .map(|a| String::from_utf8_lossy(a).into_owned())
+            .collect::<Vec<_>>();
+
+        let exe = args.first()?.rsplit('/').next().unwrap_or("");
+
+        if !exe.starts_with("qemu") && exe != "kvm" {
+            return None;
+        }
+
+        let mut ret = VmInfo {
+            pid: pid,
+            cmdline: args.clone(),
+            ..Default::default()
+        };
+
+        let mut backend_size = None;
+        let mut iter = args.iter().skip(1);
+
+        while let Some(arg) = iter.next() {
+            // qemu accepts options with either one or two dashes
+            let option = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+                Some(o) => o,
+                None => continue,
+            };
+
+            let value = match option {
+                "name" | "uuid" | "m" | "object" => match iter.next() {
+                    Some(v) => v,
+                    None => break,
+                },
+                _ => continue,
+            };
+
+            let props = split_options(value);
+
+            match option {
+                "name" => {
+                    ret.name = props.iter()
+                        .find_map(|(k, v)| match (k.as_str(), v) {
+                            ("guest", Some(v)) => Some(v.clone()),
+                            (name, None) => Some(name.to_string()),
+                            _ => None,
+                        });
+                },
+                "uuid" => ret.uuid = Some(value.to_lowercase()),
+                "m" => {
+                    ret.memory_size = props.iter()
+                        .find_map(|(k, v)| match (k.as_str(), v) {
+                            ("size", Some(v)) => Some(v.as_str()),
+                            (size, None) => Some(size),
+                            _ => None,
+                        })
+                        .and_then(|s| parse_size(s, 1 << 20));
+                },
+                _ if value.starts_with('{') => {
+                    // JSON object syntax of recent libvirt versions
+                    if let Ok(serde_json::Value::Object(o)) = serde_json::from_str::<serde_json::Value>(value) {
+                        let is_ram = o.get("qom-type").and_then(|t| t.as_str()).map(|t| t.starts_with("memory-backend-")).unwrap_or(false)
+                            && o.get("id").and_then(|i| i.as_str()).map(|i| i.ends_with("ram")).unwrap_or(false);
+
+                        if is_ram {
+                            backend_size = o.get("size").and_then(|s| s.as_u64());
+                        }
+                    }
+                },
+                _ => {
+                    // libvirt passes the RAM size through a memory backend object instead of -m
+                    let is_ram = props.first().map(|(k, _)| k.starts_with("memory-backend-")).unwrap_or(false)
+                        && props.iter().any(|(k, v)| k == "id" && v.as_ref().map(|v| v.ends_with("ram")).unwrap_or(false));
+
+                    if is_ram {
+                        backend_size = props.iter()
+                            .find(|(k, _)| k == "size")
+                            .and_then(|(_, v)| v.as_ref())
+                            .and_then(|s| parse_size(s, 1));
+                    }
+                },
+            }
+        }
+
+        if ret.memory_size.is_none() {
+            ret.memory_size = backend_size;
+        }
+
+        Some(ret)
+    }
+
+    /// Find the QMP socket of the VM
+    ///
+    /// Both `-qmp unix:<path>` and control monitors on socket chardevs, the way libvirt sets them
+    /// up, are recognized.
+    pub fn qmp_socket(&self) -> Option<PathBuf> {
+        let mut chardevs = vec![];
+        let mut monitors = vec![];
+        let mut iter = self.cmdline.iter().skip(1);
+
+        while let Some(arg) = iter.next() {
+            let option = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+                Some(o @ "qmp") | Some(o @ "qmp-pretty") | Some(o @ "chardev") | Some(o @ "mon") => o,
+                _ => continue,
+            };
+
+            let props = match iter.next() {
+                Some(v) => split_options(v),
+                None => break,
+            };
+
+            let prop = |key: &str| props.iter().find(|(k, _)| k == key).and_then(|(_, v)| v.clone());
+
+            match option {
+                "chardev" if props.first().map(|(k, _)| k == "socket").unwrap_or(false) => {
+                    if let (Some(id), Some(path)) = (prop("id"), prop("path")) {
+                        chardevs.push((id, path));
+                    }
+                },
+                "mon" if prop("mode").as_deref() == Some("control") => monitors.extend(prop("chardev")),
+                "qmp" | "qmp-pretty" => {
+                    if let Some(path) = props.first().and_then(|(k, _)| k.strip_prefix("unix:")) {
+                        return Some(PathBuf::from(path));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        monitors.iter()
+            .find_map(|m| chardevs.iter().find(|(id, _)| id == m))
+            .map(|(_, path)| PathBuf::from(path))
+    }
+}
+
+/// Split a qemu option string into its `key[=value]` pairs, `,,` being an escaped comma
+fn split_options(value: &str) -> Vec<(String, Option<String>)> {
+    let mut parts = vec![];
+    let mut cur = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ',' if chars.peek() == Some(&',') => {
+                chars.next();
+                cur.push(',');
+            },
+            ',' => parts.push(std::mem::take(&mut cur)),
+            c => cur.push(c),
+        }
+    }
+
+    parts.push(cur);
+
+    parts.into_iter().map(|p| match p.find('=') {
+        Some(i) => (p[..i].to_string(), Some(p[i + 1..].to_string())),
+        None => (p, None),
+    }).collect()
+}
+
+/// Parse a qemu size with an optional `K`, `M`, `G` or `T` suffix
+///
+/// # Arguments
+///
+/// * `s` - size string
+/// * `unit` - multiplier of sizes without a suffix
+fn parse_size(s: &str, unit: u64) -> Option<u64> {
+    let s = s.trim();
+    let (num, mul) = match s.chars().last()?.to_ascii_uppercase() {
+        'B' => (&s[..s.len() - 1], 1),
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        'T' => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, unit),
+    };
+
+    num.parse::<u64>().ok()?.checked_mul(mul)
+}
+
+/// List the qemu processes on the host
+pub fn discover_vms() -> Vec<VmInfo> {
+    discover_vms_in(PROC_ROOT)
+}
+
+/// List the qemu processes found in a proc filesystem
+///
+/// Processes whose command line can not be read, i.e. ones that have just exited, are skipped.
+///
+/// # Arguments
+///
+/// * `proc_root` - root of the proc filesystem
+pub fn discover_vms_in<P: AsRef<Path>>(proc_root: P) -> Vec<VmInfo> {
+    let entries = match std::fs::read_dir(proc_root.as_ref()) {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+
+    let mut ret = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse::<i32>().ok()))
+        .filter_map(|pid| discover_vm_in(proc_root.as_ref(), pid))
+        .collect::<Vec<_>>();
+
+    ret.sort_unstable_by_key(|v| v.pid);
+    ret
+}
+
+/// Describe a single process found in a proc filesystem
+///
+/// Returns `None` if the process does not exist, or is not qemu.
+///
+/// # Arguments
+///
+/// * `proc_root` - root of the proc filesystem
+/// * `pid` - process ID
+pub fn discover_vm_in<P: AsRef<Path>>(proc_root: P, pid: i32) -> Option<VmInfo> {
+    let path = proc_root.as_ref().join(pid.to_string());
+    let mut ret = VmInfo::from_cmdline(pid, &std::fs::read(path.join("cmdline")).ok()?)?;
+
+    // The command name may contain spaces and parentheses, the fields after it do not
+    ret.start_time = std::fs::read_to_string(path.join("stat")).ok()
+        .and_then(|s| s.rsplit_once(')').and_then(|(_, f)| f.split_whitespace().nth(19).and_then(|t| t.parse().ok())));
+
+    Some(ret)
+}
+
+/// Error produced when selecting a VM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmSelectError {
+    NoMatch,
+    /// More than one VM matches, all of them are listed
+    Ambiguous(Vec<VmInfo>),
+}
+
+impl std::fmt::Display for VmSelectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VmSelectError::NoMatch => write!(f, "no VM matches the selector"),
+            VmSelectError::Ambiguous(vms) => {
+                write!(f, "{} VMs match the selector:", vms.len())?;
+                for vm in vms {
+                    write!(f, " {} ({})", vm.pid, vm.name.as_deref().unwrap_or("unnamed"))?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl std::error::Error for VmSelectError {}
+
+/// Attributes a VM has to match. Unset attributes match any VM
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmSelector {
+    pub name: Option<String>,
+    /// UUID, compared case-insensitively
+    pub uuid: Option<String>,
+    /// RAM size in bytes
+    pub memory_size: Option<u64>,
+    /// Substring of any command line argument
+    pub cmdline: Option<String>,
+}
+
+impl VmSelector {
+    /// Select VMs by name
+    pub fn name(name: &str) -> VmSelector {
+        VmSelector {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Select VMs by UUID
+    pub fn uuid(uuid: &str) -> VmSelector {
+        VmSelector {
+            uuid: Some(uuid.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Select VMs by a command line substring
+    pub fn cmdline(cmdline: &str) -> VmSelector {
+        VmSelector {
+            cmdline: Some(cmdline.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Check whether a VM has all of the set attributes
+    pub fn matches(&self, vm: &VmInfo) -> bool {
+        self.name.as_ref().map(|n| vm.name.as_ref() == Some(n)).unwrap_or(true)
+            && self.uuid.as_ref().map(|u| vm.uuid.as_deref() == Some(u.to_lowercase().as_str())).unwrap_or(true)
+            && self.memory_size.map(|m| vm.memory_size == Some(m)).unwrap_or(true)
+            && self.cmdline.as_ref().map(|c| vm.cmdline.iter().any(|a| a.contains(c.as_str()))).unwrap_or(true)
+    }
+
+    /// Select the single matching VM
+    ///
+    /// # Arguments
+    ///
+    /// * `vms` - candidate VMs, i.e. from `discover_vms`
+    pub fn select(&self, vms: &[VmInfo]) -> Result<VmInfo, VmSelectError> {
+        let mut matching = vms.iter().filter(|v| self.matches(v)).cloned().collect::<Vec<_>>();
+
+        match matching.len() {
+            0 => Err(VmSelectError::NoMatch),
+            1 => Ok(matching.remove(0)),
+            _ => Err(VmSelectError::Ambiguous(matching)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Temporary directory laid out like `/proc`, removed on drop
+    struct FakeProc(PathBuf);
+
+    impl FakeProc {
+        fn new() -> FakeProc {
+            let root = std::env::temp_dir().join(format!("vmread-proc-{}-{}", std::process::id(), NEXT_ROOT.fetch_add(1, Ordering::Relaxed)));
+            std::fs::create_dir_all(&root).unwrap();
+            FakeProc(root)
+        }
+
+        fn add(&self, pid: i32, args: &[&str], stat: Option<&str>) {
+            let dir = self.0.join(pid.to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut cmdline = vec![];
+            for a in args {
+                cmdline.extend_from_slice(a.as_bytes());
+                cmdline.push(0);
+            }
+
+            std::fs::write(dir.join("cmdline"), cmdline).unwrap();
+
+            if let Some(stat) = stat {
+                std::fs::write(dir.join("stat"), stat).unwrap();
+            }
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn cmdline(args: &[&str]) -> Vec<u8> {
+        args.join("\0").into_bytes()
+    }
+
+    #[test]
+    fn parses_cmdline() {
+        let vm = VmInfo::from_cmdline(10, &cmdline(&["/usr/bin/qemu-system-x86_64", "-enable-kvm", "-name", "win10", "-uuid", "4C4C4544-0000-1111-2222-333344445555", "-m", "4G"])).unwrap();
+
+        assert_eq!(vm.pid, 10);
+        assert_eq!(vm.name.as_deref(), Some("win10"));
+        assert_eq!(vm.uuid.as_deref(), Some("4c4c4544-0000-1111-2222-333344445555"));
+        assert_eq!(vm.memory_size, Some(4 << 30));
+        assert_eq!(vm.cmdline.len(), 8);
+    }
+
+    #[test]
+    fn ignores_other_processes() {
+        assert_eq!(VmInfo::from_cmdline(1, &cmdline(&["/sbin/init", "-name", "x"])), None);
+        assert_eq!(VmInfo::from_cmdline(1, b""), None);
+        assert!(VmInfo::from_cmdline(1, &cmdline(&["kvm"])).is_some());
+    }
+
+    #[test]
+    fn parses_libvirt_name() {
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-name", "guest=win10,debug-threads=on"])).unwrap();
+        assert_eq!(vm.name.as_deref(), Some("win10"));
+
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "--name", "win,,10,process=qemu:win"])).unwrap();
+        assert_eq!(vm.name.as_deref(), Some("win,10"));
+    }
+
+    #[test]
+    fn parses_memory_sizes() {
+        let size = |m: &str| VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-m", m])).unwrap().memory_size;
+
+        assert_eq!(size("2048"), Some(2048 << 20));
+        assert_eq!(size("512M"), Some(512 << 20));
+        assert_eq!(size("4g"), Some(4 << 30));
+        assert_eq!(size("size=8G,slots=4,maxmem=16G"), Some(8 << 30));
+        assert_eq!(size("lots"), None);
+
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-object", "memory-backend-ram,id=pc.ram,size=1073741824"])).unwrap();
+        assert_eq!(vm.memory_size, Some(1 << 30));
+
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-object", r#"{"qom-type":"memory-backend-file","id":"pc.ram","size":2147483648}"#])).unwrap();
+        assert_eq!(vm.memory_size, Some(2 << 30));
+
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-m", "1G", "-object", "memory-backend-ram,id=pc.ram,size=4G"])).unwrap();
+        assert_eq!(vm.memory_size, Some(1 << 30));
+    }
+
+    #[test]
+    fn finds_qmp_socket() {
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-qmp", "unix:/tmp/qmp.sock,server,nowait"])).unwrap();
+        assert_eq!(vm.qmp_socket(), Some(PathBuf::from("/tmp/qmp.sock")));
+
+        let vm = VmInfo::from_cmdline(1, &cmdline(&["qemu-system-x86_64", "-chardev", "socket,id=charmonitor,path=/run/libvirt/win10.monitor,server=on,wait=off", "-mon", "chardev=charmonitor,id=monitor,mode=control"])).unwrap();
+        assert_eq!(vm.qmp_socket(), Some(PathBuf::from("/run/libvirt/win10.monitor")));
+    }
+
+    #[test]
+    fn discovers_fake_proc() {
+        let root = FakeProc::new();
+        root.add(300, &["qemu-system-x86_64", "-name", "guest=b,debug-threads=on", "-m", "2G"], Some("300 (qemu-system-x86) S 1 300 300 0 -1 4194560 1 0 0 0 5 6 0 0 20 0 8 0 4242 0 0"));
+        root.add(20, &["/usr/bin/qemu-kvm", "-name", "a"], Some("20 (weird ) name) S 1 20 20 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 77 0 0"));
+        root.add(5, &["bash"], None);
+        std::fs::create_dir_all(root.0.join("self")).unwrap();
+        std::fs::create_dir_all(root.0.join("40")).unwrap();
+
+        let vms = discover_vms_in(&root.0);
+
+        assert_eq!(vms.iter().map(|v| v.pid).collect::<Vec<_>>(), vec![20, 300]);
+        assert_eq!(vms[0].name.as_deref(), Some("a"));
+        assert_eq!(vms[0].start_time, Some(77));
+        assert_eq!(vms[1].name.as_deref(), Some("b"));
+        assert_eq!(vms[1].memory_size, Some(2 << 30));
+        assert_eq!(vms[1].start_time, Some(4242));
+
+        assert_eq!(discover_vm_in(&root.0, 5), None);
+        assert_eq!(discover_vm_in(&root.0, 40), None);
+        assert!(discover_vms_in(root.0.join("missing")).is_empty());
+    }
+
+    #[test]
+    fn selects_vms() {
+        let root = FakeProc::new();
+        root.add(1, &["qemu-system-x86_64", "-name", "win10", "-uuid", "AAAA-1", "-m", "4G"], None);
+        root.add(2, &["qemu-system-x86_64", "-name", "win10", "-uuid", "bbbb-2", "-m", "8G"], None);
+        root.add(3, &["qemu-system-x86_64", "-name", "linux", "-m", "4G", "-snapshot"], None);
+
+        let vms = discover_vms_in(&root.0);
+
+        assert_eq!(VmSelector::uuid("aaaa-1").select(&vms).map(|v| v.pid), Ok(1));
+        assert_eq!(VmSelector::cmdline("-snapshot").select(&vms).map(|v| v.pid), Ok(3));
+        assert_eq!(VmSelector::name("win7").select(&vms), Err(VmSelectError::NoMatch));
+
+        let by_size = VmSelector {
+            name: Some("win10".to_string()),
+            memory_size: Some(8 << 30),
+            ..Default::default()
+        };
+
+        assert_eq!(by_size.select(&vms).map(|v| v.pid), Ok(2));
+
+        let err = VmSelector::name("win10").select(&vms).unwrap_err();
+
+        match &err {
+            VmSelectError::Ambiguous(candidates) => assert_eq!(candidates.iter().map(|v| v.pid).collect::<Vec<_>>(), vec![1, 2]),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        assert_eq!(err.to_string(), "2 VMs match the selector: 1 (win10) 2 (win10)");
+    }
+}
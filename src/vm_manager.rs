@@ -0,0 +1,169 @@
+//! Management of contexts for every VM on the host
+//!
+//! `VmManager` discovers qemu processes, creates an independent `WinContext` for each eligible
+//! one, and follows VMs starting and stopping on every `refresh`. Failing to attach to one VM,
+//! i.e. because its guest is still booting, does not affect the others, and is retried on the
+//! next refresh.
+
+use crate::vm_discovery::*;
+use crate::win_context::*;
+use crate::health::*;
+use std::path::PathBuf;
+
+/// Change in the set of managed VMs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmEvent {
+    /// A context has been created for the VM
+    Attached(VmInfo),
+    /// Context creation failed. Reported again only if the error changes
+    AttachFailed {
+        vm: VmInfo,
+        error: ContextError,
+    },
+    /// The qemu process has exited, its context has been dropped
+    Detached(VmInfo),
+    /// The health of an attached VM's context has changed, i.e. the guest has rebooted
+    Health {
+        vm: VmInfo,
+        event: HealthEvent,
+    },
+}
+
+/// A VM with its context
+pub struct ManagedVm {
+    pub info: VmInfo,
+    pub context: WinContext,
+}
+
+/// Manager of one context per VM
+pub struct VmManager {
+    options: ContextOptions,
+    proc_root: PathBuf,
+    vms: Vec<ManagedVm>,
+    failed: Vec<(VmInfo, ContextError)>,
+}
+
+impl VmManager {
+    /// Create a manager without attaching to any VM yet
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - context creation options applied to every VM. `vm` restricts the eligible
+    ///   VMs, and is not required to match a single one. `pid` is ignored
+    pub fn new(options: ContextOptions) -> VmManager {
+        VmManager {
+            options: options,
+            proc_root: PathBuf::from(PROC_ROOT),
+            vms: vec![],
+            failed: vec![],
+        }
+    }
+
+    /// Discover VMs in a different proc filesystem root
+    pub fn proc_root<P: Into<PathBuf>>(mut self, proc_root: P) -> Self {
+        self.proc_root = proc_root.into();
+        self
+    }
+
+    /// Discover VMs, attaching to new ones and detaching from exited ones
+    ///
+    /// A VM is considered to be the same one as long as its pid, start time and command line stay
+    /// the same. The contexts of the remaining VMs get their health checked, and are recovered if
+    /// the guest has rebooted in the meantime.
+    pub fn refresh(&mut self) -> Vec<VmEvent> {
+        let found = discover_vms_in(&self.proc_root).into_iter()
+            .filter(|v| self.options.vm.as_ref().map(|s| s.matches(v)).unwrap_or(true))
+            .collect::<Vec<_>>();
+
+        let mut events = vec![];
+
+        let (kept, gone): (Vec<_>, Vec<_>) = self.vms.drain(..).partition(|m| found.contains(&m.info));
+        self.vms = kept;
+        events.extend(gone.into_iter().map(|m| VmEvent::Detached(m.info)));
+        self.failed.retain(|(v, _)| found.contains(v));
+
+        for vm in self.vms.iter_mut() {
+            let info = &vm.info;
+            events.extend(vm.context.poll_health().into_iter().map(|e| VmEvent::Health {
+                vm: info.clone(),
+                event: e,
+            }));
+        }
+
+        for vm in found {
+            if self.vms.iter().any(|m| m.info == vm) {
+                continue;
+            }
+
+            let options = ContextOptions {
+                pid: vm.pid,
+                vm: None,
+                ..self.options.clone()
+            };
+
+            match create_context_with(&options) {
+                Ok((context, _)) => {
+                    self.failed.retain(|(v, _)| *v != vm);
+                    events.push(VmEvent::Attached(vm.clone()));
+                    self.vms.push(ManagedVm {
+                        info: vm,
+                        context: context,
+                    });
+                },
+                Err(error) => {
+                    match self.failed.iter_mut().find(|(v, _)| *v == vm) {
+                        Some((_, e)) if *e == error => continue,
+                        Some((_, e)) => *e = error.clone(),
+                        None => self.failed.push((vm.clone(), error.clone())),
+                    }
+
+                    events.push(VmEvent::AttachFailed {
+                        vm: vm,
+                        error: error,
+                    });
+                },
+            }
+        }
+
+        self.vms.sort_unstable_by_key(|m| m.info.pid);
+        events
+    }
+
+    /// Get the attached VMs, sorted by pid
+    pub fn vms(&self) -> &[ManagedVm] {
+        &self.vms
+    }
+
+    /// Get the VMs that could not be attached to, along with the last error
+    pub fn failed(&self) -> &[(VmInfo, ContextError)] {
+        &self.failed
+    }
+
+    /// Find an attached VM by the pid of its qemu process
+    pub fn vm(&self, pid: i32) -> Option<&ManagedVm> {
+        self.vms.iter().find(|m| m.info.pid == pid)
+    }
+
+    /// Find an attached VM by its name
+    pub fn vm_by_name(&self, name: &str) -> Option<&ManagedVm> {
+        self.vms.iter().find(|m| m.info.name.as_deref() == Some(name))
+    }
+
+    /// Run a function on the context of a VM
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - pid of the qemu process
+    /// * `f` - function to run
+    pub fn with_vm<R, F: FnOnce(&mut WinContext) -> R>(&mut self, pid: i32, f: F) -> Option<R> {
+        let vm = self.vms.iter_mut().find(|m| m.info.pid == pid)?;
+        Some(f(&mut vm.context))
+    }
+
+    /// Run a function on every attached VM in turn
+    pub fn for_each<F: FnMut(&mut ManagedVm)>(&mut self, mut f: F) {
+        for vm in self.vms.iter_mut() {
+            f(vm);
+        }
+    }
+}
@@ -2,29 +2,158 @@
 use crate::win_process::*;
 use crate::win_dll::*;
 use crate::rwlist::*;
+use crate::offsets::*;
+use crate::win_info::*;
+use crate::win_export::*;
+use crate::kernel_module::*;
+use crate::index::*;
+use crate::address_space::*;
+use crate::memory_map::*;
+use crate::vm_discovery::*;
+use crate::tlb::*;
+use crate::health::*;
+use crate::trace::*;
+use crate::backend::*;
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Source of the IDs contexts register their cache times with
+static NEXT_CONTEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Upper bound of processes walked in Rust, against looping on a corrupted list
+const MAX_PROCESSES: usize = 0x10000;
 
 /// Context describing a particular VM instance
 ///
 /// This structure provides interfaces to parse windows process information and to perform reads and
 /// writes to memory of the VM.
 ///
-/// There is no `new` implementation, use `create_context` to retrieve an initialized context.
+/// There is no `new` implementation, use `create_context` to retrieve an initialized context, or
+/// `from_backend` and `replay` for one reading from another source than a running VM.
 pub struct WinContext {
     ctx: sys::WinCtx,
+    id: u64,
+    options: ContextOptions,
+    vm: Option<VmInfo>,
+    unhealthy: bool,
+    recovery_error: Option<ContextError>,
+    mem_cache_time: Option<usize>,
+    offsets: Arc<Offsets>,
+    offset_report: OffsetReport,
+    memory_map: OnceCell<Option<MemoryMap>>,
+    trace: Option<TraceRecorder>,
+    backend: Option<Box<dyn PhysicalMemory + Send>>,
     pub process_list: Vec<WinProcess>,
     pub kmod_list: Vec<WinDll>,
+    pub kernel_module_list: Vec<KernelModule>,
+    process_index: OnceCell<ProcessIndex>,
+    kmod_index: OnceCell<ModuleIndex>,
+}
+
+/// State of a context needed to recreate it on a memory backend, see `WinContext::from_backend`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextState {
+    /// Base address of the kernel image
+    pub nt_kernel: u64,
+    /// NT version, in vmread's format (major * 100 + minor)
+    pub nt_version: u16,
+    pub nt_build: u32,
+    /// Address of the `EPROCESS` of the system process
+    pub system_process: u64,
+    /// Page table base of the system process
+    pub system_dir_base: u64,
+    pub offsets: Offsets,
+    /// Guest RAM ranges physical accesses are validated against, if any
+    pub memory_map: Option<Vec<MemoryRange>>,
+}
+
+/// Options used for context creation
+#[derive(Clone, Debug, Default)]
+pub struct ContextOptions {
+    /// Target process ID. Value of 0 indicates automatic detection
+    pub pid: i32,
+    /// Select the qemu process by its attributes. Combined with a non-zero `pid`, both have to match
+    pub vm: Option<VmSelector>,
+    /// Offset profiles to apply on top of the built-in tables
+    pub profiles: OffsetProfiles,
+    /// Translation cache validity time in milliseconds, `None` to use the global setting
+    pub mem_cache_time: Option<usize>,
 }
 
+/// Open the debug output file of the C library
+///
+/// `vmread_dfile` is a single global of the C library, thus the file stays global as well: it is
+/// opened by the first context and shared by all contexts after it, and is never closed.
 #[cfg(feature="internal_rw")]
 fn set_vmread_dfile() {
+    static DFILE: std::sync::Once = std::sync::Once::new();
+
+    DFILE.call_once(|| {
         unsafe {
-            sys::vmread_dfile = libc::fopen("/tmp/vmread_out.txt".as_bytes().as_ptr() as *const i8, "w".as_bytes().as_ptr() as *const i8)
+            sys::vmread_dfile = libc::fopen(b"/tmp/vmread_out.txt\0".as_ptr() as *const libc::c_char, b"w\0".as_ptr() as *const libc::c_char)
         };
+    });
 }
 
 #[cfg(not(feature="internal_rw"))]
 fn set_vmread_dfile() {}
 
+/// Error produced when creating or recovering a context
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContextError {
+    /// Initialization failed with an error code of the vmread library
+    Library { code: i32, message: &'static str },
+    /// A field needed by the C library is not provided by any offset table or profile
+    IncompleteOffsets { field: &'static str },
+    /// No single VM matches the selector, ambiguous matches list the candidates
+    VmSelection(VmSelectError),
+}
+
+impl ContextError {
+    fn from_code(code: i32) -> ContextError {
+        ContextError::Library {
+            code: code,
+            message: error_string(code),
+        }
+    }
+
+    /// Get the numeric error code, as returned by `create_context`
+    pub fn code(&self) -> i32 {
+        match self {
+            ContextError::Library { code, .. } => *code,
+            ContextError::IncompleteOffsets { .. } => 10,
+            ContextError::VmSelection(VmSelectError::NoMatch) => 11,
+            ContextError::VmSelection(VmSelectError::Ambiguous(_)) => 12,
+        }
+    }
+
+    /// Get the description of the error code
+    pub fn message(&self) -> &'static str {
+        error_string(self.code())
+    }
+}
+
+impl std::fmt::Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ContextError::Library { code, message } => write!(f, "{} ({})", message, code),
+            ContextError::IncompleteOffsets { field } => write!(f, "{}: {} is missing", error_string(10), field),
+            ContextError::VmSelection(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+impl From<ContextError> for (i32, &'static str) {
+    fn from(e: ContextError) -> (i32, &'static str) {
+        (e.code(), e.message())
+    }
+}
+
 /// Initialize a new vmread context based on the specified process ID.
 ///
 /// Returns a tuple containing high-level and C contexts on success;
@@ -34,106 +163,668 @@ fn set_vmread_dfile() {}
 ///
 /// * `pid` - target process ID. Value of 0 indicates automatic detection
 pub fn create_context(pid : i32) -> Result<(WinContext, sys::WinCtx), (i32, &'static str)> {
-    let mut ctx = sys::WinCtx::default();
+    create_context_with(&ContextOptions {
+        pid: pid,
+        ..Default::default()
+    }).map_err(|e| e.into())
+}
 
+/// Initialize a new vmread context with custom options
+///
+/// Works the same way as `create_context`, but additionally applies the offset profiles. If the C
+/// library does not know the guest's Windows build, the profiles are used in place of its tables.
+/// Errors carry details, such as the offset field that is missing.
+///
+/// # Arguments
+///
+/// * `options` - context creation options
+pub fn create_context_with(options: &ContextOptions) -> Result<(WinContext, sys::WinCtx), ContextError> {
     set_vmread_dfile();
 
-    let err = unsafe { sys::InitializeContext(&mut ctx, pid) };
+    let (ctx, offsets, offset_report) = initialize_context(options)?;
+    let id = NEXT_CONTEXT_ID.fetch_add(1, Ordering::Relaxed);
+    set_vm_mem_cache_time(id, Some(&ctx.process), options.mem_cache_time);
+
+    Ok((WinContext {
+        ctx: ctx,
+        id: id,
+        options: options.clone(),
+        vm: discover_vm_in(PROC_ROOT, ctx.process.pid),
+        unhealthy: false,
+        recovery_error: None,
+        mem_cache_time: options.mem_cache_time,
+        offsets: Arc::new(offsets),
+        offset_report: offset_report,
+        memory_map: OnceCell::new(),
+        trace: None,
+        backend: None,
+        process_list: vec![],
+        kmod_list: vec![],
+        kernel_module_list: vec![],
+        process_index: OnceCell::new(),
+        kmod_index: OnceCell::new(),
+    }, ctx))
+}
+
+/// Find the qemu process, initialize the C context for it, and resolve the offsets
+fn initialize_context(options: &ContextOptions) -> Result<(sys::WinCtx, Offsets, OffsetReport), ContextError> {
+    let mut ctx = sys::WinCtx::default();
 
-    match err {
-        0 => {
-            Ok((WinContext {
-                ctx: ctx,
-                process_list: vec![],
-                kmod_list: vec![],
-            }, ctx))
+    let pid = match &options.vm {
+        Some(selector) => {
+            let vms = discover_vms().into_iter()
+                .filter(|v| options.pid == 0 || v.pid == options.pid)
+                .collect::<Vec<_>>();
+
+            selector.select(&vms).map_err(ContextError::VmSelection)?.pid
         },
-        e => Err((e, match e {
-            -1 => "Failed to find VM process",
-            1 => "Failed to parse memory maps",
-            2 => "Failed to find largest memory map",
-            3 => "CheckLow fail",
-            4 => "FindNTKernel fail",
-            5 => "GenerateExportList fail",
-            6 => "Find PsInitialSystemProcess fail",
-            7 => "Failed to read PsInitialSystemProcess",
-            8 => "GetNTVersion/GetNTBuild fail",
-            9 => "SetupOffsets fail",
-            100 => "Kernel module connection fail",
-            101 => "VM mapping fail",
-            _ => "Unknown error"
-        }))
+        None => options.pid,
+    };
+
+    // Translations of a previous kernel must not be used to find the new one
+    let err = {
+        let _time = reset_translations(options.mem_cache_time);
+        unsafe { sys::InitializeContext(&mut ctx, pid) }
+    };
+
+    let library_offsets = match err {
+        0 => Some(&ctx.offsets),
+        // SetupOffsets is the last step of initialization, thus the rest of the context is valid
+        9 if !options.profiles.matching(ctx.ntVersion, ctx.ntBuild).is_empty() => None,
+        e => return Err(ContextError::from_code(e)),
+    };
+
+    let (offsets, offset_report) = Offsets::resolve(library_offsets, &options.profiles, ctx.ntVersion, ctx.ntBuild);
+
+    if let Err(field) = offsets.to_library(&mut ctx.offsets) {
+        // The C context has been set up in full, and would leak otherwise
+        unsafe {
+            sys::FreeContext(&mut ctx);
+        }
+
+        return Err(ContextError::IncompleteOffsets { field: field });
+    }
+
+    Ok((ctx, offsets, offset_report))
+}
+
+fn error_string(e: i32) -> &'static str {
+    match e {
+        -1 => "Failed to find VM process",
+        1 => "Failed to parse memory maps",
+        2 => "Failed to find largest memory map",
+        3 => "CheckLow fail",
+        4 => "FindNTKernel fail",
+        5 => "GenerateExportList fail",
+        6 => "Find PsInitialSystemProcess fail",
+        7 => "Failed to read PsInitialSystemProcess",
+        8 => "GetNTVersion/GetNTBuild fail",
+        9 => "SetupOffsets fail",
+        10 => "Offset profile is incomplete",
+        11 => "No VM matches the selector",
+        12 => "Multiple VMs match the selector",
+        13 => "No selector, name or UUID to find the restarted VM by",
+        100 => "Kernel module connection fail",
+        101 => "VM mapping fail",
+        _ => "Unknown error"
     }
 }
 
+fn c_kernel_exports(ctx: &sys::WinCtx) -> &[sys::WinExport] {
+    let exports = &ctx.ntExports;
+
+    if exports.list.is_null() {
+        return &[];
+    }
+
+    unsafe { std::slice::from_raw_parts(exports.list, exports.size as usize) }
+}
+
+/// Find an export in the kernel export list of a C context
+pub(crate) fn find_kernel_export(ctx: &sys::WinCtx, name: &str) -> Option<u64> {
+    c_kernel_exports(ctx).iter()
+        .find(|e| unsafe { std::ffi::CStr::from_ptr(e.name) }.to_bytes() == name.as_bytes())
+        .map(|e| e.address)
+}
+
 impl Drop for WinContext {
     fn drop(&mut self) {
-        unsafe {
-            sys::FreeContext(&mut self.ctx);
+        set_vm_mem_cache_time(self.id, None, None);
+
+        // Contexts on a backend have not been set up by the C library
+        if self.backend.is_none() {
+            unsafe {
+                sys::FreeContext(&mut self.ctx);
+            }
         }
     }
 }
 
 impl WinContext {
+    /// Create a context reading guest memory from a backend instead of a running VM
+    ///
+    /// Physical reads, address spaces of the context, and the process list use the backend. Code
+    /// taking a C context, such as `WinProcess` functions, still calls into the vmread library, and
+    /// finds no VM, and `refresh_kmods` lists no modules. The context does not need to be, nor can
+    /// it be recovered.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - source of guest physical memory
+    /// * `state` - kernel location and offsets, i.e. from `state` of a live context
+    pub fn from_backend(backend: Box<dyn PhysicalMemory + Send>, state: &ContextState) -> WinContext {
+        let mut ctx = sys::WinCtx {
+            ntKernel: state.nt_kernel,
+            ntVersion: state.nt_version,
+            ntBuild: state.nt_build,
+            initialProcess: sys::WinProc {
+                process: state.system_process,
+                dirBase: state.system_dir_base,
+                pid: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // The C offsets are informational only, as the library is not used for reading
+        let _ = state.offsets.to_library(&mut ctx.offsets);
+
+        WinContext {
+            ctx: ctx,
+            id: NEXT_CONTEXT_ID.fetch_add(1, Ordering::Relaxed),
+            options: ContextOptions::default(),
+            vm: None,
+            unhealthy: false,
+            recovery_error: None,
+            mem_cache_time: None,
+            offsets: Arc::new(state.offsets),
+            offset_report: OffsetReport::default(),
+            memory_map: OnceCell::from(state.memory_map.clone().map(MemoryMap::new)),
+            trace: None,
+            backend: Some(backend),
+            process_list: vec![],
+            kmod_list: vec![],
+            kernel_module_list: vec![],
+            process_index: OnceCell::new(),
+            kmod_index: OnceCell::new(),
+        }
+    }
+
+    /// Create a context replaying a recorded trace
+    ///
+    /// Fails if the trace has been recorded without the context state.
+    ///
+    /// # Arguments
+    ///
+    /// * `trace` - trace returned by `stop_recording`
+    pub fn replay(trace: MemoryTrace) -> Result<WinContext, TraceError> {
+        let state = trace.context.clone().ok_or(TraceError::InvalidFormat("trace has no context state"))?;
+        Ok(Self::from_backend(Box::new(TraceReplay::new(trace)), &state))
+    }
+
+    /// Get the state needed to recreate the context on a backend
+    pub fn state(&self) -> ContextState {
+        ContextState {
+            nt_kernel: self.ctx.ntKernel,
+            nt_version: self.ctx.ntVersion,
+            nt_build: self.ctx.ntBuild,
+            system_process: self.ctx.initialProcess.process,
+            system_dir_base: self.ctx.initialProcess.dirBase,
+            offsets: *self.offsets,
+            memory_map: self.memory_map().map(|m| m.ranges().to_vec()),
+        }
+    }
+
+    /// Check whether guest memory is read from a backend instead of a running VM
+    pub fn has_backend(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// Set the translation cache validity time of this context
+    ///
+    /// Translation caches are shared by all contexts attached to the same VM, the shortest time
+    /// set among them applies to it. The C library keeps one time for the whole process, so
+    /// threads reading VMs with different times take turns instead of running concurrently.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_cache_time` - validity time in milliseconds, `None` to use the global setting
+    pub fn set_mem_cache_time(&mut self, mem_cache_time: Option<usize>) -> &mut Self {
+        self.mem_cache_time = mem_cache_time;
+        set_vm_mem_cache_time(self.id, Some(&self.ctx.process), mem_cache_time);
+        self
+    }
+
+    /// Get the translation cache validity time of this context
+    pub fn mem_cache_time(&self) -> Option<usize> {
+        self.mem_cache_time
+    }
+
+    /// Check whether the context still describes the running guest
+    ///
+    /// Verifies that the qemu process is still the same one, and that the system process, its page
+    /// table base and the kernel image are still where they were found at initialization.
+    pub fn check_health(&self) -> Result<(), HealthIssue> {
+        if self.backend.is_some() {
+            return Ok(());
+        }
+
+        check_vm_process(self.ctx.process.pid, self.vm.as_ref())?;
+        check_system_process(&self.ctx, &self.offsets)
+    }
+
+    /// Reinitialize the context, i.e. after the guest has rebooted
+    ///
+    /// Kernel discovery and offset setup are run again with the options the context was created
+    /// with. If the qemu process has exited, a restarted one is only looked for if the context was
+    /// created without a fixed pid, by the selector, or else by the name or UUID of the old VM.
+    /// Without any of them, recovery fails with code 13. On failure the context is left as it was.
+    ///
+    /// # Remarks
+    ///
+    /// Process and module lists are cleared, and C contexts returned by `create_context` become
+    /// invalid, `c_ctx` has to be used to get the current one.
+    pub fn recover(&mut self) -> Result<&mut Self, ContextError> {
+        if self.backend.is_some() {
+            return Ok(self);
+        }
+
+        let options = if check_vm_process(self.ctx.process.pid, self.vm.as_ref()).is_ok() {
+            ContextOptions {
+                pid: self.ctx.process.pid,
+                vm: None,
+                ..self.options.clone()
+            }
+        } else if self.options.pid == 0 {
+            // Attaching to whichever qemu process is found first could pick an unrelated VM
+            let selector = match self.options.vm.clone().or_else(|| match &self.vm {
+                Some(VmInfo { uuid: Some(uuid), .. }) => Some(VmSelector::uuid(uuid)),
+                Some(VmInfo { name: Some(name), .. }) => Some(VmSelector::name(name)),
+                _ => None,
+            }) {
+                Some(s) => s,
+                None => return Err(ContextError::from_code(13)),
+            };
+
+            ContextOptions {
+                vm: Some(selector),
+                ..self.options.clone()
+            }
+        } else {
+            return Err(ContextError::from_code(-1));
+        };
+
+        let (ctx, offsets, offset_report) = initialize_context(&options)?;
+
+        unsafe {
+            sys::FreeContext(&mut self.ctx);
+        }
+
+        self.ctx = ctx;
+        set_vm_mem_cache_time(self.id, Some(&ctx.process), self.mem_cache_time);
+        self.vm = discover_vm_in(PROC_ROOT, ctx.process.pid);
+        self.unhealthy = false;
+        self.recovery_error = None;
+        self.offsets = Arc::new(offsets);
+        self.offset_report = offset_report;
+        self.memory_map = OnceCell::new();
+        self.process_list.clear();
+        self.kmod_list.clear();
+        self.kernel_module_list.clear();
+        self.process_index = OnceCell::new();
+        self.kmod_index = OnceCell::new();
+
+        Ok(self)
+    }
+
+    /// Check the context health, and recover it if it is no longer valid
+    ///
+    /// Meant to be called periodically by long-running tools, i.e. through `HealthMonitor`. While
+    /// recovery keeps failing, i.e. because the guest is still booting, it is retried on every
+    /// call, but only changes in the outcome are reported.
+    pub fn poll_health(&mut self) -> Vec<HealthEvent> {
+        let mut events = vec![];
+
+        match self.check_health() {
+            Ok(()) => {
+                self.unhealthy = false;
+                self.recovery_error = None;
+                return events;
+            },
+            Err(issue) => {
+                if !self.unhealthy {
+                    self.unhealthy = true;
+                    events.push(HealthEvent::Unhealthy(issue));
+                }
+            },
+        }
+
+        match self.recover() {
+            Ok(ctx) => events.push(HealthEvent::Recovered {
+                pid: ctx.ctx.process.pid,
+                kernel_base: ctx.ctx.ntKernel,
+                dir_base: ctx.ctx.initialProcess.dirBase,
+            }),
+            Err(e) => {
+                if self.recovery_error.as_ref() != Some(&e) {
+                    self.recovery_error = Some(e.clone());
+                    events.push(HealthEvent::RecoveryFailed(e));
+                }
+            },
+        }
+
+        events
+    }
+
+    /// Get the structure offsets used by this context
+    pub fn offsets(&self) -> &Offsets {
+        &self.offsets
+    }
+
+    /// Get the report describing where each offset has come from
+    pub fn offset_report(&self) -> &OffsetReport {
+        &self.offset_report
+    }
+
+    /// Get the underlying vmread C context
+    pub fn c_ctx(&self) -> &sys::WinCtx {
+        &self.ctx
+    }
+
+    /// Get information about the Windows installation
+    ///
+    /// Returns `None` if `KUSER_SHARED_DATA` could not be read.
+    pub fn windows_info(&self) -> Option<WindowsInfo> {
+        WindowsInfo::new(&self.ctx)
+    }
+
+    /// Get the kernel export list
+    ///
+    /// The list gets generated by vmread during context initialization.
+    pub fn kernel_exports(&self) -> Vec<WinExport> {
+        self.c_kernel_exports().iter().map(|e| WinExport::new(*e)).collect()
+    }
+
+    /// Find a kernel export by name
+    ///
+    /// Returns the virtual address of the export, or `None` if the kernel does not export it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - name of the export, i.e. `"PsLoadedModuleList"`
+    pub fn kernel_export(&self, name: &str) -> Option<u64> {
+        find_kernel_export(&self.ctx, name)
+    }
+
+    fn c_kernel_exports(&self) -> &[sys::WinExport] {
+        c_kernel_exports(&self.ctx)
+    }
+
+    /// Get the guest physical memory map
+    ///
+    /// The map gets detected on first use, and all physical accesses performed through the context
+    /// are validated against it. `None` if it could not be detected, in which case accesses are
+    /// passed to the vmread library unvalidated, unless a map is provided with `set_memory_map`.
+    pub fn memory_map(&self) -> Option<&MemoryMap> {
+        self.memory_map.get_or_init(|| MemoryMap::detect(&self.ctx)).as_ref()
+    }
+
+    /// Detect the guest physical memory map again
+    ///
+    /// The guest memory map is used to tell where RAM is split around the PCI hole, which is only
+    /// known once the guest kernel is running.
+    pub fn refresh_memory_map(&mut self) -> &mut Self {
+        if self.backend.is_none() {
+            self.memory_map = OnceCell::from(MemoryMap::detect(&self.ctx));
+        }
+
+        self
+    }
+
+    /// Replace the guest physical memory map, i.e. with one provided by qemu through QMP
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_map` - new memory map
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) -> &mut Self {
+        self.memory_map = OnceCell::from(Some(memory_map));
+        self
+    }
+
+    /// Attach the memory map, the recording and the backend of the context to a view
+    fn attach<'a>(&'a self, space: AddressSpace<'a>) -> AddressSpace<'a> {
+        let space = match self.memory_map() {
+            Some(map) => space.with_memory_map(map),
+            None => space,
+        };
+
+        let space = match &self.trace {
+            Some(trace) => space.with_trace(trace),
+            None => space,
+        };
+
+        match &self.backend {
+            Some(backend) => space.with_backend(backend.as_ref()),
+            None => space,
+        }
+    }
+
+    /// Get a view of the physical VM memory, validated against the memory map
+    ///
+    /// Reads made through the view are recorded while a recording is running.
+    pub fn physical(&self) -> AddressSpace<'_> {
+        self.attach(AddressSpace::physical(&self.ctx))
+    }
+
+    /// Get a view of the kernel address space, as seen by the system process
+    ///
+    /// Unlike `AddressSpace::kernel`, the view reads from the backend of the context, and its reads
+    /// are recorded while a recording is running.
+    pub fn kernel(&self) -> AddressSpace<'_> {
+        self.attach(AddressSpace::kernel(&self.ctx))
+    }
+
+    /// Get a view of a virtual address space
+    ///
+    /// Unlike `AddressSpace::virt`, the view reads from the backend of the context, and its reads
+    /// are recorded while a recording is running.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir_base` - virtual address translation entry point, i.e. `WinProcess::proc.dirBase`
+    pub fn address_space(&self, dir_base: u64) -> AddressSpace<'_> {
+        self.attach(AddressSpace::virt(&self.ctx, dir_base))
+    }
+
+    /// Start recording physical reads, discarding any recording in progress
+    ///
+    /// Reads made through `read_physical`, `physical`, `kernel` and `address_space`, including
+    /// their page table walks, and the ones of `refresh_processes` are recorded. Reads made by the
+    /// vmread C library are not, see the `trace` module.
+    pub fn start_recording(&mut self) -> &mut Self {
+        self.trace = Some(TraceRecorder::new());
+        self
+    }
+
+    /// Stop recording, returning the reads recorded since `start_recording`
+    ///
+    /// The trace carries the context state, so that it can be replayed with `replay`.
+    pub fn stop_recording(&mut self) -> Option<MemoryTrace> {
+        let trace = self.trace.take()?;
+
+        Some(MemoryTrace {
+            context: Some(self.state()),
+            ..trace.finish()
+        })
+    }
+
+    /// Check whether physical reads are being recorded
+    pub fn is_recording(&self) -> bool {
+        self.trace.is_some()
+    }
+
     /// Get a read/write list for physical VM memory
     ///
     /// If multiple RW operations are to be performed at the same time, it is more efficient to use RWList
-    /// for the task
-    pub fn rwlist(&self) -> RWList {
-        RWList::new(&self.ctx, 0)
+    /// for the task. Operations outside of guest RAM are not performed.
+    pub fn rwlist(&self) -> RWList<'_> {
+        self.physical().rwlist()
     }
 
     /// Read physical VM memory
     ///
-    /// Returns a value of type `T` at a given VM's physical address
+    /// Returns a value of type `T` at a given VM's physical address. The value is zeroed if the
+    /// address is outside of guest RAM.
     ///
     /// # Arguments
     /// 
     /// * `address` - address to read the data from
     pub fn read<T>(self, address: u64) -> T {
-        let mut ret : T = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
+        self.read_physical(address).unwrap_or_else(|_| unsafe { std::mem::zeroed() })
+    }
 
-        unsafe {
-            sys::MemRead(&self.ctx.process, &mut ret as *mut T as u64, address, std::mem::size_of::<T>() as u64);
+    /// Read physical VM memory, reporting failures
+    ///
+    /// Accesses outside of guest RAM are not attempted.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address to read the data from
+    pub fn read_physical<T>(&self, address: u64) -> Result<T, PhysicalAccessError> {
+        let size = std::mem::size_of::<T>() as u64;
+
+        if let Some(map) = self.memory_map() {
+            map.validate(address, size)?;
         }
 
-        ret
+        self.physical().read(address).ok_or(PhysicalAccessError::Failed { address: address, size: size })
     }
 
     /// Write physical VM memory
     ///
-    /// Write `value` into a given VM's physical address
+    /// Write `value` into a given VM's physical address. Nothing is written if the address is
+    /// outside of guest RAM.
     ///
     /// # Arguments
     ///
     /// * `address` - address to write the data to
     /// * `value` - reference to the value that is to be written
     pub fn write<T>(&self, address: u64, value: &T) -> &WinContext {
-        unsafe {
-            sys::MemWrite(&self.ctx.process, value as *const T as u64, address, std::mem::size_of::<T>() as u64);
+        let _ = self.write_physical(address, value);
+        self
+    }
+
+    /// Write physical VM memory, reporting failures
+    ///
+    /// Accesses outside of guest RAM are not attempted. Writes to a backend always fail.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - address to write the data to
+    /// * `value` - reference to the value that is to be written
+    pub fn write_physical<T>(&self, address: u64, value: &T) -> Result<(), PhysicalAccessError> {
+        let size = std::mem::size_of::<T>() as u64;
+
+        if let Some(map) = self.memory_map() {
+            map.validate(address, size)?;
         }
 
-        self
+        if self.backend.is_some() {
+            return Err(PhysicalAccessError::Failed { address: address, size: size });
+        }
+
+        let done = unsafe {
+            sys::MemWrite(&self.ctx.process, value as *const T as u64, address, size)
+        };
+
+        if done as i64 == size as i64 {
+            Ok(())
+        } else {
+            Err(PhysicalAccessError::Failed { address: address, size: size })
+        }
     }
 
     /// Refresh the process list
     pub fn refresh_processes(&mut self) -> &mut Self {
-        let c_list = unsafe { sys::GenerateProcessList(&self.ctx) };
+        self.process_list = self.list_processes();
+        self.process_index = OnceCell::new();
+        self
+    }
 
-        self.process_list.clear();
-        self.process_list.reserve(c_list.size as usize);
+    /// Walk the process list of the guest, without touching `process_list`
+    ///
+    /// Useful for observers, like the `ProcessTracker`, that must not invalidate processes other
+    /// code has looked up in the context.
+    pub fn list_processes(&self) -> Vec<WinProcess> {
+        if self.backend.is_some() || self.trace.is_some() {
+            return self.walk_processes();
+        }
+
+        let c_list = {
+            let _time = enter_vm(&self.ctx.process);
+            unsafe { sys::GenerateProcessList(&self.ctx) }
+        };
 
         let lslice = unsafe { std::slice::from_raw_parts(c_list.list, c_list.size as usize) };
 
-        for i in lslice.iter() {
-            self.process_list.push(WinProcess::new(*i));
-        }
+        let ret = lslice.iter()
+            .map(|i| WinProcess::with_offsets(*i, self.offsets.clone()))
+            .collect();
 
         unsafe {
             sys::FreeProcessList(c_list);
         }
 
-        self
+        ret
+    }
+
+    /// Walk `ActiveProcessLinks` from the system process through the kernel view of the context
+    ///
+    /// Does the same as the C library, for contexts whose reads it can not make, or record.
+    fn walk_processes(&self) -> Vec<WinProcess> {
+        let kernel = self.kernel();
+        let eprocess = &self.offsets.eprocess;
+
+        let (apl, pid, name, dir_base) = match (eprocess.active_process_links, eprocess.unique_process_id, eprocess.image_file_name, self.offsets.kprocess.directory_table_base) {
+            (Some(apl), Some(pid), Some(name), Some(dir_base)) => (apl as u64, pid as u64, name as u64, dir_base as u64),
+            _ => return vec![],
+        };
+
+        let system = self.ctx.initialProcess.process;
+        let mut cur = system;
+        let mut ret = vec![];
+
+        while ret.len() < MAX_PROCESSES {
+            let (proc_pid, proc_dir_base) = match (kernel.read::<u64>(cur + pid), kernel.read::<u64>(cur + dir_base)) {
+                (Some(p), Some(d)) => (p, d),
+                _ => break,
+            };
+
+            // ImageFileName is 15 bytes long, and not terminated if it is filled up
+            let mut image_name = [0u8; 15];
+            kernel.read_arr(cur + name, &mut image_name);
+            let len = image_name.iter().position(|&c| c == 0).unwrap_or(image_name.len());
+            let image_name = CString::new(&image_name[..len]).unwrap_or_default();
+
+            ret.push(WinProcess::with_offsets(sys::WinProc {
+                process: cur,
+                physProcess: kernel.translate(cur).unwrap_or(0),
+                dirBase: proc_dir_base,
+                pid: proc_pid,
+                name: image_name.as_ptr() as *mut _,
+            }, self.offsets.clone()));
+
+            cur = match kernel.read::<u64>(cur + apl) {
+                Some(flink) => flink.wrapping_sub(apl),
+                None => break,
+            };
+
+            if cur == system {
+                break;
+            }
+        }
+
+        ret
     }
 
     /// Refresh the kernel module list
@@ -142,11 +833,23 @@ impl WinContext {
     ///
     /// The kernel modules are not loaded into all processes,
     /// and not all of them are loaded into the system process either.
+    /// `refresh_kernel_modules` resolves the right address space for each module, and should be
+    /// preferred when exports are needed.
     pub fn refresh_kmods(&mut self) -> &mut Self {
-        let c_list = unsafe { sys::GenerateKernelModuleList(&self.ctx) };
+        if self.backend.is_some() {
+            self.kmod_list.clear();
+            self.kmod_index = OnceCell::new();
+            return self;
+        }
+
+        let c_list = {
+            let _time = enter_vm(&self.ctx.process);
+            unsafe { sys::GenerateKernelModuleList(&self.ctx) }
+        };
 
         self.kmod_list.clear();
         self.kmod_list.reserve(c_list.size as usize);
+        self.kmod_index = OnceCell::new();
 
         let lslice = unsafe { std::slice::from_raw_parts(c_list.list, c_list.size as usize) };
 
@@ -160,4 +863,113 @@ impl WinContext {
 
         self
     }
+
+    fn process_index(&self) -> &ProcessIndex {
+        self.process_index.get_or_init(|| ProcessIndex::new(&self.process_list))
+    }
+
+    fn kmod_index(&self) -> &ModuleIndex {
+        self.kmod_index.get_or_init(|| ModuleIndex::new(&self.kmod_list))
+    }
+
+    /// Find a process by its PID
+    ///
+    /// The lookup table is built on the first call after `refresh_processes`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - process ID
+    pub fn process_by_pid(&self, pid: u64) -> Option<&WinProcess> {
+        let pos = checked_position(&self.process_list, self.process_index().by_pid(pid), |p| p.proc.pid == pid)?;
+        self.process_list.get(pos)
+    }
+
+    /// Find a process by its PID, for mutation
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - process ID
+    pub fn process_by_pid_mut(&mut self, pid: u64) -> Option<&mut WinProcess> {
+        let pos = checked_position(&self.process_list, self.process_index().by_pid(pid), |p| p.proc.pid == pid)?;
+        self.process_list.get_mut(pos)
+    }
+
+    /// Find all processes with a given name, case-insensitively
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - process name, i.e. `"svchost.exe"`
+    pub fn processes_by_name<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a WinProcess> + 'a {
+        let index = self.process_index();
+        let positions = checked_positions(&self.process_list, index.len(), index.by_name(name), |p| p.name.eq_ignore_ascii_case(name));
+        positions.into_iter().map(move |i| &self.process_list[i])
+    }
+
+    /// Find the first process with a given name, case-insensitively
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - process name, i.e. `"explorer.exe"`
+    pub fn process_by_name(&self, name: &str) -> Option<&WinProcess> {
+        let index = self.process_index().by_name(name).first().cloned();
+        let pos = checked_position(&self.process_list, index, |p| p.name.eq_ignore_ascii_case(name))?;
+        self.process_list.get(pos)
+    }
+
+    /// Find the first process with a given name, case-insensitively, for mutation
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - process name, i.e. `"explorer.exe"`
+    pub fn process_by_name_mut(&mut self, name: &str) -> Option<&mut WinProcess> {
+        let index = self.process_index().by_name(name).first().cloned();
+        let pos = checked_position(&self.process_list, index, |p| p.name.eq_ignore_ascii_case(name))?;
+        self.process_list.get_mut(pos)
+    }
+
+    /// Find a kernel module by name, case-insensitively
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - module name, i.e. `"ntoskrnl.exe"`
+    pub fn kmod_by_name(&self, name: &str) -> Option<&WinDll> {
+        let pos = checked_position(&self.kmod_list, self.kmod_index().by_name(name), |m| m.name.eq_ignore_ascii_case(name))?;
+        self.kmod_list.get(pos)
+    }
+
+    /// Find the kernel module containing an address
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - kernel virtual address inside of the module image
+    pub fn kmod_by_address(&self, address: u64) -> Option<&WinDll> {
+        let contains = |m: &WinDll| address >= m.info.baseAddress && address - m.info.baseAddress < m.info.sizeOfModule;
+        let pos = checked_position(&self.kmod_list, self.kmod_index().by_address(address), contains)?;
+        self.kmod_list.get(pos)
+    }
+
+    /// Refresh the kernel module list, with full paths and driver objects
+    ///
+    /// The process list gets refreshed as well if it is empty, since it is needed to access
+    /// session-space images.
+    ///
+    /// # Remarks
+    ///
+    /// Exports are not parsed, call `KernelModule::refresh_exports` on the modules of interest.
+    pub fn refresh_kernel_modules(&mut self) -> &mut Self {
+        if self.process_list.is_empty() {
+            self.refresh_processes();
+        }
+
+        self.kernel_module_list = self.kernel_export("PsLoadedModuleList")
+            .and_then(|head| KernelModule::list(&self.ctx, head, &self.offsets, &self.process_list))
+            .unwrap_or_default();
+
+        self
+    }
+
+    /// Get the driver objects registered in the object manager namespace
+    pub fn driver_objects(&self) -> Vec<DriverObject> {
+        driver_objects(&self.ctx, &self.offsets)
+    }
 }
@@ -1,5 +1,13 @@
 
 use crate::win_export::*;
+use crate::address_space::*;
+use crate::ldr::*;
+use crate::pe::*;
+use crate::export_cache::*;
+use crate::index::*;
+use crate::tlb::*;
+use std::cell::OnceCell;
+use std::collections::HashMap;
 
 /// Represents a single Windows process module
 ///
@@ -8,7 +16,10 @@ use crate::win_export::*;
 pub struct WinDll {
     pub name: String,
     pub info: sys::WinModule,
-    pub export_list: Vec<WinExport>
+    pub export_list: Vec<WinExport>,
+    /// Whether the module is a 64-bit image. 32-bit modules are found in WoW64 processes
+    pub is_64bit: bool,
+    export_index: OnceCell<HashMap<String, usize>>,
 }
 
 impl WinDll {
@@ -17,6 +28,8 @@ impl WinDll {
             info: info,
             name: unsafe { std::ffi::CStr::from_ptr(info.name).to_str().unwrap_or("").to_string() },
             export_list: vec![],
+            is_64bit: true,
+            export_index: OnceCell::new(),
         };
         
         ret.info.name = std::ptr::null_mut::<i8>();
@@ -24,19 +37,55 @@ impl WinDll {
         ret
     }
 
+    /// Create a module from a loader entry parsed on the Rust side
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - parsed loader entry
+    /// * `is_64bit` - bitness of the module
+    pub fn from_ldr_entry(entry: &LdrEntry, is_64bit: bool) -> WinDll {
+        WinDll {
+            name: entry.name.clone(),
+            info: sys::WinModule {
+                baseAddress: entry.base,
+                entryPoint: entry.entry_point,
+                sizeOfModule: entry.size,
+                name: std::ptr::null_mut::<i8>(),
+                ..Default::default()
+            },
+            export_list: vec![],
+            is_64bit: is_64bit,
+            export_index: OnceCell::new(),
+        }
+    }
+
     /// Refresh the export list for the module
     ///
     /// # Arguments
     ///
-    /// * `proc` - target process
+    /// * `proc` - target process. For kernel modules, a process the module is mapped in, which is
+    /// the system process (`ctx.initialProcess`) for all but session-space drivers. Prefer
+    /// `KernelModule::refresh_exports`, which picks the right address space automatically
     /// * `ctx` - vmread C context
     pub fn refresh_exports(&mut self, proc: &sys::WinProc, ctx: sys::WinCtx) -> &mut Self {
+        self.export_index = OnceCell::new();
+
+        if !self.is_64bit {
+            // vmread only parses 64-bit images
+            let mem = AddressSpace::virt(&ctx, proc.dirBase);
+            self.export_list = PeHeaders::parse(&mem, self.info.baseAddress)
+                .and_then(|h| h.exports(&mem))
+                .unwrap_or_default();
+            return self;
+        }
+
         let mut c_list = sys::WinExportList {
             list: std::ptr::null_mut(),
             size: 0 as u64
         };
 
         unsafe {
+            let _time = enter_vm(&ctx.process);
             sys::GenerateExportList(&ctx, proc, self.info.baseAddress, &mut c_list);
         }
 
@@ -56,4 +105,43 @@ impl WinDll {
         self
     }
 
+    /// Refresh the export list, reusing exports parsed for an identical image
+    ///
+    /// Falls back to `refresh_exports` if the image could not be parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `proc` - target process
+    /// * `ctx` - vmread C context
+    /// * `cache` - export cache, usually shared by all processes of a context
+    pub fn refresh_exports_cached(&mut self, proc: &sys::WinProc, ctx: &sys::WinCtx, cache: &mut ExportCache) -> &mut Self {
+        let mem = AddressSpace::virt(ctx, proc.dirBase);
+
+        match cache.exports(&mem, &self.name, self.info.baseAddress) {
+            Some(exports) => {
+                self.export_list = exports;
+                self.export_index = OnceCell::new();
+                self
+            },
+            None => self.refresh_exports(proc, *ctx),
+        }
+    }
+
+    /// Find an export by its exact name
+    ///
+    /// The lookup table is built on the first call after the export list is refreshed. If the
+    /// export list has been modified since, the lookup falls back to a linear search.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - export name
+    pub fn export(&self, name: &str) -> Option<u64> {
+        let index = self.export_index
+            .get_or_init(|| self.export_list.iter().enumerate().rev().map(|(i, e)| (e.name.clone(), i)).collect())
+            .get(name)
+            .cloned();
+
+        let pos = checked_position(&self.export_list, index, |e| e.name == name)?;
+        Some(self.export_list[pos].address)
+    }
 }
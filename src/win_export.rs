@@ -1,6 +1,6 @@
 
 /// A structure representing a single Windows module export
-#[derive(Clone, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct WinExport {
     pub name: String,
     pub address: u64,
@@ -0,0 +1,129 @@
+use crate::address_space::*;
+use crate::pe::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Difference between the Windows (1601) and UNIX (1970) epochs, in 100ns intervals
+const FILETIME_UNIX_DIFF: u64 = 116_444_736_000_000_000;
+
+const VS_FFI_SIGNATURE: u32 = 0xfeef_04bd;
+
+/// Maximum amount of resource data to search for the version information
+const MAX_RESOURCE_SCAN: u32 = 0x40_0000;
+
+/// Convert a Windows `FILETIME` value to `SystemTime`
+pub fn filetime_to_system_time(filetime: u64) -> SystemTime {
+    if filetime >= FILETIME_UNIX_DIFF {
+        UNIX_EPOCH + Duration::from_nanos((filetime - FILETIME_UNIX_DIFF).saturating_mul(100))
+    } else {
+        UNIX_EPOCH - Duration::from_nanos((FILETIME_UNIX_DIFF - filetime).saturating_mul(100))
+    }
+}
+
+/// Product type, as stored in `KUSER_SHARED_DATA.NtProductType`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProductType {
+    Workstation,
+    DomainController,
+    Server,
+    Unknown(u32),
+}
+
+impl From<u32> for ProductType {
+    fn from(v: u32) -> ProductType {
+        match v {
+            1 => ProductType::Workstation,
+            2 => ProductType::DomainController,
+            3 => ProductType::Server,
+            v => ProductType::Unknown(v),
+        }
+    }
+}
+
+/// General information about the Windows installation running in the VM
+#[derive(Clone, Debug)]
+pub struct WindowsInfo {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+    /// Update build revision. Taken from the kernel's version resource, which may be paged out
+    pub ubr: Option<u32>,
+    pub product_type: ProductType,
+    /// `KUSER_SHARED_DATA.SuiteMask`, describes the edition of the product
+    pub suite_mask: u32,
+    pub kernel_base: u64,
+    pub kernel_size: u64,
+    pub processor_count: u32,
+    pub boot_time: SystemTime,
+}
+
+impl WindowsInfo {
+    /// Gather the information from the VM
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn new(ctx: &sys::WinCtx) -> Option<WindowsInfo> {
+        let mem = AddressSpace::kernel(ctx);
+        let kernel = PeHeaders::parse(&mem, ctx.ntKernel);
+
+        Some(WindowsInfo {
+            major: mem.read::<u32>(KUSER_SHARED_DATA + 0x26c)?,
+            minor: mem.read::<u32>(KUSER_SHARED_DATA + 0x270)?,
+            build: ctx.ntBuild,
+            ubr: kernel.as_ref().and_then(|k| Self::read_ubr(&mem, k)),
+            product_type: mem.read::<u32>(KUSER_SHARED_DATA + 0x264)?.into(),
+            suite_mask: mem.read::<u32>(KUSER_SHARED_DATA + 0x2d0)?,
+            kernel_base: ctx.ntKernel,
+            kernel_size: kernel.map(|k| k.size_of_image as u64).unwrap_or(0),
+            processor_count: mem.read::<u32>(KUSER_SHARED_DATA + 0x3c0)?,
+            boot_time: Self::read_boot_time(&mem)?,
+        })
+    }
+
+    /// Get a short human readable edition name
+    pub fn edition(&self) -> &'static str {
+        match (self.product_type, self.suite_mask) {
+            (ProductType::Workstation, m) if m & 0x200 != 0 => "Home",
+            (ProductType::Workstation, _) => "Workstation",
+            (ProductType::DomainController, _) => "Domain Controller",
+            (ProductType::Server, m) if m & 0x80 != 0 => "Datacenter Server",
+            (ProductType::Server, m) if m & 0x2 != 0 => "Enterprise Server",
+            (ProductType::Server, _) => "Server",
+            (ProductType::Unknown(_), _) => "Unknown",
+        }
+    }
+
+    fn read_ksystem_time(mem: &AddressSpace, address: u64) -> Option<u64> {
+        // KSYSTEM_TIME is updated without locks, High1Time and High2Time match after a full update
+        for _ in 0..16 {
+            let [low, high1, high2] = mem.read::<[u32; 3]>(address)?;
+            if high1 == high2 {
+                return Some(((high1 as u64) << 32) | low as u64);
+            }
+        }
+
+        None
+    }
+
+    fn read_boot_time(mem: &AddressSpace) -> Option<SystemTime> {
+        let interrupt_time = Self::read_ksystem_time(mem, KUSER_SHARED_DATA + 0x8)?;
+        let system_time = Self::read_ksystem_time(mem, KUSER_SHARED_DATA + 0x14)?;
+        Some(filetime_to_system_time(system_time.saturating_sub(interrupt_time)))
+    }
+
+    fn read_ubr(mem: &AddressSpace, kernel: &PeHeaders) -> Option<u32> {
+        let rsrc = kernel.data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE)?;
+        let mut buf = vec![0u8; rsrc.size.min(MAX_RESOURCE_SCAN) as usize];
+
+        if mem.read_sparse(kernel.base + rsrc.virtual_address as u64, &mut buf) == 0 {
+            return None;
+        }
+
+        // VS_FIXEDFILEINFO is dword aligned, its dwFileVersionLS lower word holds the revision
+        buf.chunks_exact(4)
+            .position(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]) == VS_FFI_SIGNATURE)
+            .map(|i| i * 4 + 12)
+            .filter(|&off| off + 4 <= buf.len())
+            .map(|off| u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) & 0xffff)
+    }
+}
@@ -2,6 +2,18 @@ use crate::sys;
 
 use crate::win_dll::*;
 use crate::rwlist::*;
+use crate::offsets::*;
+use crate::address_space::*;
+use crate::ldr::*;
+use crate::pe::*;
+use crate::process_parameters::*;
+use crate::process_info::*;
+use crate::index::*;
+use crate::export_cache::*;
+use crate::thread::*;
+use crate::tlb::*;
+use std::cell::OnceCell;
+use std::sync::Arc;
 
 /// Structure representing a Windows process
 ///
@@ -12,14 +24,37 @@ pub struct WinProcess {
     pub proc: sys::WinProc,
     pub name: String,
     pub module_list: Vec<WinDll>,
+    /// Structure offsets of the context the process belongs to
+    pub offsets: Arc<Offsets>,
+    module_index: OnceCell<ModuleIndex>,
 }
 
 impl WinProcess {
+    /// Create a process without structure offsets
+    ///
+    /// Functionality based on the offsets, such as the PEB, threads and process information, is
+    /// not available. Use `with_offsets`, or the processes listed by `WinContext`, to have it.
+    ///
+    /// # Arguments
+    ///
+    /// * `proc` - process entry of the C library
     pub fn new(proc: sys::WinProc) -> WinProcess {
+        Self::with_offsets(proc, Arc::new(Offsets::default()))
+    }
+
+    /// Create a process using the structure offsets of its context
+    ///
+    /// # Arguments
+    ///
+    /// * `proc` - process entry of the C library
+    /// * `offsets` - structure offsets, shared with the context
+    pub fn with_offsets(proc: sys::WinProc, offsets: Arc<Offsets>) -> WinProcess {
         let mut ret = WinProcess {
             proc: proc,
             name: unsafe { std::ffi::CStr::from_ptr(proc.name).to_str().unwrap_or("").to_string() },
             module_list: vec![],
+            offsets: offsets,
+            module_index: OnceCell::new(),
         };
 
         ret.proc.name = std::ptr::null_mut::<i8>();
@@ -39,6 +74,15 @@ impl WinProcess {
         RWList::new(&ctx, self.proc.dirBase)
     }
 
+    /// Get a view of the process virtual address space
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn address_space<'a>(&self, ctx: &'a sys::WinCtx) -> AddressSpace<'a> {
+        AddressSpace::virt(ctx, self.proc.dirBase)
+    }
+
     /// Read process virtual memory
     ///
     /// Returns a value of type `T` at a given process' virtual address
@@ -51,6 +95,7 @@ impl WinProcess {
         let mut ret : T = unsafe { std::mem::MaybeUninit::uninit().assume_init() };
 
         unsafe {
+            let _time = enter_vm(&ctx.process);
             sys::VMemRead(&ctx.process, self.proc.dirBase, &mut ret as *mut T as u64, address, std::mem::size_of::<T>() as u64);
         }
 
@@ -68,6 +113,7 @@ impl WinProcess {
     /// * `value` - reference to the value that is to be written
     pub fn write<T>(&self, ctx: &sys::WinCtx, address: u64, value: &T) -> &WinProcess {
         unsafe {
+            let _time = enter_vm(&ctx.process);
             sys::VMemWrite(&ctx.process, self.proc.dirBase, value as *const T as u64, address, std::mem::size_of::<T>() as u64);
         }
 
@@ -79,11 +125,20 @@ impl WinProcess {
     /// # Arguments
     ///
     /// * `ctx` - vmread C context
+    ///
+    /// # Remarks
+    ///
+    /// For WoW64 processes the list contains both the 64-bit modules (ntdll and the WoW64 layer),
+    /// and the 32-bit modules from the 32-bit PEB. Use `WinDll::is_64bit` to tell them apart.
     pub fn refresh_modules(&mut self, ctx: sys::WinCtx) -> &mut Self {
-        let c_list = unsafe { sys::GenerateModuleList(&ctx, &self.proc) };
+        let c_list = {
+            let _time = enter_vm(&ctx.process);
+            unsafe { sys::GenerateModuleList(&ctx, &self.proc) }
+        };
 
         self.module_list.clear();
         self.module_list.reserve(c_list.size as usize);
+        self.module_index = OnceCell::new();
 
         let lslice = unsafe { std::slice::from_raw_parts(c_list.list, c_list.size as usize) };
 
@@ -95,11 +150,200 @@ impl WinProcess {
             sys::FreeModuleList(c_list);
         }
 
+        if let Some(modules32) = self.modules32(&ctx) {
+            let mem = self.address_space(&ctx);
+
+            // The main image is present in both lists, keep only its 32-bit entry
+            self.module_list.retain(|m| !modules32.iter().any(|e| e.base == m.info.baseAddress));
+
+            for m in self.module_list.iter_mut() {
+                m.is_64bit = PeHeaders::parse(&mem, m.info.baseAddress).map(|h| h.is_64bit).unwrap_or(true);
+            }
+
+            self.module_list.extend(modules32.iter().map(|e| WinDll::from_ldr_entry(e, false)));
+        }
+
         self
     }
 
+    /// Refresh the export lists of all modules, reusing exports of images already in the cache
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    /// * `cache` - export cache, usually shared by all processes of a context
+    pub fn refresh_exports_cached(&mut self, ctx: &sys::WinCtx, cache: &mut ExportCache) -> &mut Self {
+        for m in self.module_list.iter_mut() {
+            m.refresh_exports_cached(&self.proc, ctx, cache);
+        }
+
+        self
+    }
+
+    fn module_index(&self) -> &ModuleIndex {
+        self.module_index.get_or_init(|| ModuleIndex::new(&self.module_list))
+    }
+
+    fn module_position(&self, index: Option<usize>, pred: impl Fn(&WinDll) -> bool) -> Option<usize> {
+        checked_position(&self.module_list, index, pred)
+    }
+
+    /// Find a module by name, case-insensitively
+    ///
+    /// In WoW64 processes the 64-bit module takes precedence over the 32-bit one of the same name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - module name, i.e. `"ntdll.dll"`
+    pub fn module_by_name(&self, name: &str) -> Option<&WinDll> {
+        let pos = self.module_position(self.module_index().by_name(name), |m| m.name.eq_ignore_ascii_case(name))?;
+        self.module_list.get(pos)
+    }
+
+    /// Find a module by name, case-insensitively, for mutation
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - module name, i.e. `"ntdll.dll"`
+    pub fn module_by_name_mut(&mut self, name: &str) -> Option<&mut WinDll> {
+        let pos = self.module_position(self.module_index().by_name(name), |m| m.name.eq_ignore_ascii_case(name))?;
+        self.module_list.get_mut(pos)
+    }
+
+    /// Find the module containing an address
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - virtual address inside of the module image
+    pub fn module_by_address(&self, address: u64) -> Option<&WinDll> {
+        let contains = |m: &WinDll| address >= m.info.baseAddress && address - m.info.baseAddress < m.info.sizeOfModule;
+        let pos = self.module_position(self.module_index().by_address(address), contains)?;
+        self.module_list.get(pos)
+    }
+
+    /// Walk the 32-bit module list of a WoW64 process
+    ///
+    /// Returns `None` for native 64-bit processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn modules32(&self, ctx: &sys::WinCtx) -> Option<Vec<LdrEntry>> {
+        let peb32 = self.peb32(ctx)?;
+        walk_peb_modules(&self.address_space(ctx), peb32, false, &self.offsets)
+    }
+
+    /// Get the virtual address of the process' 64-bit PEB
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn peb_address(&self, ctx: &sys::WinCtx) -> Option<u64> {
+        let peb = AddressSpace::kernel(ctx).read::<u64>(self.proc.process + self.offsets.eprocess.peb? as u64)?;
+        Some(peb).filter(|&p| p != 0)
+    }
+
+    /// Get the virtual address of the 32-bit PEB of a WoW64 process
+    ///
+    /// Returns `None` if the process is not running under WoW64, or `_EPROCESS.WoW64Process`
+    /// offset is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn peb32(&self, ctx: &sys::WinCtx) -> Option<u64> {
+        let kernel = AddressSpace::kernel(ctx);
+        let wow64 = kernel.read::<u64>(self.proc.process + self.offsets.eprocess.wow64_process? as u64)?;
+
+        if wow64 == 0 {
+            return None;
+        }
+
+        // Starting with Windows 10 1607, the field points to _EWOW64PROCESS, which starts with the PEB
+        let peb32 = if ctx.ntBuild >= 14393 {
+            kernel.read::<u64>(wow64)?
+        } else {
+            wow64
+        };
+
+        Some(peb32).filter(|&p| p != 0)
+    }
+
+    /// Read extended process metadata from `_EPROCESS`
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn info(&self, ctx: &sys::WinCtx) -> ProcessInfo {
+        ProcessInfo::read(ctx, &self.proc, &self.offsets, self.is_wow64(ctx))
+    }
+
+    /// Get the ID of the session the process belongs to
+    ///
+    /// Returns `None` for processes outside of any session, like the system process.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn session_id(&self, ctx: &sys::WinCtx) -> Option<u32> {
+        ProcessInfo::read_session_id(&AddressSpace::kernel(ctx), &self.proc, &self.offsets)
+    }
+
+    /// List the threads of the process
+    ///
+    /// Returns an empty list if the thread list offsets are unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn threads(&self, ctx: &sys::WinCtx) -> Vec<WinThread> {
+        WinThread::list(ctx, &self.proc, &self.offsets).unwrap_or_default()
+    }
+
+    /// Read the process parameters from the 64-bit PEB
+    ///
+    /// Gives the full image path, command line, current directory, window title and environment.
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn process_parameters(&self, ctx: &sys::WinCtx) -> Option<ProcessParameters> {
+        self.read_process_parameters(ctx, self.peb_address(ctx)?, true)
+    }
+
+    /// Read the process parameters from the 32-bit PEB of a WoW64 process
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn process_parameters32(&self, ctx: &sys::WinCtx) -> Option<ProcessParameters> {
+        self.read_process_parameters(ctx, self.peb32(ctx)?, false)
+    }
+
+    fn read_process_parameters(&self, ctx: &sys::WinCtx, peb: u64, is_64bit: bool) -> Option<ProcessParameters> {
+        let mem = self.address_space(ctx);
+        let offset = if is_64bit { self.offsets.peb.process_parameters } else { self.offsets.peb32.process_parameters }?;
+        let params = mem.read_ptr(peb + offset as u64, is_64bit)?;
+
+        if params == 0 {
+            return None;
+        }
+
+        ProcessParameters::read(&mem, params, is_64bit, &self.offsets)
+    }
+
+    /// Check whether the process is a 32-bit process running under WoW64
+    ///
+    /// # Arguments
+    ///
+    /// * `ctx` - vmread C context
+    pub fn is_wow64(&self, ctx: &sys::WinCtx) -> bool {
+        self.peb32(ctx).is_some()
+    }
+
     /// Get process PEB
     pub fn get_peb(self, ctx: sys::WinCtx) -> sys::_PEB {
+        let _time = enter_vm(&ctx.process);
         unsafe { sys::GetPeb(&ctx, &self.proc) }
     }
 }